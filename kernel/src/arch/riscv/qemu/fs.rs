@@ -25,6 +25,10 @@ pub const ROOTDEV: u32 = 1;
 pub const ROOTIPATH: [u8; 2] = [b'/', 0];
 /// size of file system in blocks
 pub const FSSIZE: usize = 1000; 
+/// first block of the swap area, right after the file system
+pub const SWAPSTART: u32 = FSSIZE as u32;
+/// size of the swap area in pages
+pub const NSWAP: usize = 1024;
 
 pub const ROOTINUM: u32 = 1;
 
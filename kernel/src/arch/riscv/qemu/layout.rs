@@ -31,6 +31,7 @@ pub const VIRTIO0_IRQ: u32 = 1;
 pub const CLINT: usize = 0x2000000;
 pub const CLINT_MTIME: usize = CLINT + 0xBFF8;
 pub const CLINT_MTIMECMP: usize = CLINT + 0x4000;
+pub const CLINT_MSIP: usize = CLINT;
 
 // qemu puts platform-level interrupt controller (PLIC) here.
 pub const PLIC_BASE: usize = 0x0c000000;
@@ -51,6 +52,7 @@ pub const VIRT_TEST:usize = 0x100000;
 ///   fixed-size stack
 ///   expandable heap
 ///   ...
+///   trapframes of threads created by clone(), one page per proc slot
 ///   TRAPFRAME (p->trapframe, used by the trampoline)
 ///   TRAMPOLINE (the same page as in the kernel)
 
@@ -68,18 +70,31 @@ pub const PGSIZE: usize = 4096; // bytes per page
 pub const PGSHIFT: usize = 12; // bits of offset within a page
 pub const PGMASKLEN: usize = 9;
 pub const PGMASK: usize = 0x1FF;
+pub const MEGAPAGE_SIZE: usize = PGSIZE << PGMASKLEN; // bytes per level-1 leaf, 2 MiB
 
 
+/// Levels of page-table pages, 3 for Sv39 and 4 for Sv48. 
+#[cfg(not(feature = "sv48"))]
+pub const PT_LEVELS: usize = 3;
+#[cfg(feature = "sv48")]
+pub const PT_LEVELS: usize = 4;
+
 /// One beyond the highest possible virtual address.
 /// MAXVA is actually one bit less than the max allowed by
-/// Sv39, to avoid having to sign-extend virtual addresses
+/// Sv39 (or Sv48), to avoid having to sign-extend virtual addresses
 /// that have the high bit set.
-pub const MAXVA: usize =  1 << (9 + 9 + 9 + 12 - 1); 
+pub const MAXVA: usize =  1 << (PGMASKLEN * PT_LEVELS + PGSHIFT - 1); 
 
 // map the trampoline page to the highest address,
 // in both user and kernel space.
 pub const TRAMPOLINE: usize = MAXVA - PGSIZE;
 pub const TRAPFRAME: usize = TRAMPOLINE - PGSIZE;
 
+/// Where a thread in proc slot index maps its trapframe,
+/// since TRAPFRAME is taken by the owner of the address space. 
+pub const fn thread_trapframe(index: usize) -> usize {
+    TRAPFRAME - (index + 1) * PGSIZE
+}
+
 
 
@@ -1,11 +1,45 @@
-pub const NPROC:usize = 64; // maximum number of processes
+pub const NPROC:usize = 512; // maximum number of processes
+pub const PROC_CHUNK:usize = 16; // process slots allocated at a time
+pub const NKSTACK:usize = 1024; // kernel stack slots below the trampoline, see kstack.rs
+pub const NWAITQ:usize = 64; // number of wait queues sleeping channels hash to
+pub const PID_MAX:usize = 32768; // pids are allocated from 1 to PID_MAX - 1
 pub const NCPU:usize = 8; // maximum number of CPUs
+pub const ALL_CPUS:usize = (1 << NCPU) - 1; // affinity mask allowing every CPU
 pub const NDEV:usize = 10;  // maximum major device number
 pub const MAXARG:usize  = 32;  // max exec arguments
 pub const MAXPATH:usize = 128;   // maximum file path name
+pub const NPRIO:usize = 8; // number of scheduling priority levels
+pub const TIME_SLICE:usize = 4; // ticks a process runs before the timer preempts it
+pub const TICK_CYCLES:u64 = 1000000; // cycles between timer interrupts, about 1/10th second in qemu
+pub const DEFAULT_PRIORITY:u8 = 4; // priority of a new process, 0 is the highest
+
+// interactive bonus, raising the priority of processes woken after a long sleep
+pub const BONUS_SLEEP_TICKS:usize = 2; // ticks of sleep that earn one level of bonus
+pub const MAX_BONUS:u8 = 3; // most levels a process can be raised by
+
+// multi-level feedback queue, one queue per priority level
+pub const MLFQ_SLICE:[usize; NPRIO] = [1, 2, 2, 4, 4, 8, 8, 16]; // time slice of each level in ticks
+pub const MLFQ_BOOST_INTERVAL:usize = 100; // ticks between two priority boosts
+
+pub const DEFAULT_TICKETS:usize = 1; // lottery tickets of a new process
+pub const MAX_TICKETS:usize = 10000; // most lottery tickets of one process, so the pool total can't overflow
+
+pub const NRTPRIO:usize = 8; // number of real-time (SCHED_FIFO) priority levels
+
+pub const NVMA:usize = 16; // maximum number of mmap() regions per address space
+pub const NSHM:usize = 32; // maximum number of shared memory segments
+pub const SHM_MAXPAGES:usize = 64; // maximum pages of a shared memory segment
+pub const PGACCESS_MAX:usize = 512; // most pages pgaccess() reports on in one call
+
+// address space layout randomization at exec, offsets are in pages
+pub const ASLR_STACK_PAGES:usize = 256; // most pages of gap below the user stack
+pub const ASLR_MMAP_PAGES:usize = 4096; // most pages mmap() regions start below MMAP_TOP
 
 // min leaf size for buddy system
 pub const LEAF_SIZE:usize = 16;
 
 // max memory size for buddy system
 pub const MAX_ALIGNMENT:usize = 4096;
+
+// largest block kalloc_pages() hands out is 2^MAX_ORDER pages
+pub const MAX_ORDER:usize = 10;
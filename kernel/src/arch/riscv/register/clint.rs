@@ -1,18 +1,18 @@
-use core::convert::Into;
-use core::ptr;
-
-use crate::arch::riscv::qemu::layout::{CLINT_MTIME, CLINT_MTIMECMP, CLINT};
+use crate::arch::riscv::qemu::layout::{CLINT_MTIME, CLINT_MTIMECMP, CLINT, CLINT_MSIP};
+use crate::memory::mmio::Mmio;
 
 // core local interruptor (CLINT), which contains the timer.
+pub const CLINT_REGS: Mmio<u64> = unsafe{ Mmio::new(CLINT, 0x10000) };
+// the software interrupt pending registers are 32 bits.
+const CLINT_MSIP_REGS: Mmio<u32> = unsafe{ Mmio::new(CLINT, 0x10000) };
 
 #[inline]
 unsafe fn read_mtime() -> u64 {
-    ptr::read_volatile(Into::<usize>::into(CLINT_MTIME) as *const u64)
+    CLINT_REGS.read(CLINT_MTIME - CLINT)
 }
 
 unsafe fn write_mtimecmp(mhartid:usize, value: u64) {
-    let offset = Into::<usize>::into(CLINT_MTIMECMP) + 8*mhartid;
-    ptr::write_volatile(offset as *mut u64, value);
+    CLINT_REGS.write(CLINT_MTIMECMP - CLINT + 8*mhartid, value);
 }
 
 pub unsafe fn add_mtimecmp(mhartid:usize, interval:u64){
@@ -26,5 +26,17 @@ pub fn count_mtiecmp(mhartid:usize) -> usize{
     ret
 }
 
+/// Address of the machine software interrupt pending register of a hart. 
+pub fn count_msip(mhartid:usize) -> usize {
+    CLINT_MSIP + 4*mhartid
+}
 
+/// Raise a machine software interrupt on a hart. 
+pub unsafe fn send_soft(mhartid:usize) {
+    CLINT_MSIP_REGS.write(count_msip(mhartid) - CLINT, 1);
+}
 
+/// Whether the software interrupt of a hart has not been taken yet. 
+pub unsafe fn soft_pending(mhartid:usize) -> bool {
+    CLINT_MSIP_REGS.read(count_msip(mhartid) - CLINT) != 0
+}
@@ -19,10 +19,15 @@ pub mod stval;
 pub mod mcounteren;
 pub mod time;
 pub mod sp;
-pub mod ra;
 pub mod clint;
 pub mod pmp;
 
+#[inline]
+// wait for interrupt, stall the hart until an interrupt is pending.
+pub unsafe fn wfi(){
+    core::arch::asm!("wfi");
+}
+
 #[inline]
 // flush the TLB.
 pub unsafe fn sfence_vma(){
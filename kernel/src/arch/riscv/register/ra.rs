@@ -1,6 +0,0 @@
-#[inline]
-pub unsafe fn read() -> usize {
-    let ret:usize;
-    core::arch::asm!("csrr {}, ra",out(reg)ret);
-    ret
-}
\ No newline at end of file
@@ -1,5 +1,19 @@
 // use riscv's sv39 page table scheme.
 pub const SATP_SV39:usize =  8 << 60;
+// or sv48 with the sv48 feature.
+pub const SATP_SV48:usize =  9 << 60;
+
+// physical page number of the root page table.
+pub const SATP_PPN:usize = (1 << 44) - 1;
+
+// address space identifier, tags the TLB entries of a user page table.
+pub const SATP_ASID_SHIFT:usize = 44;
+pub const ASID_MAX:usize = (1 << 16) - 1;
+
+#[cfg(not(feature = "sv48"))]
+pub const SATP_MODE:usize = SATP_SV39;
+#[cfg(feature = "sv48")]
+pub const SATP_MODE:usize = SATP_SV48;
 
 // supervisor address translation and protection;
 // holds the address of the page table.
@@ -1,6 +1,7 @@
 use core::num::Wrapping;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::{lock::spinlock::Spinlock, memory::{copy_to_kernel, copy_from_kernel}, process::{CPU_MANAGER, PROC_MANAGER}};
+use crate::{lock::{spinlock::Spinlock, lockstat, mpsc::MpscQueue}, memory::{copy_to_kernel, copy_from_kernel, KERNEL_HEAP}, process::{CPU_MANAGER, PROC_MANAGER, kthread}};
 use super::uart::{UART, putc_sync, uart_get, uart_put};
 
 static CONSOLE: Spinlock<Console> = Spinlock::new(Console::new(), "console");
@@ -24,6 +25,9 @@ pub const CTRL_DEL: u8 = 0x7f;
 /// for debug, print process list
 pub const CTRL_PRINT_PROCESS: u8 = 0x10;
 
+/// for debug, print lock statistics
+pub const CTRL_PRINT_LOCKS: u8 = 0x0C;
+
 /// backspace the whole line
 // TODO
 pub const CTRL_BS_LINE: u8 = 0x15;
@@ -98,8 +102,12 @@ pub(super) fn console_read(
             break;
         }
 
-        // copy to user/kernel space memory
-        if copy_from_kernel(is_user, dst, &c as *const u8, 1).is_err() {
+        // copy to user/kernel space memory, 
+        // without the lock since it may sleep to swap a page in
+        drop(console);
+        let copied = copy_from_kernel(is_user, dst, &c as *const u8, 1);
+        console = CONSOLE.acquire();
+        if copied.is_err() {
             break;
         }
 
@@ -135,20 +143,71 @@ pub(super) fn console_write(
 }
 
 
-/// The console interrupt handler. 
+/// Input from the uart interrupt, for the console thread. 
+static RX_QUEUE: MpscQueue<u8, INPUT_BUF> = MpscQueue::new();
+/// Held to wake the console thread, and by the thread from 
+/// seeing the queue empty until it sleeps, so no wakeup is lost. 
+/// The queue itself is used without it. 
+static RX_LOCK: Spinlock<()> = Spinlock::new((), "console rx");
+
+/// Called by the uart interrupt handler with an input byte. 
+/// The byte is dropped if the console thread is that far behind. 
+pub(super) fn console_rx(c: u8) {
+    if RX_QUEUE.push(c).is_err() {
+        return
+    }
+    let guard = RX_LOCK.acquire();
+    unsafe{ PROC_MANAGER.wake_up(rx_channel()); }
+    drop(guard);
+}
+
+fn rx_channel() -> usize {
+    &RX_QUEUE as *const _ as usize
+}
+
+/// The console thread, handles the input bytes queued by 
+/// the interrupt handler, outside of interrupt context. 
+fn console_thread(_: usize) {
+    let p = unsafe{ CPU_MANAGER.myproc() }.expect("console thread: no process");
+    loop {
+        while let Some(c) = RX_QUEUE.pop() {
+            console_intr(c);
+        }
+        let guard = RX_LOCK.acquire();
+        if RX_QUEUE.is_empty() {
+            p.sleep(rx_channel(), guard);
+        } else {
+            drop(guard);
+        }
+    }
+}
+
+/// Start the console thread. 
+/// Must be called after the first user process is created. 
+pub fn console_start() {
+    kthread::spawn(console_thread, 0, "console").expect("console_start: no process slot");
+}
+
+/// The console input handler, run by the console thread. 
 /// The normal routine is: 
 /// 1. user input;
-/// 2. uart handler interrupt;
-/// 3. console handle interrupt. 
+/// 2. uart handler interrupt queues it;
+/// 3. console thread handles it. 
 /// 4. console echo back input or do extra controlling. 
-pub(super) fn console_intr(c: u8) {
+fn console_intr(c: u8) {
     let mut console = CONSOLE.acquire();
 
     match c {
         CTRL_PRINT_PROCESS => {
             unsafe {
-                PROC_MANAGER.proc_dump();
+                PROC_MANAGER.dump();
+                KERNEL_HEAP.dump();
             }
+            lockstat::dump();
+        },
+
+        CTRL_PRINT_LOCKS => {
+            lockstat::dump();
         },
 
         CTRL_BS_LINE => {
@@ -186,14 +245,17 @@ pub(super) fn console_intr(c: u8) {
     }
 }
 
-use core::sync::atomic::AtomicBool;
 pub(crate) static PANICKED: AtomicBool = AtomicBool::new(false);
 
 /// must be called only once in rmain.rs:rust_main
 pub unsafe fn console_init() {
-    use crate::fs::DEVICE_LIST;
-    use crate::arch::riscv::qemu::devices::CONSOLE;
     super::uart::uart_init();
-    DEVICE_LIST.table[CONSOLE].write = console_write as *const u8;
-    DEVICE_LIST.table[CONSOLE].read = console_read as *const u8;
+}
+
+/// Make the console the CONSOLE device of the file system. 
+/// Called once the kernel heap is set up. 
+pub fn console_register() {
+    use crate::fs::register_device;
+    use crate::arch::riscv::qemu::devices::CONSOLE;
+    register_device(CONSOLE, console_read, console_write);
 }
\ No newline at end of file
@@ -1,45 +1,58 @@
-use core::ptr;
+//! The riscv Platform Level Interrupt Controller (PLIC).
+//!
+//! Routes the interrupts of the devices in DEVICE_IRQS to the 
+//! S-mode of every hart. A hart claims an interrupt in trap.rs, 
+//! calls the driver, and completes it to get the next one. 
 
 use crate::{arch::riscv::qemu::layout::{PLIC_BASE, UART0_IRQ, VIRTIO0_IRQ}, process::{cpu, cpuid}};
+use crate::memory::mmio::Mmio;
 
-const PLIC_PRIORITY: usize = PLIC_BASE;
-const PLIC_PENDING: usize = PLIC_BASE + 0x1000;
+pub const PLIC_REGS: Mmio<u32> = unsafe{ Mmio::new(PLIC_BASE, 0x400000) };
+
+/// The interrupts the kernel has drivers for. 
+const DEVICE_IRQS: [u32; 2] = [UART0_IRQ, VIRTIO0_IRQ];
+
+// register offsets
+const PLIC_PRIORITY: usize = 0;
+const PLIC_PENDING: usize = 0x1000;
 
 fn PLIC_MENABLE(hart_id: usize) -> usize {
-    PLIC_BASE + 0x2000 + hart_id * 0x100
+    0x2000 + hart_id * 0x100
 }
 
 fn PLIC_SENABLE(hart_id: usize) -> usize {
-    PLIC_BASE + 0x2080 + hart_id * 0x100
+    0x2080 + hart_id * 0x100
 }
 
 fn PLIC_MPRIORITY(hart_id: usize) -> usize {
-    PLIC_BASE + 0x200000 + hart_id * 0x2000
+    0x200000 + hart_id * 0x2000
 }
 
 fn PLIC_SPRIORITY(hart_id: usize) -> usize {
-    PLIC_BASE + 0x201000 + hart_id * 0x2000
+    0x201000 + hart_id * 0x2000
 }
 
 fn PLIC_MCLAIM(hart_id: usize) -> usize {
-    PLIC_BASE + 0x200004 + hart_id * 0x2000
+    0x200004 + hart_id * 0x2000
 }
 
 fn PLIC_SCLAIM(hart_id: usize) -> usize {
-    PLIC_BASE + 0x201004 + hart_id * 0x2000
+    0x201004 + hart_id * 0x2000
 }
 
 pub fn plic_init() {
     // set desired IRQ priorities non-zero (otherwise disable)
-    write(PLIC_BASE + (UART0_IRQ * 4) as usize, 1);
-    write(PLIC_BASE + (VIRTIO0_IRQ * 4) as usize, 1);
+    for irq in DEVICE_IRQS {
+        write(PLIC_PRIORITY + (irq * 4) as usize, 1);
+    }
 }
 
 pub fn plic_init_hart() {
     let hart_id = unsafe{ cpuid() };
 
-    // Set UART's enable bit for this hart's S-mode. 
-    write(PLIC_SENABLE(hart_id), (1 << UART0_IRQ) | (1 << VIRTIO0_IRQ));
+    // Set the devices' enable bits for this hart's S-mode. 
+    let enable = DEVICE_IRQS.iter().fold(0, |mask, irq| mask | (1 << irq));
+    write(PLIC_SENABLE(hart_id), enable);
 
     // Set this hart's S-mode pirority threshold to 0. 
     write(PLIC_SPRIORITY(hart_id), 0);
@@ -67,14 +80,10 @@ pub fn plic_complete(interrupt: u32) {
 }
 
 
-fn write(addr: usize, val: u32) {
-    unsafe {
-        ptr::write(addr as *mut u32, val);
-    }
+fn write(reg: usize, val: u32) {
+    PLIC_REGS.write(reg, val);
 }
 
-fn read(addr: usize) -> u32 {
-    unsafe {
-        ptr::read(addr as *const u32)
-    }
+fn read(reg: usize) -> u32 {
+    PLIC_REGS.read(reg)
 }
\ No newline at end of file
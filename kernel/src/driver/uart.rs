@@ -1,14 +1,14 @@
 use core::num::Wrapping;
-use core::ptr;
 use core::convert::{ Into, TryInto };
 use core::fmt::{self, Write, Error};
 use core::sync::atomic::Ordering;
 
-use crate::process::{CPU_MANAGER, PROC_MANAGER, pop_off, push_off};
-use crate::{arch::riscv::qemu::layout::UART0, println};
+use crate::process::{CPU_MANAGER, PROC_MANAGER, IntrGuard};
+use crate::{arch::riscv::qemu::layout::{UART0, PGSIZE}, println};
+use crate::memory::mmio::Mmio;
 use crate::lock::spinlock::*;
 
-use super::console::console_intr;
+use super::console::console_rx;
 use super::console::PANICKED;
 
 /// receive holding register (for input bytes)
@@ -35,7 +35,7 @@ const LCR_BAUD_LATCH: usize = 1 << 7; // special mode to set baud rate
 const LSR_RX_READY: usize = 1 << 0; // input is waiting to be read from RHR
 const LSR_TX_IDLE: usize = 1 << 5; // THR can accept another character to send
 
-const UART_BASE_ADDR: usize = UART0;
+pub const UART_REGS: Mmio<u8> = unsafe{ Mmio::new(UART0, PGSIZE) };
 
 const UART_BUF_SIZE:usize = 32;
 pub static UART: Spinlock<Uart> = Spinlock::new(Uart::new(), "uart");
@@ -69,26 +69,26 @@ impl Uart {
     /// init uart device
     pub fn init(&mut self) {
         // disable interrupts
-        write_reg(UART_BASE_ADDR + IER, 0x00);
+        write_reg(IER, 0x00);
 
         // special mode to set baud rate. 
-        write_reg(UART_BASE_ADDR + LCR, LCR_BAUD_LATCH as u8);
+        write_reg(LCR, LCR_BAUD_LATCH as u8);
 
         // LSB for baud rate of 38.4K
-        write_reg(UART_BASE_ADDR, 0x03);
+        write_reg(0, 0x03);
 
         // MSB for baud rate of 38.4k 
-        write_reg(UART_BASE_ADDR + 1, 0x00);
+        write_reg(1, 0x00);
 
         // leave set-baud mode, 
         // and set word length to 8 bits, no parity. 
-        write_reg(UART_BASE_ADDR + LCR, LCR_EIGHT_BITS as u8);
+        write_reg(LCR, LCR_EIGHT_BITS as u8);
 
         // reset and enable FIFOs. 
-        write_reg(UART_BASE_ADDR + FCR, FCR_FIFO_ENABLE as u8 | FCR_FIFO_CLEAR as u8);
+        write_reg(FCR, FCR_FIFO_ENABLE as u8 | FCR_FIFO_CLEAR as u8);
 
         // enable transmit and receive interrupts. 
-        write_reg(UART_BASE_ADDR + IER, IER_TX_ENABLE as u8 | IER_RX_ENABLE as u8);
+        write_reg(IER, IER_TX_ENABLE as u8 | IER_RX_ENABLE as u8);
     }
 
     /// Add a chacter to the output buffer and tell the
@@ -98,30 +98,24 @@ impl Uart {
     /// it's only suitable for use
     /// by write()
     pub fn put(&mut self, c: u8) {
-        let ptr = UART_BASE_ADDR as *mut u8;
         loop {
             // write until previous data is flushed
-            if unsafe{ ptr.add(5).read_volatile() } & (1 << 5) != 0 {
+            if read_reg(LSR) & LSR_TX_IDLE as u8 != 0 {
                 break;
             }
         }
-        unsafe {
-            // write data
-            ptr.add(0).write_volatile(c);
-        }
+        // write data
+        write_reg(THR, c);
     }
 
     /// get a chacter from uart
     pub fn get(&mut self) -> Option<u8> {
-        let ptr = UART_BASE_ADDR as *mut u8;
-        unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                // DR bit is 0, meaning no data
-                None
-            }else {
-                // DR bit is 1, meaning data
-                Some(ptr.add(0).read_volatile())
-            }
+        if read_reg(LSR) & LSR_RX_READY as u8 == 0 {
+            // DR bit is 0, meaning no data
+            None
+        }else {
+            // DR bit is 1, meaning data
+            Some(read_reg(RHR))
         }
     }
 
@@ -135,7 +129,7 @@ impl Uart {
             unsafe{
                 PROC_MANAGER.wake_up(&self.read_index as *const Wrapping<_> as usize);
             }
-            write_reg(UART_BASE_ADDR + THR, c);
+            write_reg(THR, c);
         }
     }
 
@@ -159,12 +153,12 @@ impl Spinlock<Uart> {
         loop {
             // read and process incoming characters. 
             let c: u8;
-            if read_reg(UART_BASE_ADDR + LSR) & 1 > 0 {
-                c = read_reg(UART_BASE_ADDR + RHR)
+            if read_reg(LSR) & 1 > 0 {
+                c = read_reg(RHR)
             } else {
                 break;
             }
-            console_intr(c);
+            console_rx(c);
         }
         // transmit
         self.acquire().transmit();
@@ -200,18 +194,11 @@ impl Spinlock<Uart> {
 }
 
 pub fn uart_get() -> u8 {
-    let c: u8;
-    let mut uart_guard = UART.acquire();
-    c = uart_guard.get().expect("Fail to get char");
-    drop(uart_guard);
-    c
+    UART.with(|uart| uart.get().expect("Fail to get char"))
 }
 
 pub fn uart_put(c: u8) {
-    let mut uart_guard = UART.acquire();
-
-    uart_guard.put(c);
-    drop(uart_guard);
+    UART.with(|uart| uart.put(c));
 }
 
 
@@ -241,32 +228,27 @@ pub fn uart_intr() {
     }
 }
 
-fn write_reg(addr: usize, val: u8) {
-    unsafe{
-        ptr::write(addr as *mut u8, val);
-    }
+fn write_reg(reg: usize, val: u8) {
+    UART_REGS.write(reg, val);
 }
 
-fn read_reg(addr: usize) -> u8 {
-    unsafe {
-        ptr::read(addr as *const u8)
-    }
+fn read_reg(reg: usize) -> u8 {
+    UART_REGS.read(reg)
 }
 
 /// Read the LSR to see if it is able to transmit data. 
 fn idle() -> bool {
-    read_reg(UART_BASE_ADDR + LSR) & (1 << 5) > 0
+    read_reg(LSR) & (1 << 5) > 0
 }
 
 /// Non-blocking write to uart device. 
 pub(super) fn putc_sync(c: u8) {
-    push_off();
+    let _intr = IntrGuard::new();
     if PANICKED.load(Ordering::Relaxed) {
         loop{}
     }
     while !idle() {}
-    write_reg(UART_BASE_ADDR + THR, c);
-    pop_off();
+    write_reg(THR, c);
 }
 
 
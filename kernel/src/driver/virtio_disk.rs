@@ -12,7 +12,6 @@ use array_macro::array;
 use core::convert::TryFrom;
 use core::option::Option;
 use core::sync::atomic::{fence, Ordering};
-use core::ptr;
 use core::convert::TryInto;
 
 use crate::arch::riscv::qemu::layout::{PGSHIFT, PGSIZE, VIRTIO0};
@@ -20,10 +19,17 @@ use crate::arch::riscv::qemu::fs::BSIZE;
 use crate::arch::riscv::qemu::virtio::*;
 use crate::fs::Buf;
 use crate::lock::spinlock::Spinlock;
+use crate::lock::semaphore::Semaphore;
 use crate::process::{PROC_MANAGER, CPU_MANAGER};
+use crate::memory::mmio::Mmio;
+
+pub const VIRTIO_REGS: Mmio<u32> = unsafe{ Mmio::new(VIRTIO0, PGSIZE) };
 
 pub static DISK: Spinlock<Disk> = Spinlock::new(Disk::new(), "virtio_disk");
 
+/// Requests in flight, each takes three descriptors. 
+static DISK_REQUESTS: Semaphore = Semaphore::new(NUM / 3);
+
 #[repr(C, align(4096))]
 pub struct Disk {
     // a page
@@ -127,7 +133,7 @@ impl Disk {
                 Some(ix) => idx[i] = ix,
                 None => {
                     for j in 0..i {
-                        self.free_desc(j);
+                        self.free_desc(idx[j]);
                     }
                     return false;
                 }
@@ -158,9 +164,6 @@ impl Disk {
         self.desc[i].flags = 0;
         self.desc[i].next = 0;
         self.free[i] = true;
-        unsafe {
-            PROC_MANAGER.wake_up(&self.free[0] as *const bool as usize);
-        }
     }
 
     /// Free a chain of descriptors.
@@ -180,10 +183,8 @@ impl Disk {
     /// Called by the trap/interrupt handler in the kernel 
     /// when the disk sends an interrupt.
     pub fn intr(&mut self) {
-        unsafe {
-            let intr_stat = read(VIRTIO_MMIO_INTERRUPT_STATUS);
-            write(VIRTIO_MMIO_INTERRUPT_ACK, intr_stat & 0x3);
-        }
+        let intr_stat = read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        write(VIRTIO_MMIO_INTERRUPT_ACK, intr_stat & 0x3);
 
         fence(Ordering::SeqCst);
 
@@ -210,19 +211,15 @@ impl Disk {
 impl Spinlock<Disk> {
     /// Read or write a certain Buf, which is returned after the op is done. 
     pub fn rw(&self, buf: &mut Buf<'_>, writing: bool) {
+        // Wait for room for the request, 
+        // then its descriptors are sure to be free. 
+        DISK_REQUESTS.down();
         let mut guard = self.acquire();
         let buf_raw_data = buf.raw_data_mut();
 
         let mut idx: [usize; 3] = [0; 3];
-        loop {
-            if guard.alloc3_desc(&mut idx) {
-                break;
-            } else {
-                unsafe {
-                    CPU_MANAGER.myproc().unwrap().sleep(&guard.free[0] as *const bool as usize, guard);
-                }
-                guard = self.acquire();
-            }
+        if !guard.alloc3_desc(&mut idx) {
+            panic!("virtio disk: no free descriptors");
         }
 
         // format descriptors
@@ -265,7 +262,7 @@ impl Spinlock<Disk> {
 
         fence(Ordering::SeqCst);
 
-        unsafe { write(VIRTIO_MMIO_QUEUE_NOTIFY, 0); }
+        write(VIRTIO_MMIO_QUEUE_NOTIFY, 0);
 
         // wait for the disk to handle the buf data
         while guard.info[idx[0]].disk {
@@ -279,6 +276,7 @@ impl Spinlock<Disk> {
         guard.free_chain(idx[0]);
 
         drop(guard);
+        DISK_REQUESTS.up();
     }
 }
 
@@ -447,13 +445,11 @@ const VIRTIO_BLK_T_OUT: u32 = 1; // write the disk
 const NUM: usize = 8;
 
 #[inline]
-unsafe fn read(offset: usize) -> u32 {
-    let src = (Into::<usize>::into(VIRTIO0) + offset) as *const u32;
-    ptr::read_volatile(src)
+fn read(offset: usize) -> u32 {
+    VIRTIO_REGS.read(offset)
 }
 
 #[inline]
-unsafe fn write(offset: usize, data: u32) {
-    let dst = (Into::<usize>::into(VIRTIO0) + offset) as *mut u32;
-    ptr::write_volatile(dst, data);
+fn write(offset: usize, data: u32) {
+    VIRTIO_REGS.write(offset, data);
 }
@@ -52,7 +52,9 @@ impl Bcache {
             .for_each(|(i, b)| b.index = i);
     }
 
-    fn bget(&self, dev: u32, blockno: u32) -> Buf<'_> {
+    /// Get the buf without reading it from the disk, 
+    /// for a caller about to overwrite the whole block. 
+    pub fn bget(&self, dev: u32, blockno: u32) -> Buf<'_> {
         let mut ctrl = self.ctrl.acquire();
 
         // find cached block
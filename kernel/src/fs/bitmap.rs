@@ -26,7 +26,7 @@ fn locate_inode_offset(inum: u32) -> usize {
 
 /// Free a block in the disk by setting the relevant bit in bitmap to 0.
 pub fn bfree(dev: u32, blockno: u32) {
-    let bm_blockno = unsafe { SUPER_BLOCK.bitmap_blockno(blockno) };
+    let bm_blockno = SUPER_BLOCK.bitmap_blockno(blockno);
     let bm_offset = blockno % BPB;
     let index = (bm_offset / 8) as isize;
     let bit = (bm_offset % 8) as usize;
@@ -44,9 +44,9 @@ pub fn bfree(dev: u32, blockno: u32) {
 /// Allocate a zeroed disk block 
 pub fn balloc(dev: u32) -> u32 {
     let mut b = 0;
-    let sb_size = unsafe{ SUPER_BLOCK.size() };
+    let sb_size = SUPER_BLOCK.size();
     while b < sb_size {
-        let bm_blockno = unsafe{ SUPER_BLOCK.bitmap_blockno(b) };
+        let bm_blockno = SUPER_BLOCK.bitmap_blockno(b);
         let mut buf = BCACHE.bread(dev, bm_blockno);
         let mut bi = 0;
         while bi < BPB && b + bi < sb_size {
@@ -69,9 +69,9 @@ pub fn balloc(dev: u32) -> u32 {
 }
 
 pub fn inode_alloc(dev: u32, itype: InodeType) -> u32 {
-    let size = unsafe { SUPER_BLOCK.ninodes() };
+    let size = SUPER_BLOCK.ninodes();
     for inum in 1..size {
-        let blockno = unsafe { SUPER_BLOCK.locate_inode(inum) };
+        let blockno = SUPER_BLOCK.locate_inode(inum);
         let offset = locate_inode_offset(inum) as isize;
         let mut buf = BCACHE.bread(dev, blockno);
         let dinode = unsafe { (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
@@ -1,12 +1,14 @@
 use crate::arch::riscv::qemu::param::NDEV;
-
-use core::mem::transmute;
+use crate::lock::rcu::Rcu;
 
 type ReadFn = fn(bool, usize, usize) -> Option<usize>;
 type WriteFn = fn(bool, usize, usize) -> Option<usize>;
 
-pub static mut DEVICE_LIST: DeviceList = DeviceList::uninit();
+/// Read on every device read and write, set once per device 
+/// at boot, so readers take no lock. 
+pub static DEVICE_LIST: Rcu<DeviceList> = Rcu::new(DeviceList::uninit(), "devices");
 
+#[derive(Clone, Copy)]
 pub struct DeviceList {
     pub table: [Device;NDEV]
 }
@@ -17,34 +19,37 @@ impl DeviceList {
             table: [Device::new();NDEV]
         }
     }
+
+    /// The device of major number, if it has been registered. 
+    pub fn get(&self, major: i16) -> Option<&Device> {
+        if major < 0 {
+            return None
+        }
+        self.table.get(major as usize)
+    }
 }
 
 /// map major device number to device functions.
 #[derive(Clone, Copy)]
 pub struct Device {
-    pub read: *const u8,
-    pub write: *const u8
+    pub read: Option<ReadFn>,
+    pub write: Option<WriteFn>
 }
 
 impl Device {
     const fn new() -> Self {
         Self {
-            read: 0 as *const u8,
-            write: 0 as *const u8
+            read: None,
+            write: None
         }
     }
+}
 
-    pub fn read(&self) -> ReadFn {
-        let func = unsafe {
-            transmute::<*const u8, ReadFn>(self.read)
-        };
-        func
-    }
-
-    pub fn write(&self) -> WriteFn {
-        let func = unsafe {
-            transmute::<*const u8, WriteFn>(self.write)
-        };
-        func
-    }
-}
\ No newline at end of file
+/// Set the functions of device major. Needs the kernel heap. 
+pub fn register_device(major: usize, read: ReadFn, write: WriteFn) {
+    DEVICE_LIST.update(|list| {
+        let mut list = *list;
+        list.table[major] = Device{ read: Some(read), write: Some(write) };
+        list
+    });
+}
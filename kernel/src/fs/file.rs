@@ -1,8 +1,8 @@
 use crate::arch::riscv::qemu::fs::{ BSIZE, MAXOPBLOCKS };
-use crate::arch::riscv::qemu::param::NDEV;
 use crate::lock::spinlock::Spinlock;
+use crate::lock::rcu;
 use crate::lock::sleeplock::SleepLock;
-use crate::process::CPU_MANAGER;
+use crate::process::{ CPU_MANAGER, RLIMIT_FSIZE, RLIM_INFINITY };
 use super::pipe::Pipe;
 use super::inode::Inode;
 use super::devices::DEVICE_LIST;
@@ -86,14 +86,12 @@ impl VFile {
             },
 
             FileType::Device => {
-                if self.major < 0 || 
-                self.major as usize >= NDEV || 
-                unsafe{ DEVICE_LIST.table[self.major as usize].read as usize == 0 }{
-                    return Err("[Error] vfs: Fail to read device")
-                }
-                let read = unsafe { 
-                    DEVICE_LIST.table[self.major as usize].read()
-                };               
+                // Copy the function out, it may sleep. 
+                let read = {
+                    let rcu = rcu::read_lock();
+                    DEVICE_LIST.read(&rcu).get(self.major).and_then(|dev| dev.read)
+                };
+                let read = read.ok_or("[Error] vfs: Fail to read device")?;
                 ret = read(true, addr, len).ok_or("Fail to read device")?;
                 return Ok(ret)
             },
@@ -141,15 +139,11 @@ impl VFile {
             },
 
             FileType::Device => {
-                if self.major < 0 || 
-                self.major as usize >= NDEV || 
-                unsafe{ DEVICE_LIST.table[self.major as usize].write as usize == 0 } {
-                    return Err("Fail to write to device")
-                }
-
-                let write = unsafe{ 
-                    DEVICE_LIST.table[self.major as usize].write()
+                let write = {
+                    let rcu = rcu::read_lock();
+                    DEVICE_LIST.read(&rcu).get(self.major).and_then(|dev| dev.write)
                 };
+                let write = write.ok_or("Fail to write to device")?;
                 ret = write(true, addr, len).ok_or("Fail to write device")?;
                 Ok(ret)
             },
@@ -162,6 +156,15 @@ impl VFile {
                 // this really belongs lower down, since inode write
                 // might be writing a device like console. 
                 let max = ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE;
+                // Only write up to RLIMIT_FSIZE, fail if already there. 
+                let fsize = unsafe{ 
+                    CPU_MANAGER.myproc()
+                        .map_or(RLIM_INFINITY, |p| p.data.get_mut().rlimits.cur(RLIMIT_FSIZE))
+                };
+                if self.offset as usize >= fsize {
+                    return Err("File size limit exceeded")
+                }
+                let len = len.min(fsize - self.offset as usize);
                 let mut count  = 0;
                 while count < len {
                     let mut write_bytes = len - count;
@@ -232,8 +235,8 @@ impl VFile {
                 //     stat.dev, stat.inum, stat.nlink, stat.size, stat.itype
                 // );
                 let pdata = p.data.get_mut();
-                let page_table = pdata.pagetable.as_mut().unwrap();
-                page_table.copy_out(addr, (&stat) as *const Stat as *const u8, size_of::<Stat>())?;
+                let vm = pdata.vm();
+                vm.copy_out(addr, (&stat) as *const Stat as *const u8, size_of::<Stat>())?;
                 Ok(())
             },  
 
@@ -95,14 +95,10 @@ impl InodeCache {
     /// Mark it as allocated by giving it type type. 
     /// Returns an unlocked but allocated and reference inode 
     pub fn alloc(&self, dev: u32, itype: InodeType) -> Option<Inode> {
-        let ninodes = unsafe {
-            SUPER_BLOCK.ninodes()
-        };
+        let ninodes = SUPER_BLOCK.ninodes();
         for inum in 1 ..= ninodes {
             // get block id
-            let block_id = unsafe {
-                SUPER_BLOCK.locate_inode(inum)
-            };
+            let block_id = SUPER_BLOCK.locate_inode(inum);
             // read block into buffer by device and block_id
             let mut block = BCACHE.bread(dev, block_id);
         
@@ -416,7 +412,7 @@ impl InodeData {
     pub fn update(&mut self) {
         let mut buf = BCACHE.bread(
             self.dev, 
-            unsafe { SUPER_BLOCK.locate_inode(self.inum)}
+            SUPER_BLOCK.locate_inode(self.inum)
         );
         let offset = locate_inode_offset(self.inum) as isize;
         let dinode = unsafe{ (buf.raw_data_mut() as *mut DiskInode).offset(offset) };
@@ -697,7 +693,7 @@ impl Inode {
         let mut guard = ICACHE.data[self.index].lock();
         
         if !guard.valid {
-            let blockno = unsafe{ SUPER_BLOCK.locate_inode(self.inum) };
+            let blockno = SUPER_BLOCK.locate_inode(self.inum);
             let buf = BCACHE.bread(self.dev, blockno);
             let offset = locate_inode_offset(self.inum) as isize;
             let dinode = unsafe{ (buf.raw_data() as *const DiskInode).offset(offset) };
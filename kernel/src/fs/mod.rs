@@ -21,7 +21,7 @@ pub use file::{ VFile, FileType };
 pub use inode::{ Inode, InodeData, ICACHE };
 pub use dinode::{ DiskInode, DirEntry, InodeType };
 pub use superblock::{ SUPER_BLOCK, SuperBlock };
-pub use devices::DEVICE_LIST;
+pub use devices::{ DEVICE_LIST, register_device };
 pub use pipe::Pipe;
 
 use log::Log;
@@ -1,14 +1,26 @@
-use core::ptr::drop_in_place;
-use crate::{lock::spinlock::Spinlock, memory::{ RawPage, PageAllocator }, process::{CPU, CPU_MANAGER, PROC_MANAGER}};
+use core::ptr::NonNull;
+use crate::lock::arc::Arc;
+use crate::{lock::{spinlock::Spinlock, condvar::Condvar}, memory::slab::KmemCache, process::{CPU, CPU_MANAGER, MemCg}};
 
 use super::{FileType, VFile};
 
 // use super::File;
 
 const PIPE_SIZE: usize = 512;
+
+/// Pipes are a little over PIPE_SIZE bytes, several fit in a page. 
+static PIPE_CACHE: KmemCache<Pipe> = KmemCache::new("pipe_cache");
+
+/// Not bounded with a Semaphore, see semaphore.rs. 
 #[repr(C)]
 pub struct Pipe {
-    guard: Spinlock<PipeGuard>
+    guard: Spinlock<PipeGuard>,
+    /// data written, or the write end closed
+    readable: Condvar,
+    /// data read, or the read end closed
+    writable: Condvar,
+    /// charged a page for the buffer, that of the process making it
+    memcg: Option<Arc<MemCg>>
 }
 
 #[repr(C)]
@@ -26,23 +38,41 @@ struct PipeGuard {
 }
 
 impl Pipe {
-    pub fn alloc(rf: &mut &mut VFile, wf: &mut &mut VFile) -> Self {
-        let pipe_guard = unsafe{ *PipeGuard::alloc() }; 
-        let mut pipe = Self {
-            guard: Spinlock::new(pipe_guard, "pipe")
+    /// Allocate a pipe and set rf and wf up as its two ends. 
+    /// None when out of memory or over the page limit. 
+    pub fn alloc(rf: &mut &mut VFile, wf: &mut &mut VFile) -> Option<NonNull<Pipe>> {
+        let memcg = unsafe{ CPU_MANAGER.myproc() }
+            .and_then(|p| unsafe{ (*p.data.get()).vm.as_ref() })
+            .map(|vm| Arc::clone(vm.memcg()));
+        if let Some(memcg) = &memcg {
+            memcg.try_charge(1).ok()?;
+        }
+        let pipe = match PIPE_CACHE.alloc(Self {
+            guard: Spinlock::new(PipeGuard::new(), "pipe"),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            memcg: memcg.clone()
+        }) {
+            Some(pipe) => pipe,
+            None => {
+                if let Some(memcg) = memcg {
+                    memcg.uncharge(1);
+                }
+                return None
+            }
         };
         **rf = VFile::init();
         **wf = VFile::init();
         rf.ftype = FileType::Pipe;
         rf.readable = true;
         rf.writeable = false;
-        rf.pipe = Some(&mut pipe as *mut Pipe);
+        rf.pipe = Some(pipe.as_ptr());
         wf.ftype = FileType::Pipe;
         wf.readable = false;
         wf.writeable = true;
-        wf.pipe = Some(&mut pipe as *mut Pipe);
+        wf.pipe = Some(pipe.as_ptr());
 
-        pipe
+        Some(pipe)
     }
 
     pub fn read(&self, addr: usize, len: usize) -> Result<usize, &'static str> {
@@ -58,29 +88,25 @@ impl Pipe {
                 return Err("pipe read: current process has been killed")
             }
             // pipe read sleep
-            my_proc.sleep(
-                &pipe_guard.read_number as *const _ as usize, 
-                pipe_guard
-            );
-            pipe_guard = self.guard.acquire();
+            pipe_guard = self.readable.wait(pipe_guard);
         }
 
-        let mut i = 0;
-        for index in 0..len {
-            if pipe_guard.read_number == pipe_guard.write_number { break; }
+        // Take the bytes out under the lock and copy them to the 
+        // user after, the copy may sleep to swap a page back in. 
+        let mut buf = [0u8; PIPE_SIZE];
+        let mut count = 0;
+        while count < len.min(PIPE_SIZE) && pipe_guard.read_number != pipe_guard.write_number {
             let read_cursor = pipe_guard.read_number % PIPE_SIZE;
-            let ch = pipe_guard.data[read_cursor % PIPE_SIZE];
+            buf[count] = pipe_guard.data[read_cursor];
             pipe_guard.read_number += 1;
-            let pgt = my_proc.page_table();
-            if pgt.copy_out(addr + index, &ch as *const u8, 1).is_err() {
-                break;
-            }
-            i = index;
+            count += 1;
         }
 
-        unsafe{ PROC_MANAGER.wake_up(&pipe_guard.write_number as *const _ as usize) };
+        self.writable.notify_all();
         drop(pipe_guard);
-        Ok(i)
+        let vm = my_proc.vm();
+        vm.copy_out(addr, buf.as_ptr(), count)?;
+        Ok(count)
     }
 
     pub fn write(&self, addr: usize, len: usize) -> Result<usize, &'static str> {
@@ -88,36 +114,40 @@ impl Pipe {
             CPU_MANAGER.myproc().ok_or("Fail to get current process")?
         };
 
-        let mut pipe_guard = self.guard.acquire();
+        let mut buf = [0u8; PIPE_SIZE];
         let mut i = 0;
         while i < len {
-            if !pipe_guard.read_open || my_proc.killed() {
-                drop(pipe_guard);
-                return Err("pipe write: pipe read close or current process has been killed")
+            // Copy a chunk in before taking the lock, 
+            // as read() does the other way round. 
+            let count = (len - i).min(PIPE_SIZE);
+            let vm = my_proc.vm();
+            if vm.copy_in(buf.as_mut_ptr(), addr + i, count).is_err() {
+                break;
             }
 
-            if pipe_guard.write_number == pipe_guard.read_number + PIPE_SIZE {
-                unsafe {
-                    PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
+            let mut pipe_guard = self.guard.acquire();
+            let mut j = 0;
+            while j < count {
+                if !pipe_guard.read_open || my_proc.killed() {
+                    drop(pipe_guard);
+                    return Err("pipe write: pipe read close or current process has been killed")
                 }
-                my_proc.sleep(&pipe_guard.write_number as *const _ as usize, pipe_guard);
-                pipe_guard = self.guard.acquire();
-            } else {
-                let mut char: u8 = 0;
-                let pgt = my_proc.page_table();
-                if pgt.copy_in(&mut char as *mut u8, addr + i, 1).is_err() {
-                    break;
+
+                if pipe_guard.write_number == pipe_guard.read_number + PIPE_SIZE {
+                    self.readable.notify_all();
+                    pipe_guard = self.writable.wait(pipe_guard);
+                } else {
+                    let write_cursor = pipe_guard.write_number % PIPE_SIZE;
+                    pipe_guard.data[write_cursor] = buf[j];
+                    pipe_guard.write_number += 1;
+                    j += 1;
                 }
-                let write_cursor = pipe_guard.write_number % PIPE_SIZE;
-                pipe_guard.data[write_cursor % PIPE_SIZE] = char;
-                i += 1;
             }
-        }
 
-        unsafe {
-            PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
+            self.readable.notify_all();
+            drop(pipe_guard);
+            i += count;
         }
-        drop(pipe_guard);
 
         Ok(i)
     }
@@ -126,19 +156,18 @@ impl Pipe {
         let mut pipe_guard = self.guard.acquire();
         if writeable {
             pipe_guard.write_open = false;
-            unsafe {
-                PROC_MANAGER.wake_up(&pipe_guard.read_number as *const _ as usize);
-            }
+            self.readable.notify_all();
         } else {
             pipe_guard.read_open = false;
-            unsafe {
-                PROC_MANAGER.wake_up(&pipe_guard.write_number as *const _ as usize);
-            }
+            self.writable.notify_all();
         }
         
         if !pipe_guard.read_open && !pipe_guard.write_open {
-            pipe_guard.free();
             drop(pipe_guard);
+            if let Some(memcg) = &self.memcg {
+                memcg.uncharge(1);
+            }
+            unsafe{ PIPE_CACHE.free(NonNull::from(self)); }
         } else {
             drop(pipe_guard);
         }
@@ -146,19 +175,13 @@ impl Pipe {
 }
 
 impl PipeGuard {
-    fn alloc() -> *mut Self {
-        let pipe = unsafe{ RawPage::new_zeroed() as *mut PipeGuard };
-        let pipe = unsafe{ &mut *pipe };
-        pipe.read_number = 0;
-        pipe.write_number = 0;
-        pipe.read_open = true;
-        pipe.write_open = true;
-        pipe as *mut Self 
-    }
-
-    pub fn free(&mut self) {
-        unsafe {
-            drop_in_place(self as *const _ as *mut RawPage)
+    const fn new() -> Self {
+        Self {
+            data: [0; PIPE_SIZE],
+            read_number: 0,
+            write_number: 0,
+            read_open: true,
+            write_open: true
         }
     }
 }
@@ -1,61 +1,47 @@
 //! Super block operations
 
 use core::ptr;
-use core::mem::{self, MaybeUninit};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::mem;
 
 use crate::arch::riscv::qemu::fs::{ FSMAGIC, IPB, BPB };
+use crate::lock::once::LazyInit;
 use super::{ BCACHE, BufData };
 
-pub static mut SUPER_BLOCK: SuperBlock = SuperBlock::uninit();
+pub static SUPER_BLOCK: SuperBlock = SuperBlock::uninit();
 
 /// In-memory copy of superblock
-#[derive(Debug)]
 pub struct SuperBlock {
-    data: MaybeUninit<RawSuperBlock>,
-    initialized: AtomicBool,
+    data: LazyInit<RawSuperBlock>,
 }
 
-unsafe impl Sync for SuperBlock {}
-
 impl SuperBlock {
     const fn uninit() -> Self {
         Self {
-            data: MaybeUninit::uninit(),
-            initialized: AtomicBool::new(false),
+            data: LazyInit::new("super block"),
         }
     }
 
     /// Read and init the super block from disk into memory.
     /// SAFETY: it should only be called by the first regular process alone.
-    pub unsafe fn init(&mut self, dev: u32) {
+    pub unsafe fn init(&self, dev: u32) {
         debug_assert_eq!(mem::align_of::<BufData>() % mem::align_of::<RawSuperBlock>(), 0);
-        if self.initialized.load(Ordering::Relaxed) {
-            return
-        }
         let buf = BCACHE.bread(dev, 1);
-        ptr::copy_nonoverlapping(
-            buf.raw_data() as *const RawSuperBlock,
-            self.data.as_mut_ptr(),
-            1,
-        );
+        let sb = ptr::read(buf.raw_data() as *const RawSuperBlock);
+        drop(buf);
         println!("check magic number");
-        if self.data.as_ptr().as_ref().unwrap().magic != FSMAGIC {
+        if sb.magic != FSMAGIC {
             panic!("invalid file system magic num");
         }
-        self.initialized.store(true, Ordering::SeqCst);
-        drop(buf);
+        self.data.init(sb);
 
         #[cfg(feature = "verbose_init_info")]
-        println!("super block data: {:?}", self.data.as_ptr().as_ref().unwrap());
+        println!("super block data: {:?}", self.read());
     }
 
     /// Read the info of super block.
+    /// Panics if it is not read from disk yet. 
     fn read(&self) -> &RawSuperBlock {
-        debug_assert!(self.initialized.load(Ordering::Relaxed));
-        unsafe {
-            self.data.as_ptr().as_ref().unwrap()
-        }
+        self.data.get()
     }
 
     /// Load the log info of super block.
@@ -0,0 +1,135 @@
+//! Atomically reference counted pointers, for kernel objects shared
+//! between processes: open files, address spaces shared by threads,
+//! memory groups.
+//!
+//! The count and the data live in one block from the kernel heap.
+//! Unlike alloc's Arc, making one can fail without a panic, with
+//! try_new, so a syscall can return an error when memory runs out.
+
+use core::sync::atomic::{ AtomicUsize, Ordering, fence };
+use core::ops::Deref;
+use core::ptr::{ self, NonNull };
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use alloc::alloc::{ alloc, dealloc, handle_alloc_error, Layout };
+
+/// More references than this is a leak, not a workload.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+struct ArcInner<T> {
+    count: AtomicUsize,
+    data: T,
+}
+
+pub struct Arc<T> {
+    ptr: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+impl<T> Arc<T> {
+    /// Panics if the kernel heap is out of memory.
+    pub fn new(data: T) -> Self {
+        match Self::try_new(data) {
+            Ok(arc) => arc,
+            Err(_) => handle_alloc_error(Layout::new::<ArcInner<T>>())
+        }
+    }
+
+    /// Gives data back if the kernel heap is out of memory.
+    pub fn try_new(data: T) -> Result<Self, T> {
+        let layout = Layout::new::<ArcInner<T>>();
+        let inner = unsafe{ alloc(layout) } as *mut ArcInner<T>;
+        let ptr = match NonNull::new(inner) {
+            Some(ptr) => ptr,
+            None => return Err(data)
+        };
+        unsafe{ ptr.as_ptr().write(ArcInner {
+            count: AtomicUsize::new(1),
+            data,
+        }); }
+        Ok(Self { ptr, _marker: PhantomData })
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe{ self.ptr.as_ref() }
+    }
+
+    /// References to the data, this one included.
+    /// Others may take or drop some right after.
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().count.load(Ordering::Acquire)
+    }
+
+    /// The data, if this is the only reference to it.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.inner().count.load(Ordering::Acquire) == 1 {
+            Some(unsafe{ &mut (*this.ptr.as_ptr()).data })
+        } else {
+            None
+        }
+    }
+
+    /// Drop this reference, and give back the data if it was the
+    /// last one. Of several references dropped at once on different
+    /// harts exactly one gets it, unlike checking strong_count first.
+    pub fn into_inner(this: Self) -> Option<T> {
+        let this = ManuallyDrop::new(this);
+        if this.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+            return None
+        }
+        // As in drop().
+        fence(Ordering::Acquire);
+        unsafe {
+            let data = ptr::read(&(*this.ptr.as_ptr()).data);
+            dealloc(this.ptr.as_ptr() as *mut u8, Layout::new::<ArcInner<T>>());
+            Some(data)
+        }
+    }
+
+    /// Whether both point to the same data.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    pub fn as_ptr(this: &Self) -> *const T {
+        &this.inner().data
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        // A new reference is made from an existing one,
+        // which keeps the data alive, so no ordering is needed.
+        let old = self.inner().count.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            panic!("arc: refcount overflow");
+        }
+        Self { ptr: self.ptr, _marker: PhantomData }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().data
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        if self.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+            return
+        }
+        // Every use of the data through the other references
+        // happened before their drop, see it before freeing.
+        fence(Ordering::Acquire);
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            dealloc(self.ptr.as_ptr() as *mut u8, Layout::new::<ArcInner<T>>());
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
@@ -0,0 +1,38 @@
+//! Spinning barrier, for harts to wait for each other.
+//!
+//! Each of n harts calls wait() and none returns before all n have.
+//! It can be used again right away, for the next step.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::hint::spin_loop;
+
+pub struct Barrier {
+    n: usize,
+    count: AtomicUsize, // arrived in this round
+    generation: AtomicUsize, // rounds completed
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Self {
+        Self {
+            n,
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until n harts have called wait().
+    /// Returns true on the one that came last.
+    pub fn wait(&self) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 == self.n {
+            self.count.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            return true
+        }
+        while self.generation.load(Ordering::Acquire) == generation {
+            spin_loop();
+        }
+        false
+    }
+}
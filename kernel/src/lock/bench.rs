@@ -0,0 +1,103 @@
+//! Lock tests run at boot, with the lock-bench feature.
+//!
+//! Every hart calls run() once boot is done, before it enters
+//! the scheduler, so nothing else competes for the cpus. Boot with
+//! 4 to 8 harts, e.g. make run CPUS=8 FEATURES=lock-bench, for the
+//! numbers to mean anything.
+//!
+//! contention() has every hart take one Spinlock over and over for
+//! BENCH_CYCLES and prints how many times each hart got it. With the
+//! ticket-lock feature the counts come out about even, harts are
+//! served in turn; with test-and-set some harts get it far less.
+//!
+//! compare() times BENCH_ROUNDS acquisitions on every hart at once,
+//! first of a Spinlock, then of an McsLock, the lock kalloc's buddy
+//! system uses. MCS waiters spin on their own node rather than the
+//! lock word, which pays off as the harts grow in number.
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::{ NCPU, TICK_CYCLES };
+use crate::arch::riscv::register::time;
+use crate::process::cpuid;
+use super::barrier::Barrier;
+use super::spinlock::Spinlock;
+use super::mcslock::McsLock;
+
+/// How long contention() hammers the lock, in time CSR cycles.
+const BENCH_CYCLES: usize = 10 * TICK_CYCLES as usize;
+
+/// Acquisitions per hart of each lock in compare().
+const BENCH_ROUNDS: usize = 100000;
+
+static BENCH_SPINLOCK: Spinlock<usize> = Spinlock::new(0, "bench");
+static BENCH_MCSLOCK: McsLock<usize> = McsLock::new(0, "bench mcs");
+/// Acquisitions of BENCH_SPINLOCK by each hart.
+static ACQUIRED: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+/// Cycles each hart took for its rounds in compare().
+static SPIN_CYCLES: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+static MCS_CYCLES: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+
+/// Run the tests. barrier must be for every hart that boots.
+pub fn run(barrier: &Barrier) {
+    contention(barrier);
+    compare(barrier);
+}
+
+fn contention(barrier: &Barrier) {
+    let me = unsafe{ cpuid() };
+    barrier.wait();
+    let end = unsafe{ time::read() } + BENCH_CYCLES;
+    let mut count = 0;
+    while unsafe{ time::read() } < end {
+        let mut guard = BENCH_SPINLOCK.acquire();
+        *guard += 1;
+        drop(guard);
+        count += 1;
+    }
+    ACQUIRED[me].store(count, Ordering::Relaxed);
+    // The last to arrive prints for everyone.
+    if barrier.wait() {
+        let kind = if cfg!(feature = "ticket-lock") { "ticket" } else { "test-and-set" };
+        println!("lock bench: {} spinlock, acquisitions per hart:", kind);
+        for (hart, count) in ACQUIRED.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                println!("  hart {}: {}", hart, count);
+            }
+        }
+    }
+}
+
+fn compare(barrier: &Barrier) {
+    let me = unsafe{ cpuid() };
+    barrier.wait();
+    let start = unsafe{ time::read() };
+    for _ in 0..BENCH_ROUNDS {
+        let mut guard = BENCH_SPINLOCK.acquire();
+        *guard += 1;
+        drop(guard);
+    }
+    SPIN_CYCLES[me].store(unsafe{ time::read() } - start, Ordering::Relaxed);
+
+    barrier.wait();
+    let start = unsafe{ time::read() };
+    for _ in 0..BENCH_ROUNDS {
+        let mut guard = BENCH_MCSLOCK.acquire();
+        *guard += 1;
+        drop(guard);
+    }
+    MCS_CYCLES[me].store(unsafe{ time::read() } - start, Ordering::Relaxed);
+
+    if barrier.wait() {
+        println!("lock bench: cycles for {} acquisitions per hart, spinlock / mcs:", BENCH_ROUNDS);
+        for hart in 0..NCPU {
+            let spin = SPIN_CYCLES[hart].load(Ordering::Relaxed);
+            let mcs = MCS_CYCLES[hart].load(Ordering::Relaxed);
+            if spin != 0 {
+                println!("  hart {}: {} / {}", hart, spin, mcs);
+            }
+        }
+    }
+}
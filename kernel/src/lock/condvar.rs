@@ -0,0 +1,68 @@
+//! Condition variable
+//!
+//! Sleeping on a channel number means agreeing on which address
+//! stands for which condition. A Condvar is its own channel: wait
+//! on it with the guard of the lock protecting the condition, and
+//! notify it after changing the condition under that lock. As with
+//! sleep(), a waiter may wake up with the condition still false and
+//! must check it again, which wait_while() does.
+
+use crate::process::{ CPU_MANAGER, PROC_MANAGER };
+use super::spinlock::SpinlockGuard;
+
+pub struct Condvar {
+    _chan: u8, // gives the condvar an address of its own
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            _chan: 0,
+        }
+    }
+
+    fn channel(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Release the lock of guard and sleep until notified,
+    /// then take the lock again.
+    pub fn wait<'a, T>(&self, guard: SpinlockGuard<'a, T>) -> SpinlockGuard<'a, T> {
+        let lock = guard.spinlock();
+        let p = unsafe{ CPU_MANAGER.myproc() }.expect("condvar: wait outside a process");
+        p.sleep(self.channel(), guard);
+        lock.acquire()
+    }
+
+    /// Like wait(), but wake up anyway after timeout ticks.
+    /// Also returns false if it was the timeout that woke it.
+    pub fn wait_timeout<'a, T>(&self, guard: SpinlockGuard<'a, T>, timeout: usize) -> (SpinlockGuard<'a, T>, bool) {
+        let lock = guard.spinlock();
+        let p = unsafe{ CPU_MANAGER.myproc() }.expect("condvar: wait outside a process");
+        let notified = p.sleep_timeout(self.channel(), guard, timeout);
+        (lock.acquire(), notified)
+    }
+
+    /// Wait for as long as cond holds, or the process is killed.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: SpinlockGuard<'a, T>,
+        mut cond: impl FnMut(&mut T) -> bool
+    ) -> SpinlockGuard<'a, T> {
+        let p = unsafe{ CPU_MANAGER.myproc() }.expect("condvar: wait outside a process");
+        while cond(&mut *guard) && !p.killed() {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake the process that has waited the longest.
+    pub fn notify_one(&self) {
+        unsafe{ PROC_MANAGER.wake_one(self.channel()); }
+    }
+
+    /// Wake every waiting process.
+    pub fn notify_all(&self) {
+        unsafe{ PROC_MANAGER.wake_up(self.channel()); }
+    }
+}
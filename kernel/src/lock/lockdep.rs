@@ -0,0 +1,161 @@
+//! Lock dependency checker, a small lockdep, on in debug builds.
+//!
+//! Locks are grouped in classes by name, all the process locks are
+//! one class for example. Whenever a lock is taken while others are
+//! held, the order "held, then taken" is recorded for the classes.
+//! Taking a lock in an order that closes a cycle with the orders seen
+//! before, A then B somewhere and B then A here, could deadlock two
+//! harts, and panics with both names even if it didn't deadlock this
+//! time. Locks of the same class held together are not checked.
+//!
+//! Spinlocks and McsLocks are tracked. Interrupts are off while they
+//! are held, so each hart keeps the classes it holds.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::hint::spin_loop;
+
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::process::cpuid;
+
+const MAX_CLASSES: usize = 128;
+const MAX_HELD: usize = 16;
+const WORDS: usize = MAX_CLASSES / 64;
+
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    nr_classes: usize,
+    /// bit b of after[a] set if b was taken while holding a
+    after: [[u64; WORDS]; MAX_CLASSES],
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    classes: [usize; MAX_HELD],
+    depth: usize,
+    overflow: usize, // held beyond MAX_HELD, not checked
+}
+
+/// Guards GRAPH. Not a Spinlock, which would check itself.
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+static mut GRAPH: Graph = Graph {
+    names: [""; MAX_CLASSES],
+    nr_classes: 0,
+    after: [[0; WORDS]; MAX_CLASSES],
+};
+static mut HELD: [Held; NCPU] = [Held{ classes: [0; MAX_HELD], depth: 0, overflow: 0 }; NCPU];
+/// Set once a cycle is found, so the panic can print.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+impl Graph {
+    /// Class of the locks named name, None if the table is full.
+    fn class(&mut self, name: &'static str) -> Option<usize> {
+        if let Some(class) = self.names[..self.nr_classes].iter().position(|&n| n == name) {
+            return Some(class)
+        }
+        if self.nr_classes == MAX_CLASSES {
+            return None
+        }
+        self.names[self.nr_classes] = name;
+        self.nr_classes += 1;
+        Some(self.nr_classes - 1)
+    }
+
+    fn has_edge(&self, from: usize, to: usize) -> bool {
+        self.after[from][to / 64] & (1 << (to % 64)) != 0
+    }
+
+    /// Whether to was taken after from, directly or through other classes.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = [0u64; WORDS];
+        let mut stack = [0usize; MAX_CLASSES];
+        let mut top = 1;
+        stack[0] = from;
+        visited[from / 64] |= 1 << (from % 64);
+        while top > 0 {
+            top -= 1;
+            let class = stack[top];
+            if class == to {
+                return true
+            }
+            for next in 0..self.nr_classes {
+                if self.has_edge(class, next) && visited[next / 64] & (1 << (next % 64)) == 0 {
+                    visited[next / 64] |= 1 << (next % 64);
+                    stack[top] = next;
+                    top += 1;
+                }
+            }
+        }
+        false
+    }
+}
+
+fn graph_lock() {
+    while GRAPH_LOCK.swap(true, Ordering::Acquire) {
+        spin_loop();
+    }
+}
+
+fn graph_unlock() {
+    GRAPH_LOCK.store(false, Ordering::Release);
+}
+
+/// Called with interrupts off before spinning for the lock named name.
+pub fn acquire(name: &'static str) {
+    if !cfg!(debug_assertions) || DISABLED.load(Ordering::Relaxed) {
+        return
+    }
+    let held = unsafe{ &mut HELD[cpuid()] };
+    graph_lock();
+    let graph = unsafe{ &mut GRAPH };
+    let class = match graph.class(name) {
+        Some(class) => class,
+        None => {
+            graph_unlock();
+            held.overflow += 1;
+            return
+        }
+    };
+    let mut cycle = None;
+    for &h in held.classes[..held.depth].iter() {
+        if h == class || graph.has_edge(h, class) {
+            continue
+        }
+        if graph.reaches(class, h) {
+            cycle = Some(h);
+            break
+        }
+        graph.after[h][class / 64] |= 1 << (class % 64);
+    }
+    graph_unlock();
+    if let Some(h) = cycle {
+        DISABLED.store(true, Ordering::Relaxed);
+        panic!("lockdep: {} taken while holding {}, but {} was taken after {} before",
+            name, graph.names[h], graph.names[h], name);
+    }
+    if held.depth < MAX_HELD {
+        held.classes[held.depth] = class;
+        held.depth += 1;
+    } else {
+        held.overflow += 1;
+    }
+}
+
+/// Called with interrupts off when the lock named name is released.
+pub fn release(name: &'static str) {
+    if !cfg!(debug_assertions) || DISABLED.load(Ordering::Relaxed) {
+        return
+    }
+    let held = unsafe{ &mut HELD[cpuid()] };
+    graph_lock();
+    let graph = unsafe{ &GRAPH };
+    let class = graph.names[..graph.nr_classes].iter().position(|&n| n == name);
+    graph_unlock();
+    // Locks need not be released in the order they were taken.
+    match class.and_then(|class| held.classes[..held.depth].iter().rposition(|&h| h == class)) {
+        Some(i) => {
+            held.classes.copy_within(i + 1..held.depth, i);
+            held.depth -= 1;
+        },
+        None => held.overflow = held.overflow.saturating_sub(1)
+    }
+}
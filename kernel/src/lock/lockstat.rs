@@ -0,0 +1,95 @@
+//! Spinlock and McsLock statistics, per lock name.
+//!
+//! For each name, summed over all the locks called so: how often
+//! they were acquired, how often they were found held, how many times
+//! waiters went round the spin loop, and the longest any was held, in
+//! timer ticks of the time CSR. Printed with procdump on ^P, and on
+//! their own on ^L, to see which locks harts fight over.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use array_macro::array;
+
+const MAX_CLASSES: usize = 128;
+
+/// A lock that has not looked up its class yet.
+pub const UNKNOWN: usize = usize::MAX;
+
+struct LockStat {
+    acquisitions: AtomicUsize,
+    contended: AtomicUsize,
+    spins: AtomicUsize,
+    max_hold: AtomicUsize,
+}
+
+impl LockStat {
+    const fn new() -> Self {
+        Self {
+            acquisitions: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            spins: AtomicUsize::new(0),
+            max_hold: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Guards NAMES. Not a Spinlock, which would count itself.
+static NAMES_LOCK: AtomicBool = AtomicBool::new(false);
+static mut NAMES: [&str; MAX_CLASSES] = [""; MAX_CLASSES];
+static NR_CLASSES: AtomicUsize = AtomicUsize::new(0);
+static STATS: [LockStat; MAX_CLASSES] = array![_ => LockStat::new(); MAX_CLASSES];
+
+/// Index of the statistics for locks named name,
+/// MAX_CLASSES if the table is full, and they are not counted.
+pub fn class(name: &'static str) -> usize {
+    while NAMES_LOCK.swap(true, Ordering::Acquire) {
+        spin_loop();
+    }
+    let names = unsafe{ &mut NAMES };
+    let nr = NR_CLASSES.load(Ordering::Relaxed);
+    let class = match names[..nr].iter().position(|&n| n == name) {
+        Some(class) => class,
+        None if nr < MAX_CLASSES => {
+            names[nr] = name;
+            NR_CLASSES.store(nr + 1, Ordering::Release);
+            nr
+        },
+        None => MAX_CLASSES
+    };
+    NAMES_LOCK.store(false, Ordering::Release);
+    class
+}
+
+/// A lock of class was acquired after spins rounds of waiting.
+pub fn acquired(class: usize, spins: usize) {
+    if let Some(stat) = STATS.get(class) {
+        stat.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins > 0 {
+            stat.contended.fetch_add(1, Ordering::Relaxed);
+            stat.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A lock of class is released after being held for ticks.
+pub fn released(class: usize, ticks: usize) {
+    if let Some(stat) = STATS.get(class) {
+        stat.max_hold.fetch_max(ticks, Ordering::Relaxed);
+    }
+}
+
+/// Print the statistics of every lock name used so far.
+pub fn dump() {
+    println!("lock                 acquired  contended      spins  max hold");
+    let nr = NR_CLASSES.load(Ordering::Acquire);
+    for (class, stat) in STATS[..nr].iter().enumerate() {
+        println!(
+            "{:<16} {:>12} {:>10} {:>10} {:>9}",
+            unsafe{ NAMES[class] },
+            stat.acquisitions.load(Ordering::Relaxed),
+            stat.contended.load(Ordering::Relaxed),
+            stat.spins.load(Ordering::Relaxed),
+            stat.max_hold.load(Ordering::Relaxed)
+        );
+    }
+}
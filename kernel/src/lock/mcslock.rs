@@ -0,0 +1,183 @@
+//! MCS queue lock
+//!
+//! Under a Spinlock all waiting harts spin on the same word, and
+//! every release sends its cache line to all of them. An McsLock
+//! queues the waiters instead, each spinning on a node of its own
+//! cache line, and the holder hands the lock straight to the next
+//! in line when it releases it, so waiters are served in order.
+//! Meant for the hot locks, the run queues and the buddy allocator.
+//!
+//! Interrupts are off while it is held, so a node is only needed
+//! for each lock a hart holds or waits for at the same time, of
+//! which there are MCS_NODES per hart.
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::process::{ push_off, pop_off, cpuid };
+use crate::arch::riscv::register::time;
+use super::{ lockdep, lockstat };
+
+/// McsLocks a hart may hold at once.
+const MCS_NODES: usize = 4;
+
+#[repr(align(64))]
+struct McsNode {
+    next: AtomicPtr<McsNode>, // the waiter queued after this one
+    locked: AtomicBool, // true while the owner of this node must wait
+}
+
+impl McsNode {
+    const fn new() -> Self {
+        Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+static NODES: [[McsNode; MCS_NODES]; NCPU] = array![_ => array![_ => McsNode::new(); MCS_NODES]; NCPU];
+/// Bit i set if node i of the hart is in use.
+static NODES_USED: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+
+pub struct McsLock<T: ?Sized> {
+    tail: AtomicPtr<McsNode>, // last waiter, or the holder, null if free
+    name: &'static str,
+    cpu_id: Cell<isize>,
+    stat_class: Cell<usize>, // its statistics, see lockstat.rs
+    held_since: Cell<usize>, // time CSR when acquired
+    data: UnsafeCell<T>,
+}
+
+pub struct McsLockGuard<'a, T: ?Sized> {
+    lock: &'a McsLock<T>,
+    node: usize, // index of the node among this hart's
+}
+
+impl<T> McsLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            name,
+            cpu_id: Cell::new(-1),
+            stat_class: Cell::new(lockstat::UNKNOWN),
+            held_since: Cell::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> McsLock<T> {
+    pub fn acquire(&self) -> McsLockGuard<'_, T> {
+        push_off();
+        if self.holding() {
+            panic!("mcslock {} acquire", self.name);
+        }
+        lockdep::acquire(self.name);
+        let cpu = unsafe{ cpuid() };
+        // Interrupts are off, nothing else takes this hart's nodes meanwhile.
+        let used = NODES_USED[cpu].load(Ordering::Relaxed);
+        let index = (!used).trailing_zeros() as usize;
+        if index >= MCS_NODES {
+            panic!("mcslock {}: more than {} held", self.name, MCS_NODES);
+        }
+        NODES_USED[cpu].store(used | (1 << index), Ordering::Relaxed);
+        let node = &NODES[cpu][index];
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.locked.store(true, Ordering::Relaxed);
+
+        let node_ptr = node as *const McsNode as *mut McsNode;
+        let prev = self.tail.swap(node_ptr, Ordering::AcqRel);
+        let mut spins = 0;
+        if !prev.is_null() {
+            // Queue behind prev and wait for it to hand over.
+            unsafe{ (*prev).next.store(node_ptr, Ordering::Release); }
+            while node.locked.load(Ordering::Acquire) {
+                spin_loop();
+                spins += 1;
+            }
+        }
+        self.cpu_id.set(cpu as isize);
+        if self.stat_class.get() == lockstat::UNKNOWN {
+            self.stat_class.set(lockstat::class(self.name));
+        }
+        lockstat::acquired(self.stat_class.get(), spins);
+        self.held_since.set(unsafe{ time::read() });
+        McsLockGuard{ lock: self, node: index }
+    }
+
+    fn release(&self, index: usize) {
+        if !self.holding() {
+            panic!("mcslock {} release", self.name);
+        }
+        self.cpu_id.set(-1);
+        lockstat::released(self.stat_class.get(), unsafe{ time::read() } - self.held_since.get());
+        let cpu = unsafe{ cpuid() };
+        let node = &NODES[cpu][index];
+        let node_ptr = node as *const McsNode as *mut McsNode;
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // No one queued, unless one is between its swap and linking in.
+            if self.tail.compare_exchange(
+                node_ptr,
+                ptr::null_mut(),
+                Ordering::Release,
+                Ordering::Relaxed
+            ).is_err() {
+                loop {
+                    next = node.next.load(Ordering::Acquire);
+                    if !next.is_null() {
+                        break
+                    }
+                    spin_loop();
+                }
+            }
+        }
+        if !next.is_null() {
+            unsafe{ (*next).locked.store(false, Ordering::Release); }
+        }
+        NODES_USED[cpu].fetch_and(!(1 << index), Ordering::Relaxed);
+        lockdep::release(self.name);
+        pop_off();
+    }
+
+    /// Whether any cpu holds or waits for the lock right now.
+    /// Only a hint, it may change as soon as it returns.
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Whether this cpu holds the lock.
+    /// Interrupts must be off.
+    pub fn holding(&self) -> bool {
+        self.is_locked() && self.cpu_id.get() == unsafe{ cpuid() } as isize
+    }
+}
+
+impl<T: ?Sized> Deref for McsLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for McsLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for McsLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(self.node);
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for McsLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for McsLock<T> {}
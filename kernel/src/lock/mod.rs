@@ -1,2 +1,25 @@
 pub mod spinlock;
-pub mod sleeplock;
\ No newline at end of file
+pub mod sleeplock;
+pub mod rwlock;
+pub mod mcslock;
+pub mod condvar;
+pub mod semaphore;
+pub mod arc;
+pub mod rcu;
+pub mod once;
+pub mod mpsc;
+pub mod barrier;
+pub mod lockstat;
+#[cfg(feature = "lock-bench")]
+pub mod bench;
+mod lockdep;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The guard of a spinning lock, which Process::sleep() lets go of 
+/// once it is on the wait queue, so a wakeup under the same lock 
+/// can't be missed. Sealed, only the guards in this module are one: 
+/// a reference to a guard, or (), would keep or hold no lock. 
+pub trait SleepGuard: sealed::Sealed {}
@@ -0,0 +1,111 @@
+//! Lock-free queue of fixed capacity, many producers, one consumer.
+//!
+//! For interrupt handlers to hand work to a kernel thread without
+//! taking a lock the thread also takes. Producers claim a slot by
+//! moving tail on with a compare-and-swap, fill it, and then mark it
+//! full. The consumer takes slots in order from head and marks them
+//! empty again for the next lap. Each slot counts its turns, even
+//! while it waits to be filled in lap turn / 2, odd while it holds
+//! the value of that lap, so no slot is read before it is written.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+
+struct Slot<T> {
+    turn: AtomicUsize,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const EMPTY: Self = Self {
+        turn: AtomicUsize::new(0),
+        data: UnsafeCell::new(MaybeUninit::uninit()),
+    };
+}
+
+pub struct MpscQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize, // next slot to take, only moved by the consumer
+    tail: AtomicUsize, // next slot to fill
+    consuming: AtomicBool, // to catch a second consumer
+}
+
+impl<T, const N: usize> MpscQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [Slot::EMPTY; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            consuming: AtomicBool::new(false),
+        }
+    }
+
+    /// Add data at the tail. Gives it back if the queue is full.
+    /// Never waits, so it may be called from an interrupt handler.
+    pub fn push(&self, data: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail % N];
+            let lap = tail / N;
+            let turn = slot.turn.load(Ordering::Acquire);
+            if turn == 2 * lap {
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    tail + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed
+                ) {
+                    Ok(_) => {
+                        unsafe{ (*slot.data.get()).as_mut_ptr().write(data); }
+                        slot.turn.store(2 * lap + 1, Ordering::Release);
+                        return Ok(())
+                    },
+                    Err(now) => tail = now
+                }
+            } else if turn < 2 * lap {
+                // The value of the last lap is not taken yet.
+                return Err(data)
+            } else {
+                // Another producer filled it first.
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Take the value at the head, None if there is none yet.
+    /// Only one consumer may call it at a time.
+    pub fn pop(&self) -> Option<T> {
+        if self.consuming.swap(true, Ordering::Acquire) {
+            panic!("mpsc: more than one consumer");
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let slot = &self.slots[head % N];
+        let lap = head / N;
+        let data = if slot.turn.load(Ordering::Acquire) == 2 * lap + 1 {
+            let data = unsafe{ (*slot.data.get()).as_ptr().read() };
+            slot.turn.store(2 * lap + 2, Ordering::Release);
+            self.head.store(head + 1, Ordering::Relaxed);
+            Some(data)
+        } else {
+            None
+        };
+        self.consuming.store(false, Ordering::Release);
+        data
+    }
+
+    /// Whether the queue looks empty. Only a hint for
+    /// the consumer, producers may add to it right away.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Relaxed)
+    }
+}
+
+impl<T, const N: usize> Drop for MpscQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Send for MpscQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for MpscQueue<T, N> {}
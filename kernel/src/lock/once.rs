@@ -0,0 +1,127 @@
+//! One-time initialization, for the globals set up at boot.
+//!
+//! The subsystems start in a fixed order in rust_main, kalloc, kvm,
+//! the process table, then the drivers. A Once records that a step
+//! has run, so the next can check it instead of trusting the order,
+//! and a LazyInit holds a value that is only there once it is set.
+//! Running a step twice or using a value before it is set panics.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::hint::spin_loop;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct Once {
+    state: AtomicU8,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+        }
+    }
+
+    /// Run f if no one has yet. Otherwise wait
+    /// until the first caller's f has returned.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.state.compare_exchange(
+            INCOMPLETE,
+            RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire
+        ).is_ok() {
+            f();
+            self.state.store(COMPLETE, Ordering::Release);
+            return
+        }
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            spin_loop();
+        }
+    }
+
+    /// Run f, a step that must only run once.
+    /// Panics if it has been run before.
+    pub fn run(&self, name: &str, f: impl FnOnce()) {
+        if self.state.compare_exchange(
+            INCOMPLETE,
+            RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire
+        ).is_err() {
+            panic!("{}: initialized twice", name);
+        }
+        f();
+        self.state.store(COMPLETE, Ordering::Release);
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Check, in debug builds, that the step has run
+    /// before name, which needs it.
+    pub fn assert_done(&self, name: &str) {
+        debug_assert!(self.is_completed(), "{}: used before init", name);
+    }
+}
+
+/// A value set once at boot and only read after.
+pub struct LazyInit<T> {
+    once: Once,
+    name: &'static str,
+    data: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> LazyInit<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            once: Once::new(),
+            name,
+            data: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Set the value. Panics if it was set before.
+    pub fn init(&self, data: T) {
+        self.once.run(self.name, || unsafe{
+            (*self.data.get()).as_mut_ptr().write(data);
+        });
+    }
+
+    pub fn is_init(&self) -> bool {
+        self.once.is_completed()
+    }
+
+    /// The value. Panics if it is not set yet.
+    pub fn get(&self) -> &T {
+        if !self.once.is_completed() {
+            panic!("{}: used before init", self.name);
+        }
+        unsafe{ &*(*self.data.get()).as_ptr() }
+    }
+}
+
+impl<T> Deref for LazyInit<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T> Drop for LazyInit<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe{ (*self.data.get()).as_mut_ptr().drop_in_place(); }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for LazyInit<T> {}
+unsafe impl<T: Send + Sync> Sync for LazyInit<T> {}
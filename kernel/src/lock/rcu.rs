@@ -0,0 +1,126 @@
+//! Read-copy-update, for data read often and changed rarely.
+//!
+//! Readers of an Rcu take no lock and write no shared word, they only
+//! turn interrupts off, with an RcuReadGuard, so they cannot switch
+//! away while reading. A writer copies the data, changes the copy and
+//! puts it in place with one pointer store. Readers that started
+//! before may still be reading the old copy, so it is freed only once
+//! every other cpu has passed a quiescent state, after which none of
+//! them can be reading it: gone once round the scheduler loop, or
+//! taken a timer interrupt, which a read-side section keeps out. The
+//! timer tick is what lets a hart that never schedules, running a
+//! SCHED_FIFO process, let a writer go on.
+//!
+//! Writers wait for the other cpus, so an update must not be made
+//! with interrupts off, nor from inside a read-side section.
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use core::ptr;
+use alloc::boxed::Box;
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::process::{ CPU_MANAGER, IntrGuard, cpuid };
+use super::spinlock::Spinlock;
+
+/// Quiescent states each cpu has passed.
+static QUIESCENT: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>, // current copy, null while it is still init
+    init: T, // the copy before the first update, never freed
+    writer: Spinlock<()>, // serializes updates
+}
+
+/// A read-side section. Data read from an Rcu
+/// stays valid until the guard is dropped.
+pub struct RcuReadGuard {
+    _intr: IntrGuard,
+}
+
+/// Start a read-side section on this cpu. It must not sleep,
+/// the guard keeps interrupts off.
+pub fn read_lock() -> RcuReadGuard {
+    RcuReadGuard{ _intr: IntrGuard::new() }
+}
+
+/// Called by each cpu's scheduler loop, where it runs no process,
+/// and on each timer interrupt, taken with interrupts on,
+/// so neither is in a read-side section.
+pub fn quiescent() {
+    QUIESCENT[unsafe{ cpuid() }].fetch_add(1, Ordering::Release);
+}
+
+/// Wait until every other cpu running the scheduler has
+/// passed a quiescent state, so all the read-side sections
+/// that started before the call have ended.
+pub fn synchronize() {
+    let me = unsafe{ cpuid() };
+    let online = CPU_MANAGER.online_mask();
+    let mut seen = [0usize; NCPU];
+    for (cpu, count) in QUIESCENT.iter().enumerate() {
+        seen[cpu] = count.load(Ordering::Acquire);
+    }
+    for cpu in 0..NCPU {
+        if cpu == me || online & (1 << cpu) == 0 {
+            continue
+        }
+        while QUIESCENT[cpu].load(Ordering::Acquire) == seen[cpu] {
+            spin_loop();
+        }
+    }
+}
+
+impl<T> Rcu<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            init: data,
+            writer: Spinlock::new((), name),
+        }
+    }
+
+    /// The current copy, valid while guard lives.
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        let data = self.ptr.load(Ordering::Acquire);
+        if data.is_null() {
+            &self.init
+        } else {
+            unsafe{ &*data }
+        }
+    }
+
+    /// Replace the data with f applied to the current copy, then
+    /// wait for the readers of the old copy and free it.
+    /// Needs the kernel heap.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) {
+        let guard = self.writer.acquire();
+        let old = self.ptr.load(Ordering::Relaxed);
+        let new = if old.is_null() {
+            f(&self.init)
+        } else {
+            f(unsafe{ &*old })
+        };
+        self.ptr.store(Box::into_raw(Box::new(new)), Ordering::Release);
+        // Other writers spin with interrupts off for the lock,
+        // which would hold up synchronize().
+        drop(guard);
+        synchronize();
+        if !old.is_null() {
+            drop(unsafe{ Box::from_raw(old) });
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let data = *self.ptr.get_mut();
+        if !data.is_null() {
+            drop(unsafe{ Box::from_raw(data) });
+        }
+    }
+}
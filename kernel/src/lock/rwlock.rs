@@ -0,0 +1,141 @@
+//! Reader-writer spinlock
+//!
+//! Any number of readers, or one writer, may hold it. For data that
+//! is read much more often than it changes, readers don't wait for
+//! each other as they would behind a Spinlock. Readers are preferred,
+//! a writer waits until there are none left, so a reader may take it
+//! again while holding it. Interrupts are off while it is held, as
+//! for a Spinlock.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+
+use crate::process::{ push_off, pop_off, cpuid };
+
+/// state when a writer holds the lock, otherwise it is the number of readers
+const WRITER: usize = !(usize::MAX >> 1);
+
+pub struct RwSpinlock<T: ?Sized> {
+    state: AtomicUsize,
+    name: &'static str,
+    writer_cpu: Cell<isize>, // cpu holding it for writing, -1 if none
+    data: UnsafeCell<T>,
+}
+
+pub struct RwSpinlockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinlock<T>,
+}
+
+pub struct RwSpinlockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinlock<T>,
+}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            name,
+            writer_cpu: Cell::new(-1),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    /// Acquire it shared, with other readers.
+    pub fn read(&self) -> RwSpinlockReadGuard<'_, T> {
+        push_off();
+        if self.holding_write() {
+            panic!("rwspinlock {} read while writing", self.name);
+        }
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 && self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed
+            ).is_ok() {
+                break
+            }
+            spin_loop();
+        }
+        RwSpinlockReadGuard{ lock: self }
+    }
+
+    /// Acquire it exclusive, once no reader or writer holds it.
+    pub fn write(&self) -> RwSpinlockWriteGuard<'_, T> {
+        push_off();
+        if self.holding_write() {
+            panic!("rwspinlock {} write", self.name);
+        }
+        while self.state.compare_exchange_weak(
+            0,
+            WRITER,
+            Ordering::Acquire,
+            Ordering::Relaxed
+        ).is_err() {
+            spin_loop();
+        }
+        self.writer_cpu.set(unsafe{ cpuid() } as isize);
+        RwSpinlockWriteGuard{ lock: self }
+    }
+
+    /// Whether this cpu holds it for writing.
+    /// Interrupts must be off.
+    pub fn holding_write(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == WRITER
+            && self.writer_cpu.get() == unsafe{ cpuid() } as isize
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinlockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinlockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        pop_off();
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinlockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinlockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinlockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.lock.holding_write() {
+            panic!("rwspinlock {} release", self.lock.name);
+        }
+        self.lock.writer_cpu.set(-1);
+        self.lock.state.store(0, Ordering::Release);
+        pop_off();
+    }
+}
+
+impl<T: ?Sized> super::sealed::Sealed for RwSpinlockReadGuard<'_, T> {}
+impl<T: ?Sized> super::SleepGuard for RwSpinlockReadGuard<'_, T> {}
+impl<T: ?Sized> super::sealed::Sealed for RwSpinlockWriteGuard<'_, T> {}
+impl<T: ?Sized> super::SleepGuard for RwSpinlockWriteGuard<'_, T> {}
+
+// Readers share &T across cpus, so T must also be Sync.
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
@@ -0,0 +1,57 @@
+//! Counting semaphore
+//!
+//! Holds count units of some resource. down() takes one, sleeping
+//! until one is free, and up() gives one back. Good for bounding
+//! how many users a resource has, like requests in flight.
+//!
+//! Pipes keep their Condvars instead: a reader or writer must also
+//! wake when the other end is closed or it is killed, which down()
+//! can't tell it, and the free bytes are already counted under the
+//! pipe lock that has to be held to copy them anyway.
+
+use super::spinlock::Spinlock;
+use super::condvar::Condvar;
+
+pub struct Semaphore {
+    count: Spinlock<usize>,
+    available: Condvar,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: Spinlock::new(count, "semaphore"),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Take a unit, sleeping until there is one.
+    /// Only for processes.
+    pub fn down(&self) {
+        let mut count = self.count.acquire();
+        while *count == 0 {
+            count = self.available.wait(count);
+        }
+        *count -= 1;
+        drop(count);
+    }
+
+    /// Take a unit if there is one, without sleeping.
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.acquire();
+        let taken = *count > 0;
+        if taken {
+            *count -= 1;
+        }
+        drop(count);
+        taken
+    }
+
+    /// Give a unit back, waking a process waiting for it.
+    pub fn up(&self) {
+        let mut count = self.count.acquire();
+        *count += 1;
+        self.available.notify_one();
+        drop(count);
+    }
+}
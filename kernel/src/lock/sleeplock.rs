@@ -1,21 +1,21 @@
 //! sleeplock
+//!
+//! A lock for data held a long time, over disk I/O for example. 
+//! Waiters give up the CPU with Process::sleep() instead of spinning, 
+//! so unlike a spinlock it can be held with interrupts on and while 
+//! sleeping, but only a process can take it. 
 
 use core::ops::{Deref, DerefMut, Drop};
-use core::sync::atomic::{AtomicBool, fence, Ordering};
 use core::cell::{Cell, UnsafeCell};
-use core::hint::spin_loop;
 
-use crate::process::{push_off, pop_off};
-use crate::process::{ PROC_MANAGER, CPU_MANAGER };
+use crate::process::{ PROC_MANAGER, CPU_MANAGER, Pid };
 
 use super::spinlock::Spinlock;
 
-pub struct SleepChannel(u8);
-
 pub struct SleepLock<T: ?Sized> {
-    lock: Spinlock<()>,
+    lock: Spinlock<()>, // protects locked and holder
     locked: Cell<bool>,
-    chan: SleepChannel,
+    holder: Cell<Option<Pid>>, // process holding the lock
     name: &'static str,
     data: UnsafeCell<T>,
 }
@@ -29,24 +29,30 @@ impl<T> SleepLock<T> {
         Self {
             lock: Spinlock::new((), "sleeplock"),
             locked: Cell::new(false),
-            chan: SleepChannel(0),
+            holder: Cell::new(None),
             name,
             data: UnsafeCell::new(data),
         }
     }
+
+    /// The data, without locking, as no one else can hold a reference. 
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
 }
 
 impl<T: ?Sized> SleepLock<T> {
     /// non-blocking, but might sleep if other p lock this sleeplock
     pub fn lock(&self) -> SleepLockGuard<T> {
+        let p = unsafe{ CPU_MANAGER.myproc() }
+            .unwrap_or_else(|| panic!("sleeplock {}: lock outside a process", self.name));
         let mut guard = self.lock.acquire();
         while self.locked.get() {
-            unsafe {
-                CPU_MANAGER.myproc().unwrap().sleep(self.locked.as_ptr() as usize, guard);
-            }
+            p.sleep(self.locked.as_ptr() as usize, guard);
             guard = self.lock.acquire();
         }
         self.locked.set(true);
+        self.holder.set(Some(p.pid()));
         drop(guard);
         SleepLockGuard {
             lock: &self,
@@ -54,10 +60,37 @@ impl<T: ?Sized> SleepLock<T> {
         }
     }
 
+    /// Take the lock if it is free, without sleeping. 
+    pub fn try_lock(&self) -> Option<SleepLockGuard<T>> {
+        let p = unsafe{ CPU_MANAGER.myproc() }?;
+        let guard = self.lock.acquire();
+        if self.locked.get() {
+            drop(guard);
+            return None
+        }
+        self.locked.set(true);
+        self.holder.set(Some(p.pid()));
+        drop(guard);
+        Some(SleepLockGuard {
+            lock: &self,
+            data: unsafe { &mut *self.data.get() }
+        })
+    }
+
+    /// Whether the current process holds the lock. 
+    pub fn holding(&self) -> bool {
+        let pid = unsafe{ CPU_MANAGER.myproc() }.map(|p| p.pid());
+        let guard = self.lock.acquire();
+        let holding = self.locked.get() && pid.is_some() && self.holder.get() == pid;
+        drop(guard);
+        holding
+    }
+
     /// Called by its guard when dropped
-    pub fn unlock(&self) {
+    fn unlock(&self) {
         let guard = self.lock.acquire();
         self.locked.set(false);
+        self.holder.set(None);
         self.wake_up();
         drop(guard);
     }
@@ -1,15 +1,38 @@
-use core::sync::atomic::{AtomicBool, Ordering, fence};
+//! Spinlock
+//!
+//! By default a test-and-set lock: whichever hart's swap wins 
+//! takes it, so under heavy contention one hart may keep losing. 
+//! With the ticket-lock feature every lock is a ticket lock 
+//! instead, harts take a ticket and are served in order. 
+
+#[cfg(not(feature = "ticket-lock"))]
+use core::sync::atomic::AtomicBool;
+#[cfg(feature = "ticket-lock")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{Ordering, fence};
 use core::hint::spin_loop;
 use core::cell::{Cell, UnsafeCell};
 use core::ops::{Deref, DerefMut};
+use core::mem::forget;
 
 use crate::process::{ CPU_MANAGER, push_off, pop_off, cpuid };
+use crate::arch::riscv::register::time;
+use super::{ lockdep, lockstat };
 
 #[derive(Debug,Default)]
 pub struct Spinlock<T: ?Sized>{
+    #[cfg(not(feature = "ticket-lock"))]
     locked:AtomicBool,
+    /// next ticket to hand out
+    #[cfg(feature = "ticket-lock")]
+    next_ticket: AtomicUsize,
+    /// ticket of the holder, or of the next one when free
+    #[cfg(feature = "ticket-lock")]
+    now_serving: AtomicUsize,
     name: &'static str,
     cpu_id: Cell<isize>,
+    stat_class: Cell<usize>, // its statistics, see lockstat.rs
+    held_since: Cell<usize>, // time CSR when acquired
     data:UnsafeCell<T>,
 }
 
@@ -17,33 +40,56 @@ pub struct SpinlockGuard<'a, T>{
     spinlock:&'a Spinlock<T>
 }
 
+/// A guard that only gives access to part of the data, 
+/// made by SpinlockGuard::map(). Still releases the whole lock. 
+pub struct MappedSpinlockGuard<'a, T, U: ?Sized>{
+    spinlock: &'a Spinlock<T>,
+    data: *mut U,
+}
+
 impl<T> Spinlock<T>{
 
     pub const fn new(data: T, name: &'static str) -> Self {
         let lock = Spinlock {
+            #[cfg(not(feature = "ticket-lock"))]
             locked: AtomicBool::new(false),
+            #[cfg(feature = "ticket-lock")]
+            next_ticket: AtomicUsize::new(0),
+            #[cfg(feature = "ticket-lock")]
+            now_serving: AtomicUsize::new(0),
             name: name,
             cpu_id: Cell::new(-1),
+            stat_class: Cell::new(lockstat::UNKNOWN),
+            held_since: Cell::new(0),
             data: UnsafeCell::new(data)
         };
         lock
     }
 
+    /// Run f with the lock held. 
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut guard = self.acquire();
+        f(&mut guard)
+    }
+
     pub fn acquire(&self) -> SpinlockGuard<'_, T> {
 
         push_off();
         if self.holding() {
             panic!("spinlock {} acquire", self.name);
         }
+        lockdep::acquire(self.name);
         
-        while self.locked.swap(true, Ordering::Acquire){
-            // Now we signals the processor that it is inside a busy-wait spin-loop 
-            spin_loop();
-        }
+        let spins = self.lock();
         fence(Ordering::SeqCst);
         unsafe {
             self.cpu_id.set(cpuid() as isize);
         }
+        if self.stat_class.get() == lockstat::UNKNOWN {
+            self.stat_class.set(lockstat::class(self.name));
+        }
+        lockstat::acquired(self.stat_class.get(), spins);
+        self.held_since.set(unsafe{ time::read() });
 
         SpinlockGuard{spinlock: &self}
     }
@@ -53,28 +99,120 @@ impl<T> Spinlock<T>{
             panic!("spinlock {} release", self.name);
         }
         self.cpu_id.set(-1);
+        lockstat::released(self.stat_class.get(), unsafe{ time::read() } - self.held_since.get());
         fence(Ordering::SeqCst);
-        self.locked.store(false, Ordering::Release);
+        self.unlock();
+        lockdep::release(self.name);
         pop_off();
     }
 
-    // Check whether this cpu is holding the lock.
-    // Interrupts must be off.
-    pub fn holding(&self) -> bool{
-        // self.locked.load(Ordering::Relaxed) && (self.cpu_id.get() == unsafe{ cpuid() } as isize)
-        if self.locked.load(Ordering::Relaxed) && self.cpu_id.get() == unsafe{ cpuid() } as isize {
-            return true
+    /// Take the lock, returns how many times it spun. 
+    #[cfg(not(feature = "ticket-lock"))]
+    fn lock(&self) -> usize {
+        let mut spins = 0;
+        while self.locked.swap(true, Ordering::Acquire){
+            // Now we signals the processor that it is inside a busy-wait spin-loop 
+            spin_loop();
+            spins += 1;
         }
-        false
+        spins
+    }
+
+    /// Take the lock, returns how many times it spun. 
+    #[cfg(feature = "ticket-lock")]
+    fn lock(&self) -> usize {
+        let mut spins = 0;
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+            spins += 1;
+        }
+        spins
+    }
+
+    #[cfg(not(feature = "ticket-lock"))]
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    #[cfg(feature = "ticket-lock")]
+    fn unlock(&self) {
+        // Only the holder writes now_serving. 
+        let next = self.now_serving.load(Ordering::Relaxed).wrapping_add(1);
+        self.now_serving.store(next, Ordering::Release);
+    }
+
+    /// Whether any cpu holds the lock right now. 
+    /// Only a hint, it may change as soon as it returns. 
+    #[cfg(not(feature = "ticket-lock"))]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Whether any cpu holds the lock right now. 
+    /// Only a hint, it may change as soon as it returns. 
+    #[cfg(feature = "ticket-lock")]
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Access the data without acquiring the lock. 
+    /// Only for debugging output such as procdump, 
+    /// where a stuck lock must not wedge the machine further. 
+    pub unsafe fn get_unchecked(&self) -> &T {
+        &*self.data.get()
+    }
+
+    /// Check whether this cpu is holding the lock. 
+    /// Interrupts must be off. 
+    pub fn holding(&self) -> bool{
+        self.is_locked() && self.cpu_id.get() == unsafe{ cpuid() } as isize
     }
 
 
 }
 
 impl<'a, T> SpinlockGuard<'a, T>{
-    pub unsafe fn holding(&self) -> bool{
+    /// Whether this cpu holds the lock, always true 
+    /// unless the guard was moved to another cpu. 
+    pub fn holding(&self) -> bool{
         self.spinlock.holding()
     }
+
+    /// The lock this guard holds. 
+    pub fn spinlock(&self) -> &'a Spinlock<T> {
+        self.spinlock
+    }
+
+    /// Keep holding the lock, but only give access to 
+    /// the part of the data that f picks. 
+    pub fn map<U: ?Sized>(guard: Self, f: impl FnOnce(&mut T) -> &mut U) -> MappedSpinlockGuard<'a, T, U> {
+        let spinlock = guard.spinlock;
+        let data = f(unsafe{ &mut *spinlock.data.get() }) as *mut U;
+        // Released by the mapped guard instead. 
+        forget(guard);
+        MappedSpinlockGuard{ spinlock, data }
+    }
+}
+
+impl<T, U: ?Sized> Deref for MappedSpinlockGuard<'_, T, U>{
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        unsafe{ &*self.data }
+    }
+}
+
+impl<T, U: ?Sized> DerefMut for MappedSpinlockGuard<'_, T, U>{
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe{ &mut *self.data }
+    }
+}
+
+impl<T, U: ?Sized> Drop for MappedSpinlockGuard<'_, T, U>{
+    fn drop(&mut self){
+        self.spinlock.release()
+    }
 }
 
 impl<T> Deref for SpinlockGuard<'_, T>{
@@ -101,6 +239,9 @@ impl<T> Drop for SpinlockGuard<'_, T>{
     }
 }
 
+impl<T> super::sealed::Sealed for SpinlockGuard<'_, T>{}
+impl<T> super::SleepGuard for SpinlockGuard<'_, T>{}
+
 
 // We need to force Send and Sync traits because our mutex has
 // UnsafeCell, which don't realize it
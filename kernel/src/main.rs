@@ -46,20 +46,22 @@ mod fs;
 mod driver;
 mod net;
 mod misc;
+mod random;
 mod trap;
 
-use core::sync::atomic::{ AtomicBool, Ordering };
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use crate::lock::{ barrier::Barrier, once::LazyInit };
 
 use crate::driver::plic::{plic_init, plic_init_hart};
 use crate::process::cpu::cpuid;
 use crate::logo::LOGO;
-use crate::driver::console::console_init;
+use crate::driver::console::{ console_init, console_register, console_start };
 use crate::driver::uart::UART;
 use crate::trap::trap_init_hart;
 use crate::memory::{
     RawPage,
     kalloc::*,
-    mapping::kernel_map::{ kvm_init, kvm_init_hart }
+    kvm::{ kvm_init, kvm_init_hart }
 };
 use crate::process::*;
 use crate::fs::*;
@@ -68,10 +70,17 @@ use crate::arch::riscv::{
     mstatus, mepc, satp, medeleg, mideleg, sie, mhartid, tp, clint, 
     mscratch, mtvec, mie, sstatus, pmp,
 };
-use crate::arch::riscv::qemu::param::NCPU;
-
-static mut TIMER_SCRATCH:[[u64; 5]; NCPU] = [[0u64; 5]; NCPU];
-static STARTED:AtomicBool = AtomicBool::new(false);
+use crate::arch::riscv::qemu::param::{ NCPU, TICK_CYCLES };
+
+static mut TIMER_SCRATCH:[[u64; 6]; NCPU] = [[0u64; 6]; NCPU];
+/// Harts that came out of reset, counted in start(). 
+static HARTS: AtomicUsize = AtomicUsize::new(0);
+/// Set by hart 0 once the kernel is set up, the other harts wait for it. 
+/// However many harts qemu starts, none waits for a count. 
+static BOOT_DONE: AtomicBool = AtomicBool::new(false);
+/// For the lock tests, made by hart 0 from HARTS before BOOT_DONE. 
+#[cfg(feature = "lock-bench")]
+static BENCH_BARRIER: LazyInit<Barrier> = LazyInit::new("bench barrier");
 
 /// 引导启动程序,进行寄存器的初始化操作
 #[no_mangle]
@@ -102,6 +111,8 @@ pub unsafe fn start() -> !{
     let id:usize = mhartid::read(); 
     tp::write(id);
 
+    HARTS.fetch_add(1, Ordering::SeqCst);
+
     // switch to supervisor mode and jump to main().
     core::arch::asm!("mret");
 
@@ -112,14 +123,14 @@ pub unsafe fn start() -> !{
 /// set up to receive timer interrupts in machine mode,
 /// which arrive at timervec in kernelvec.S,
 /// which turns them into software interrupts for
-/// devintr() in trap.rs.
+/// timer_intr() in trap.rs.
 /// 启动时钟中断
 unsafe fn timer_init(){
     // each CPU has a separate source of timer interrupts.
     let id = mhartid::read();
 
     // ask the CLINT for a timer interrupt.
-    let interval = 1000000;// cycles; about 1/10th second in qemu.
+    let interval = TICK_CYCLES;
     clint::add_mtimecmp(id, interval);
 
 
@@ -127,8 +138,10 @@ unsafe fn timer_init(){
     // scratch[0..2] : space for timervec to save registers.
     // scratch[3] : address of CLINT MTIMECMP register.
     // scratch[4] : desired interval (in cycles) between timer interrupts.
+    // scratch[5] : address of CLINT MSIP register, for tlb shootdowns.
     TIMER_SCRATCH[id][3] = clint::count_mtiecmp(id) as u64;
     TIMER_SCRATCH[id][4] = interval;
+    TIMER_SCRATCH[id][5] = clint::count_msip(id) as u64;
     mscratch::write(TIMER_SCRATCH[id].as_ptr() as usize);
 
     // set the machine-mode trap handler.
@@ -141,8 +154,9 @@ unsafe fn timer_init(){
     // enable machine-mode interrupts.
     mstatus::enable_interrupt();
 
-    // enable machine-mode timer interrupts.
-    mie::write(mie::read() | mie::MIE::MTIE as usize);
+    // enable machine-mode timer interrupts, 
+    // and software interrupts from other harts. 
+    mie::write(mie::read() | mie::MIE::MTIE as usize | mie::MIE::MSIE as usize);
 
 }
 
@@ -154,6 +168,7 @@ pub unsafe extern "C" fn rust_main() {
         println!("{}",LOGO); 
         println!("xv6-rust kernel is booting!");
         KERNEL_HEAP.kinit(); // physical page allocator
+        console_register(); // console device file
         kvm_init(); // create kernel page table
         kvm_init_hart(); // turn on paging
         PROC_MANAGER.init(); // process table
@@ -163,16 +178,26 @@ pub unsafe extern "C" fn rust_main() {
         BCACHE.binit(); // buffer cache
         DISK.acquire().init(); // emulated hard disk
         PROC_MANAGER.user_init(); // first user process
-        STARTED.store(true, Ordering::SeqCst);
+        console_start(); // console input thread
+        // Only a test, by now the other harts are long past start(). 
+        #[cfg(feature = "lock-bench")]
+        BENCH_BARRIER.init(Barrier::new(HARTS.load(Ordering::SeqCst)));
+        BOOT_DONE.store(true, Ordering::Release);
         sstatus::intr_on();
     } else {
-        while !STARTED.load(Ordering::SeqCst){}
+        // Paging needs the kernel page table, 
+        // the scheduler the process table. 
+        while !BOOT_DONE.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
         println!("hart {} starting\n", cpu::cpuid());
         kvm_init_hart(); // turn on paging
         trap_init_hart(); // install kernel trap vector
         plic_init(); // set up interrupt controller
         plic_init_hart(); // ask PLIC for device interrupts
     }
+    #[cfg(feature = "lock-bench")]
+    lock::bench::run(&BENCH_BARRIER);
     CPU_MANAGER.scheduler();
     
 }
@@ -60,8 +60,72 @@ pub trait Addr{
     fn add_page(&mut self){
         *self.data_mut() += PGSIZE;
     }
+
+    /// The address rounded up to a page boundary. 
+    #[inline]
+    fn page_round_up(&self) -> Self where Self: Copy {
+        let mut addr = *self;
+        addr.pg_round_up();
+        addr
+    }
+
+    /// The start of the page the address is in. 
+    #[inline]
+    fn page_round_down(&self) -> Self where Self: Copy {
+        let mut addr = *self;
+        addr.pg_round_down();
+        addr
+    }
+
+    /// The address len bytes on, None if that overflows. 
+    #[inline]
+    fn checked_add(&self, len: usize) -> Option<Self> where Self: Copy {
+        let mut addr = *self;
+        *addr.data_mut() = self.as_usize().checked_add(len)?;
+        Some(addr)
+    }
+}
+
+/// The pages of [start, end), each as its page-aligned address. 
+/// Partial pages at either end are included. 
+#[derive(Debug, Clone, Copy)]
+pub struct PageRange<A: Addr + Copy> {
+    next: A,
+    end: A,
 }
 
+impl<A: Addr + Copy> PageRange<A> {
+    pub fn new(start: A, end: A) -> Self {
+        let next = start.page_round_down();
+        // The last page of the address space has no end to round up to. 
+        let end = match end.as_usize().checked_add(PGSIZE - 1) {
+            Some(_) => end.page_round_up(),
+            None => end.page_round_down(),
+        };
+        Self { next, end }
+    }
+}
+
+impl<A: Addr + Copy> Iterator for PageRange<A> {
+    type Item = A;
+
+    fn next(&mut self) -> Option<A> {
+        if self.next.as_usize() >= self.end.as_usize() {
+            return None
+        }
+        let page = self.next;
+        self.next = page.checked_add(PGSIZE).unwrap_or(self.end);
+        Some(page)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.end.as_usize().saturating_sub(self.next.as_usize()) / PGSIZE;
+        (n, Some(n))
+    }
+}
+
+impl<A: Addr + Copy> ExactSizeIterator for PageRange<A> {}
+
 impl From<PhysicalAddress> for usize{
     fn from(pa: PhysicalAddress) -> Self{
         pa.0
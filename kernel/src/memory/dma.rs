@@ -0,0 +1,38 @@
+//! Memory for device DMA.
+//!
+//! Devices reach memory by physical address, so a buffer they use
+//! must be physically contiguous. A single page from kalloc always
+//! is, and for more this asks kalloc_pages() for a buddy block, a
+//! power of two pages, which is contiguous too. Nothing is set aside
+//! at boot, the memory is only taken once a driver asks for it.
+//! Like all RAM it is mapped at the same virtual address, and on
+//! qemu's virt machine devices see memory coherently with the harts,
+//! so the memory can be given to a device as it is.
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::arch::riscv::qemu::param::MAX_ORDER;
+use super::{ page_round_up, kalloc_pages, kfree_pages };
+
+/// Order of the smallest buddy block of at least len bytes.
+fn order(len: usize) -> usize {
+    (page_round_up(len) / PGSIZE).next_power_of_two().trailing_zeros() as usize
+}
+
+/// Zeroed, physically contiguous memory of len bytes, page aligned.
+/// Its physical and kernel virtual address, which are the same.
+/// None if no free block is big enough.
+pub fn alloc_coherent(len: usize) -> Option<usize> {
+    if len == 0 || len > PGSIZE << MAX_ORDER {
+        return None
+    }
+    kalloc_pages(order(len))
+}
+
+/// Give back the len bytes at pa from alloc_coherent().
+/// The device must be done with them.
+pub fn free_coherent(pa: usize, len: usize) {
+    if pa % PGSIZE != 0 || len == 0 || len > PGSIZE << MAX_ORDER {
+        panic!("free_coherent: bad address {:#x}", pa);
+    }
+    unsafe{ kfree_pages(pa, order(len)); }
+}
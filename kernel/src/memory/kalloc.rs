@@ -1,45 +1,324 @@
-use crate::lock::spinlock::Spinlock;
-use crate::arch::riscv::qemu::param::{ LEAF_SIZE, MAX_ALIGNMENT };
+use crate::lock::mcslock::{ McsLock, McsLockGuard };
+use crate::lock::once::Once;
+use crate::arch::riscv::qemu::param::{ LEAF_SIZE, MAX_ALIGNMENT, MAX_ORDER, NCPU };
 use crate::arch::riscv::qemu::layout::{PGSIZE, PHYSTOP};
+use crate::process::{ IntrGuard, cpuid };
 use super::address::{PhysicalAddress, Addr};
+use super::refcount::PAGE_REF;
 use core::alloc::{ GlobalAlloc, Layout };
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::sync::atomic::{ AtomicUsize, Ordering };
 
 use allocator::*;
+use array_macro::array;
 
-use core::ptr::{write_volatile, write, NonNull};
+use core::ptr::{write_volatile, write, write_bytes, NonNull, null_mut};
 
 // Buddy System for memory allocate
 
+/// The kernel heap backs alloc::boxed::Box, Vec, String, BTreeMap and friends 
+/// as well as the page allocator, so kernel code uses the alloc crate directly. 
+/// Nothing may allocate before kinit() hands it the free memory. 
 #[global_allocator]
 pub static KERNEL_HEAP: KernelHeap = KernelHeap::uninit();
 
+/// Done once kinit() has run. 
+pub static KINIT: Once = Once::new();
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
-    panic!("alloc error: {:?}", layout);
+    panic!("kernel heap exhausted: size: {:#x} align: {:#x}", layout.size(), layout.align());
+}
+
+/// Most pages a cpu keeps for itself before draining to the buddy system. 
+const PCP_HIGH: usize = 64;
+/// Pages moved between a cpu's list and the buddy system at a time. 
+const PCP_BATCH: usize = 16;
+
+/// Junk every free page on the per-cpu lists is filled with, 
+/// so use-after-free reads garbage and writes are caught by alloc_page(). 
+/// Freed multi-page blocks are filled with it too, only to read garbage. 
+const POISON: u8 = 0x5a;
+
+/// Single pages cached by one cpu, like Linux's per-cpu pageset. 
+/// Only its own cpu touches the list, with interrupts off, 
+/// so the buddy lock is taken once per PCP_BATCH pages instead of once per page. 
+struct PerCpuPages {
+    count: usize,
+    pages: [usize; PCP_HIGH],
+    hits: AtomicUsize, // pages served without the buddy lock
+    refills: AtomicUsize,
+    drains: AtomicUsize,
+}
+
+impl PerCpuPages {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            pages: [0; PCP_HIGH],
+            hits: AtomicUsize::new(0),
+            refills: AtomicUsize::new(0),
+            drains: AtomicUsize::new(0),
+        }
+    }
 }
 
 // kernel heap
-pub struct KernelHeap(Spinlock<BuddySystem>);
+pub struct KernelHeap {
+    buddy: McsLock<BuddySystem>,
+    pcp: [UnsafeCell<PerCpuPages>; NCPU],
+    contended: AtomicUsize, // buddy lock acquisitions that found it held
+    total: AtomicUsize, // bytes handed to the buddy system by kinit()
+    used: AtomicUsize, // bytes allocated, pages on the per-cpu lists count as free
+    failures: AtomicUsize, // allocations that found no memory
+}
+
+/// Memory statistics of the kernel heap, see KernelHeap::stats(). 
+#[derive(Debug, Clone, Copy)]
+pub struct KmemStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub alloc_failures: usize,
+}
+
+unsafe impl Sync for KernelHeap {}
 
 unsafe impl GlobalAlloc for KernelHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0.acquire().alloc(layout)
+        let ptr = if is_page(layout) {
+            let page = self.alloc_page();
+            if !page.is_null() {
+                PAGE_REF.init(page as usize);
+            }
+            page
+        } else {
+            let ptr = self.buddy().alloc(layout);
+            if !ptr.is_null() {
+                for i in 0..block_pages(layout) {
+                    PAGE_REF.init(ptr as usize + i * PGSIZE);
+                }
+            }
+            ptr
+        };
+        if ptr.is_null() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.used.fetch_add(block_size(layout), Ordering::Relaxed);
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.0.acquire().dealloc(ptr, layout)
+        self.free(ptr, layout, None)
+    }
+}
+
+/// Panic on freeing a page which is not allocated. 
+/// The page's reference count doubles as its allocated/free bit: 
+/// PAGE_REF.init() sets it on allocation and the last free clears it, 
+/// for every page of a multi-page block too. 
+/// caller is known for kfree_pages(), not for frees through alloc. 
+fn check_free(pa: usize, caller: Option<&Location>) {
+    extern "C" {
+        fn end();
+    }
+    let bad = if pa % PGSIZE != 0 || pa < end as usize || pa >= PHYSTOP {
+        "bad address"
+    } else if PAGE_REF.get(pa) == 0 {
+        "double free or never allocated"
+    } else {
+        return
+    };
+    match caller {
+        Some(caller) => panic!("kfree: {} {:#x}, caller: {}", bad, pa, caller),
+        None => panic!("kfree: {} {:#x}", bad, pa)
+    }
+}
+
+/// Panic if a free page was written since it was poisoned. 
+unsafe fn check_poison(page: *mut u8) {
+    let words = core::slice::from_raw_parts(page as *const u64, PGSIZE / 8);
+    let poison = u64::from_ne_bytes([POISON; 8]);
+    if let Some(i) = words.iter().position(|w| *w != poison) {
+        panic!("kalloc: page {:#x} written after free at offset {:#x}", page as usize, i * 8);
+    }
+}
+
+/// Bytes the buddy system spends on layout, a power of two. 
+fn block_size(layout: Layout) -> usize {
+    layout.size().max(layout.align()).next_power_of_two().max(LEAF_SIZE)
+}
+
+/// Single pages go through the per-cpu lists. 
+fn is_page(layout: Layout) -> bool {
+    layout.size() == PGSIZE && layout.align() <= PGSIZE
+}
+
+/// Whole pages of a block from the buddy system, 
+/// 0 for a block smaller than a page, which is not tracked. 
+fn block_pages(layout: Layout) -> usize {
+    let size = block_size(layout);
+    if size >= PGSIZE { size / PGSIZE } else { 0 }
+}
+
+/// Allocate 2^order physically contiguous zeroed pages, 
+/// e.g. for DMA rings, kernel stacks and large buffers. 
+/// Returns None when the buddy system has no block that big. 
+pub fn kalloc_pages(order: usize) -> Option<usize> {
+    if order > MAX_ORDER {
+        return None
+    }
+    let ptr = unsafe{ KERNEL_HEAP.alloc_zeroed(page_layout(order)) };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr as usize)
     }
 }
 
+/// Free pages from kalloc_pages(), order must be the same. 
+/// A single page is only freed with its last reference. 
+#[track_caller]
+pub unsafe fn kfree_pages(pa: usize, order: usize) {
+    KERNEL_HEAP.free(pa as *mut u8, page_layout(order), Some(Location::caller()))
+}
+
+fn page_layout(order: usize) -> Layout {
+    Layout::from_size_align(PGSIZE << order, PGSIZE).unwrap()
+}
+
 impl KernelHeap {
     const fn uninit() -> Self {
-        Self(Spinlock::new(BuddySystem::uninit(), "kernel heap"))
+        Self {
+            buddy: McsLock::new(BuddySystem::uninit(), "kernel heap"),
+            pcp: array![_ => UnsafeCell::new(PerCpuPages::new()); NCPU],
+            contended: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            used: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Total and free pages, and how many allocations failed, 
+    /// to measure leaks and out of memory behaviour. 
+    pub fn stats(&self) -> KmemStats {
+        let total = self.total.load(Ordering::Relaxed);
+        let used = self.used.load(Ordering::Relaxed);
+        KmemStats {
+            total_pages: total / PGSIZE,
+            free_pages: total.saturating_sub(used) / PGSIZE,
+            alloc_failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    fn buddy(&self) -> McsLockGuard<'_, BuddySystem> {
+        if self.buddy.is_locked() {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        self.buddy.acquire()
+    }
+
+    unsafe fn alloc_page(&self) -> *mut u8 {
+        let _intr = IntrGuard::new();
+        let pcp = &mut *self.pcp[cpuid()].get();
+        if pcp.count == 0 {
+            // Refill a batch under one acquisition of the buddy lock. 
+            let mut buddy = self.buddy();
+            while pcp.count < PCP_BATCH {
+                let page = buddy.alloc(page_layout(0));
+                if page.is_null() {
+                    break;
+                }
+                write_bytes(page, POISON, PGSIZE);
+                pcp.pages[pcp.count] = page as usize;
+                pcp.count += 1;
+            }
+            drop(buddy);
+            pcp.refills.fetch_add(1, Ordering::Relaxed);
+        } else {
+            pcp.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        if pcp.count > 0 {
+            pcp.count -= 1;
+            let page = pcp.pages[pcp.count] as *mut u8;
+            check_poison(page);
+            page
+        } else {
+            null_mut()
+        }
+    }
+
+    /// dealloc(), with the caller to report a bad free if it is known. 
+    unsafe fn free(&self, ptr: *mut u8, layout: Layout, caller: Option<&Location>) {
+        if is_page(layout) {
+            check_free(ptr as usize, caller);
+            // Someone else still shares the page. 
+            if PAGE_REF.dec(ptr as usize) > 0 {
+                return
+            }
+            self.free_page(ptr);
+        } else {
+            // Like a single page, every page of a multi-page block 
+            // is checked and poisoned. 
+            let pages = block_pages(layout);
+            for i in 0..pages {
+                check_free(ptr as usize + i * PGSIZE, caller);
+            }
+            for i in 0..pages {
+                PAGE_REF.clear(ptr as usize + i * PGSIZE);
+            }
+            write_bytes(ptr, POISON, pages * PGSIZE);
+            self.buddy().dealloc(ptr, layout);
+        }
+        self.used.fetch_sub(block_size(layout), Ordering::Relaxed);
+    }
+
+    unsafe fn free_page(&self, page: *mut u8) {
+        let _intr = IntrGuard::new();
+        let pcp = &mut *self.pcp[cpuid()].get();
+        if pcp.count == PCP_HIGH {
+            // Give the oldest batch back so other cpus can use it. 
+            let mut buddy = self.buddy();
+            for i in 0..PCP_BATCH {
+                buddy.dealloc(pcp.pages[i] as *mut u8, page_layout(0));
+            }
+            drop(buddy);
+            pcp.pages.copy_within(PCP_BATCH.., 0);
+            pcp.count -= PCP_BATCH;
+            pcp.drains.fetch_add(1, Ordering::Relaxed);
+        }
+        write_bytes(page, POISON, PGSIZE);
+        pcp.pages[pcp.count] = page as usize;
+        pcp.count += 1;
+    }
+
+    /// Print the per-cpu page list counters. For debugging. 
+    /// Runs with procdump when user types ^P on console. 
+    pub fn dump(&self) {
+        let stats = self.stats();
+        println!(
+            "kalloc: pages: {} free: {} failures: {}", 
+            stats.total_pages, stats.free_pages, stats.alloc_failures
+        );
+        println!("kalloc: buddy lock contended: {}", self.contended.load(Ordering::Relaxed));
+        for (id, pcp) in self.pcp.iter().enumerate() {
+            let pcp = unsafe{ &*pcp.get() };
+            println!(
+                "cpu {}: cached: {} hits: {} refills: {} drains: {}", 
+                id, pcp.count, 
+                pcp.hits.load(Ordering::Relaxed),
+                pcp.refills.load(Ordering::Relaxed),
+                pcp.drains.load(Ordering::Relaxed)
+            );
+        }
     }
 
     unsafe fn init(&self, start: usize, end: usize) {
-        let res = self.0.acquire().init(start, end, LEAF_SIZE, MAX_ALIGNMENT);
+        let res = self.buddy.acquire().init(start, end, LEAF_SIZE, MAX_ALIGNMENT);
         match res {
             Ok(()) => {
+                self.total.store(end - start, Ordering::Relaxed);
                 println!("KernelHeap: success to init.");
             },
 
@@ -55,6 +334,6 @@ impl KernelHeap {
         }
         let end = end as usize;
         println!("KernelHeap: available memory: [{:#x}, {:#x})", end, PHYSTOP);
-        self.init(end, PHYSTOP);
+        KINIT.run("kinit", || self.init(end, PHYSTOP));
     }
 }
@@ -0,0 +1,165 @@
+//! Kernel virtual memory.
+//!
+//! The kernel page table maps RAM and the devices at their physical
+//! addresses, the kernel text read-only and executable, and the
+//! trampoline at the highest address. kvm_init() builds it from the
+//! fixed layout of qemu's virt machine in layout.rs. More devices,
+//! say found in the device tree, are added with kvm_map_device()
+//! before the other harts turn paging on. Device regions are recorded,
+//! so that none is mapped twice or over RAM.
+
+use super::mapping::{ page_table::PageTable, page_table_entry::MapPerm, page_round_up, page_round_down };
+use super::address::{ VirtualAddress, PhysicalAddress, Addr };
+use super::{ PageAllocator, RawPage };
+use crate::arch::riscv::qemu::layout::{
+    PGSIZE, KERNEL_BASE, PHYSTOP, TRAMPOLINE,
+    E1000_REGS, ECAM
+};
+use crate::arch::riscv::{ satp, sfence_vma, clint::CLINT_REGS };
+use crate::driver::{ uart::UART_REGS, plic::PLIC_REGS, virtio_disk::VIRTIO_REGS };
+use crate::lock::spinlock::Spinlock;
+use crate::lock::once::Once;
+use super::kalloc::KINIT;
+use crate::shutdown::VIRT_TEST_REGS;
+
+use core::mem::{ size_of, align_of };
+
+pub static mut KERNEL_PAGETABLE:PageTable = PageTable::empty();
+extern "C" {
+    fn etext();
+    fn trampoline();
+}
+
+/// Most device regions the kernel maps. 
+const NDEVICE: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Device {
+    name: &'static str,
+    start: usize, // page aligned
+    end: usize, // page aligned
+}
+
+static DEVICES: Spinlock<[Option<Device>; NDEVICE]> = Spinlock::new([None; NDEVICE], "kvm_devices");
+
+/// Done once the kernel page table is built. 
+pub static KVM_INIT: Once = Once::new();
+
+/// Initialize the one kernel_pagetable
+#[no_mangle]
+pub unsafe fn kvm_init(){
+    // page-table pages come from the kernel heap
+    KINIT.assert_done("kvm_init");
+    KVM_INIT.run("kvm_init", || kvm_make());
+}
+
+unsafe fn kvm_make() {
+    // check if RawPage and PageTable have the same memory layout
+    assert_eq!(size_of::<RawPage>(), PGSIZE);
+    assert_eq!(align_of::<RawPage>(), PGSIZE);
+    assert_eq!(size_of::<RawPage>(), size_of::<PageTable>());
+    assert_eq!(align_of::<RawPage>(), align_of::<PageTable>());
+
+    println!("kernel page map");
+    let devices = [
+        // VIRT_TEST for shutdown or reboot
+        ("virt_test", VIRT_TEST_REGS.base(), VIRT_TEST_REGS.size()),
+        ("uart", UART_REGS.base(), UART_REGS.size()),
+        // virtio mmio disk interface
+        ("virtio", VIRTIO_REGS.base(), VIRTIO_REGS.size()),
+        // PCI-E ECAM (configuration space), for pci.rs
+        ("ecam", ECAM, 0x10000000),
+        // pci maps the e1000's registers here.
+        ("e1000", E1000_REGS, 0x20000),
+        ("clint", CLINT_REGS.base(), CLINT_REGS.size()),
+        ("plic", PLIC_REGS.base(), PLIC_REGS.size()),
+    ];
+    for (name, base, size) in devices {
+        if let Err(err) = kvm_map_device(name, base, size) {
+            panic!("kvm_init: {}: {}", name, err);
+        }
+    }
+
+    // map kernel text exectuable and read-only
+    kvm_map(KERNEL_BASE, KERNEL_BASE, etext as usize - KERNEL_BASE, MapPerm::KernelRX);
+
+    // map kernel data and the physical RAM we'll make use of
+    kvm_map(etext as usize, etext as usize, PHYSTOP - etext as usize, MapPerm::KernelRW);
+
+    // map the trampoline for trap entry/exit
+    // the highest virtual address in the kernel
+    kvm_map(TRAMPOLINE, trampoline as usize, PGSIZE, MapPerm::Trampoline);
+
+    kvm_dump();
+}
+
+/// Switch h/w page table register to the kernel's page table,
+/// and enable paging.
+pub unsafe fn kvm_init_hart() {
+    KVM_INIT.assert_done("kvm_init_hart");
+    satp::write(KERNEL_PAGETABLE.as_satp());
+    sfence_vma();
+}
+
+/// Map [pa, pa + size) at va in the kernel page table. 
+/// Panics if out of memory for page-table pages. 
+/// Other harts may only use a mapping that was 
+/// never there before, no TLB is flushed. 
+pub unsafe fn kvm_map(va: usize, pa: usize, size: usize, perm: MapPerm) {
+    KERNEL_PAGETABLE.kernel_map(
+        VirtualAddress::new(va),
+        PhysicalAddress::new(pa),
+        size,
+        perm
+    );
+}
+
+/// kvm_map() that returns false instead of panicking 
+/// when out of memory, with nothing mapped. 
+pub unsafe fn kvm_try_map(va: usize, pa: usize, size: usize, perm: MapPerm) -> bool {
+    KERNEL_PAGETABLE.try_kernel_map(
+        VirtualAddress::new(va),
+        PhysicalAddress::new(pa),
+        size,
+        perm
+    )
+}
+
+/// Map the registers of device name, size bytes at base, 
+/// at the same virtual address, read-write. 
+/// Must be called at boot, before other harts turn on paging. 
+pub unsafe fn kvm_map_device(name: &'static str, base: usize, size: usize) -> Result<(), &'static str> {
+    let start = page_round_down(base);
+    let end = base.checked_add(size).map(page_round_up).ok_or("device region wraps")?;
+    if size == 0 {
+        return Err("empty device region")
+    }
+    if end > KERNEL_BASE {
+        return Err("device region overlaps RAM")
+    }
+    let mut devices = DEVICES.acquire();
+    if devices.iter().flatten().any(|dev| dev.start < end && start < dev.end) {
+        drop(devices);
+        return Err("device regions overlap")
+    }
+    let slot = match devices.iter_mut().find(|dev| dev.is_none()) {
+        Some(slot) => slot,
+        None => {
+            drop(devices);
+            return Err("too many devices")
+        }
+    };
+    *slot = Some(Device{ name, start, end });
+    drop(devices);
+    kvm_map(start, start, end - start, MapPerm::KernelRW);
+    Ok(())
+}
+
+/// Print the device regions mapped. 
+pub fn kvm_dump() {
+    let devices = DEVICES.acquire();
+    for dev in devices.iter().flatten() {
+        println!("kvm: {:<12} 0x{:x}-0x{:x}", dev.name, dev.start, dev.end);
+    }
+    drop(devices);
+}
@@ -1,11 +1,9 @@
 pub mod page_table;
 pub mod page_table_entry;
-pub mod kernel_map;
 
 
 pub use page_table::*;
 pub use page_table_entry::*;
-pub use kernel_map::*;
 
 use crate::arch::riscv::qemu::layout::PGSIZE;
 
@@ -13,6 +11,11 @@ pub fn page_round_up(addr: usize) -> usize{
     (addr + PGSIZE - 1) & !(PGSIZE - 1)
 }
 
+/// page_round_up for a length from user space, None if it wraps. 
+pub fn checked_page_round_up(addr: usize) -> Option<usize>{
+    addr.checked_add(PGSIZE - 1).map(|addr| addr & !(PGSIZE - 1))
+}
+
 pub fn page_round_down(addr: usize) -> usize{
     addr & !(PGSIZE - 1)
 }
\ No newline at end of file
@@ -1,21 +1,23 @@
-use core::ptr::{write, read, write_bytes, copy_nonoverlapping};
-use core::ptr::drop_in_place;
+use core::ptr::{write, read, copy_nonoverlapping};
 use core::ptr::copy;
 
 use crate::trap::kernel_trap;
 use crate::arch::riscv::{ sfence_vma, satp };
-use crate::memory::mapping::page_table_entry::{ PageTableEntry, PteFlags};
-use crate::arch::riscv::qemu::layout::{ PGSIZE, MAXVA, PGSHIFT, TRAMPOLINE, TRAPFRAME };
+use crate::memory::mapping::page_table_entry::{ PageTableEntry, PteFlags, MapPerm };
+use crate::arch::riscv::qemu::layout::{ PGSIZE, MAXVA, PGSHIFT, PGMASKLEN, TRAMPOLINE, TRAPFRAME, MEGAPAGE_SIZE, PT_LEVELS };
 use crate::memory::{
-    address::{ VirtualAddress, PhysicalAddress, Addr }, 
+    address::{ VirtualAddress, PhysicalAddress, Addr, PageRange }, 
     kalloc::KERNEL_HEAP,
     RawPage,
-    PageAllocator
+    PageAllocator,
+    swap::{ alloc_user_page, free_slot, read_slot },
+    zero_page
 };
 use crate::misc::mem_copy;
 
 
 use alloc::boxed::Box;
+use alloc::alloc::{ alloc_zeroed, Layout };
 use super::*;
 
 #[derive(Debug, Clone )]
@@ -24,9 +26,6 @@ pub struct PageTable{
     pub entries: [PageTableEntry; PGSIZE/8],
 }
 
-static mut KERNEL_PAGETABLE:PageTable = PageTable::empty();
-
-
 impl PageTable{
     pub fn as_addr(&self) -> usize{
         self.entries.as_ptr() as usize
@@ -41,7 +40,7 @@ impl PageTable{
     /// Convert the page table to be the usize
     /// that can be written in satp register
     pub fn as_satp(&self) -> usize {
-        satp::SATP_SV39 | ((self.entries.as_ptr() as usize) >> PGSHIFT)
+        satp::SATP_MODE | ((self.entries.as_ptr() as usize) >> PGSHIFT)
     }
 
     #[inline]
@@ -58,24 +57,63 @@ impl PageTable{
     }
 
 
-    /// Recursively free page-table pages.
-    /// All leaf mappings must already have been removed.
-    pub fn free(&mut self) {
-        // there are 2^9 = 512 PTEs in a pagetable
-        for i in 0..self.entries.len() {
-            let pte = self.entries[i];
-            if pte.is_valid() && !pte.is_leaf() {
-                // this PTE points to a lower-level page. 
-                unsafe {
-                    let child_pgt = &mut *(pte.as_pagetable());
-                    child_pgt.free();
-                }
-                self.entries[i] = PageTableEntry::new(0);
-            } else if pte.is_valid() {
-                panic!("pagetable free(): leaf not be removed");
+    /// Print the page table recursively, a line for each valid or 
+    /// swapped PTE with its virtual range, physical target and flags. 
+    /// For debugging. 
+    pub fn vmprint(&self) {
+        println!("page table {:#x}", self.as_addr());
+        self.vmprint_level(PT_LEVELS - 1, 0);
+    }
+
+    fn vmprint_level(&self, level: usize, base: usize) {
+        const DOTS: &str = " .. .. .. ..";
+        let indent = &DOTS[..3 * (PT_LEVELS - level)];
+        let span = PGSIZE << (level * PGMASKLEN);
+        for (i, pte) in self.entries.iter().enumerate() {
+            let va = base + i * span;
+            if pte.is_swapped() {
+                println!("{}{}: va {:#x}-{:#x} swapped to slot {}", indent, i, va, va + span, pte.swap_slot());
+                continue;
+            }
+            if !pte.is_valid() {
+                continue;
+            }
+            let flags = pte.as_flags();
+            let bit = |flag: PteFlags, c: char| if flags & flag.bits() != 0 { c } else { '-' };
+            println!(
+                "{}{}: va {:#x}-{:#x} pa {:#x} {}{}{}{}{}{}",
+                indent, i, va, va + span, pte.as_pagetable() as usize,
+                bit(PteFlags::R, 'r'), bit(PteFlags::W, 'w'), bit(PteFlags::X, 'x'),
+                bit(PteFlags::U, 'u'), bit(PteFlags::A, 'a'), bit(PteFlags::D, 'd')
+            );
+            if level > 0 && !pte.is_leaf() {
+                unsafe{ (*pte.as_pagetable()).vmprint_level(level - 1, va) };
             }
         }
-        drop(self);
+    }
+
+    /// Print the PTEs a walk for va goes through, from the top 
+    /// level down to the first invalid or leaf one. 
+    /// For diagnosing page faults. 
+    pub fn print_walk(&self, va: usize) {
+        if va >= MAXVA {
+            println!("  va 0x{:x} is beyond MAXVA", va);
+            return
+        }
+        let va = VirtualAddress::new(va);
+        let mut page_table = self as *const PageTable;
+        for level in (0..PT_LEVELS).rev() {
+            let index = va.page_num(level);
+            let pte = unsafe{ (*page_table).entries[index] };
+            println!(
+                "  level {}: table 0x{:x} [{}] = 0x{:x}", 
+                level, page_table as usize, index, pte.as_usize()
+            );
+            if !pte.is_valid() || pte.is_leaf() {
+                return
+            }
+            page_table = pte.as_pagetable();
+        }
     }
 
     /// Return the address of the PTE in page table pagetable
@@ -90,12 +128,15 @@ impl PageTable{
     ///   21..29 -- 9 bits of level-1 index.
     ///   12..20 -- 9 bits of level-0 index.
     ///    0..11 -- 12 bits of byte offset within the page.
+    /// With the sv48 feature there are four levels, bits 39..47 
+    /// index level 3 and only 48..63 must be zero. 
     /// 
     /// Look up a virtual address, return the physical address,
     /// or 0 if not mapped.
     /// Can only be used to look up user pages.
+    /// A megapage of the kernel map yields its level-1 leaf. 
     /// 将虚拟地址翻译成物理地址，返回页表项
-    fn translate(
+    pub fn translate(
         &mut self,
         va: VirtualAddress
     ) -> Option<&mut PageTableEntry> {
@@ -103,8 +144,11 @@ impl PageTable{
             return None
         }
         let mut page_table = self as *mut PageTable;
-        for level in (1..=2).rev() {
+        for level in (1..PT_LEVELS).rev() {
             let pte = unsafe{ &mut (*page_table).entries[va.page_num(level)] };
+            if pte.is_valid() && pte.is_leaf() {
+                return Some(pte)
+            }
             if pte.is_valid() {
                 page_table = pte.as_pagetable();
     
@@ -120,26 +164,45 @@ impl PageTable{
     fn translate_or_alloc(
         &mut self,
         va: VirtualAddress
+    ) -> Option<&mut PageTableEntry> {
+        self.translate_or_alloc_level(va, 0)
+    }
+
+    /// translate_or_alloc() that stops at the PTE of the given level, 
+    /// 1 for a megapage leaf and 0 for a normal page. 
+    /// None if out of memory for a page-table page. 
+    fn translate_or_alloc_level(
+        &mut self,
+        va: VirtualAddress,
+        leaf_level: usize
     ) -> Option<&mut PageTableEntry> {
         let mut pagetable = self as *mut PageTable;
         let real_addr:usize = va.as_usize();
         if real_addr > MAXVA {
             panic!("walk");
         }
-        for level in (1..=2).rev() {
+        for level in (leaf_level + 1..PT_LEVELS).rev() {
             let pte = unsafe{ &mut (*pagetable).entries[va.page_num(level)] };
+            if pte.is_valid() && pte.is_leaf() {
+                panic!("walk: va {:#x} inside a megapage", real_addr);
+            }
             if pte.is_valid() {
                 pagetable = pte.as_pagetable();
     
             }else {
-                let zeroed_pgt: Box<PageTable> = unsafe{ 
-                    Box::new_zeroed().assume_init()
-                };
-                pagetable = Box::into_raw(zeroed_pgt);
+                // Not Box::new_zeroed(), which panics when memory runs out. 
+                // Freed as a Box<PageTable> with the table, same layout. 
+                let zeroed_pgt = unsafe{ 
+                    alloc_zeroed(Layout::new::<PageTable>()) 
+                } as *mut PageTable;
+                if zeroed_pgt.is_null() {
+                    return None
+                }
+                pagetable = zeroed_pgt;
                 pte.0 = (((pagetable as usize) >> 12) << 10) | (PteFlags::V.bits());
             }
         }
-        Some(unsafe{&mut (*pagetable).entries[va.page_num(0)]})
+        Some(unsafe{&mut (*pagetable).entries[va.page_num(leaf_level)]})
     }
 
     /// Look up a virtual address, return the physical address,
@@ -172,6 +235,28 @@ impl PageTable{
     }
 
 
+    /// Whether va has a valid leaf mapping, user or not. 
+    pub fn is_mapped(&mut self, va: VirtualAddress) -> bool {
+        self.translate(va).map_or(false, |pte| pte.is_valid())
+    }
+
+    /// The leaf PTE of va, if it is mapped. 
+    pub fn lookup(&mut self, va: VirtualAddress) -> Option<PageTableEntry> {
+        self.translate(va).filter(|pte| pte.is_valid()).map(|pte| *pte)
+    }
+
+    /// Physical address of the user page at va for the copy 
+    /// functions, which must be writable if write. 
+    /// The pages are not faulted in here, AddressSpace's 
+    /// copy functions do that before calling these. 
+    fn user_page(&mut self, va: VirtualAddress, write: bool) -> Result<PhysicalAddress, &'static str> {
+        match self.lookup(va).filter(|pte| pte.is_user()) {
+            Some(pte) if !write || pte.is_write() => Ok(PhysicalAddress::new(pte.as_pagetable() as usize)),
+            Some(_) => Err("copy: user page not writable"),
+            None => Err("copy: user address not mapped")
+        }
+    }
+
     /// Create PTEs for virtual addresses starting at va that refer to
     /// physical addresses starting at pa. va and size might not
     /// be page-aligned. Returns 0 on success, -1 if walk() couldn't
@@ -179,16 +264,36 @@ impl PageTable{
     /// 将虚拟地址与物理地址建立映射，并写入MMU中
     pub unsafe fn map(
         &mut self, 
-        mut va: VirtualAddress, 
+        va: VirtualAddress, 
+        pa: PhysicalAddress, 
+        size:usize, 
+        perm:MapPerm
+    ) -> bool {
+        self.map_pages(va, pa, size, perm, false)
+    }
+
+    /// map(), and if huge, with 2 MiB megapages wherever va, pa 
+    /// and the rest of the range are megapage-aligned. 
+    /// Only for the kernel map, user pages are unmapped one page at a time. 
+    unsafe fn map_pages(
+        &mut self, 
+        va: VirtualAddress, 
         mut pa: PhysicalAddress, 
         size:usize, 
-        perm:PteFlags
+        perm:MapPerm,
+        huge: bool
     ) -> bool {
-        let mut last = VirtualAddress::new(va.as_usize() + size);
-        va.pg_round_down();
-        last.pg_round_up();
-        while va != last{
-            match self.translate_or_alloc(va){
+        let last = match va.checked_add(size) {
+            Some(last) => last.page_round_up(),
+            None => return false
+        };
+        let mut va = va.page_round_down();
+        while va.as_usize() < last.as_usize() {
+            let mega = huge
+                && va.as_usize() % MEGAPAGE_SIZE == 0
+                && pa.as_usize() % MEGAPAGE_SIZE == 0
+                && last.as_usize() - va.as_usize() >= MEGAPAGE_SIZE;
+            match self.translate_or_alloc_level(va, if mega { 1 } else { 0 }){
                 Some(pte) => {
                 // TODO - is_valid?
                 if pte.is_valid() {
@@ -200,9 +305,14 @@ impl PageTable{
                     );
                     panic!("remap");
                 }
-                pte.write_perm(pa, perm);
-                va.add_page();
-                pa.add_page();
+                pte.write_perm(pa, perm.flags());
+                if mega {
+                    va = VirtualAddress::new(va.as_usize() + MEGAPAGE_SIZE);
+                    pa = PhysicalAddress::new(pa.as_usize() + MEGAPAGE_SIZE);
+                } else {
+                    va.add_page();
+                    pa.add_page();
+                }
 
                 }
                 None => return false
@@ -211,15 +321,14 @@ impl PageTable{
         true
     }
 
-    /// add a mapping to the kernel page table.
-    /// only used when booting
+    /// add a mapping to the kernel page table, see kvm_map().
     /// does not flush TLB or enable paging   
     pub unsafe fn kernel_map(
         &mut self, 
         va:VirtualAddress, 
         pa:PhysicalAddress, 
         size:usize, 
-        perm:PteFlags
+        perm:MapPerm
     ) {
         // println!(
         //     "kvm_map: va={:#x}, pa={:#x}, size={:#x}",
@@ -227,11 +336,28 @@ impl PageTable{
         //     pa.as_usize(),
         //     size
         // );
-        if !self.map(va, pa, size, perm){
+        if !self.map_pages(va, pa, size, perm, true){
             panic!("内核虚拟地址映射失败");
         }
     }
 
+    /// kernel_map() for mappings made after boot, with 4 KiB pages. 
+    /// Returns false if out of memory for a page-table page, 
+    /// with nothing of the range left mapped. 
+    pub unsafe fn try_kernel_map(
+        &mut self, 
+        va:VirtualAddress, 
+        pa:PhysicalAddress, 
+        size:usize, 
+        perm:MapPerm
+    ) -> bool {
+        if self.map_pages(va, pa, size, perm, false) {
+            return true
+        }
+        self.uvm_unmap(va.page_round_down(), page_round_up(size) / PGSIZE, false);
+        false
+    }
+
 
     /// Create an empty user page table.
     /// return None if out of memory
@@ -242,50 +368,58 @@ impl PageTable{
     /// Load the user initcode into address 0 of pagetable
     /// for the very first process
     /// size must be less than a page
+    /// The page is mapped U|R|X, not W, to keep W^X as exec() does; 
+    /// initcode only runs exec("/init") and writes no memory. 
     pub unsafe fn uvm_init(&mut self, src: &[u8]){
         if src.len() >= PGSIZE{
-            panic!("inituvm: more than a page");
+            panic!("uvminit: more than a page");
         }
 
         let mem = RawPage::new_zeroed() as *mut u8;
-        write_bytes(mem, 0, PGSIZE);
+        copy_nonoverlapping(src.as_ptr(), mem, src.len());
 
-        self.map(
+        if !self.map(
             VirtualAddress::new(0), 
             PhysicalAddress::new(mem as usize), 
             PGSIZE, 
-            PteFlags::W | PteFlags::R | PteFlags::X | PteFlags::U
-        );
-
-        copy_nonoverlapping(src.as_ptr(), mem, src.len());
+            MapPerm::UserRX
+        ) {
+            panic!("uvminit: fail to map the first page");
+        }
     }
 
 
     /// Allocate PTEs and physical memory to grow process from old_size to
     /// new_size, which need not be page aligned.  Returns new size or 0 on error.
+    /// New pages are mapped with perm. 
     pub unsafe fn uvm_alloc(
         &mut self, 
-        mut old_size: usize, 
-        new_size: usize
+        old_size: usize, 
+        new_size: usize,
+        perm: MapPerm
     ) -> Option<usize> {
         if new_size < old_size {
             return Some(old_size)
         }
 
-        old_size = page_round_up(old_size);
-
-        for cur_size in (old_size..new_size).step_by(PGSIZE) {
-            let memory = RawPage::new_zeroed();
-            write_bytes(memory as *mut u8, 0, PGSIZE);
+        let start = VirtualAddress::new(old_size).page_round_up();
+        for va in PageRange::new(start, VirtualAddress::new(new_size)) {
+            let memory = match alloc_user_page() {
+                Some(memory) => memory,
+                None => {
+                    self.uvm_dealloc(va.as_usize(), start.as_usize());
+                    return None
+                }
+            };
 
             if !self.map(
-                VirtualAddress::new(cur_size), 
+                va, 
                 PhysicalAddress::new(memory), 
                 PGSIZE, 
-                PteFlags::W | PteFlags::R | PteFlags::X | PteFlags::U
+                perm
             ){
-                drop_in_place(memory as *mut RawPage);
-                self.uvm_dealloc(cur_size, old_size);
+                RawPage::free(memory);
+                self.uvm_dealloc(va.as_usize(), start.as_usize());
                 return None
             }
         }
@@ -293,23 +427,6 @@ impl PageTable{
         Some(new_size)
     }
 
-    /// Free user memory pages,
-    /// then free page-table pages
-    pub fn uvm_free(&mut self, size: usize) {
-        if size > 0 {
-            let mut pa = PhysicalAddress::new(size);
-            pa.pg_round_up();
-            let ppn = pa.as_usize() / PGSIZE;
-            self.uvm_unmap(
-                VirtualAddress::new(0),
-                 ppn,
-                true
-            );
-        }
-        drop(self);
-    }
-
-
     /// Deallocate user pages to bring the process size from old_size to
     /// new_size.  old_size and new_size need not be page-aligned, nor does new_size
     /// need to be less than old_size.  old_size can be larger than the actual
@@ -323,13 +440,10 @@ impl PageTable{
             return old_size
         }
 
-        if page_round_up(new_size) < page_round_up(old_size) {
-            let pages_num = (page_round_up(old_size) - page_round_up(new_size)) / PGSIZE;
-            self.uvm_unmap(
-                VirtualAddress::new(page_round_up(new_size)), 
-            pages_num, 
-                true
-            );
+        let start = VirtualAddress::new(new_size).page_round_up();
+        let pages = PageRange::new(start, VirtualAddress::new(old_size)).len();
+        if pages > 0 {
+            self.uvm_unmap(start, pages, true);
         }
 
         new_size
@@ -338,8 +452,10 @@ impl PageTable{
 
 
     /// Remove npages of mappings starting from va. va must be
-    /// page-aligned. The mappings must exist.
-    /// Optionally free the physical memory.
+    /// page-aligned. Pages that were never touched since sbrk() 
+    /// have no mapping and are skipped.
+    /// Optionally free the physical memory, or the swap slot 
+    /// of a page that is swapped out.
     pub fn uvm_unmap(
         &mut self, 
         mut va: VirtualAddress, 
@@ -352,73 +468,97 @@ impl PageTable{
         
         for _ in 0..npages {
             match self.translate(va) {
-                Some(pte) => {
-                    if !pte.is_valid() {
-                        panic!("uvm_unmap: not mapped");
-                    }
+                Some(pte) if pte.is_valid() => {
                     if pte.as_flags() == PteFlags::V.bits() {
                         panic!("uvm_unmap: not a leaf");
                     }
+                    let pa = pte.as_pagetable() as usize;
+                    if free && pa != zero_page() {
+                        unsafe{ RawPage::free(pa) };
+                    }
+                    pte.write_zero();
+                },
+
+                Some(pte) if pte.is_swapped() => {
                     if free {
-                        let pa = pte.as_pagetable();
-                        unsafe{ drop_in_place(pa) };
+                        free_slot(pte.swap_slot());
                     }
+                    pte.write_zero();
                 },
 
-                None => {
-                    panic!("uvm_unmap");
-                }
+                _ => {}
             }
             va.add_page();
         }
     }
 
 
-    /// Given a parent process's page table, copy
-    /// its memory into a child's page table.
-    /// Copies both the page table and the
-    /// physical memory.
-    /// returns 0 on success, -1 on failure.
-    /// frees any allocated pages on failure.
-    pub unsafe fn uvm_copy(
+    /// Given a parent process's page table, copy the user pages 
+    /// in [start, end) into a child's page table, start must be 
+    /// page-aligned. Copies both the page table and the
+    /// physical memory, pages not allocated yet stay that way.
+    /// Swapped out pages are read back into the child's copy. 
+    /// On failure every page already copied is unmapped and 
+    /// freed from the child again, so fork() only frees the process. 
+    pub unsafe fn uvm_copy_range(
         &mut self, 
         child_pgt: &mut Self, 
-        size: usize
+        start: usize,
+        end: usize
     ) -> Result<(), &'static str> {
-        let mut va = VirtualAddress::new(0);
-        while va.as_usize() != size {
-            match self.translate(va) {
-                Some(pte) => {
-                    if !pte.is_valid() {
-                        panic!("uvmcopy(): page not present");
-                    }
-
-                    let page_table = pte.as_pagetable();
-                    let flags = pte.as_flags();
-                    let flags = PteFlags::new(flags);
-
-                    let allocated_pgt = &mut *(RawPage::new_zeroed() as *mut PageTable);
-                    allocated_pgt.write(& *page_table);
-
-                    // println!("uvm_copy: va: 0x{:x}", va.as_usize());
-                    if !child_pgt.map(
-                        va,
-                        PhysicalAddress::new(allocated_pgt.as_addr()),
-                        PGSIZE,
-                        flags
-                    ) {
-                        drop(allocated_pgt);
+        let mut va = VirtualAddress::new(start);
+        while va.as_usize() < end {
+            let present = self.translate(va)
+                .map_or(false, |pte| pte.is_valid() || pte.is_swapped());
+            let zero = self.lookup(va)
+                .map_or(false, |pte| pte.as_pagetable() as usize == zero_page());
+            if zero {
+                // The zero page is shared, not copied. 
+                let perm = MapPerm::from_flags(PteFlags::new(self.lookup(va).unwrap().as_flags()))
+                    .ok_or("uvmcopy: bad permissions.")?;
+                if !child_pgt.map(va, PhysicalAddress::new(zero_page()), PGSIZE, perm) {
+                    child_pgt.uvm_unmap(
+                        VirtualAddress::new(start), 
+                        (va.as_usize() - start) / PGSIZE, 
+                        true
+                    );
+                    return Err("uvmcopy: Fail.")
+                }
+            } else if present {
+                let memory = match alloc_user_page() {
+                    Some(memory) => memory,
+                    None => {
                         child_pgt.uvm_unmap(
-                            VirtualAddress::new(0), 
-                            va.as_usize() / PGSIZE, 
+                            VirtualAddress::new(start), 
+                            (va.as_usize() - start) / PGSIZE, 
                             true
                         );
-                        return Err("uvmcopy: Fail.")
+                        return Err("uvmcopy: out of memory.")
                     }
-                },
-
-                None => {
-                    panic!("uvmcopy: No exist pte(pte should exist)");
+                };
+                // Look again, the allocation may have swapped it out. 
+                let pte = *self.translate(va).unwrap();
+                if pte.is_valid() {
+                    copy_nonoverlapping(pte.as_pagetable() as *const RawPage, memory as *mut RawPage, 1);
+                } else {
+                    read_slot(pte.swap_slot(), memory);
+                }
+                let perm = MapPerm::from_flags(PteFlags::new(pte.as_flags()));
+
+                // println!("uvm_copy: va: 0x{:x}", va.as_usize());
+                if !perm.map_or(false, |perm| child_pgt.map(
+                    va,
+                    PhysicalAddress::new(memory),
+                    PGSIZE,
+                    perm
+                )) {
+                    RawPage::free(memory);
+                    child_pgt.uvm_unmap(
+                        VirtualAddress::new(start), 
+                        (va.as_usize() - start) / PGSIZE, 
+                        true
+                    );
+                    return Err("uvmcopy: Fail.")
                 }
             }
             va.add_page();
@@ -427,16 +567,6 @@ impl PageTable{
         Ok(())
     }
 
-    /// mark a PTE invalid for user access.
-    /// used by exec for the user stack guard page.
-    pub fn uvm_clear(&mut self, va: VirtualAddress) {
-        if let Some(pte) = self.translate(va) {
-            pte.rm_user_bit();
-        }else {
-            panic!("uvmclear(): Not found valid pte for virtualaddress");
-        }
-    }
-
     /// Copy from kernel to user.
     /// Copy len bytes from src to virtual address dstva in a given page table.
     /// Return Result<(), Err>. 
@@ -446,6 +576,7 @@ impl PageTable{
         src: *const u8,
         mut len: usize 
     ) -> Result<(), &'static str> {
+        check_user_range(dst, len)?;
         // 从内核空间向用户空间拷贝数据
         // 拷贝的起始地址为 dst, 拷贝的结束地址为 dst + len
         // 首先将目标地址转成虚拟地址并进行页对齐
@@ -458,7 +589,7 @@ impl PageTable{
         // 拷贝地址的偏移量，即已经拷贝了多少字节
         let mut offset = 0;
         // 将目标地址的虚拟地址翻译成物理地址
-        let mut pa = self.pgt_translate(va).unwrap();
+        let mut pa = self.user_page(va, true)?;
         // 计算需要拷贝的虚拟地址的位置
         let mut dst_ptr = unsafe{
             pa.as_mut_ptr().offset((dst - va.as_usize()) as isize)
@@ -469,7 +600,7 @@ impl PageTable{
             // 由于在内核中数据区是直接映射，因此在访问物理地址的时候
             // 经过 MMU 不会报错
             // println!("[Debug] count: {}, len: {}", count, len);
-            if count > len {
+            if count >= len {
                 // 如果页内剩余的容量大于生于要拷贝的容量，则将count替换成len
                 count = len;
                 unsafe{
@@ -493,7 +624,7 @@ impl PageTable{
                 len -= count;
                 offset += count;
                 va.add_page();
-                pa = self.pgt_translate(va).unwrap();
+                pa = self.user_page(va, true)?;
                 count = PGSIZE;
                 dst_ptr = pa.as_mut_ptr();
             }
@@ -507,17 +638,18 @@ impl PageTable{
     pub fn copy_in(
         &mut self, 
         mut dst: *mut u8, 
-        src: usize, 
+        mut src: usize, 
         mut len: usize
     ) -> Result<(), &'static str> {
+        check_user_range(src, len)?;
         let mut va = VirtualAddress::new(src);
         va.pg_round_down();
         loop {
             // Get physical address by virtual address
-            let pa = self.pgt_translate(va).unwrap();
+            let pa = self.user_page(va, false)?;
             // Get copy bytes of current page.
             let count = PGSIZE - (src - va.as_usize());
-            if len < count {
+            if len <= count {
                 mem_copy(
                     dst as usize, 
                     pa.as_usize() + ( src - va.as_usize() ), 
@@ -534,26 +666,28 @@ impl PageTable{
             len -= count;
             dst = unsafe{ dst.offset(count as isize) };
             va.add_page();
+            src = va.as_usize();
         }
     }
 
     /// Copy a null-trrminated string from user to kernel. 
     /// Copy bytes to dst from virtual address src in a given table. 
     /// until a '\0', or max. 
-    /// Return Result. 
+    /// Return Result, an error if there is no '\0' within max bytes. 
     pub fn copy_in_str(
         &mut self, 
-        dst: *mut u8,
-        src: usize,
+        mut dst: *mut u8,
+        mut src: usize,
         mut max: usize
     ) -> Result<(),&'static str> {
+        check_user_range(src, 0)?;
         // 将 src 作为虚拟地址
         let mut va = VirtualAddress::new(src as usize);
         // 将虚拟地址进行页对齐
         va.pg_round_down();
         loop {
             // 将用户态的虚拟地址转成物理地址
-            let pa = self.pgt_translate(va).unwrap();
+            let pa = self.user_page(va, false)?;
             // 计算该页所要读取的字节数
             let count = PGSIZE - (src - va.as_usize());
             let s = (pa.as_usize() + (src - va.as_usize())) as *const u8;
@@ -570,7 +704,7 @@ impl PageTable{
                         write(dst_ptr, val);
                     }
                 }
-                return Ok(())
+                return Err("copy_in_str: string too long")
             }
 
             for i in 0..count {
@@ -583,14 +717,17 @@ impl PageTable{
                 }
             }
             max -= count;
+            dst = unsafe{ dst.offset(count as isize) };
             va.add_page();
+            src = va.as_usize();
         }
     }
 
 
-    /// Free a process's page table, and free the
-    /// physical memory it refers to.
-    pub fn proc_free_pagetable(&mut self, size: usize) {
+    /// Unmap the trampoline and trapframe of a process's page table, 
+    /// whose user memory has been unmapped already. The page-table 
+    /// pages are freed when it is dropped. 
+    pub fn proc_free_pagetable(&mut self) {
         self.uvm_unmap(
             VirtualAddress::new(TRAMPOLINE ), 
             1, 
@@ -602,16 +739,40 @@ impl PageTable{
             1,
             false
         );
+    }
 
-        self.uvm_free(size);
+    /// Number of page-table pages in the tree, this one included. 
+    pub fn nr_tables(&self) -> usize {
+        1 + self.entries.iter()
+            .filter(|pte| pte.is_valid() && !pte.is_leaf())
+            .map(|pte| unsafe{ (*pte.as_pagetable()).nr_tables() })
+            .sum::<usize>()
     }
+}
 
+/// A user range [va, va+len) must not wrap or reach past MAXVA. 
+fn check_user_range(va: usize, len: usize) -> Result<(), &'static str> {
+    match va.checked_add(len) {
+        Some(end) if end <= MAXVA => Ok(()),
+        _ => Err("copy: user range out of bounds")
+    }
 }
 
-// impl Drop for PageTable {
-//     /// Recursively free non-first-level pagetables.
-//     /// Physical memory should already be freed.
-//     fn drop(&mut self) {
-//         self.entries.iter_mut().for_each(|pte| pte.free());
-//     }
-// }
\ No newline at end of file
+impl Drop for PageTable {
+    /// Recursively free the page-table pages below this one. 
+    /// All leaf mappings must already have been removed, 
+    /// for a user page table AddressSpace's drop does that. 
+    fn drop(&mut self) {
+        // there are 2^9 = 512 PTEs in a pagetable
+        for pte in self.entries.iter_mut() {
+            if pte.is_valid() && !pte.is_leaf() {
+                // this PTE points to a lower-level page, 
+                // allocated as a Box<PageTable> by the walk. 
+                unsafe{ drop(Box::from_raw(pte.as_pagetable())); }
+                pte.write_zero();
+            } else if pte.is_valid() {
+                panic!("pagetable drop: leaf not removed");
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -2,13 +2,15 @@ use alloc::boxed::Box;
 use crate::memory::address::{ PhysicalAddress, Addr};
 use super::page_table::PageTable;
 
-use core::ptr::drop_in_place;
 
 pub const PTE_V:usize = 1 << 0; // valid
 pub const PTE_R:usize = 1 << 1;
 pub const PTE_W:usize = 1 << 2;
 pub const PTE_X:usize = 1 << 3;
 pub const PTE_U:usize = 1 << 4; // 1 -> user can access
+pub const PTE_A:usize = 1 << 6; // accessed
+pub const PTE_D:usize = 1 << 7; // dirty
+pub const PTE_S:usize = 1 << 8; // swapped out, software bit
 
 #[derive(Debug, Clone, Copy)]
 pub struct PageTableEntry(pub usize);
@@ -21,6 +23,9 @@ bitflags!{
         const W = PTE_W;
         const X = PTE_X;
         const U = PTE_U;
+        const A = PTE_A;
+        const D = PTE_D;
+        const S = PTE_S;
     }
 
 }
@@ -33,6 +38,72 @@ impl PteFlags {
     }
 }
 
+/// The permissions a page can be mapped with. map() only takes 
+/// these, so a mapping with a nonsense combination, like a user 
+/// page both writable and executable or a trapframe that is 
+/// executable, can't be written down. 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapPerm {
+    /// A user page with no access. Readable without PTE_U, 
+    /// as V alone would make the PTE point to a page table. 
+    UserNone,
+    UserR,
+    UserX,
+    UserRX,
+    UserRW,
+    #[cfg(feature = "allow-wx")]
+    UserRWX,
+    /// Kernel text. 
+    KernelRX,
+    /// Kernel data, devices, kernel stacks and trapframes. 
+    KernelRW,
+    /// The trampoline page, at the same address in every page table. 
+    Trampoline,
+}
+
+impl MapPerm {
+    pub fn flags(self) -> PteFlags {
+        match self {
+            MapPerm::UserNone => PteFlags::R,
+            MapPerm::UserR => PteFlags::U | PteFlags::R,
+            MapPerm::UserX => PteFlags::U | PteFlags::X,
+            MapPerm::UserRX => PteFlags::U | PteFlags::R | PteFlags::X,
+            MapPerm::UserRW => PteFlags::U | PteFlags::R | PteFlags::W,
+            #[cfg(feature = "allow-wx")]
+            MapPerm::UserRWX => PteFlags::U | PteFlags::R | PteFlags::W | PteFlags::X,
+            MapPerm::KernelRX | MapPerm::Trampoline => PteFlags::R | PteFlags::X,
+            MapPerm::KernelRW => PteFlags::R | PteFlags::W,
+        }
+    }
+
+    /// The permission of a user page whose PTE has flags, 
+    /// None if no MapPerm has them. 
+    pub fn from_flags(flags: PteFlags) -> Option<Self> {
+        let flags = flags & (PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U);
+        [
+            MapPerm::UserNone, MapPerm::UserR, MapPerm::UserX, MapPerm::UserRX, MapPerm::UserRW,
+            #[cfg(feature = "allow-wx")]
+            MapPerm::UserRWX,
+        ].iter().copied().find(|perm| perm.flags() == flags)
+    }
+
+    /// The same without write access, for pages that are 
+    /// copied on the first write. 
+    pub fn read_only(self) -> Self {
+        match self {
+            MapPerm::UserRW => MapPerm::UserR,
+            #[cfg(feature = "allow-wx")]
+            MapPerm::UserRWX => MapPerm::UserRX,
+            MapPerm::KernelRW => panic!("read_only: kernel page"),
+            perm => perm,
+        }
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.flags().contains(PteFlags::W)
+    }
+}
+
 
 impl PageTableEntry{
     #[inline]
@@ -58,7 +129,7 @@ impl PageTableEntry{
 
     #[inline]
     pub fn is_user(&self) -> bool {
-        (self.0 & (PteFlags::V.bits())) > 0
+        (self.0 & (PteFlags::U.bits())) > 0
     }
 
     #[inline] 
@@ -76,6 +147,52 @@ impl PageTableEntry{
         (self.0 & (PteFlags::X.bits())) > 0
     }
 
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        (self.0 & (PteFlags::D.bits())) > 0
+    }
+
+    #[inline]
+    pub fn clear_dirty(&mut self) {
+        self.0 &= !(PteFlags::D.bits());
+    }
+
+    #[inline]
+    pub fn is_accessed(&self) -> bool {
+        (self.0 & (PteFlags::A.bits())) > 0
+    }
+
+    #[inline]
+    pub fn clear_accessed(&mut self) {
+        self.0 &= !(PteFlags::A.bits());
+    }
+
+    #[inline]
+    pub fn is_swapped(&self) -> bool {
+        !self.is_valid() && (self.0 & (PteFlags::S.bits())) > 0
+    }
+
+    /// Swap slot of a swapped-out page, kept in the ppn field. 
+    #[inline]
+    pub fn swap_slot(&self) -> usize {
+        self.0 >> 10
+    }
+
+    /// Mark the page swapped out to slot, 
+    /// keeping the permissions for when it comes back. 
+    #[inline]
+    pub fn write_swapped(&mut self, slot: usize) {
+        let perm = self.0 & (PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U).bits();
+        self.0 = (slot << 10) | perm | PteFlags::S.bits();
+    }
+
+    /// Map the page swapped back in at pa. 
+    #[inline]
+    pub fn write_swapped_in(&mut self, pa: PhysicalAddress) {
+        let perm = PteFlags::new(self.0 & (PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U).bits());
+        self.write_perm(pa, perm);
+    }
+
     #[inline]
     pub fn is_leaf(&self) -> bool {
         let flag_bits = self.0 & (PteFlags::R | PteFlags::W | PteFlags::X).bits();
@@ -133,16 +250,6 @@ impl PageTableEntry{
         self.0 = addr
     }
 
-    pub fn free(&mut self) {
-        if self.is_valid() {
-            if !self.is_leaf() {
-                unsafe{ drop_in_place(self.as_pagetable()) };
-            } else {
-                panic!("freeing a pte leaf")
-            }
-        }
-    }
-    
 }
 
 // impl Drop for PageTableEntry {
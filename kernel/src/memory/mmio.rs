@@ -0,0 +1,66 @@
+//! Memory-mapped device registers.
+//!
+//! Every device qemu gives us sits at a fixed physical address,
+//! which kvm_init() maps at the same virtual address. An Mmio
+//! names one register block and its size, and reads and writes
+//! registers of type T at byte offsets into it with volatile
+//! accesses, fenced against normal memory so e.g. a virtio
+//! descriptor written before the notify is seen by the device.
+
+use core::marker::PhantomData;
+use core::ptr;
+
+
+#[derive(Clone, Copy)]
+pub struct Mmio<T> {
+    base: usize,
+    size: usize,
+    _reg: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// SAFETY: [base, base + size) must be the registers of one device, 
+    /// reachable at base wherever the Mmio is used: mapped there by 
+    /// kvm_init(), or used in machine mode without paging. Every 
+    /// access of a T inside it must be one the device allows, 
+    /// read() and write() can then be safe. 
+    pub const unsafe fn new(base: usize, size: usize) -> Self {
+        Self {
+            base,
+            size,
+            _reg: PhantomData,
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn addr(&self, offset: usize) -> usize {
+        assert!(offset + core::mem::size_of::<T>() <= self.size, "mmio: offset out of range");
+        self.base + offset
+    }
+
+    /// Read the register at offset.
+    pub fn read(&self, offset: usize) -> T {
+        let val = unsafe{ ptr::read_volatile(self.addr(offset) as *const T) };
+        io_fence();
+        val
+    }
+
+    /// Write the register at offset.
+    pub fn write(&self, offset: usize, val: T) {
+        io_fence();
+        unsafe{ ptr::write_volatile(self.addr(offset) as *mut T, val); }
+    }
+}
+
+/// Order device accesses against memory accesses.
+#[inline]
+fn io_fence() {
+    unsafe{ core::arch::asm!("fence iorw, iorw"); }
+}
@@ -1,12 +1,22 @@
 pub mod kalloc;
 pub mod mapping;
 pub mod address;
+pub mod swap;
+pub mod slab;
+pub mod refcount;
+pub mod tlb;
+pub mod mmio;
+pub mod page_box;
+pub mod kvm;
+pub mod dma;
 
 use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut, self};
 
 pub use kalloc::*;
 pub use mapping::*;
 pub use address::*;
+pub use refcount::PAGE_REF;
+pub use page_box::PageBox;
 
 use crate::{arch::riscv::qemu::layout::PGSIZE, process::{ CPU_MANAGER }};
 use crate::misc::mem_copy;
@@ -19,6 +29,11 @@ pub trait PageAllocator: Sized {
         let ptr = Box::into_raw(boxed_page) as usize;
         ptr
     }
+
+    /// Free memory from new_zeroed(). 
+    unsafe fn free(ptr: usize) {
+        drop(Box::from_raw(ptr as *mut Self));
+    }
 }
 
 #[repr(C, align(4096))]
@@ -28,12 +43,13 @@ pub struct RawPage {
 
 impl PageAllocator for RawPage{}
 
-#[repr(C, align(4096))]
-pub struct Stack {
-    data: [u8; PGSIZE * 4]
-}
+/// The page of zeros mapped read-only wherever user memory 
+/// has been read but never written. Never freed. 
+static ZERO_PAGE: RawPage = RawPage { data: [0; PGSIZE] };
 
-impl PageAllocator for Stack{}
+pub fn zero_page() -> usize {
+    &ZERO_PAGE as *const RawPage as usize
+}
 
 
 /// Copy from either a user address, or kernel address,
@@ -51,8 +67,8 @@ pub fn copy_to_kernel(
         
         if is_user {
             let pdata = &mut *(my_proc.data.get());
-            let page_table = pdata.pagetable.as_mut().unwrap();
-            page_table.copy_in(
+            let vm = pdata.vm();
+            vm.copy_in(
                 dst,
                 src,
                 len
@@ -82,8 +98,8 @@ pub fn copy_from_kernel(
         let p = CPU_MANAGER.myproc().unwrap();
         if is_user {
             let pdata = p.data.get_mut();
-            let page_table = pdata.pagetable.as_mut().unwrap();
-            page_table
+            let vm = pdata.vm();
+            vm
                 .copy_out(
                     dst,
                     src,
@@ -0,0 +1,62 @@
+//! A page of its own for one value.
+//!
+//! Some kernel data must fill a whole page, because the page is
+//! mapped somewhere by itself, like the trapframe that trampoline.S
+//! reaches at TRAPFRAME in user space. A PageBox owns such a page
+//! and gives it back to the page allocator when dropped, so it is
+//! freed on every path that drops its owner.
+
+use core::ops::{ Deref, DerefMut };
+use core::ptr::NonNull;
+use core::mem::size_of;
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use super::kalloc::{ kalloc_pages, kfree_pages };
+
+pub struct PageBox<T> {
+    ptr: NonNull<T>,
+}
+
+impl<T> PageBox<T> {
+    /// A zeroed page holding a T, None when out of memory.
+    /// All zeros must be a valid T.
+    pub unsafe fn new_zeroed() -> Option<Self> {
+        assert!(size_of::<T>() <= PGSIZE, "PageBox: type larger than a page");
+        let pa = kalloc_pages(0)?;
+        Some(Self {
+            ptr: NonNull::new_unchecked(pa as *mut T),
+        })
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// Physical address of the page, to map it.
+    pub fn pa(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+}
+
+impl<T> Deref for PageBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe{ self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe{ self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        unsafe{
+            self.ptr.as_ptr().drop_in_place();
+            kfree_pages(self.pa(), 0);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Reference counts of physical pages. 
+//!
+//! Every page from the page allocator starts with one reference. 
+//! Sharing it, e.g. for COW fork, shared memory or a shared file 
+//! mapping, takes another with inc(), and freeing the page only 
+//! drops a reference; the page goes back to the allocator when 
+//! the last one is dropped. 
+
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::layout::{ KERNEL_BASE, PHYSTOP, PGSIZE };
+
+const NFRAME: usize = (PHYSTOP - KERNEL_BASE) / PGSIZE;
+
+pub static PAGE_REF: PageRefCount = PageRefCount::new();
+
+pub struct PageRefCount {
+    counts: [AtomicU32; NFRAME]
+}
+
+impl PageRefCount {
+    const fn new() -> Self {
+        Self {
+            counts: array![_ => AtomicU32::new(0); NFRAME]
+        }
+    }
+
+    fn count(&self, pa: usize) -> &AtomicU32 {
+        if pa < KERNEL_BASE || pa >= PHYSTOP {
+            panic!("page_ref: pa {:#x} out of range", pa);
+        }
+        &self.counts[(pa - KERNEL_BASE) / PGSIZE]
+    }
+
+    /// Take another reference to the page at pa. 
+    pub fn inc(&self, pa: usize) {
+        let old = self.count(pa).fetch_add(1, Ordering::AcqRel);
+        if old == 0 {
+            panic!("page_ref: inc of free page {:#x}", pa);
+        }
+    }
+
+    /// Drop a reference to the page at pa, 
+    /// returns the references left. 
+    pub fn dec(&self, pa: usize) -> usize {
+        let old = self.count(pa).fetch_sub(1, Ordering::AcqRel);
+        if old == 0 {
+            panic!("page_ref: dec of free page {:#x}", pa);
+        }
+        old as usize - 1
+    }
+
+    /// The number of references to the page at pa. 
+    pub fn get(&self, pa: usize) -> usize {
+        self.count(pa).load(Ordering::Acquire) as usize
+    }
+
+    /// A fresh page from the allocator has a single reference. 
+    pub(super) fn init(&self, pa: usize) {
+        self.count(pa).store(1, Ordering::Release);
+    }
+
+    /// A page of a multi-page block going back to the allocator 
+    /// has none, it is never shared with inc(). 
+    pub(super) fn clear(&self, pa: usize) {
+        self.count(pa).store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,106 @@
+//! Slab caches of fixed-size kernel objects. 
+//!
+//! A KmemCache<T> carves whole pages from kalloc_pages() into 
+//! objects of T and keeps the free ones on a list, so a small 
+//! object no longer takes a page of its own. Pages stay with 
+//! the cache once taken and are reused for its later objects. 
+
+use core::marker::PhantomData;
+use core::mem::{ size_of, align_of };
+use core::ptr::{ self, NonNull };
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::lock::spinlock::Spinlock;
+use super::kalloc_pages;
+
+/// A free object, linked through its own memory. 
+struct FreeObj {
+    next: *mut FreeObj,
+}
+
+struct CacheInner {
+    free: *mut FreeObj,
+    pages: usize, // pages taken from the page allocator
+    active: usize, // objects handed out
+}
+
+unsafe impl Send for CacheInner {}
+
+pub struct KmemCache<T> {
+    inner: Spinlock<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T> Sync for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            inner: Spinlock::new(CacheInner {
+                free: ptr::null_mut(),
+                pages: 0,
+                active: 0,
+            }, name),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Size of one object in the page, big enough 
+    /// to hold the free list link and keep T aligned. 
+    const fn obj_size() -> usize {
+        let size = if size_of::<T>() > size_of::<FreeObj>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeObj>()
+        };
+        let align = if align_of::<T>() > align_of::<FreeObj>() {
+            align_of::<T>()
+        } else {
+            align_of::<FreeObj>()
+        };
+        (size + align - 1) & !(align - 1)
+    }
+
+    /// Move value into a new object of the cache. 
+    /// Returns None if a fresh page is needed and there is none. 
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        assert!(Self::obj_size() <= PGSIZE, "KmemCache: object larger than a page");
+        let mut inner = self.inner.acquire();
+        if inner.free.is_null() {
+            let page = kalloc_pages(0)?;
+            for i in (0..PGSIZE / Self::obj_size()).rev() {
+                let obj = (page + i * Self::obj_size()) as *mut FreeObj;
+                unsafe{ (*obj).next = inner.free; }
+                inner.free = obj;
+            }
+            inner.pages += 1;
+        }
+        let obj = inner.free;
+        inner.free = unsafe{ (*obj).next };
+        inner.active += 1;
+        drop(inner);
+
+        let obj = obj as *mut T;
+        unsafe{ ptr::write(obj, value); }
+        NonNull::new(obj)
+    }
+
+    /// Drop the object and give it back to the cache. 
+    /// SAFETY: obj must come from alloc() of this cache 
+    /// and not be used afterwards. 
+    pub unsafe fn free(&self, obj: NonNull<T>) {
+        ptr::drop_in_place(obj.as_ptr());
+        let obj = obj.as_ptr() as *mut FreeObj;
+        let mut inner = self.inner.acquire();
+        (*obj).next = inner.free;
+        inner.free = obj;
+        inner.active -= 1;
+        drop(inner);
+    }
+
+    /// Pages the cache holds and objects in use. 
+    pub fn stats(&self) -> (usize, usize) {
+        let inner = self.inner.acquire();
+        (inner.pages, inner.active)
+    }
+}
@@ -0,0 +1,169 @@
+//! Swapping of user pages to the swap area on the disk, 
+//! which is the NSWAP pages right after the file system. 
+//!
+//! A swapped-out page keeps its PTE with the valid bit clear, 
+//! PTE_S set and the swap slot in place of the physical page 
+//! number, so the page fault handler can bring it back in. 
+//! A slot is busy while its page is being written out, and 
+//! a fault on it has to wait for the write to finish. 
+
+use core::ptr;
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, ROOTDEV, SWAPSTART, NSWAP };
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::fs::BCACHE;
+use crate::lock::spinlock::Spinlock;
+use crate::process::{ CPU_MANAGER, PROC_MANAGER, OOM_RETRIES };
+use super::{ RawPage, PageAllocator, PageTableEntry, PhysicalAddress, kalloc_pages };
+use super::tlb::flush_range;
+
+const BLOCKS_PER_PAGE: usize = PGSIZE / BSIZE;
+
+static SWAP_MAP: Spinlock<SwapMap> = Spinlock::new(SwapMap::new(), "swap");
+
+struct SwapMap {
+    used: [u64; NSWAP / 64],
+    busy: [u64; NSWAP / 64], // being written out
+}
+
+impl SwapMap {
+    const fn new() -> Self {
+        Self {
+            used: [0; NSWAP / 64],
+            busy: [0; NSWAP / 64],
+        }
+    }
+
+    fn test(bits: &[u64], slot: usize) -> bool {
+        bits[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn set(bits: &mut [u64], slot: usize, on: bool) {
+        if on {
+            bits[slot / 64] |= 1 << (slot % 64);
+        } else {
+            bits[slot / 64] &= !(1 << (slot % 64));
+        }
+    }
+
+    /// Take a free slot, busy until the write is done. 
+    /// A freed slot can't be reused while still busy. 
+    fn alloc(&mut self) -> Option<usize> {
+        let slot = (0..NSWAP).find(|&slot| {
+            !Self::test(&self.used, slot) && !Self::test(&self.busy, slot)
+        })?;
+        Self::set(&mut self.used, slot, true);
+        Self::set(&mut self.busy, slot, true);
+        Some(slot)
+    }
+}
+
+#[inline]
+fn slot_channel(slot: usize) -> usize {
+    &SWAP_MAP as *const _ as usize + slot
+}
+
+/// Allocate a zeroed page for user memory. When the 
+/// allocator runs dry, user pages are swapped out to make 
+/// room, and when there is nothing left to swap out, 
+/// a process is killed for its memory, see oom_kill(). 
+/// None if the caller is to die as well, or if the memory 
+/// still isn't there after OOM_RETRIES kills or waits. 
+/// May sleep, so no spinlock can be held. 
+pub fn alloc_user_page() -> Option<usize> {
+    let mut retries = 0;
+    loop {
+        if let Some(page) = kalloc_pages(0) {
+            return Some(page)
+        }
+        if unsafe{ PROC_MANAGER.swap_out() } {
+            continue
+        }
+        if retries == OOM_RETRIES || !unsafe{ PROC_MANAGER.oom_kill() } {
+            return None
+        }
+        retries += 1;
+    }
+}
+
+/// Copy the page into the slot on the disk. 
+fn write_slot(slot: usize, pa: usize) {
+    for i in 0..BLOCKS_PER_PAGE {
+        let blockno = SWAPSTART + (slot * BLOCKS_PER_PAGE + i) as u32;
+        // The whole block is overwritten, no need to read it. 
+        let mut buf = BCACHE.bget(ROOTDEV, blockno);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (pa + i * BSIZE) as *const u8, 
+                buf.raw_data_mut() as *mut u8, 
+                BSIZE
+            );
+        }
+        buf.bwrite();
+        drop(buf);
+    }
+}
+
+/// Copy the slot on the disk into the page, 
+/// waiting for it to be written out first. 
+pub fn read_slot(slot: usize, pa: usize) {
+    let mut guard = SWAP_MAP.acquire();
+    while SwapMap::test(&guard.busy, slot) {
+        let p = unsafe{ CPU_MANAGER.myproc().expect("swap: no process to sleep") };
+        p.sleep(slot_channel(slot), guard);
+        guard = SWAP_MAP.acquire();
+    }
+    drop(guard);
+    for i in 0..BLOCKS_PER_PAGE {
+        let blockno = SWAPSTART + (slot * BLOCKS_PER_PAGE + i) as u32;
+        let buf = BCACHE.bread(ROOTDEV, blockno);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                buf.raw_data() as *const u8, 
+                (pa + i * BSIZE) as *mut u8, 
+                BSIZE
+            );
+        }
+        drop(buf);
+    }
+}
+
+pub fn free_slot(slot: usize) {
+    let mut guard = SWAP_MAP.acquire();
+    SwapMap::set(&mut guard.used, slot, false);
+    drop(guard);
+}
+
+/// Swap out the mapped user page of pte. 
+/// The PTE is switched over and the TLBs of the harts 
+/// running its address space, asid, are flushed of va 
+/// before the page is written. 
+/// Returns false if the swap area is full. 
+pub fn swap_out_page(pte: &mut PageTableEntry, asid: usize, va: usize) -> bool {
+    let slot = match SWAP_MAP.acquire().alloc() {
+        Some(slot) => slot,
+        None => return false
+    };
+    let pa = pte.as_pagetable() as usize;
+    pte.write_swapped(slot);
+    // No thread may write the page any more once it is being saved. 
+    flush_range(asid, va, PGSIZE);
+    write_slot(slot, pa);
+
+    let mut guard = SWAP_MAP.acquire();
+    SwapMap::set(&mut guard.busy, slot, false);
+    unsafe{ PROC_MANAGER.wake_up(slot_channel(slot)); }
+    drop(guard);
+    unsafe{ RawPage::free(pa); }
+    true
+}
+
+/// Bring the page of a swapped-out pte back in. 
+pub fn swap_in_page(pte: &mut PageTableEntry) -> Result<(), &'static str> {
+    let slot = pte.swap_slot();
+    let page = alloc_user_page().ok_or("swap in: out of memory")?;
+    read_slot(slot, page);
+    pte.write_swapped_in(PhysicalAddress::new(page));
+    free_slot(slot);
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! TLB shootdown.
+//!
+//! A hart only caches the translations of a user page table while it
+//! runs in user mode, since trampoline.S flushes the whole TLB on every
+//! switch of satp. So after a user mapping is removed or loses a
+//! permission, the harts that may still use the old PTE are those
+//! running the same address space in user mode at that moment.
+//! flush_range() interrupts each of them with a machine software
+//! interrupt, whose handler in kernelvec.S runs sfence.vma, and waits
+//! until they all have.
+
+use core::sync::atomic::{ AtomicUsize, Ordering, fence };
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::arch::riscv::qemu::param::NCPU;
+use crate::arch::riscv::{ clint, satp::ASID_MAX };
+use crate::process::{ IntrGuard, cpuid };
+
+/// Next ASID to hand out, 0 is never given to a user address space.
+static NEXT_ASID: AtomicUsize = AtomicUsize::new(1);
+
+/// The ASID each hart runs in user mode, 0 while in the kernel.
+static ACTIVE: [AtomicUsize; NCPU] = array![_ => AtomicUsize::new(0); NCPU];
+
+/// Pick an ASID for a new address space.
+/// ASIDs are reused after ASID_MAX address spaces, two spaces sharing
+/// one only costs some needless shootdowns.
+pub fn alloc_asid() -> usize {
+    NEXT_ASID.fetch_add(1, Ordering::Relaxed) % ASID_MAX + 1
+}
+
+/// Record that this hart is about to run asid in user mode,
+/// or has left user mode with 0. Interrupts must be off.
+pub fn set_active(asid: usize) {
+    ACTIVE[unsafe{ cpuid() }].store(asid, Ordering::SeqCst);
+}
+
+/// Flush the TLB entries of [va, va + len) in address space asid
+/// on every hart, after its PTEs have been changed.
+/// Returns when no hart can use the old translations any more,
+/// so the pages may be freed then.
+pub fn flush_range(asid: usize, va: usize, len: usize) {
+    for page in (va..va + len).step_by(PGSIZE) {
+        unsafe{ core::arch::asm!("sfence.vma {}, {}", in(reg)page, in(reg)asid); }
+    }
+    // The PTE writes must be visible before a hart
+    // that starts running asid after this reads them.
+    fence(Ordering::SeqCst);
+    let _intr = IntrGuard::new();
+    let me = unsafe{ cpuid() };
+    let mut sent = 0usize;
+    for hart in 0..NCPU {
+        if hart != me && ACTIVE[hart].load(Ordering::SeqCst) == asid {
+            unsafe{ clint::send_soft(hart); }
+            sent |= 1 << hart;
+        }
+    }
+    for hart in 0..NCPU {
+        if sent & (1 << hart) != 0 {
+            while unsafe{ clint::soft_pending(hart) } {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
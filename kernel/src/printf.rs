@@ -8,15 +8,11 @@ use crate::shutdown::*;
 
 pub fn _print(args: fmt::Arguments) {
     use fmt::Write;
-    let mut uart = UART.acquire();
-    uart.write_fmt(args).unwrap();
-    drop(uart);
+    UART.with(|uart| uart.write_fmt(args).unwrap());
 }
 
 pub fn console_ptr(c: u8) {
-    let mut uart = UART.acquire();
-    uart.put(c);
-    drop(uart);
+    UART.with(|uart| uart.put(c));
 }
 
 /// implement print and println! macro
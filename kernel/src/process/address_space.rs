@@ -0,0 +1,855 @@
+//! User address space of a process.
+//!
+//! The page table and the regions of user memory are kept out of ProcData
+//! so that threads created by clone() can share them. Every process
+//! holds an Arc to its address space, and user memory is freed when
+//! the last process using it is freed.
+//!
+//! User memory is the list of regions, see vma.rs: a user address is 
+//! good exactly when some region contains it. Pages of a region are 
+//! allocated the first time they are touched, see fault(). Until they 
+//! are written reads see the zero page, mapped read-only and shared 
+//! by everyone. 
+//!
+//! exec() records the program segments, the stack above them and 
+//! the heap above the stack, which sbrk() only moves the end of. 
+//! mmap() regions are placed downwards from mmap_top, at most MMAP_TOP 
+//! below the thread trapframes. 
+//!
+//! Under memory pressure private pages are swapped out, picked by 
+//! a clock that each address space keeps over its own pages. 
+//!
+//! The pages an address space owns, swapped out or not, and its 
+//! page-table pages are charged to its memory group, see memcg.rs. 
+//! Page-table pages are counted again by sync_tables(). 
+//!
+//! Threads of one address space may run on several harts at once, 
+//! so a mapping is only freed after tlb::flush_range() on its ASID. 
+//!
+//! The page table and the regions are behind a sleep lock, taken by 
+//! every method here, since faults and write-backs read and write 
+//! files while holding it. Inode locks are taken under it, so a copy 
+//! into user memory under an inode lock, as read() does, must not 
+//! fault on a mapping of that same file. 
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use alloc::boxed::Box;
+use crate::lock::arc::Arc;
+use alloc::vec::Vec;
+use array_macro::array;
+
+use crate::arch::riscv::qemu::layout::{ PGSIZE, thread_trapframe };
+use crate::arch::riscv::qemu::param::{ NPROC, NVMA };
+use crate::fs::{ VFile, LOG };
+use crate::lock::sleeplock::{ SleepLock, SleepLockGuard };
+use crate::memory::{ PageTable, PteFlags, VirtualAddress, PhysicalAddress, Addr, RawPage, PageAllocator, page_round_up, checked_page_round_up, page_round_down, zero_page };
+use crate::arch::riscv::sfence_vma;
+use crate::memory::swap::{ alloc_user_page, swap_in_page, swap_out_page };
+use crate::memory::tlb::{ alloc_asid, flush_range };
+use crate::arch::riscv::satp::SATP_ASID_SHIFT;
+use super::vma::*;
+use super::shm::*;
+use super::memcg::MemCg;
+use super::rlimit::RLIM_INFINITY;
+
+/// mmap() regions end below the lowest thread trapframe. 
+pub const MMAP_TOP: usize = thread_trapframe(NPROC - 1);
+
+pub struct AddressSpace {
+    mm: SleepLock<Mm>,
+    satp: usize, // the root page-table page never moves
+    asid: usize, // tags its TLB entries, for shootdowns
+    memcg: UnsafeCell<Arc<MemCg>>, // the group its pages are charged to, set before it is shared
+    charged: AtomicUsize, // user pages charged
+    tables: AtomicUsize, // page-table pages charged
+}
+
+/// What changes in an address space, behind its lock. 
+struct Mm {
+    pagetable: Box<PageTable>,
+    brk: usize, // program break, the heap region ends at it rounded up
+    vmas: [Option<Vma>; NVMA], // regions of user memory
+    clock_hand: usize, // where the next swap_out_one() looks
+    mmap_top: usize, // where mmap() regions start, exec() may randomize it
+}
+
+impl Mm {
+    /// Lowest address used by mmap(), the heap must stay below it. 
+    fn mmap_base(&self) -> usize {
+        self.vmas.iter().flatten()
+            .filter(|vma| matches!(vma.kind, VmaKind::Mmap | VmaKind::Shm(_)))
+            .map(|vma| vma.start)
+            .fold(self.mmap_top, usize::min)
+    }
+
+    /// Whether no region overlaps [start, end). 
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        !self.vmas.iter().flatten().any(|vma| vma.len > 0 && vma.start < end && start < vma.end())
+    }
+
+    /// Record a region, whose place has been checked by the caller. 
+    fn add_vma(&mut self, vma: Vma) -> Result<(), &'static str> {
+        let slot = self.vmas.iter_mut().find(|vma| vma.is_none()).ok_or("too many mappings")?;
+        *slot = Some(vma);
+        Ok(())
+    }
+
+    fn in_bounds(&self, va: usize) -> bool {
+        self.vmas.iter().flatten().any(|vma| vma.contains(va))
+    }
+
+    fn is_guard_page(&self, va: usize) -> bool {
+        !self.in_bounds(va) && self.vmas.iter().flatten()
+            .any(|vma| vma.kind == VmaKind::Stack && va < vma.start && va >= vma.start.saturating_sub(PGSIZE))
+    }
+}
+
+impl AddressSpace {
+    pub fn new(pagetable: Box<PageTable>) -> Arc<Self> {
+        let asid = alloc_asid();
+        Arc::new(Self {
+            satp: pagetable.as_satp() | asid << SATP_ASID_SHIFT,
+            mm: SleepLock::new(Mm {
+                pagetable,
+                brk: 0,
+                vmas: array![_ => None; NVMA],
+                clock_hand: 0,
+                mmap_top: MMAP_TOP,
+            }, "address space"),
+            asid,
+            memcg: UnsafeCell::new(MemCg::new(None, RLIM_INFINITY)),
+            charged: AtomicUsize::new(0),
+            tables: AtomicUsize::new(0),
+        })
+    }
+
+    fn lock(&self) -> SleepLockGuard<'_, Mm> {
+        self.mm.lock()
+    }
+
+    pub fn memcg(&self) -> &Arc<MemCg> {
+        unsafe{ &*self.memcg.get() }
+    }
+
+    /// Charge to memcg instead, before anything is charged. 
+    pub fn set_memcg(&self, memcg: Arc<MemCg>) {
+        assert!(self.charged_pages() == 0, "set_memcg: pages charged");
+        unsafe{ *self.memcg.get() = memcg; }
+    }
+
+    /// Charge n user pages, unless that goes over a limit. 
+    pub fn charge(&self, n: usize) -> Result<(), &'static str> {
+        self.memcg().try_charge(n)?;
+        self.charged.fetch_add(n, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn uncharge(&self, n: usize) {
+        self.charged.fetch_sub(n, Ordering::Relaxed);
+        self.memcg().uncharge(n);
+    }
+
+    /// Pages charged, user and page-table pages. 
+    pub fn charged_pages(&self) -> usize {
+        self.charged.load(Ordering::Relaxed) + self.tables.load(Ordering::Relaxed)
+    }
+
+    /// Bring the charge for page-table pages up to date. 
+    pub fn sync_tables(&self) {
+        let mm = self.lock();
+        self.sync_tables_locked(&mm);
+        drop(mm);
+    }
+
+    fn sync_tables_locked(&self, mm: &Mm) {
+        let (old, new) = (self.tables.load(Ordering::Relaxed), mm.pagetable.nr_tables());
+        if new > old {
+            self.memcg().charge(new - old);
+        } else {
+            self.memcg().uncharge(old - new);
+        }
+        self.tables.store(new, Ordering::Relaxed);
+    }
+
+    pub fn asid(&self) -> usize {
+        self.asid
+    }
+
+    /// What satp is set to to run in this address space. 
+    pub fn satp(&self) -> usize {
+        self.satp
+    }
+
+    /// Run f on the page table, with the lock held. 
+    pub fn with_page_table<R>(&self, f: impl FnOnce(&mut PageTable) -> R) -> R {
+        let mut mm = self.lock();
+        let ret = f(&mut mm.pagetable);
+        drop(mm);
+        ret
+    }
+
+    /// Set up the first process's image, code at address 0 in one 
+    /// page. Called at boot, before any process runs to take the lock. 
+    pub fn init_code(&mut self, code: &[u8]) {
+        let mm = self.mm.get_mut();
+        unsafe{ mm.pagetable.uvm_init(code); }
+        // The page is init's whole image, it has no stack or heap. 
+        mm.add_vma(Vma::anonymous(0, PGSIZE, PROT_READ | PROT_EXEC, VmaKind::Segment))
+            .expect("user_init: Fail to add the image region");
+        self.charge(1).expect("user_init: Fail to charge the image");
+        let tables = self.mm.get_mut().pagetable.nr_tables();
+        self.memcg().charge(tables);
+        self.tables.store(tables, Ordering::Relaxed);
+    }
+
+    /// The program break, end of the heap. 
+    pub fn brk(&self) -> usize {
+        let mm = self.lock();
+        let brk = mm.brk;
+        drop(mm);
+        brk
+    }
+
+    /// Move the program break to brk, growing or shrinking the heap 
+    /// region, whose pages above the new end are freed. 
+    /// It can't grow into another region. 
+    pub fn set_brk(&self, brk: usize) -> Result<(), &'static str> {
+        let mut mm = self.lock();
+        let heap = mm.vmas.iter().flatten()
+            .find(|vma| vma.kind == VmaKind::Heap)
+            .ok_or("sbrk: no heap")?;
+        let (start, old_end) = (heap.start, heap.end());
+        if brk < start {
+            return Err("sbrk: below the heap")
+        }
+        let end = page_round_up(brk);
+        if end > old_end {
+            if end > mm.mmap_base() || !mm.is_free(old_end, end) {
+                return Err("sbrk: out of address space")
+            }
+        } else {
+            self.unmap_range(&mut mm, end, old_end, true);
+        }
+        mm.vmas.iter_mut().flatten()
+            .find(|vma| vma.kind == VmaKind::Heap)
+            .unwrap()
+            .len = end - start;
+        mm.brk = brk;
+        drop(mm);
+        Ok(())
+    }
+
+    /// Place later mmap() regions below top instead of MMAP_TOP. 
+    pub fn set_mmap_top(&self, top: usize) {
+        self.lock().mmap_top = top;
+    }
+
+    /// Record a region, whose place has been checked by the caller. 
+    pub fn add_vma(&self, vma: Vma) -> Result<(), &'static str> {
+        self.lock().add_vma(vma)
+    }
+
+    /// Whether all of [va, va + len) is user memory. 
+    pub fn range_in_bounds(&self, va: usize, len: usize) -> bool {
+        let end = match va.checked_add(len) {
+            Some(end) => end,
+            None => return false
+        };
+        let mm = self.lock();
+        let good = (page_round_down(va)..end).step_by(PGSIZE).all(|va| mm.in_bounds(va));
+        drop(mm);
+        good
+    }
+
+    /// Whether va is in the guard page below the user stack, 
+    /// which no region covers. 
+    pub fn is_guard_page(&self, va: usize) -> bool {
+        self.lock().is_guard_page(va)
+    }
+
+    /// Handle a page fault at user address va by mapping 
+    /// a page there, if va is inside a region and its page 
+    /// has not been allocated yet. 
+    /// A page that is all zeros gets the shared zero page on a read, 
+    /// and its own page on the first write. 
+    /// An error means the access is bad and the process should die. 
+    pub fn fault(&self, va: usize, write: bool) -> Result<(), &'static str> {
+        let mut mm = self.lock();
+        let ret = self.fault_locked(&mut mm, va, write);
+        drop(mm);
+        ret
+    }
+
+    fn fault_locked(&self, mm: &mut Mm, va: usize, write: bool) -> Result<(), &'static str> {
+        let mut page = VirtualAddress::new(va);
+        page.pg_round_down();
+        if let Some(pte) = mm.pagetable.translate(page).filter(|pte| pte.is_swapped()) {
+            return swap_in_page(pte)
+        }
+        if mm.is_guard_page(va) {
+            return Err("user stack overflow")
+        }
+        let start = page.as_usize();
+        let Mm { pagetable: page_table, vmas, .. } = mm;
+        let vma = vmas.iter().flatten()
+            .find(|vma| vma.contains(va))
+            .ok_or("page fault outside user memory")?;
+        // Shared memory is mapped whole at attach. 
+        if vma.shm().is_some() {
+            return Err("page fault in shared memory")
+        }
+        // Private regions start out as zeros past their file. 
+        let zero = vma.flags & MAP_PRIVATE != 0 && (vma.file.is_none() || start - vma.start >= vma.file_len);
+        let perm = vma.map_perm();
+        if let Some(pte) = page_table.lookup(page) {
+            if !write || !perm.is_writable() {
+                return Err("page fault on a mapped page")
+            }
+            if pte.as_pagetable() as usize != zero_page() {
+                // Another thread wrote first, the TLB here is stale. 
+                unsafe{ sfence_vma(); }
+                return Ok(())
+            }
+            // First write to the zero page, other threads 
+            // must not keep reading zeros from it. 
+            page_table.uvm_unmap(page, 1, true);
+            flush_range(self.asid, start, PGSIZE);
+        } else if zero && !write {
+            return match unsafe{ page_table.map(page, PhysicalAddress::new(zero_page()), PGSIZE, perm.read_only()) } {
+                true => Ok(()),
+                false => Err("page fault: out of memory")
+            }
+        }
+        self.charge(1).map_err(|_| "page fault: over the memory limit")?;
+        let mem = match alloc_user_page() {
+            Some(mem) => mem,
+            None => {
+                self.uncharge(1);
+                return Err("page fault: out of memory")
+            }
+        };
+        let mapped = vma.fill_page(start, mem).and_then(|_| {
+            match unsafe{ page_table.map(page, PhysicalAddress::new(mem), PGSIZE, perm) } {
+                true => Ok(()),
+                false => Err("page fault: out of memory")
+            }
+        });
+        if mapped.is_err() {
+            unsafe{ RawPage::free(mem); }
+            self.uncharge(1);
+        }
+        mapped
+    }
+
+    /// Physical address of the user page at va, faulting it in 
+    /// if needed, writable if write. 
+    fn user_page(&self, mm: &mut Mm, va: usize, write: bool) -> Result<usize, &'static str> {
+        if !mm.in_bounds(va) {
+            return Err("copy: user address out of bounds")
+        }
+        let page = VirtualAddress::new(page_round_down(va));
+        if let Some(pte) = mm.pagetable.lookup(page).filter(|pte| pte.is_user()) {
+            let pa = pte.as_pagetable() as usize;
+            if !write || pte.is_write() {
+                return Ok(pa)
+            }
+            if pa != zero_page() {
+                return Err("copy: user page not writable")
+            }
+        }
+        self.fault_locked(mm, va, write)?;
+        mm.pagetable.lookup(page)
+            .map(|pte| pte.as_pagetable() as usize)
+            .ok_or("copy: user address not mapped")
+    }
+
+    /// Fault in the pages of [va, va + len) for a copy. 
+    fn fault_in(&self, mm: &mut Mm, va: usize, len: usize, write: bool) -> Result<(), &'static str> {
+        if len == 0 {
+            return Ok(())
+        }
+        let end = va.checked_add(len).ok_or("copy: user range out of bounds")?;
+        for page in (page_round_down(va)..end).step_by(PGSIZE) {
+            self.user_page(mm, page, write)?;
+        }
+        Ok(())
+    }
+
+    /// Copy len bytes from src in the kernel to dst in user memory. 
+    pub fn copy_out(&self, dst: usize, src: *const u8, len: usize) -> Result<(), &'static str> {
+        let mut mm = self.lock();
+        let ret = self.fault_in(&mut mm, dst, len, true)
+            .and_then(|_| mm.pagetable.copy_out(dst, src, len));
+        drop(mm);
+        ret
+    }
+
+    /// Copy len bytes from src in user memory to dst in the kernel. 
+    pub fn copy_in(&self, dst: *mut u8, src: usize, len: usize) -> Result<(), &'static str> {
+        let mut mm = self.lock();
+        let ret = self.fault_in(&mut mm, src, len, false)
+            .and_then(|_| mm.pagetable.copy_in(dst, src, len));
+        drop(mm);
+        ret
+    }
+
+    /// Copy a null-terminated string of at most max bytes 
+    /// from src in user memory to dst in the kernel. 
+    pub fn copy_in_str(&self, dst: *mut u8, src: usize, max: usize) -> Result<(), &'static str> {
+        let mut mm = self.lock();
+        // Fault in the pages up to the end of the string. 
+        let mut va = page_round_down(src);
+        let ret = loop {
+            let pa = match self.user_page(&mut mm, va, false) {
+                Ok(pa) => pa,
+                Err(err) => break Err(err)
+            };
+            let from = src.max(va) - va;
+            let bytes = unsafe{ core::slice::from_raw_parts((pa + from) as *const u8, PGSIZE - from) };
+            if bytes.contains(&0) || va + PGSIZE - src >= max {
+                break mm.pagetable.copy_in_str(dst, src, max)
+            }
+            va += PGSIZE;
+        };
+        drop(mm);
+        ret
+    }
+
+    /// Reserve a region of len bytes for mmap(), backed by file 
+    /// from offset or zero-filled if there is no file. 
+    /// Returns the start address. 
+    pub fn mmap(
+        &self, 
+        len: usize, 
+        prot: usize, 
+        flags: usize, 
+        file: Option<Arc<VFile>>, 
+        offset: usize
+    ) -> Result<usize, &'static str> {
+        check_wx(prot)?;
+        let len = checked_page_round_up(len).ok_or("mmap: bad length")?;
+        let mut mm = self.lock();
+        let start = mm.mmap_base().checked_sub(len).ok_or("mmap: out of address space")?;
+        if !mm.is_free(start, start + len) {
+            return Err("mmap: out of address space")
+        }
+        mm.add_vma(Vma{ start, len, prot, flags, file, offset, file_len: len, kind: VmaKind::Mmap })?;
+        drop(mm);
+        Ok(start)
+    }
+
+    /// Attach shared memory segment id below the mmap() regions, 
+    /// mapping all its pages now. Returns the start address. 
+    pub fn shmat(&self, id: usize, readonly: bool) -> Result<usize, &'static str> {
+        let mut mm = self.lock();
+        if mm.vmas.iter().all(|vma| vma.is_some()) {
+            return Err("too many mappings")
+        }
+        let prot = if readonly { PROT_READ } else { PROT_READ | PROT_WRITE };
+        let mut vma = Vma{ start: 0, len: 0, prot, flags: MAP_SHARED, file: None, offset: 0, file_len: 0, kind: VmaKind::Shm(id) };
+        let perm = vma.map_perm();
+        let start = shm_attach(id, |pages| {
+            let len = pages.len() * PGSIZE;
+            let start = mm.mmap_base().checked_sub(len).ok_or("shmat: out of address space")?;
+            if !mm.is_free(start, start + len) {
+                return Err("shmat: out of address space")
+            }
+            shm_map_pages(pages, &mut mm.pagetable, start, perm)?;
+            vma.start = start;
+            vma.len = len;
+            Ok(start)
+        })?;
+        mm.add_vma(vma)?;
+        drop(mm);
+        Ok(start)
+    }
+
+    /// Write the pages of shared file mappings in [addr, addr + len) 
+    /// that were modified since they were last written back to the file. 
+    /// The dirty bit is cleared and every hart's TLB flushed of the page 
+    /// before the write, so a store during it marks the page dirty again. 
+    pub fn msync(&self, addr: usize, len: usize) -> Result<(), &'static str> {
+        if addr % PGSIZE != 0 {
+            return Err("msync: bad range")
+        }
+        let end = checked_page_round_up(len)
+            .and_then(|len| addr.checked_add(len))
+            .ok_or("msync: bad range")?;
+        let mut mm = self.lock();
+        let Mm { pagetable: page_table, vmas, .. } = &mut *mm;
+        for va in (addr..end).step_by(PGSIZE) {
+            let vma = vmas.iter().flatten()
+                .find(|vma| vma.contains(va))
+                .ok_or("msync: not mapped")?;
+            if !vma.is_shared_file() {
+                continue
+            }
+            let pte = match page_table.translate(VirtualAddress::new(va)) {
+                Some(pte) if pte.is_valid() && pte.is_dirty() => pte,
+                _ => continue
+            };
+            pte.clear_dirty();
+            flush_range(self.asid, va, PGSIZE);
+            vma.write_back(va, pte.as_pagetable() as usize)?;
+        }
+        drop(mm);
+        Ok(())
+    }
+
+    /// Set bit i of mask if page i from addr was accessed since the 
+    /// last look, clearing the accessed bits. Pages not mapped in 
+    /// count as not accessed. 
+    pub fn pgaccess(&self, addr: usize, npages: usize, mask: &mut [u8]) -> Result<(), &'static str> {
+        if addr % PGSIZE != 0 || npages > mask.len() * 8 {
+            return Err("pgaccess: bad range")
+        }
+        let len = npages.checked_mul(PGSIZE).ok_or("pgaccess: bad range")?;
+        let end = addr.checked_add(len).ok_or("pgaccess: bad range")?;
+        let mut mm = self.lock();
+        if !(addr..end).step_by(PGSIZE).all(|va| mm.in_bounds(va)) {
+            return Err("pgaccess: not mapped")
+        }
+        for i in 0..npages {
+            let va = VirtualAddress::new(addr + i * PGSIZE);
+            if let Some(pte) = mm.pagetable.translate(va).filter(|pte| pte.is_valid() && pte.is_accessed()) {
+                pte.clear_accessed();
+                mask[i / 8] |= 1 << (i % 8);
+            }
+        }
+        // A hart would not set the bit again through a cached entry. 
+        flush_range(self.asid, addr, len);
+        drop(mm);
+        Ok(())
+    }
+
+    /// Change the protection of [addr, addr + len) to prot. The range 
+    /// must lie in one region, which is split if the range covers only 
+    /// part of it. Mapped and swapped out pages get the new permissions 
+    /// now, the rest when they are faulted in. 
+    pub fn mprotect(&self, addr: usize, len: usize, prot: usize) -> Result<(), &'static str> {
+        if addr % PGSIZE != 0 || len == 0 {
+            return Err("mprotect: bad range")
+        }
+        check_wx(prot)?;
+        let len = checked_page_round_up(len).ok_or("mprotect: bad range")?;
+        let end = addr.checked_add(len).ok_or("mprotect: bad range")?;
+        let mut mm = self.lock();
+        let free = mm.vmas.iter().filter(|vma| vma.is_none()).count();
+        let vma = mm.vmas.iter_mut().flatten()
+            .find(|vma| vma.contains(addr))
+            .ok_or("mprotect: not mapped")?;
+        if end > vma.end() {
+            return Err("mprotect: bad range")
+        }
+        if vma.shm().is_some() {
+            return Err("mprotect: shared memory keeps the protection it was attached with")
+        }
+        // Split, they would be no longer one region. 
+        if vma.kind == VmaKind::Heap || vma.kind == VmaKind::Stack {
+            return Err("mprotect: not on the heap or the stack")
+        }
+        if prot & PROT_WRITE != 0 && vma.flags & MAP_SHARED != 0 && 
+            vma.file.as_ref().map_or(false, |file| !file.writeable) {
+            return Err("mprotect: file not open for writing")
+        }
+        let pieces = (addr != vma.start) as usize + (end != vma.end()) as usize;
+        if free < pieces {
+            return Err("too many mappings")
+        }
+        let above = if end != vma.end() { Some(vma.split_off(end)) } else { None };
+        let (rest, perm) = if addr != vma.start {
+            let mut rest = vma.split_off(addr);
+            rest.prot = prot;
+            let perm = rest.map_perm();
+            (Some(rest), perm)
+        } else {
+            vma.prot = prot;
+            (None, vma.map_perm())
+        };
+        for piece in above.into_iter().chain(rest) {
+            mm.add_vma(piece)?;
+        }
+
+        let mask = (PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U).bits();
+        for va in (addr..end).step_by(PGSIZE) {
+            let pte = match mm.pagetable.translate(VirtualAddress::new(va)) {
+                Some(pte) => pte,
+                None => continue
+            };
+            if pte.is_valid() {
+                let pa = pte.as_pagetable() as usize;
+                // The zero page is copied on the first write. 
+                let perm = if pa == zero_page() { perm.read_only() } else { perm };
+                let kept = PteFlags::new(pte.as_flags() & (PteFlags::A | PteFlags::D).bits());
+                pte.write_perm(PhysicalAddress::new(pa), perm.flags() | kept);
+            } else if pte.is_swapped() {
+                pte.write((pte.as_usize() & !mask) | perm.flags().bits());
+            }
+        }
+        flush_range(self.asid, addr, len);
+        drop(mm);
+        Ok(())
+    }
+
+    /// Remove the mappings of [start, end), both page-aligned, and 
+    /// free their pages once no hart can reach them through its TLB. 
+    /// owned tells whether the pages are charged to this address space, 
+    /// shared memory pages are not. 
+    fn unmap_range(&self, mm: &mut Mm, start: usize, end: usize, owned: bool) {
+        if start >= end {
+            return
+        }
+        let page_table = &mut mm.pagetable;
+        let mut pages = Vec::new();
+        let mut freed = 0;
+        for va in (start..end).step_by(PGSIZE) {
+            let page = VirtualAddress::new(va);
+            if let Some(pte) = page_table.lookup(page) {
+                let pa = pte.as_pagetable() as usize;
+                if pa != zero_page() {
+                    pages.push(pa);
+                }
+                page_table.uvm_unmap(page, 1, false);
+            } else {
+                if page_table.translate(page).map_or(false, |pte| pte.is_swapped()) {
+                    freed += 1;
+                }
+                // Only frees the swap slot of a swapped out page. 
+                page_table.uvm_unmap(page, 1, true);
+            }
+        }
+        flush_range(self.asid, start, end - start);
+        if owned {
+            self.uncharge(freed + pages.len());
+        }
+        for pa in pages {
+            unsafe{ RawPage::free(pa); }
+        }
+    }
+
+    /// Detach the shared memory segment attached at addr. 
+    pub fn shmdt(&self, addr: usize) -> Result<(), &'static str> {
+        let len = self.lock().vmas.iter().flatten()
+            .find(|vma| vma.shm().is_some() && vma.start == addr)
+            .map(|vma| vma.len)
+            .ok_or("shmdt: no segment attached here")?;
+        self.munmap(addr, len)
+    }
+
+    /// Remove [addr, addr + len) from the region containing it, 
+    /// writing dirty pages of a shared file mapping back first. 
+    /// The range must be at the start or the end of the region, 
+    /// a hole in the middle is not supported. 
+    pub fn munmap(&self, addr: usize, len: usize) -> Result<(), &'static str> {
+        if addr % PGSIZE != 0 || len == 0 {
+            return Err("munmap: bad range")
+        }
+        let len = checked_page_round_up(len).ok_or("munmap: bad range")?;
+        let mut mm = self.lock();
+        let index = mm.vmas.iter()
+            .position(|vma| vma.as_ref().map_or(false, |vma| vma.contains(addr)))
+            .ok_or("munmap: not mapped")?;
+        let vma = mm.vmas[index].clone().unwrap();
+        let end = addr.checked_add(len).ok_or("munmap: bad range")?;
+        if end > vma.end() || (addr != vma.start && end != vma.end()) {
+            return Err("munmap: bad range")
+        }
+        if vma.shm().is_some() && (addr != vma.start || end != vma.end()) {
+            return Err("munmap: shared memory is detached whole")
+        }
+        if vma.kind == VmaKind::Heap || vma.kind == VmaKind::Stack {
+            return Err("munmap: not on the heap or the stack")
+        }
+
+        self.write_back_dirty(&mut mm, &vma, addr, end)?;
+        self.unmap_range(&mut mm, addr, end, vma.shm().is_none());
+
+        let slot = &mut mm.vmas[index];
+        let region = slot.as_mut().unwrap();
+        if addr == region.start {
+            region.start += len;
+            region.offset = region.offset.saturating_add(len);
+            region.file_len = region.file_len.saturating_sub(len);
+        }
+        region.len -= len;
+        let removed = if region.len == 0 { slot.take() } else { None };
+        if let Some(id) = removed.as_ref().and_then(|vma| vma.shm()) {
+            shm_detach(id);
+        }
+        drop(mm);
+        // Once the region is gone, its copy here may hold the last 
+        // reference to the file, whose inode must be put inside a transaction. 
+        LOG.begin_op();
+        drop(removed);
+        drop(vma);
+        LOG.end_op();
+        Ok(())
+    }
+
+    /// Write the dirty pages of [addr, end) in vma back, 
+    /// if it is a shared file mapping. 
+    fn write_back_dirty(&self, mm: &mut Mm, vma: &Vma, addr: usize, end: usize) -> Result<(), &'static str> {
+        if !vma.is_shared_file() {
+            return Ok(())
+        }
+        for va in (addr..end).step_by(PGSIZE) {
+            if let Some(pte) = mm.pagetable.lookup(VirtualAddress::new(va)).filter(|pte| pte.is_dirty()) {
+                vma.write_back(va, pte.as_pagetable() as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop a process's reference to the address space, at exit or 
+    /// exec. Whoever drops the last one, even racing another thread 
+    /// on another hart, writes the shared mappings back to their files. 
+    pub fn put(this: Arc<Self>) {
+        if let Some(vm) = Arc::into_inner(this) {
+            vm.unmap_all();
+            // Its regions may hold the last reference to a file, 
+            // whose inode must be put inside a transaction. 
+            LOG.begin_op();
+            drop(vm);
+            LOG.end_op();
+        }
+    }
+
+    /// Unmap every region, writing back the shared ones, 
+    /// once no process uses the address space, see put(). 
+    fn unmap_all(&self) {
+        let mut removed: [Option<Vma>; NVMA] = array![_ => None; NVMA];
+        let mut mm = self.lock();
+        for i in 0..NVMA {
+            let vma = match mm.vmas[i].take() {
+                Some(vma) => vma,
+                None => continue
+            };
+            // Left for Drop to free if it can't be written back. 
+            if let Err(err) = self.write_back_dirty(&mut mm, &vma, vma.start, vma.end()) {
+                println!("unmap_all: {}, start: 0x{:x}", err, vma.start);
+                mm.vmas[i] = Some(vma);
+                continue
+            }
+            self.unmap_range(&mut mm, vma.start, vma.end(), vma.shm().is_none());
+            if let Some(id) = vma.shm() {
+                shm_detach(id);
+            }
+            removed[i] = Some(vma);
+        }
+        drop(mm);
+        // The files of the regions are put inside a transaction, 
+        // the last reference to an inode may free it on disk. 
+        LOG.begin_op();
+        drop(removed);
+        LOG.end_op();
+    }
+
+    /// Run the clock over the pages of the private regions, 
+    /// once round from where it stopped last time. A page that was accessed since gets 
+    /// the accessed bit cleared and another chance, the first 
+    /// one that wasn't is swapped out. 
+    /// Returns false if no page was swapped out, or if the address 
+    /// space is locked, by a fault that is looking for a free page 
+    /// for example. 
+    /// busy tells whether a process other than the caller runs in 
+    /// this address space, it is asked with the lock held, so no 
+    /// region changes between the check and the unmap. 
+    pub fn swap_out_one(&self, busy: impl FnOnce() -> bool) -> bool {
+        let mut mm = match self.mm.try_lock() {
+            Some(mm) => mm,
+            None => return false
+        };
+        if busy() {
+            return false
+        }
+        let mut ranges = [(0, 0); NVMA];
+        for (range, vma) in ranges.iter_mut().zip(mm.vmas.iter()) {
+            if let Some(vma) = vma.as_ref().filter(|vma| vma.flags & MAP_SHARED == 0) {
+                *range = (vma.start, vma.end());
+            }
+        }
+        let npages: usize = ranges.iter().map(|(start, end)| (end - start) / PGSIZE).sum();
+        // Pages in clock order, the hand counts pages into the ranges. 
+        let page_at = |mut n: usize| {
+            for (start, end) in ranges.iter() {
+                let len = (end - start) / PGSIZE;
+                if n < len {
+                    return start + n * PGSIZE
+                }
+                n -= len;
+            }
+            unreachable!()
+        };
+
+        for i in 0..npages {
+            let hand = (mm.clock_hand + i) % npages;
+            let pte = match mm.pagetable.translate(VirtualAddress::new(page_at(hand))) {
+                Some(pte) if pte.is_valid() && pte.is_leaf() && pte.as_flags() & PteFlags::U.bits() != 0 
+                    && pte.as_pagetable() as usize != zero_page() => pte,
+                _ => continue
+            };
+            if pte.is_accessed() {
+                pte.clear_accessed();
+                continue
+            }
+            if swap_out_page(pte, self.asid, page_at(hand)) {
+                mm.clock_hand = hand + 1;
+                return true
+            }
+            return false
+        }
+        false
+    }
+
+    /// Give the address space of a fork child 
+    /// copies of the regions and their pages, 
+    /// shared memory is attached to the child too. 
+    pub fn copy_to(&self, child: &AddressSpace) -> Result<(), &'static str> {
+        // The child owns a copy of every private page, charged 
+        // before copying so going over the limit fails early. 
+        child.charge(self.charged.load(Ordering::Relaxed))?;
+        let mut mm = self.lock();
+        let mut child_mm = child.lock();
+        child_mm.brk = mm.brk;
+        child_mm.mmap_top = mm.mmap_top;
+        for i in 0..NVMA {
+            if let Some(vma) = mm.vmas[i].clone() {
+                if let Some(id) = vma.shm() {
+                    shm_map(id, &mut child_mm.pagetable, vma.start, vma.map_perm())?;
+                } else {
+                    unsafe{ mm.pagetable.uvm_copy_range(&mut child_mm.pagetable, vma.start, vma.end())? };
+                }
+                child_mm.vmas[i] = Some(vma);
+            }
+        }
+        child.sync_tables_locked(&child_mm);
+        drop(child_mm);
+        drop(mm);
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mm = self.mm.get_mut();
+        // Whatever unmap_all() didn't get to is dropped 
+        // without writing back. 
+        for vma in mm.vmas.iter_mut() {
+            if let Some(vma) = vma.take() {
+                mm.pagetable.uvm_unmap(
+                    VirtualAddress::new(vma.start), 
+                    vma.len / PGSIZE, 
+                    true
+                );
+                if let Some(id) = vma.shm() {
+                    shm_detach(id);
+                }
+            }
+        }
+        mm.pagetable.proc_free_pagetable();
+        // Dropping the page table then frees the page-table pages. 
+        self.memcg().uncharge(self.charged_pages());
+    }
+}
@@ -0,0 +1,54 @@
+use alloc::boxed::Box;
+
+use super::Trapframe;
+
+// Periodic user alarm set by sigalarm(). Every `interval` ticks of 
+// CPU time the process is sent to `handler`, with the interrupted 
+// trapframe saved until the handler calls sigreturn(). 
+// Only the process itself touches it, so it lives in ProcData. 
+pub struct Alarm {
+    interval: usize, // ticks between two calls, 0 means disabled
+    handler: usize, // user address of the handler
+    left: usize, // ticks until the next call
+    saved: Option<Box<Trapframe>>, // set while the handler is running
+}
+
+impl Alarm {
+    pub const fn new() -> Self {
+        Self {
+            interval: 0,
+            handler: 0,
+            left: 0,
+            saved: None,
+        }
+    }
+
+    /// Arm the alarm, an interval of 0 turns it off. 
+    pub fn set(&mut self, interval: usize, handler: usize) {
+        self.interval = interval;
+        self.handler = handler;
+        self.left = interval;
+    }
+
+    /// Count one tick spent by the process, on expiry save tf 
+    /// and make it return to the handler. The handler is not 
+    /// re-entered while a previous call hasn't returned. 
+    pub fn tick(&mut self, tf: &mut Trapframe) {
+        if self.interval == 0 || self.saved.is_some() {
+            return
+        }
+        self.left -= 1;
+        if self.left == 0 {
+            self.left = self.interval;
+            self.saved = Some(Box::new(*tf));
+            tf.epc = self.handler;
+        }
+    }
+
+    /// Put back the trapframe saved when the handler was entered. 
+    pub fn restore(&mut self, tf: &mut Trapframe) -> Result<(), ()> {
+        let saved = self.saved.take().ok_or(())?;
+        *tf = *saved;
+        Ok(())
+    }
+}
@@ -1,24 +1,31 @@
 use array_macro::array;
 use crate::fs::VFile;
-use crate::arch::riscv::{ tp, sstatus };
+use crate::arch::riscv::{ tp, sstatus, wfi };
 use crate::arch::riscv::qemu::param::NCPU;
-use crate::lock::spinlock::{SpinlockGuard, Spinlock};
-use core::cell::RefCell;
+use crate::lock::spinlock::SpinlockGuard;
+use crate::lock::mcslock::McsLock;
+use crate::lock::rcu;
+use core::cell::{ Cell, RefCell, UnsafeCell };
 use core::ops::IndexMut;
-use core::ptr::NonNull;
+use super::percpu::PerCpu;
+use core::ptr::{ self, NonNull };
+use core::marker::PhantomData;
+use core::sync::atomic::{ AtomicBool, Ordering };
 use super::*;
 pub struct CPU {
-    pub process: Option<NonNull<Process>>, // The process running on this cpu, or null.
-    pub context: Context, // swtch() here to enter scheduler().
-    pub noff: usize, // Depth of push_off() nesting.
-    pub intena: usize // Were interrupts enabled before push_off()?
+    process: Cell<Option<NonNull<Process>>>, // The process running on this cpu, or null.
+    context: UnsafeCell<Context>, // swtch() here to enter scheduler().
+    noff: Cell<usize>, // Depth of push_off() nesting.
+    intena: Cell<bool>, // Were interrupts enabled before push_off()?
+    pub run_queue: McsLock<Classes>, // Processes waiting to run on this cpu.
+    pub online: AtomicBool, // Has this cpu entered scheduler()?
 }
 
 pub struct CPUManager{
-    cpus: [CPU; NCPU]
+    cpus: PerCpu<CPU>
 }
 
-pub static mut CPU_MANAGER:CPUManager = CPUManager::new();
+pub static CPU_MANAGER:CPUManager = CPUManager::new();
 
 pub unsafe fn cpuid() -> usize {
     let id = tp::read();
@@ -28,24 +35,77 @@ pub unsafe fn cpuid() -> usize {
 impl CPUManager{
     pub const fn new() -> Self{
         Self{
-            cpus: array![_ => CPU::new(); NCPU],
+            cpus: PerCpu::new(array![_ => CPU::new(); NCPU]),
         }
     }
 
-    pub unsafe fn mycpu(&mut self) -> &mut CPU{
-        let cpu_id = cpuid();
-        &mut self.cpus[cpu_id]
+    /// This cpu. 
+    /// SAFETY: interrupts must be off, as for PerCpu::current(). 
+    /// Otherwise use with(). 
+    pub unsafe fn mycpu(&self) -> &CPU{
+        self.cpus.current()
     }
 
-    pub unsafe fn myproc(&mut self) -> Option<&mut Process>{
-        push_off();
-        let c = CPU_MANAGER.mycpu();
-        let p = &mut *c.process.unwrap().as_ptr();
-        pop_off();
-        Some(p)
+    /// Run f on this cpu with interrupts off. 
+    pub fn with<R>(&self, f: impl FnOnce(&CPU) -> R) -> R {
+        self.cpus.with(f)
+    }
+
+    pub fn cpus(&self) -> impl Iterator<Item = &CPU> {
+        self.cpus.iter()
     }
 
-    pub fn yield_proc(&mut self) {
+    /// Mask of the cpus that have entered scheduler(). 
+    pub fn online_mask(&self) -> usize {
+        self.cpus()
+            .enumerate()
+            .filter(|(_, c)| c.online.load(Ordering::Acquire))
+            .fold(0, |mask, (id, _)| mask | (1 << id))
+    }
+
+    /// The online cpu in affinity with the fewest waiting processes. 
+    /// If no such cpu is scheduling yet, the current cpu. 
+    pub fn least_loaded(&self, affinity: usize) -> &CPU {
+        let mut target = unsafe{ cpuid() };
+        let mut load = usize::MAX;
+        for (id, c) in self.cpus().enumerate() {
+            if !c.online.load(Ordering::Acquire) || affinity & (1 << id) == 0 {
+                continue;
+            }
+            let len = c.run_queue.acquire().len();
+            if len < load {
+                target = id;
+                load = len;
+            }
+        }
+        self.cpus.get(target)
+    }
+
+    /// Steal a waiting process from the busiest cpu other than thief. 
+    pub fn steal(&self, thief: usize) -> Option<NonNull<Process>> {
+        let mut victim = None;
+        let mut load = 0;
+        for (id, c) in self.cpus().enumerate() {
+            if id == thief {
+                continue;
+            }
+            let len = c.run_queue.acquire().len();
+            if len > load {
+                victim = Some(id);
+                load = len;
+            }
+        }
+        self.cpus.get(victim?).run_queue.acquire().pick_next()
+    }
+
+    /// Return the process running on this cpu, or None 
+    /// if the cpu is in the scheduler or an interrupt 
+    /// arrived while no process was running. 
+    pub unsafe fn myproc(&self) -> Option<&mut Process>{
+        self.with(|c| c.process.get()).map(|p| &mut *p.as_ptr())
+    }
+
+    pub fn yield_proc(&self) {
         if let Some(my_proc) = unsafe{ self.myproc() } {
             let guard = my_proc.meta.acquire();
             if guard.state == ProcState::RUNNING {
@@ -64,13 +124,16 @@ impl CPUManager{
     ///  - switch to start running that process.
     ///  - eventually that process transfers control
     ///    via switch back to the scheduler.
-    pub unsafe fn scheduler(&mut self){
+    pub unsafe fn scheduler(&self){
         extern "C" {
             fn switch(old: *mut Context, new: *mut Context);
         }
 
         let c = self.mycpu();
+        c.online.store(true, Ordering::Release);
         loop {
+            // No process runs here, so no rcu reader either. 
+            rcu::quiescent();
             // Avoid deadlock by ensuring that devices can interrupt.
             sstatus::intr_on();
             match PROC_MANAGER.seek_runnable() {
@@ -81,58 +144,80 @@ impl CPUManager{
                     c.set_proc(NonNull::new(proc as *mut Process));
                     let mut pmeta = proc.meta.acquire();
                     pmeta.state = ProcState::RUNNING;
-                    switch(
-                        c.get_context_mut(),
-                        &mut proc.data.get_mut().context as *mut Context
-                    );
-                    if c.get_context_mut().is_null() {
-                        panic!("context switch back with no process reference.");
-                    }
+                    // Past here only the context is touched, 
+                    // still under p->lock. 
+                    let mut ctx = SpinlockGuard::map(pmeta, |m| &mut m.context);
+                    switch(c.context(), &mut *ctx);
                     // Process is done running for now. 
                     // It should have changed it's process state before coming back. 
                     c.set_proc(None);
-                    drop(pmeta);
+                    drop(ctx);
                 }
 
-                None => {}
+                None => {
+                    // Nothing to run, stop the hart until the next 
+                    // timer or device interrupt instead of spinning. 
+                    // Interrupts are on, so the interrupt is taken 
+                    // right after wfi. 
+                    wfi();
+                }
             }
         }
     }
 
-    pub fn alloc_fd(&mut self, file:&VFile) -> Result<usize, &'static str> {
+    pub fn alloc_fd(&self, file:&VFile) -> Result<usize, &'static str> {
         let proc = unsafe{ self.myproc().ok_or("Fail to find current process")? };
         proc.fd_alloc(file)
     }
 
-    pub fn fd_close(&mut self, fd: usize) {
+    pub fn fd_close(&self, fd: usize) {
         let proc = unsafe {
             self.myproc().unwrap()
         };
         let pdata = unsafe{ &mut *proc.data.get() };
         pdata.open_files[fd].take();
     }
+
+    /// Yield the running process if any and it's RUNNING
+    /// and has used up its time slice.
+    /// Directly return if none.
+    pub fn try_yield_proc(&self) {
+        if let Some(proc) = unsafe{ self.myproc() } {
+            if proc.timer_tick(false) {
+                proc.yielding();
+            }
+        }
+    }
 }
 
+// The process pointer is only a handle to a process any hart may 
+// run, and the Cells are touched by this cpu only, see PerCpu. 
+unsafe impl Send for CPU {}
+
 impl CPU{
     pub const fn new() -> Self{
         Self{
-            process:None,
-            context:Context::new(),
-            noff:0,
-            intena:0
+            process: Cell::new(None),
+            context: UnsafeCell::new(Context::new()),
+            noff: Cell::new(0),
+            intena: Cell::new(false),
+            run_queue: McsLock::new(Classes::new(), "run_queue"),
+            online: AtomicBool::new(false),
         }
     }
 
-    pub fn set_proc(&mut self, proc:Option<NonNull<Process>>){
-        self.process = proc;
+    pub fn set_proc(&self, proc:Option<NonNull<Process>>){
+        self.process.set(proc);
     }
 
-    pub fn get_context_mut(&mut self) -> *mut Context{
-        &mut self.context as *mut Context
+    /// Where switch() saves the scheduler, only used by this cpu. 
+    fn context(&self) -> *mut Context{
+        self.context.get()
     }
 
 
-    /// Switch to scheduler.  Must hold only p->lock
+    /// Switch to scheduler, saving the process in the context 
+    /// behind guard.  Must hold only p->lock
     /// and have changed proc->state. Saves and restores
     /// intena because intena is a property of this
     /// kernel thread, not this CPU. It should
@@ -141,9 +226,8 @@ impl CPU{
     /// there's no process.
     pub unsafe fn sched<'a>
     (
-        &mut self, 
-        guard: SpinlockGuard<'a, ProcMeta>, 
-        ctx: *mut Context
+        &self, 
+        mut guard: SpinlockGuard<'a, ProcMeta>
     ) 
     -> SpinlockGuard<'a, ProcMeta>
     {
@@ -154,10 +238,14 @@ impl CPU{
         if !guard.holding() {
             panic!("sched: not holding proc's lock");
         }
+        // and it is the lock of the process running here
+        match self.process.get() {
+            Some(p) if ptr::eq(&p.as_ref().meta, guard.spinlock()) => {},
+            _ => panic!("sched: not the running proc's lock")
+        }
         // only holding self.proc.lock
-        if self.noff != 1 {
-            println!("self noff is {}", self.noff);
-            panic!("sched: cpu hold mutliple locks");
+        if self.noff.get() != 1 {
+            panic!("sched: cpu hold mutliple locks, noff is {}", self.noff.get());
         }
             
         // proc is not running. 
@@ -170,39 +258,35 @@ impl CPU{
             panic!("sched: interruptible");
         }
 
-        let intena = self.intena;
+        // Count the switch for the process, 
+        // a zombie never runs again. 
+        if let Some(proc) = self.process.get() {
+            let rusage = &mut (*proc.as_ref().data.get()).rusage;
+            match guard.state {
+                ProcState::SLEEPING | ProcState::STOPPED => rusage.nvcsw += 1,
+                ProcState::RUNNABLE => rusage.nivcsw += 1,
+                _ => {}
+            }
+        }
+
+        let intena = self.intena.get();
         // println!("[Kernel] switch");
-        // println!("[Kernel] old_context: 0x{:x}, new_context: 0x{:x}", ctx as usize, &mut self.context as *mut Context as usize);
+        // println!("[Kernel] old_context: 0x{:x}, new_context: 0x{:x}", &guard.context as *const Context as usize, self.context() as usize);
         switch(
-            ctx, 
-            &mut self.context as *mut Context
+            &mut guard.context, 
+            self.context()
         );
-        self.intena = intena;
+        self.intena.set(intena);
         guard
         
     }
 
-    /// Yield the holding process if any and it's RUNNING.
-    /// Directly return if none.
-    pub fn try_yield_proc(&mut self) {
-        if !self.process.is_none() {
-            let guard = unsafe {
-                (&mut *self.process.unwrap().as_ptr()).meta.acquire()
-            };
-            if guard.state == ProcState::RUNNING {
-                drop(guard);
-                unsafe { self.process.unwrap().as_mut().yielding() }
-            } else {
-                drop(guard);
-            }
-        }
-    }
-
 }
 
 /// push_off/pop_off are like intr_off()/intr_on() except that they are matched:
 /// it takes two pop_off()s to undo two push_off()s.  Also, if interrupts
 /// are initially off, then push_off, pop_off leaves them off.
+/// Prefer IntrGuard where the pair is in one scope.
 
 pub fn push_off(){
     let old_enable;
@@ -211,11 +295,11 @@ pub fn push_off(){
         sstatus::intr_off();
     }
     let my_cpu = unsafe{ CPU_MANAGER.mycpu() };
-    if my_cpu.noff == 0 {
-        my_cpu.intena = old_enable as usize;
+    if my_cpu.noff.get() == 0 {
+        my_cpu.intena.set(old_enable);
     }
 
-    my_cpu.noff += 1;
+    my_cpu.noff.set(my_cpu.noff.get() + 1);
 }
 
 
@@ -224,11 +308,40 @@ pub fn pop_off() {
         panic!("pop_off(): interruptable");
     }
     let c = unsafe { CPU_MANAGER.mycpu() };
-    if c.noff.checked_sub(1).is_none() {
-        panic!("pop_off(): count not match");
-    }
-    c.noff -= 1;
-    if c.noff == 0 && c.intena != 0 {
+    let noff = match c.noff.get().checked_sub(1) {
+        Some(noff) => noff,
+        None => panic!("pop_off(): count not match")
+    };
+    c.noff.set(noff);
+    if noff == 0 && c.intena.get() {
         unsafe{ sstatus::intr_on() };
     }
 }
+
+/// Interrupts stay off on this cpu while an IntrGuard lives. 
+/// Made with push_off(), and dropping it does the matching 
+/// pop_off() on every way out of the scope, so they nest 
+/// and the state before the outermost one comes back. 
+/// Not Send, it must be dropped on the cpu that made it. 
+pub struct IntrGuard {
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl IntrGuard {
+    pub fn new() -> Self {
+        push_off();
+        Self{ _not_send: PhantomData }
+    }
+
+    /// Depth of push_off() nesting on this cpu, 
+    /// including this guard. 
+    pub fn depth(&self) -> usize {
+        unsafe{ CPU_MANAGER.mycpu() }.noff.get()
+    }
+}
+
+impl Drop for IntrGuard {
+    fn drop(&mut self) {
+        pop_off();
+    }
+}
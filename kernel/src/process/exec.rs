@@ -0,0 +1,273 @@
+use crate::lock::sleeplock::SleepLockGuard;
+use crate::memory::{Addr, PageTable, MapPerm, VirtualAddress, page_round_up};
+use crate::arch::riscv::qemu::layout::{ PGSIZE, TRAPFRAME };
+use crate::arch::riscv::qemu::param::{ MAXARG, ASLR_STACK_PAGES, ASLR_MMAP_PAGES };
+use crate::fs::{ICACHE, Inode, InodeData, FileType, VFile, LOG};
+use crate::misc::str_len;
+
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use super::*;
+
+use alloc::boxed::Box;
+use crate::lock::arc::Arc;
+
+/// Whether exec() places the user stack and the mmap() regions 
+/// at random page offsets. Turned off by sysctl() for runs that 
+/// must be repeatable. 
+pub static RANDOMIZE_VA: AtomicBool = AtomicBool::new(true);
+
+/// A random offset of less than pages pages, or 0 without RANDOMIZE_VA. 
+fn random_pages(pages: usize) -> usize {
+    if !RANDOMIZE_VA.load(Ordering::Relaxed) {
+        return 0
+    }
+    (crate::random::rand() as usize % pages) * PGSIZE
+}
+
+/// Read the program headers of the ELF image at the locked inode 
+/// into a fresh address space. The segments are not read here, 
+/// each is recorded as a region that pages fault in from the 
+/// file on first access. 
+/// Returns the address space and the end of the last segment. 
+unsafe fn load_elf(
+    p: &Process,
+    elf: &ElfHeader,
+    inode: &Inode,
+    inode_guard: &mut SleepLockGuard<InodeData>
+) -> Result<(Arc<AddressSpace>, usize), &'static str> {
+    let page_table = p.proc_pagetable().ok_or("exec: Fail to alloc pagetable.")?;
+    // Freed along with the page table on failure. 
+    let vm = AddressSpace::new(page_table);
+    let mut end = 0;
+
+    // The segments read from the executable through their own file. 
+    let file = Arc::new(VFile {
+        ftype: FileType::Inode,
+        readable: true,
+        inode: Some(inode.clone()),
+        ..VFile::init()
+    });
+
+    let mut ph = Box::<ProgHeader>::new_zeroed().assume_init();
+    let ph_size = size_of::<ProgHeader>();
+    for i in 0..elf.phnum as usize {
+        let off = elf.phoff + i * ph_size;
+        if inode_guard.read(
+            false,
+            &mut *ph as *mut ProgHeader as usize,
+            off as u32,
+            ph_size as u32
+        ).is_err() {
+            return Err("exec: Fail to read program header.")
+        }
+
+        if ph.prog_type != ELF_PROG_LOAD || ph.mem_size == 0 { continue; }
+
+        // Check program header size
+        if ph.mem_size < ph.file_size {
+            return Err("exec: memory size is less than file size.")
+        }
+
+        if ph.vaddr.checked_add(ph.mem_size).is_none() {
+            return Err("exec: vaddr + mem_size overflow.")
+        }
+
+        if ph.vaddr % PGSIZE != 0 {
+            return Err("exec: program header vaddr must be page aligned.")
+        }
+
+        if ph.vaddr < end {
+            return Err("exec: program segments overlap.")
+        }
+
+        check_wx(ph.prot())?;
+        vm.add_vma(Vma {
+            start: ph.vaddr,
+            len: page_round_up(ph.mem_size),
+            prot: ph.prot(),
+            flags: MAP_PRIVATE,
+            file: Some(Arc::clone(&file)),
+            offset: ph.off,
+            file_len: ph.file_size,
+            kind: VmaKind::Segment,
+        })?;
+        end = ph.vaddr + ph.mem_size;
+    }
+
+    Ok((vm, end))
+}
+
+/// Push argument strings and the argv array onto the user stack
+/// whose top is sp, not going below stack_base.
+/// Returns the final stack pointer and argc.
+unsafe fn push_args(
+    vm: &AddressSpace,
+    mut sp: usize,
+    stack_base: usize,
+    argv: &[*const u8]
+) -> Result<(usize, usize), &'static str> {
+    let mut user_stack: [usize; MAXARG + 1] = [0; MAXARG + 1];
+
+    // Push argument strings, prepare rest of stack in ustack.
+    let mut argc = 0;
+    while argc < argv.len() && !argv[argc].is_null() {
+        if argc >= MAXARG {
+            return Err("exec: argc is more than MAXARG.")
+        }
+        let len = str_len(argv[argc]) + 1;
+        sp = sp.checked_sub(len).ok_or("exec: user stack overflow.")?;
+        // riscv sp must be 16-byte aligned.
+        sp = align_sp(sp);
+        if sp < stack_base {
+            return Err("exec: user stack overflow.")
+        }
+
+        // Copy arguments into stack top
+        vm.copy_out(sp, argv[argc], len)?;
+        user_stack[argc] = sp;
+        argc += 1;
+    }
+    user_stack[argc] = 0;
+
+    // Push the array of argv pointers.
+    let argv_size = (argc + 1) * size_of::<usize>();
+    sp = align_sp(sp - argv_size);
+    if sp < stack_base {
+        return Err("exec: user stack overflow.")
+    }
+    vm.copy_out(sp, user_stack.as_ptr() as *const u8, argv_size)?;
+
+    Ok((sp, argc))
+}
+
+/// Replace the current process image with the ELF executable at path.
+/// The new image is built in a separate page table, and the old one is
+/// only released once nothing else can fail, so a failed exec leaves
+/// the caller untouched.
+/// Returns argc, which ends up in a0 as the first argument to main.
+pub unsafe fn exec(
+    path: &str,
+    argv: &[*const u8]
+) -> Result<usize, &'static str> {
+    let p = CPU_MANAGER.myproc().unwrap();
+    let mut elf = Box::<ElfHeader>::new_zeroed().assume_init();
+
+    LOG.begin_op();
+
+    // Get current inode by path
+    let inode = match ICACHE.namei(path.as_bytes()) {
+        Some(inode) => inode,
+        None => {
+            LOG.end_op();
+            return Err("exec: Fail to find executable file.")
+        }
+    };
+
+    // Get inode data by sleeplock
+    let mut inode_guard = inode.lock();
+
+    // Check ELF header
+    if inode_guard.read(
+        false,
+        &mut *elf as *mut ElfHeader as usize,
+        0,
+        size_of::<ElfHeader>() as u32
+    ).is_err() || elf.magic != ELF_MAGIC {
+        drop(inode_guard);
+        drop(inode);
+        LOG.end_op();
+        return Err("exec: Bad elf header.")
+    }
+
+    let loaded = load_elf(p, &elf, &inode, &mut inode_guard);
+    drop(inode_guard);
+    drop(inode);
+    LOG.end_op();
+    let (vm, end) = loaded?;
+    // The new image is charged to the same group as the old one. 
+    vm.set_memcg(Arc::clone(p.data.get_mut().vm().memcg()));
+
+    let (sp, argc) = match setup_stack(&vm, end, argv) {
+        Ok(res) => res,
+        Err(err) => {
+            // The segments hold the executable, whose inode 
+            // must be put inside a transaction. 
+            LOG.begin_op();
+            drop(vm);
+            LOG.end_op();
+            return Err(err)
+        }
+    };
+
+    // Save program name for debugging, which is the
+    // last component of the path.
+    let pdata = p.data.get_mut();
+    let path = path.as_bytes();
+    let path_len = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+    let name_start = path[..path_len]
+        .iter()
+        .rposition(|&c| c == b'/')
+        .map_or(0, |i| i + 1);
+    let name = &path[name_start..path_len];
+    let name_len = name.len().min(pdata.name.len() - 1);
+    pdata.name = [0u8; 16];
+    copy_nonoverlapping(name.as_ptr(), pdata.name.as_mut_ptr(), name_len);
+
+    // Commit to user image.
+    // Caught signals go back to the default, the handlers are gone. 
+    pdata.signals.reset_handlers();
+    let old_vm = pdata.vm.replace(vm).unwrap();
+    // A thread leaves the address space it shared, 
+    // its trapframe is at TRAPFRAME in the new one. 
+    if pdata.trapframe_va != TRAPFRAME {
+        old_vm.with_page_table(|pt| pt.uvm_unmap(VirtualAddress::new(pdata.trapframe_va), 1, false));
+        pdata.trapframe_va = TRAPFRAME;
+    }
+
+    // arguments to user main(argc, argv)
+    // argc is returned via the system call return
+    // value, which goes in a0.
+    let trapframe = pdata.trapframe();
+    trapframe.a1 = sp;
+    // initial program counter = main
+    trapframe.epc = elf.entry;
+    // initial stack pointer
+    trapframe.sp = sp;
+
+    AddressSpace::put(old_vm);
+
+    Ok(argc)
+}
+
+/// The user stack is a page at the next page boundary above end, after 
+/// a random gap and the guard page, which stay unmapped. 
+/// It is allocated now to push the arguments onto it. 
+/// The heap starts empty above it. 
+/// Returns the stack pointer and argc. 
+unsafe fn setup_stack(
+    vm: &AddressSpace,
+    end: usize,
+    argv: &[*const u8]
+) -> Result<(usize, usize), &'static str> {
+    let stack_base = page_round_up(end) + random_pages(ASLR_STACK_PAGES) + PGSIZE;
+    let stack_top = stack_base + PGSIZE;
+    vm.add_vma(Vma::anonymous(stack_base, PGSIZE, PROT_READ | PROT_WRITE, VmaKind::Stack))?;
+    vm.charge(1).map_err(|_| "exec: user stack over the memory limit.")?;
+    if vm.with_page_table(|pt| pt.uvm_alloc(stack_base, stack_top, MapPerm::UserRW)).is_none() {
+        return Err("exec: Fail to allocate user stack.")
+    }
+    vm.sync_tables();
+    vm.add_vma(Vma::anonymous(stack_top, 0, PROT_READ | PROT_WRITE, VmaKind::Heap))?;
+    vm.set_brk(stack_top)?;
+    vm.set_mmap_top(MMAP_TOP - random_pages(ASLR_MMAP_PAGES));
+    push_args(vm, stack_top, stack_base, argv)
+}
+
+
+#[inline]
+fn align_sp(sp: usize) -> usize {
+    sp - (sp % 16)
+}
@@ -0,0 +1,97 @@
+//! Futexes, for user space to sleep on a word of its memory.
+//!
+//! futex_wait() sleeps while the u32 at a user address still holds
+//! the value the caller last saw, futex_wake() wakes those sleeping on
+//! it, so a user-space lock only enters the kernel when contended.
+//! A futex is keyed by the physical address of its word, which the
+//! page table of the caller maps it to: the threads sharing a page
+//! table meet on the same futex, and so do all the processes attaching
+//! a shared memory segment. The page is faulted in writable first, a
+//! private page must not still be the shared zero page.
+//!
+//! The word is checked and the caller queued to sleep under FUTEX_LOCK,
+//! which futex_wake() takes too, so no wakeup is lost in between.
+
+use core::ptr;
+
+use crate::lock::arc::Arc;
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::lock::spinlock::Spinlock;
+use crate::memory::{ PageTable, VirtualAddress };
+use super::*;
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+static FUTEX_LOCK: Spinlock<()> = Spinlock::new((), "futex");
+
+/// Physical address page_table maps addr to, 
+/// if it is mapped writable for user mode. 
+fn lookup(page_table: &mut PageTable, addr: usize) -> Option<usize> {
+    page_table.lookup(VirtualAddress::new(addr))
+        .filter(|pte| pte.is_user() && pte.is_write())
+        .map(|pte| pte.as_pagetable() as usize + addr % PGSIZE)
+}
+
+/// The key of the futex at addr, faulting its page in. 
+fn futex_key(vm: &AddressSpace, addr: usize) -> Result<usize, &'static str> {
+    if addr % 4 != 0 {
+        return Err("futex: unaligned address")
+    }
+    loop {
+        match vm.with_page_table(|pt| lookup(pt, addr)) {
+            Some(key) => return Ok(key),
+            None => vm.fault(addr, true)?
+        }
+    }
+}
+
+/// Sleep on the futex at addr if it holds val, until woken by 
+/// futex_wake(), or timeout ticks have passed if timeout isn't 0. 
+pub fn futex_wait(addr: usize, val: u32, timeout: usize) -> Result<(), &'static str> {
+    let p = unsafe{ CPU_MANAGER.myproc() }.ok_or("futex: no process")?;
+    let vm = Arc::clone(unsafe{ (*p.data.get()).vm() });
+    let (key, guard) = loop {
+        let key = futex_key(&vm, addr)?;
+        // Another thread may have unmapped it meanwhile. FUTEX_LOCK 
+        // is taken before the address space lock is let go, so the 
+        // page stays at key until this process sleeps on it. 
+        let found = vm.with_page_table(|pt| {
+            if lookup(pt, addr) == Some(key) { Some(FUTEX_LOCK.acquire()) } else { None }
+        });
+        if let Some(guard) = found {
+            break (key, guard)
+        }
+    };
+    // All RAM is mapped at its physical address in the kernel. 
+    if unsafe{ ptr::read_volatile(key as *const u32) } != val {
+        drop(guard);
+        return Err("futex: value changed")
+    }
+    drop(vm);
+    if timeout == 0 {
+        p.sleep(key, guard);
+    } else if !p.sleep_timeout(key, guard, timeout) {
+        return Err("futex: timed out")
+    }
+    if p.killed() {
+        return Err("futex: killed")
+    }
+    Ok(())
+}
+
+/// Wake at most n processes sleeping on the futex at addr, 
+/// those that waited longest first. Returns how many woke. 
+pub fn futex_wake(addr: usize, n: usize) -> Result<usize, &'static str> {
+    let p = unsafe{ CPU_MANAGER.myproc() }.ok_or("futex: no process")?;
+    let vm = Arc::clone(unsafe{ (*p.data.get()).vm() });
+    let key = futex_key(&vm, addr)?;
+    let guard = FUTEX_LOCK.acquire();
+    let mut woken = 0;
+    while woken < n && unsafe{ PROC_MANAGER.wake_one(key) } {
+        woken += 1;
+    }
+    drop(guard);
+    Ok(woken)
+}
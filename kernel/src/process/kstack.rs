@@ -0,0 +1,91 @@
+//! Kernel stacks.
+//!
+//! Each process gets a kernel stack when it is created, allocated
+//! from the page allocator and mapped high in kernel virtual memory,
+//! in one of NKSTACK slots of 8 pages below the trampoline: 4 pages
+//! of stack at the bottom, and above them the 4 unmapped guard pages
+//! of the slot before. kernelvec in kernelvec.S depends on this layout.
+//!
+//! A slot, once mapped, stays mapped to its stack. A freed stack waits
+//! in its slot for the next process, so no hart can ever hold a stale
+//! translation of a kernel stack and unmapping needs no shootdown.
+
+use crate::arch::riscv::qemu::param::{ NCPU, NKSTACK };
+use crate::arch::riscv::qemu::layout::{ PGSIZE, TRAMPOLINE };
+use crate::lock::spinlock::Spinlock;
+use crate::memory::{ kalloc::{ kalloc_pages, kfree_pages }, kvm::kvm_try_map, MapPerm };
+
+const KSTACK_SLOT_PAGES: usize = 8;
+pub const KSTACK_PAGES: usize = 4;
+
+// kernelvec.S has these numbers written into its guard check, 
+// change it along with them. 
+const _: () = assert!(KSTACK_SLOT_PAGES * PGSIZE == 1 << 15, "kernelvec: srli t0, t0, 15");
+const _: () = assert!(NKSTACK + 1 == 1025, "kernelvec: sltiu t0, t0, 1025");
+const _: () = assert!(
+    KSTACK_SLOT_PAGES == 2 * KSTACK_PAGES && KSTACK_PAGES == 4,
+    "kernelvec: andi t0, t0, 4"
+);
+const _: () = assert!(NCPU == 8, "kernelvec: overflow_stack is 4096*8");
+
+#[derive(Clone, Copy, PartialEq)]
+enum Slot {
+    Unmapped,
+    Free,
+    Used,
+}
+
+static KSTACKS: Spinlock<[Slot; NKSTACK]> = Spinlock::new([Slot::Unmapped; NKSTACK], "kstack");
+
+#[inline]
+fn kernel_stack(slot: usize) -> usize {
+    TRAMPOLINE - (slot + 1) * KSTACK_SLOT_PAGES * PGSIZE
+}
+
+/// The slot whose kernel stack addr overflowed into,
+/// if addr is in the guard pages below a kernel stack.
+pub fn kstack_guard_slot(addr: usize) -> Option<usize> {
+    if addr >= TRAMPOLINE {
+        return None
+    }
+    let page = (TRAMPOLINE - 1 - addr) / PGSIZE;
+    let slot = page / KSTACK_SLOT_PAGES;
+    if slot == 0 || slot > NKSTACK || page % KSTACK_SLOT_PAGES >= KSTACK_SLOT_PAGES - KSTACK_PAGES {
+        return None
+    }
+    Some(slot - 1)
+}
+
+/// A kernel stack for a new process, the lowest address of it.
+/// A stack freed before is reused, else a new one is allocated
+/// and mapped. None if out of memory or slots.
+pub fn kstack_alloc() -> Option<usize> {
+    let mut slots = KSTACKS.acquire();
+    if let Some(slot) = slots.iter().position(|slot| *slot == Slot::Free) {
+        slots[slot] = Slot::Used;
+        return Some(kernel_stack(slot))
+    }
+    let slot = slots.iter().position(|slot| *slot == Slot::Unmapped)?;
+    let order = KSTACK_PAGES.trailing_zeros() as usize;
+    let pa = kalloc_pages(order)?;
+    let va = kernel_stack(slot);
+    // the guard pages below it are left unmapped.
+    if !unsafe{ kvm_try_map(va, pa, PGSIZE * KSTACK_PAGES, MapPerm::KernelRW) } {
+        unsafe{ kfree_pages(pa, order); }
+        return None
+    }
+    // The slot was never mapped before, so no hart can hold
+    // a stale translation for it, flushing here is enough.
+    unsafe{ core::arch::asm!("sfence.vma zero, zero"); }
+    slots[slot] = Slot::Used;
+    Some(va)
+}
+
+/// Give back the kernel stack at va of a freed process,
+/// which must not be running on it.
+pub fn kstack_free(va: usize) {
+    let slot = (TRAMPOLINE - va) / (KSTACK_SLOT_PAGES * PGSIZE) - 1;
+    let mut slots = KSTACKS.acquire();
+    assert!(slots[slot] == Slot::Used, "kstack_free: not in use");
+    slots[slot] = Slot::Free;
+}
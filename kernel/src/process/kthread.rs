@@ -0,0 +1,51 @@
+//! Kernel threads, processes that only ever run in the kernel.
+//!
+//! A kernel thread has a proc slot, a kernel stack and a context like
+//! any other process, but no user page table and no trapframe. It is
+//! scheduled as usual and preempted by the timer in kernel_trap().
+//! Its parent is init, which reaps it once it returns.
+
+use core::cmp::min;
+
+use super::*;
+
+/// Entry of a kernel thread, called with the argument given to spawn().
+pub type KthreadFn = fn(usize);
+
+/// Start a kernel thread running func(arg) and return its pid.
+/// The thread exits with status 0 when func returns. It is not
+/// killed by kill() on its own, func should check killed()
+/// if it loops forever.
+/// Must be called after the first user process is created.
+pub fn spawn(func: KthreadFn, arg: usize, name: &str) -> Option<Pid> {
+    let manager = unsafe{ &mut PROC_MANAGER };
+    let init_proc = manager.init_proc().expect("kthread::spawn: no init process");
+    let p = manager.alloc_slot()?;
+
+    let pdata = p.data.get_mut();
+    pdata.kthread = Some((func, arg));
+    let name = name.as_bytes();
+    pdata.name = [0u8; 16];
+    pdata.set_name(&name[..min(name.len(), 15)]);
+
+    manager.set_parent(p, unsafe{ &*init_proc });
+
+    let mut pmeta = p.meta.acquire();
+    let pid = pmeta.pid;
+    pmeta.context.write_ra(kthread_start as usize);
+    make_runnable(p, &mut pmeta);
+    drop(pmeta);
+    Some(pid)
+}
+
+/// A kernel thread's very first scheduling by scheduler()
+/// will switch to kthread_start.
+unsafe fn kthread_start() -> ! {
+    let p = CPU_MANAGER.myproc().unwrap();
+    // Still holding p->lock from scheduler
+    p.meta.release();
+
+    let (func, arg) = (*p.data.get()).kthread.take().unwrap();
+    func(arg);
+    exit(0)
+}
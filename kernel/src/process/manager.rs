@@ -1,103 +1,160 @@
 use array_macro::array;
+use alloc::boxed::Box;
+use crate::lock::arc::Arc;
 use core::cell::RefCell;
 use core::str::{from_utf8, from_utf8_unchecked};
-use core::{mem::size_of_val, ptr::NonNull};
+use core::{mem::size_of, ptr::{ self, NonNull }};
+use core::sync::atomic::{ AtomicPtr, AtomicUsize, Ordering };
 use core::ops::{ DerefMut };
 use super::*;
+use crate::lock::once::Once;
+use crate::memory::kvm::KVM_INIT;
 use crate::arch::riscv::qemu::fs::ROOTIPATH;
 use crate::arch::riscv::qemu::{
-    param::NPROC,
-    layout::{ PGSIZE, TRAMPOLINE }
+    param::{ NPROC, PROC_CHUNK, NPRIO, NRTPRIO, ALL_CPUS, MAX_TICKETS },
+    layout::{ PGSIZE, TRAPFRAME }
 };
 use crate::fs::VFile;
 use crate::lock::spinlock::{ Spinlock, SpinlockGuard };
+use crate::lock::rwlock::{ RwSpinlock, RwSpinlockReadGuard, RwSpinlockWriteGuard };
 use crate::arch::riscv::register::sstatus::intr_on;
 use crate::memory::*;
+use crate::trap::{ ticks, ticks_channel, TICKS_LOCK };
+
+/// The process table grows by one chunk at a time and a chunk 
+/// is never freed, so a Process doesn't move once it exists. 
+type ProcChunk = [Process; PROC_CHUNK];
+const NCHUNK: usize = NPROC / PROC_CHUNK;
 
 pub struct ProcManager {
-    proc: [Process; NPROC],
+    chunks: [AtomicPtr<ProcChunk>; NCHUNK],
+    /// number of chunks in use, published after the chunk pointer
+    nchunk: AtomicUsize,
+    grow_lock: Spinlock<()>,
+    /// earliest deadline of a sleep_timeout(), usize::MAX if none
+    next_deadline: AtomicUsize,
+    /// slot whose address space swap_out() tries next
+    swap_hand: AtomicUsize,
     init_proc: *mut Process,
-    pid_lock: Spinlock<usize>,
+    pid_lock: Spinlock<PidAllocator>,
+    /// guards the process tree, i.e. every p->parent.
     /// helps ensure that wakeups of wait()ing
     /// parents are not lost. helps obey the
     /// memory model when using p->parent.
     /// must be acquired before any p->lock.
-    pub wait_lock: Spinlock<()>,
+    /// Taken shared by those only looking up a parent. 
+    proc_tree_lock: RwSpinlock<()>,
 }
 
 pub static mut PROC_MANAGER:ProcManager = ProcManager::new();
 
+/// Done once the process table is set up. 
+static PROC_INIT: Once = Once::new();
+
+/// waitpid() option: return at once if no child has exited. 
+pub const WNOHANG: usize = 1;
+
 
 impl ProcManager{
     pub const fn new() -> Self {
         Self{
-            proc: array![_ => Process::new(); NPROC],
+            chunks: array![_ => AtomicPtr::new(ptr::null_mut()); NCHUNK],
+            nchunk: AtomicUsize::new(0),
+            grow_lock: Spinlock::new((), "proc_grow"),
+            next_deadline: AtomicUsize::new(usize::MAX),
+            swap_hand: AtomicUsize::new(0),
             init_proc: 0 as *mut Process,
-            pid_lock: Spinlock::new(0, "pid_lock"),
-            wait_lock: Spinlock::new((), "wait_lock"),
+            pid_lock: Spinlock::new(PidAllocator::new(), "pid_lock"),
+            proc_tree_lock: RwSpinlock::new((), "proc_tree_lock"),
         }
     }
     
-    pub fn get_table_mut(&mut self) -> &mut [Process; NPROC] {
-        &mut self.proc
+    /// Iterate over every process slot allocated so far. 
+    pub fn procs(&self) -> impl Iterator<Item = &Process> {
+        let n = self.nchunk.load(Ordering::Acquire);
+        self.chunks[..n].iter().flat_map(|chunk| {
+            unsafe{ (*chunk.load(Ordering::Relaxed)).iter() }
+        })
+    }
+
+    /// Same as procs(), the slot's own lock still guards its fields. 
+    pub fn procs_mut(&self) -> impl Iterator<Item = &mut Process> {
+        let n = self.nchunk.load(Ordering::Acquire);
+        self.chunks[..n].iter().flat_map(|chunk| {
+            unsafe{ (*chunk.load(Ordering::Relaxed)).iter_mut() }
+        })
+    }
+
+    /// Number of slots in use. 
+    pub fn nr_procs(&self) -> usize {
+        self.procs().filter(|p| p.meta.acquire().state != ProcState::UNUSED).count()
+    }
+
+    /// Add a chunk of UNUSED slots to the table. 
+    /// Returns false when the table is already full. 
+    fn grow(&self) -> bool {
+        let guard = self.grow_lock.acquire();
+        let n = self.nchunk.load(Ordering::Acquire);
+        if n == NCHUNK {
+            drop(guard);
+            return false
+        }
+        let mut chunk: Box<ProcChunk> = Box::new(array![_ => Process::new(); PROC_CHUNK]);
+        for proc in chunk.iter_mut() {
+            proc.init();
+        }
+        self.chunks[n].store(Box::into_raw(chunk), Ordering::Relaxed);
+        self.nchunk.store(n + 1, Ordering::Release);
+        drop(guard);
+        true
     }
 
-    pub fn alloc_pid(&mut self) -> usize {
+    pub fn alloc_pid(&self) -> Option<Pid> {
         let mut guard = self.pid_lock.acquire();
-        let pid;       
-        *guard += 1;
-        pid = *guard;        
+        let pid = guard.alloc();
         drop(guard);
         pid
     }
 
-    /// initialize the proc table at boot time.
+    /// Give back the pid of a proc being freed. 
+    pub fn free_pid(&self, pid: Pid) {
+        let mut guard = self.pid_lock.acquire();
+        guard.free(pid);
+        drop(guard);
+    }
+
+    /// initialize the proc table at boot time, 
+    /// with only the first chunk of slots. 
     /// Only used in boot.
     pub unsafe fn init(&mut self){
         println!("process init......");
-        for (pos, proc) in self.proc.iter_mut().enumerate() {
-            proc.init(kernel_stack(pos));
-        }
-    }
-
-    /// Allocate 4 page for each process's kernel stack.
-    /// Map it high in memory, followed by an invalid 
-    /// group page
-    pub unsafe fn proc_mapstacks(&mut self) {
-        for (pos, _) in self.proc.iter_mut().enumerate() {
-            let pa = Stack::new_zeroed();
-            let va = kernel_stack(pos);
-
-            // map process stack into kernel, 
-            // which contain 5 page(stack for 4 page and 1 for guard page). 
-            KERNEL_PAGETABLE.kernel_map(
-                VirtualAddress::new(va),
-                PhysicalAddress::new(pa),
-                PGSIZE * 4,
-                PteFlags::R | PteFlags::W
-            );
-            
-        }
+        // kernel stacks are mapped in the kernel page table
+        KVM_INIT.assert_done("process init");
+        PROC_INIT.run("process init", || {
+            if !self.grow() {
+                panic!("process init: fail to allocate process table");
+            }
+        });
     }
 
     /// Set up first user programe
     pub unsafe fn user_init(&mut self) {
+        PROC_INIT.assert_done("user_init");
         println!("first user process init......");
         let p = self.alloc_proc().expect("Fail to get unused process");
+        // Nothing else exists yet, init gets the first slot and pid 1. 
+        assert_eq!(p.pid(), Pid::new(1), "user_init: init must be pid 1");
 
         // allocate one user page and copy init's instructions
         // and data into it.
         let pdata = &mut *p.data.get();
-        pdata.pagetable.as_mut().unwrap().uvm_init(
-            &INITCODE,
-        );
-
-        pdata.size = PGSIZE;
+        let vm = pdata.vm.as_mut().and_then(Arc::get_mut).expect("user_init: no address space");
+        vm.init_code(&INITCODE);
 
         // prepare for the very first "return" from kernel to user. 
-        let tf =  &mut *pdata.trapframe;
+        let tf = pdata.trapframe();
         tf.epc = 0; // user program counter
-        tf.sp = 4 * PGSIZE; // user stack pointer
+        tf.sp = PGSIZE; // user stack pointer, top of the only page
 
         let init_name = b"initname\0";
         pdata.set_name(init_name);
@@ -105,7 +162,10 @@ impl ProcManager{
         pdata.cwd = Some(ICACHE.namei(&ROOTIPATH).expect("cannot find root inode"));
         
         let mut guard = p.meta.acquire();
-        guard.set_state(ProcState::RUNNABLE);
+        // init leads the first session and process group
+        guard.pgid = guard.pid;
+        guard.sid = guard.pid;
+        make_runnable(p, &mut guard);
         drop(guard);
 
         // Set init process
@@ -119,79 +179,398 @@ impl ProcManager{
     /// If there are a free procs, or a memory allocation fails, return 0. 
 
     /// WARNING: possible error occurs here.
-    pub fn alloc_proc(&mut self) -> Option<&mut Process> {
-        let alloc_pid = self.alloc_pid();
-        // self.proc_dump();
-        for proc in self.proc.iter_mut() {
-            let mut pmeta = proc.meta.acquire();
-            match pmeta.state {
-                ProcState::UNUSED => {
-                    pmeta.pid = alloc_pid;
-                    pmeta.set_state(ProcState::ALLOCATED);
-                    let pdata = proc.data.get_mut();
-                    // Allocate a trapframe page.
-                    let trapframe = unsafe{ RawPage::new_zeroed() as *mut u8 };
-                    pdata.set_trapframe(trapframe as *mut Trapframe);
-                    // An empty user page table
-                    unsafe{
-                        pdata.proc_pagetable();
+    pub fn alloc_proc(&self) -> Option<&mut Process> {
+        let proc = self.alloc_slot()?;
+        let pdata = proc.data.get_mut();
+        // Allocate a trapframe page, 
+        // and an empty user page table. 
+        pdata.trapframe = unsafe{ PageBox::new_zeroed() };
+        if pdata.trapframe.is_none() || !unsafe{ pdata.proc_pagetable() } {
+            proc.free_proc();
+            return None
+        }
+        Some(proc)
+    }
+
+    /// Claim an UNUSED proc and give it a pid, without any user state. 
+    /// Its context starts executing at forkret. 
+    pub fn alloc_slot(&self) -> Option<&mut Process> {
+        let alloc_pid = self.alloc_pid()?;
+        // self.dump();
+        loop {
+            for proc in self.procs_mut() {
+                let mut pmeta = proc.meta.acquire();
+                match pmeta.state {
+                    ProcState::UNUSED => {
+                        let kstack = match kstack_alloc() {
+                            Some(kstack) => kstack,
+                            None => {
+                                drop(pmeta);
+                                self.free_pid(alloc_pid);
+                                return None
+                            }
+                        };
+                        pmeta.pid = alloc_pid;
+                        pmeta.set_state(ProcState::ALLOCATED);
+                        // Set up new context to start executing at forkret, 
+                        // which returns to user space. 
+                        pmeta.init_context(kstack);
+                        let pdata = proc.data.get_mut();
+                        pdata.set_kstack(kstack);
+                        pdata.start_time = ticks();
+                        drop(pmeta);
+                        return Some(proc)
                     }
-                    // Set up new context to start executing at forkret, 
-                    // which returns to user space. 
-                    pdata.init_context();
-                    drop(pmeta);
-                    return Some(proc)
+                    _ => {}
                 }
-                _ => {}
+            }
+            // Every slot is taken, try again with a new chunk. 
+            if !self.grow() {
+                self.free_pid(alloc_pid);
+                return None
             }
         }
-        None
+    }
+
+    /// Index of the slot of p in the process table. 
+    pub fn slot_index(&self, p: &Process) -> usize {
+        let addr = p as *const Process as usize;
+        let n = self.nchunk.load(Ordering::Acquire);
+        for (index, chunk) in self.chunks[..n].iter().enumerate() {
+            let base = chunk.load(Ordering::Relaxed) as usize;
+            if addr >= base && addr < base + size_of::<ProcChunk>() {
+                return index * PROC_CHUNK + (addr - base) / size_of::<Process>()
+            }
+        }
+        panic!("slot_index: process not in table");
+    }
+
+    /// The init process, parent of orphans and kernel threads. 
+    pub fn init_proc(&self) -> Option<*mut Process> {
+        if self.init_proc.is_null() { None } else { Some(self.init_proc) }
     }
 
 
     /// Wake up all processes sleeping on chan.
     /// Must be called without any p->lock.
+    /// Only the wait queue of chan is looked at, the caller itself
+    /// is never on it, since it only queues from within sleep(). 
     pub fn wake_up(&self, channel: usize) {
-        for p in self.proc.iter() {
+        let mut queue = wait_queue(channel).acquire();
+        queue.drain(channel, |p| {
+            let p = unsafe{ p.as_ref() };
             let mut guard = p.meta.acquire();
             if guard.state == ProcState::SLEEPING && guard.channel == channel {
                 // println!("[Debug] Wake up process {}", guard.pid);
-                guard.state = ProcState::RUNNABLE;
+                task_wakeup(&mut guard);
+                make_runnable(p, &mut guard);
+            }
+            drop(guard);
+        });
+        drop(queue);
+    }
+
+    /// Wake the process that has slept on channel the longest. 
+    /// Returns false if none was sleeping on it. 
+    pub fn wake_one(&self, channel: usize) -> bool {
+        let mut queue = wait_queue(channel).acquire();
+        let mut woken = false;
+        while !woken {
+            let p = match queue.take_oldest(channel) {
+                Some(p) => unsafe{ p.as_ref() },
+                None => break
+            };
+            let mut guard = p.meta.acquire();
+            if guard.state == ProcState::SLEEPING && guard.channel == channel {
+                task_wakeup(&mut guard);
+                make_runnable(p, &mut guard);
+                woken = true;
             }
             drop(guard);
         }
+        drop(queue);
+        woken
     }
 
-    /// Find a runnable and set status to allocated
-    pub fn seek_runnable(&mut self) -> Option<&mut Process> {
-        for p in self.proc.iter_mut() {
+    /// Make sure expire_deadlines() looks at the table by the tick deadline. 
+    /// Called after setting p->deadline. 
+    pub fn arm_deadline(&self, deadline: usize) {
+        self.next_deadline.fetch_min(deadline, Ordering::AcqRel);
+    }
+
+    /// Wake up the sleepers whose deadline has passed, called each tick. 
+    /// The table is only scanned once the earliest deadline comes. 
+    pub fn expire_deadlines(&self, now: usize) {
+        if now < self.next_deadline.load(Ordering::Acquire) {
+            return
+        }
+        self.next_deadline.store(usize::MAX, Ordering::Release);
+        for p in self.procs() {
             let mut guard = p.meta.acquire();
-            match guard.state {
-                ProcState::RUNNABLE => {
-                    guard.state = ProcState::ALLOCATED;
-                    drop(guard);
-                    return Some(p)
+            if guard.state == ProcState::SLEEPING && guard.deadline != 0 {
+                if guard.deadline <= now {
+                    guard.deadline = 0;
+                    task_wakeup(&mut guard);
+                    make_runnable(p, &mut guard);
+                } else {
+                    self.arm_deadline(guard.deadline);
+                }
+            }
+            drop(guard);
+        }
+    }
+
+    /// Make room for a page by swapping out a user page of some 
+    /// address space, going round the table from where the last 
+    /// call stopped. An address space is passed over while a 
+    /// process other than the caller runs in it. 
+    /// Each is tried twice, the first clock pass may only 
+    /// clear accessed bits. Returns false if nothing was swapped out. 
+    pub fn swap_out(&self) -> bool {
+        let me = unsafe{ CPU_MANAGER.myproc() }.map_or(ptr::null(), |p| p as *const Process);
+        let n = self.procs().count();
+        let start = self.swap_hand.load(Ordering::Relaxed);
+        for i in 0..2 * n {
+            let index = (start + i) % n;
+            let p = self.procs().nth(index).unwrap();
+            // vm can't change while it sleeps or waits to run, 
+            // exec() and free_proc() only happen when it's running 
+            // or a zombie. 
+            let guard = p.meta.acquire();
+            let vm = match guard.state {
+                ProcState::SLEEPING | ProcState::RUNNABLE | ProcState::STOPPED => {
+                    unsafe{ (*p.data.get()).vm.clone() }
                 },
-                _ => {
-                    drop(guard);
+                ProcState::RUNNING if p as *const Process == me => {
+                    unsafe{ (*p.data.get()).vm.clone() }
                 },
+                _ => None
+            };
+            drop(guard);
+            let vm = match vm {
+                Some(vm) => vm,
+                None => continue
+            };
+            let busy = || self.procs().any(|q| {
+                q as *const Process != me && 
+                q.meta.acquire().state == ProcState::RUNNING && 
+                unsafe{ (*q.data.get()).vm.as_ref() }.map_or(false, |qvm| Arc::ptr_eq(qvm, &vm))
+            });
+            if vm.swap_out_one(busy) {
+                self.swap_hand.store(index + 1, Ordering::Relaxed);
+                return true
+            }
+        }
+        false
+    }
+
+    /// Take the next process off this cpu's run queue, or steal one 
+    /// from another cpu if it is empty, and set status to allocated. 
+    /// Entries whose process is no longer runnable are skipped. 
+    pub fn seek_runnable(&mut self) -> Option<&mut Process> {
+        loop {
+            let mut next = CPU_MANAGER.with(|c| c.run_queue.acquire().pick_next());
+            if next.is_none() {
+                next = unsafe{ CPU_MANAGER.steal(cpuid()) };
+            }
+            let p = unsafe{ &mut *next?.as_ptr() };
+            let mut guard = p.meta.acquire();
+            if guard.state == ProcState::RUNNABLE && !guard.can_run_on(cpuid()) {
+                // Stolen from another cpu but not allowed here, 
+                // hand it on and let the scheduler try again. 
+                make_runnable(p, &mut guard);
+                drop(guard);
+                return None
+            }
+            if guard.state == ProcState::RUNNABLE {
+                guard.state = ProcState::ALLOCATED;
+                drop(guard);
+                return Some(p)
+            }
+            drop(guard);
+        }
+    }
+
+    /// Periodic priority boost, e.g. MLFQ moves every process to the top level. 
+    pub fn priority_boost(&self) {
+        for p in self.procs() {
+            let mut guard = p.meta.acquire();
+            if guard.state != ProcState::UNUSED {
+                Classes::boost_proc(&mut guard);
+            }
+            drop(guard);
+        }
+        for cpu in CPU_MANAGER.cpus() {
+            cpu.run_queue.acquire().boost();
+        }
+    }
+
+    /// Set the lottery tickets of the process, 
+    /// which must be at least one and at most MAX_TICKETS. 
+    pub fn set_tickets(&self, proc: &Process, tickets: usize) -> Result<usize, ()> {
+        if tickets == 0 || tickets > MAX_TICKETS {
+            return Err(())
+        }
+        let mut guard = proc.meta.acquire();
+        guard.tickets = tickets;
+        requeue(proc, &guard);
+        drop(guard);
+        Ok(0)
+    }
+
+    /// Change the scheduling priority of the process with the given pid. 
+    /// Level 0 is the highest priority. 
+    pub fn set_priority(&self, pid: Pid, priority: usize) -> Result<usize, ()> {
+        if priority >= NPRIO {
+            return Err(())
+        }
+        for proc in self.procs() {
+            let mut guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                guard.priority = priority as u8;
+                requeue(proc, &guard);
+                drop(guard);
+                return Ok(0)
             }
+            drop(guard);
         }
-        None
+        Err(())
     }
 
-    /// Pass p's abandonded children to init. 
+    /// Restrict the process with the given pid to the cpus in mask. 
+    /// The mask must contain at least one online cpu. 
+    pub fn set_affinity(&self, pid: Pid, mask: usize) -> Result<usize, ()> {
+        if mask & CPU_MANAGER.online_mask() == 0 {
+            return Err(())
+        }
+        for proc in self.procs() {
+            let mut guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                guard.affinity = mask & ALL_CPUS;
+                drop(guard);
+                return Ok(0)
+            }
+            drop(guard);
+        }
+        Err(())
+    }
+
+    /// Set the scheduling class of the process with the given pid, 
+    /// rt_priority is only used by the real-time class, 0 is the highest. 
+    /// The target must be caller itself, one of its children or init, 
+    /// a real-time class can starve every other process. 
+    pub fn set_scheduler(&self, caller: &Process, pid: Pid, class: SchedClass, rt_priority: usize) -> Result<usize, ()> {
+        if rt_priority >= NRTPRIO {
+            return Err(())
+        }
+        // hold the tree lock so the parent link can't change underneath. 
+        let tree = self.tree_read();
+        for proc in self.procs() {
+            let mut guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                let allowed = ptr::eq(proc, caller) || pid == Pid::new(1)
+                    || unsafe{ (*proc.data.get()).parent }.map_or(false, |parent| ptr::eq(parent, caller));
+                if allowed {
+                    guard.class = class;
+                    guard.rt_priority = rt_priority as u8;
+                    requeue(proc, &guard);
+                }
+                drop(guard);
+                drop(tree);
+                return if allowed { Ok(0) } else { Err(()) }
+            }
+            drop(guard);
+        }
+        drop(tree);
+        Err(())
+    }
+
+    /// Length in ticks of the time slice of the process with the given pid, 
+    /// 0 if it runs until it gives up the CPU. 
+    pub fn time_slice(&self, pid: Pid) -> Result<usize, ()> {
+        for proc in self.procs() {
+            let guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                let slice = Classes::time_slice(&guard);
+                drop(guard);
+                return Ok(slice)
+            }
+            drop(guard);
+        }
+        Err(())
+    }
+
+    /// Get the affinity mask of the process with the given pid. 
+    pub fn get_affinity(&self, pid: Pid) -> Result<usize, ()> {
+        for proc in self.procs() {
+            let guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                let mask = guard.affinity;
+                drop(guard);
+                return Ok(mask)
+            }
+            drop(guard);
+        }
+        Err(())
+    }
+
+    /// Pid of the parent of proc, or 1 (init) if the parent 
+    /// has already exited. 
+    pub fn parent_pid(&self, proc: &Process) -> Pid {
+        let tree = self.tree_read();
+        let parent = unsafe{ (*proc.data.get()).parent };
+        let ppid = match parent {
+            Some(parent) => {
+                let pmeta = parent.meta.acquire();
+                let ppid = match pmeta.state {
+                    ProcState::UNUSED | ProcState::ZOMBIE => Pid::new(1),
+                    _ => pmeta.pid
+                };
+                drop(pmeta);
+                ppid
+            },
+            None => Pid::new(1)
+        };
+        drop(tree);
+        ppid
+    }
+
+    /// Acquire proc_tree_lock, needed to read or change any p->parent
+    /// and to sleep waiting for a child. 
+    pub fn wait_lock(&self) -> RwSpinlockWriteGuard<'_, ()> {
+        self.proc_tree_lock.write()
+    }
+
+    /// Acquire proc_tree_lock shared, enough to read p->parent. 
+    pub fn tree_read(&self) -> RwSpinlockReadGuard<'_, ()> {
+        self.proc_tree_lock.read()
+    }
+
+    /// Make parent the parent of child. 
+    /// Must be called without proc_tree_lock. 
+    pub fn set_parent(&self, child: &Process, parent: &'static Process) {
+        let tree = self.wait_lock();
+        unsafe{ (*child.data.get()).set_parent(Some(parent)); }
+        drop(tree);
+    }
+
+    /// Pass p's abandonded children to init, 
+    /// and wake init once if there were any, so that 
+    /// the ones which already exited get reaped. 
     /// Caller must hold wait lock. 
     pub fn reparent(&self, proc: &mut Process) {
-        for index in 0..self.proc.len() {
-            let p = &self.proc[index];
-                let pdata = unsafe{ &mut *p.data.get() };
-                if let Some(parent) = pdata.parent {
-                    if parent as *const _ == proc as *const _ {
-                        pdata.parent = Some(self.init_proc);
-                        self.wake_up(self.init_proc as usize);
-                    }
+        let mut orphans = 0;
+        for p in self.procs() {
+            let pdata = unsafe{ &mut *p.data.get() };
+            if let Some(parent) = pdata.parent {
+                if ptr::eq(parent, proc) {
+                    pdata.parent = Some(unsafe{ &*self.init_proc });
+                    orphans += 1;
                 }
+            }
+        }
+        if orphans > 0 {
+            self.wake_up(self.init_proc as usize);
         }
     }
     
@@ -202,28 +581,44 @@ impl ProcManager{
         let my_proc = unsafe {
             CPU_MANAGER.myproc().expect("Current cpu's process is none.")
         };
-        // close all open files. 
+        if my_proc as *const Process == self.init_proc as *const Process {
+            panic!("init exiting");
+        }
         let pdata = unsafe{ &mut *my_proc.data.get() };
-        let open_files = &mut pdata.open_files;
-        // 遍历该进程打开的文件，夺取所有权，即将引用计数减一
-        for index in 0..open_files.len() {
-            if open_files[index].is_some() {
-                open_files[index].take();
+        // A thread takes its own trapframe out of the shared space, 
+        // and the last user of the address space writes 
+        // its shared mappings back to their files. 
+        if let Some(vm) = pdata.vm.take() {
+            if pdata.trapframe_va != TRAPFRAME {
+                vm.with_page_table(|pt| pt.uvm_unmap(
+                    VirtualAddress::new(pdata.trapframe_va),
+                    1,
+                    false
+                ));
+                tlb::flush_range(vm.asid(), pdata.trapframe_va, PGSIZE);
+                pdata.trapframe_va = TRAPFRAME;
             }
+            AddressSpace::put(vm);
+        }
+        // close all open files. 
+        // 遍历该进程打开的文件，夺取所有权，即将引用计数减一
+        for file in pdata.open_files.iter_mut() {
+            file.take();
         }
 
+        // The inode must be put inside a transaction, 
+        // since it may be the last reference. 
         LOG.begin_op();
-        let cwd = pdata.cwd.as_mut().expect("Fail to get inode");
-        drop(cwd);
+        drop(pdata.cwd.take());
         LOG.end_op();
-        pdata.cwd = None;
 
-        let wait_guard = self.wait_lock.acquire();
+        let wait_guard = self.wait_lock();
         // Give any children to init. 
         self.reparent(my_proc);
         // Parent might be sleeping in wait. 
         // 唤醒父进程
-        self.wake_up(pdata.parent.expect("Fail to find parent process") as usize);
+        let parent = pdata.parent.expect("Fail to find parent process");
+        self.wake_up(parent as *const Process as usize);
 
         let mut proc_data = my_proc.meta.acquire();
         // 设置退出状态
@@ -237,52 +632,138 @@ impl ProcManager{
             CPU_MANAGER.mycpu()
         };
         unsafe {
-            my_cpu.sched(
-                proc_data, 
-                &mut pdata.context as *mut Context
-            );
+            my_cpu.sched(proc_data);
         }
 
         panic!("zombie exit!");
     }
 
+    /// Bring the system down: kill every other process, wait until 
+    /// they have all exited, flush the log and then power off, 
+    /// or restart the machine if restart is set. 
+    /// init and the kernel threads are left running, 
+    /// they have nothing to write back. 
+    pub fn shutdown(&mut self, restart: bool) -> ! {
+        let my_proc = unsafe {
+            CPU_MANAGER.myproc().expect("Current cpu's process is none.")
+        };
+        let me = my_proc as *const Process;
+        let init = self.init_proc as *const Process;
+        loop {
+            let mut alive = false;
+            for p in self.procs() {
+                let ptr = p as *const Process;
+                if ptr == me || ptr == init || unsafe{ (*p.data.get()).kthread.is_some() } {
+                    continue
+                }
+                // Kill again on every pass, 
+                // the dying may have forked in the meantime. 
+                let mut guard = p.meta.acquire();
+                if guard.state != ProcState::UNUSED && guard.state != ProcState::ZOMBIE {
+                    post_signal(p, &mut guard, signal::SIGKILL);
+                    alive = true;
+                }
+                drop(guard);
+            }
+            if !alive {
+                break
+            }
+            let ticks_guard = unsafe{ TICKS_LOCK.acquire() };
+            my_proc.sleep(ticks_channel(), ticks_guard);
+        }
+
+        // Wait for any transaction still in flight to commit. 
+        LOG.begin_op();
+        LOG.end_op();
+
+        if restart {
+            crate::shutdown::reboot();
+        } else {
+            crate::shutdown::shutdown();
+        }
+        unreachable!("shutdown returned");
+    }
+
     /// Wait for a child process to exit and return its pid. 
     /// 等待子进程退出并返回 pid
-    pub fn wait(&mut self, addr: usize) -> Option<usize> {
+    pub fn wait(&mut self, addr: usize) -> Option<Pid> {
+        self.reap(-1, addr, 0, false)
+    }
+
+    /// Wait for the children selected by pid to exit, as in waitpid(2): 
+    /// pid > 0 is that child, -1 is any child, 0 is any child in the 
+    /// caller's process group and pid < -1 any child in group -pid. 
+    /// With WNOHANG, return pid 0 if such children exist but none has exited. 
+    pub fn waitpid(&mut self, pid: isize, addr: usize, options: usize) -> Option<Pid> {
+        self.reap(pid, addr, options, false)
+    }
+
+    /// Wait for a thread created by clone() to exit and return its pid. 
+    pub fn join(&mut self, addr: usize) -> Option<Pid> {
+        self.reap(-1, addr, 0, true)
+    }
+
+    /// Wait for a child to exit, free it and return its pid. 
+    /// Children sharing the caller's address space are threads 
+    /// and only reaped by join, the others only by wait. 
+    fn reap(&mut self, target: isize, addr: usize, options: usize, threads: bool) -> Option<Pid> {
         let pid;
         let my_proc = unsafe {
             CPU_MANAGER.myproc().expect("Fail to get my process")
         };
-        let mut wait_guard = self.wait_lock.acquire();
+        let my_pgid = my_proc.meta.acquire().pgid;
+        let mut wait_guard = self.wait_lock();
         loop {
             let mut have_kids = false;
             // Scan through table looking for exited children. 
             // 遍历所有进程是否为其他进程的子进程
-            for index in 0..self.proc.len() {
-                let p = &mut self.proc[index];
+            for p in self.procs_mut() {
                 let pdata = unsafe {
                     p.data.get().as_mut().unwrap()
                 };
+                let my_vm = unsafe{ &(*my_proc.data.get()).vm };
+                let is_thread = match (&pdata.vm, my_vm) {
+                    (Some(vm), Some(my_vm)) => Arc::ptr_eq(vm, my_vm),
+                    _ => false
+                };
                 if let Some(parent) = pdata.parent {
-                    if parent as *const _ == my_proc as *const _ {
+                    if ptr::eq(parent, my_proc) && is_thread == threads {
                         // 确报子进程不会退出或者进行被调度出去
                         let proc_meta = p.meta.acquire();
+                        let selected = match target {
+                            -1 => true,
+                            0 => proc_meta.pgid == my_pgid,
+                            t if t > 0 => proc_meta.pid == Pid::new(t as usize),
+                            t => proc_meta.pgid == Pid::new((-t) as usize)
+                        };
+                        if !selected {
+                            drop(proc_meta);
+                            continue;
+                        }
                         have_kids = true;
                         // make sure the child isn't still in exit or swtch. 
                         if proc_meta.state == ProcState::ZOMBIE {
                             // Found one 
                             pid = proc_meta.pid;
-                            let page_table = pdata.pagetable.as_mut().expect("Fail to get pagetable");
                             // 这里是要获取子进程退出的状态，当 addr 的值为 0 的时候为悬空指针，表示
                             // 不需要获取子进程退出的状态
-                            if addr != 0 && page_table.copy_out(addr, proc_meta.xstate as *const u8, size_of_val(&proc_meta.xstate)).is_err() {
-                                drop(proc_meta);
-                                drop(wait_guard);
-                                return None
-                            }
+                            // The status is copied into the waiting process's 
+                            // address space as a C int, once the locks are 
+                            // released since the page may have to be swapped in. 
+                            let xstate = proc_meta.xstate as i32;
                             drop(proc_meta);
-                            p.free_proc();
+                            // Only this process reaps its children, so the 
+                            // zombie stays put after the tree lock is let go. 
+                            // free_proc() may sleep on the address space lock. 
                             drop(wait_guard);
+                            p.free_proc();
+                            if addr != 0 && my_proc.vm().copy_out(
+                                addr, 
+                                &xstate as *const i32 as *const u8, 
+                                size_of::<i32>()
+                            ).is_err() {
+                                return None
+                            }
                             return Some(pid);
                         }
                         drop(proc_meta);
@@ -298,46 +779,182 @@ impl ProcManager{
             }
             // 释放锁，否则会死锁
             drop(my_proc_data);
+            if options & WNOHANG != 0 {
+                drop(wait_guard);
+                return Some(Pid::new(0))
+            }
             // Wait for a child to exit.
             my_proc.sleep(
                 my_proc as *const _ as usize, 
                 wait_guard
             );
-            wait_guard = self.wait_lock.acquire();
+            wait_guard = self.wait_lock();
         }
     }
 
-    /// Kill the process with the given pid. 
-    /// The victim won't exit until it tries to return. 
+    /// Send signal sig to the process with the given pid, 
+    /// sig 0 only checks that the process exists. 
+    /// The signal is acted on when the victim returns 
     /// to user space (user_trap)
-    pub fn kill(&mut self, pid: usize) -> Result<usize, ()> {
-        for proc in self.proc.iter_mut() {
-            if proc.pid() == pid {
-                proc.set_killed(true);
-                if proc.state() == ProcState::SLEEPING {
-                    // Wake process from sleep. 
-                    proc.set_state(ProcState::RUNNABLE);
-                    return Ok(0)
-                }
+    pub fn kill(&mut self, pid: Pid, sig: usize) -> Result<usize, ()> {
+        if sig >= signal::NSIG {
+            return Err(())
+        }
+        for proc in self.procs() {
+            // Check and mark under the same lock so the slot
+            // can't be freed and reused in between. 
+            let mut guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                post_signal(proc, &mut guard, sig);
+                drop(guard);
+                return Ok(0)
             }
+            drop(guard);
         }
         Err(())
     }
 
-    /// Print a process listing to console. For debugging. 
-    /// Runs when user type ^P on console. 
-    /// No lock to avoid wedging a stuck machine further
-    pub fn proc_dump(&self) {
-        for proc in self.proc.iter() {
-            if proc.state() == ProcState::UNUSED { continue; }
-            else {
-                println!("pid: {} state: {:?} name: {}", proc.pid(), proc.state(), proc.name());
+    /// Send signal sig to every process in the process group pgid. 
+    pub fn kill_group(&mut self, pgid: Pid, sig: usize) -> Result<usize, ()> {
+        if sig >= signal::NSIG {
+            return Err(())
+        }
+        let mut found = false;
+        for proc in self.procs() {
+            let mut guard = proc.meta.acquire();
+            if guard.pgid == pgid && guard.state != ProcState::UNUSED {
+                post_signal(proc, &mut guard, sig);
+                found = true;
+            }
+            drop(guard);
+        }
+        if found { Ok(0) } else { Err(()) }
+    }
+
+    /// Send signal sig to every process except init and caller. 
+    pub fn kill_all(&mut self, caller: &Process, sig: usize) -> Result<usize, ()> {
+        if sig >= signal::NSIG {
+            return Err(())
+        }
+        let mut found = false;
+        for proc in self.procs() {
+            if ptr::eq(proc, caller) {
+                continue
+            }
+            let mut guard = proc.meta.acquire();
+            if guard.pid != Pid::new(1) && guard.state != ProcState::UNUSED {
+                post_signal(proc, &mut guard, sig);
+                found = true;
+            }
+            drop(guard);
+        }
+        if found { Ok(0) } else { Err(()) }
+    }
+
+    /// Put the process pid into the process group pgid, 
+    /// pgid 0 means a new group led by pid. 
+    /// The target must be caller itself or one of its children, 
+    /// and the group must belong to the caller's session. 
+    pub fn set_pgid(&self, caller: &mut Process, pid: Pid, pgid: Pid) -> Result<usize, ()> {
+        let pgid = if pgid == Pid::new(0) { pid } else { pgid };
+        let caller_meta = caller.meta.acquire();
+        let sid = caller_meta.sid;
+        let caller_pid = caller_meta.pid;
+        drop(caller_meta);
+
+        // hold the tree lock so the parent link can't change underneath. 
+        let wait = self.tree_read();
+        let target = self.procs().find(|p| {
+            let guard = p.meta.acquire();
+            let found = guard.pid == pid && guard.state != ProcState::UNUSED;
+            drop(guard);
+            found
+        });
+        let target = match target {
+            Some(p) if pid == caller_pid 
+                || unsafe{ (*p.data.get()).parent }.map_or(false, |parent| ptr::eq(parent, caller)) => p,
+            _ => {
+                drop(wait);
+                return Err(())
+            }
+        };
+        let group_exists = pgid == pid || self.procs().any(|p| {
+            let guard = p.meta.acquire();
+            let found = guard.pgid == pgid && guard.sid == sid 
+                && guard.state != ProcState::UNUSED;
+            drop(guard);
+            found
+        });
+
+        let mut guard = target.meta.acquire();
+        let res = if group_exists && guard.sid == sid {
+            guard.pgid = pgid;
+            Ok(0)
+        } else {
+            Err(())
+        };
+        drop(guard);
+        drop(wait);
+        res
+    }
+
+    /// Get the process group of the process pid. 
+    pub fn get_pgid(&self, pid: Pid) -> Result<usize, ()> {
+        for proc in self.procs() {
+            let guard = proc.meta.acquire();
+            if guard.pid == pid && guard.state != ProcState::UNUSED {
+                let pgid = guard.pgid;
+                drop(guard);
+                return Ok(pgid.as_usize())
             }
+            drop(guard);
+        }
+        Err(())
+    }
+
+    /// Print a process listing to console. For debugging. 
+    /// Runs when user types ^P on console. 
+    /// No lock to avoid wedging a stuck machine further. 
+    pub fn dump(&self) {
+        println!("");
+        for proc in self.procs() {
+            let pmeta = unsafe{ proc.meta.get_unchecked() };
+            if pmeta.state == ProcState::UNUSED { continue; }
+            let start_time = unsafe{ (*proc.data.get()).start_time };
+            println!(
+                "pid: {} state: {:?} name: {} chan: 0x{:x} age: {}", 
+                pmeta.pid, pmeta.state, proc.name(), pmeta.channel, 
+                ticks().wrapping_sub(start_time)
+            );
         }
     }
 }
 
-#[inline]
-fn kernel_stack(pos: usize) -> usize {
-    TRAMPOLINE - (pos + 1) * 5 * PGSIZE
-}
\ No newline at end of file
+/// Mark sig pending, SIGKILL also kills the process at once. 
+/// A sleeping process is woken up to notice it. 
+/// SIGCONT continues a stopped process right away, and cancels
+/// pending stop signals, which in turn cancel a pending SIGCONT. 
+/// p->lock must be held. 
+pub(super) fn post_signal(proc: &Process, guard: &mut SpinlockGuard<ProcMeta>, sig: usize) {
+    if sig == 0 {
+        return
+    }
+    let bit = signal::sig_bit(sig);
+    if bit & signal::STOP_SIGNALS != 0 {
+        guard.pending &= !signal::sig_bit(signal::SIGCONT);
+    }
+    guard.pending |= bit;
+    match sig {
+        signal::SIGKILL => guard.killed = true,
+        signal::SIGCONT => guard.pending &= !signal::STOP_SIGNALS,
+        _ => {}
+    }
+    let wake = match guard.state {
+        ProcState::SLEEPING => true,
+        ProcState::STOPPED => sig == signal::SIGCONT || sig == signal::SIGKILL,
+        _ => false
+    };
+    if wake {
+        make_runnable(proc, guard);
+    }
+}
@@ -0,0 +1,101 @@
+// Memory accounting, like a memory cgroup. Each address space
+// charges the pages it uses to a group: its user pages, swapped
+// out ones included, and its page-table pages. A pipe is charged
+// a whole page to the group of the process that made it.
+// A fork child gets a group of its own inside its parent's, and
+// every charge also counts in all the groups above, so a group
+// holds the pages of a whole process subtree. Its limit comes
+// from RLIMIT_PAGES of the process owning it.
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::lock::arc::Arc;
+
+pub struct MemCg {
+    parent: Option<Arc<MemCg>>,
+    pages: AtomicUsize, // pages charged to it and the groups inside
+    limit: AtomicUsize, // most pages, RLIM_INFINITY for none
+}
+
+impl MemCg {
+    pub fn new(parent: Option<Arc<MemCg>>, limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            parent,
+            pages: AtomicUsize::new(0),
+            limit: AtomicUsize::new(limit),
+        })
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed)
+    }
+
+    /// Charge n pages to this group and the groups above,
+    /// unless one of them would go over its limit.
+    pub fn try_charge(&self, n: usize) -> Result<(), &'static str> {
+        let mut group = Some(self);
+        while let Some(cg) = group {
+            let old = cg.pages.fetch_add(n, Ordering::Relaxed);
+            if old.saturating_add(n) > cg.limit() {
+                cg.pages.fetch_sub(n, Ordering::Relaxed);
+                self.uncharge_below(cg, n);
+                return Err("over the memory limit")
+            }
+            group = cg.parent.as_deref();
+        }
+        Ok(())
+    }
+
+    /// Charge n pages whatever the limits, for memory
+    /// already in use.
+    pub fn charge(&self, n: usize) {
+        let mut group = Some(self);
+        while let Some(cg) = group {
+            cg.pages.fetch_add(n, Ordering::Relaxed);
+            group = cg.parent.as_deref();
+        }
+    }
+
+    /// Give back n pages charged before.
+    pub fn uncharge(&self, n: usize) {
+        let mut group = Some(self);
+        while let Some(cg) = group {
+            let old = cg.pages.fetch_sub(n, Ordering::Relaxed);
+            assert!(old >= n, "memcg: uncharge more than charged");
+            group = cg.parent.as_deref();
+        }
+    }
+
+    /// Whether n more pages fit in this group and the groups above.
+    pub fn fits(&self, n: usize) -> bool {
+        let mut group = Some(self);
+        while let Some(cg) = group {
+            if cg.pages().saturating_add(n) > cg.limit() {
+                return false
+            }
+            group = cg.parent.as_deref();
+        }
+        true
+    }
+
+    /// Undo a failed try_charge() in the groups from this one up
+    /// to, not including, the group top that refused it.
+    fn uncharge_below(&self, top: &MemCg, n: usize) {
+        let mut group = Some(self);
+        while let Some(cg) = group {
+            if core::ptr::eq(cg, top) {
+                return
+            }
+            cg.pages.fetch_sub(n, Ordering::Relaxed);
+            group = cg.parent.as_deref();
+        }
+    }
+}
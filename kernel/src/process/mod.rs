@@ -1,6 +1,7 @@
 use core::ptr::{ copy_nonoverlapping, NonNull };
 use core::mem::size_of;
-use alloc::sync::Arc;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use crate::lock::arc::Arc;
 use alloc::vec;
 use array_macro::array;
 
@@ -11,17 +12,49 @@ use crate::syscall::SysResult;
 
 
 pub mod cpu;
+mod percpu;
+pub mod kthread;
 mod context;
 mod trapframe;
 mod manager;
 mod elf;
+mod exec;
 mod process;
+mod scheduler;
+mod address_space;
+mod rusage;
+mod alarm;
+mod rlimit;
+mod wait_queue;
+mod vma;
+mod shm;
+mod kstack;
+mod oom;
+mod memcg;
+mod futex;
+pub mod signal;
+mod pid;
 pub use context::*;
 pub use trapframe::*;
 pub use cpu::*;
 pub use process::*;
 pub use manager::*;
 pub use elf::*;
+pub use exec::*;
+pub use scheduler::*;
+pub use address_space::*;
+pub use kstack::*;
+pub use oom::*;
+pub use memcg::*;
+pub use futex::*;
+pub use rusage::*;
+pub use alarm::*;
+pub use rlimit::*;
+pub use wait_queue::*;
+pub use vma::*;
+pub use shm::*;
+pub use pid::*;
+pub use kthread::KthreadFn;
 
 static INITCODE: [u8; 51] = [
     0x17, 0x05, 0x00, 0x00, 0x13, 0x05, 0x05, 0x02, 0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0x05, 0x02,
@@ -33,48 +66,8 @@ static INITCODE: [u8; 51] = [
 /// Exit the current process. Does not return. 
 /// An exited process remains in the zombie state
 /// until its parent calls wait()
-pub unsafe fn exit(status: i32) {
-    let my_proc = CPU_MANAGER.myproc().unwrap();
-
-    // TODO: initproc
-
-    // Get extern data in current process. 
-    let pdata = my_proc.data.get_mut();
-
-    // Close all open files
-    for f in pdata.open_files.iter_mut() {
-        f.take();
-    }
-    pdata.open_files = array![_ => None; NFILE];
-
-    LOG.begin_op();
-    // extern_data.cwd.as_ref().unwrap().put();
-    // ICACHE.put(extern_data.cwd.as_ref());
-    drop(pdata.cwd.as_mut());
-    LOG.end_op();
-    pdata.cwd = None;
-
-    let wait_guard = PROC_MANAGER.wait_lock.acquire();
-    // TODO: Give any children to init
-    
-    // Parent might be sleeping in wait(). 
-    PROC_MANAGER.wake_up(pdata.parent.unwrap() as usize);
-
-    let mut guard = my_proc.meta.acquire();
-
-    guard.set_state(ProcState::ZOMBIE);
-    guard.xstate = status as usize;
-    
-    drop(guard);
-
-    drop(wait_guard);
-
-    // Jump into scheduler, never to return. 
-    CPU_MANAGER.scheduler();
-    panic!("zombine exit");
-
-
-
+pub unsafe fn exit(status: i32) -> ! {
+    PROC_MANAGER.exit(status as usize)
 }
 
 /// A fork child's very first scheduling by scheduler()
@@ -82,14 +75,15 @@ pub unsafe fn exit(status: i32) {
 /// 
 /// Need to be handled carefully, because CPU use ra to jump here
 unsafe fn fork_ret() -> ! {
-    static mut FIRST: bool = true;
+    static FIRST: AtomicBool = AtomicBool::new(true);
     
     // Still holding p->lock from scheduler
     CPU_MANAGER.myproc().unwrap().meta.release();
     
-    if FIRST {
-        // File system initialization
-        FIRST = false;
+    // File system initialization, by the first process to run. 
+    // That is init, and every other process is forked from it 
+    // once it is in user space, so none of them has to wait here. 
+    if FIRST.swap(false, Ordering::AcqRel) {
         init(ROOTDEV);
     }
     // println!("user trap return");
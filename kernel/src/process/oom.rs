@@ -0,0 +1,134 @@
+//! Out-of-memory killer.
+//!
+//! When no page for user memory is left even after swapping,
+//! alloc_user_page() calls oom_kill() to get memory back by killing
+//! a process. The policy scores every candidate, by default by the
+//! pages charged to it, and the highest score loses. init and kernel
+//! threads are never picked. The threads sharing the victim's address
+//! space die with it, the memory is only freed when the last one exits.
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::lock::arc::Arc;
+
+use crate::lock::spinlock::Spinlock;
+use crate::trap::{ TICKS_LOCK, ticks_channel };
+use super::*;
+use super::manager::post_signal;
+
+/// Scores a process whose address space is vm,
+/// the highest is killed first, 0 is never killed.
+pub type OomPolicy = fn(&Process, &AddressSpace) -> usize;
+
+static OOM_POLICY: Spinlock<OomPolicy> = Spinlock::new(oom_largest, "oom_policy");
+
+/// Processes killed for memory since boot.
+static OOM_KILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Times an allocation calls oom_kill() before it fails, a tick apart,
+/// in case the victim is stuck and never frees its memory.
+pub const OOM_RETRIES: usize = 10;
+
+/// The default policy, the process using the most memory.
+pub fn oom_largest(_proc: &Process, vm: &AddressSpace) -> usize {
+    vm.charged_pages()
+}
+
+/// Choose how victims are picked.
+pub fn set_oom_policy(policy: OomPolicy) {
+    *OOM_POLICY.acquire() = policy;
+}
+
+pub fn oom_kills() -> usize {
+    OOM_KILLS.load(Ordering::Relaxed)
+}
+
+impl ProcManager {
+    /// Kill a process to free memory after an allocation for user
+    /// memory failed. Returns true when the caller should retry, having
+    /// slept a tick for the victim to exit. Returns false when the
+    /// allocation should fail: the caller is killed, maybe as the victim,
+    /// or there is nothing to kill.
+    /// May sleep, so no spinlock can be held.
+    pub fn oom_kill(&self) -> bool {
+        let me = match unsafe{ CPU_MANAGER.myproc() } {
+            Some(p) => p,
+            None => return false
+        };
+        if me.killed() {
+            return false
+        }
+        // A victim picked before and still exiting will free its memory soon.
+        let dying = self.procs().any(|p| {
+            let guard = p.meta.acquire();
+            let dying = guard.killed && guard.state != ProcState::UNUSED && guard.state != ProcState::ZOMBIE;
+            drop(guard);
+            dying
+        });
+        if dying {
+            wait_tick(me);
+            return true
+        }
+
+        let policy = *OOM_POLICY.acquire();
+        let mut victim: Option<(usize, &Process, Arc<AddressSpace>)> = None;
+        let init = self.init_proc().unwrap_or(core::ptr::null_mut());
+        for p in self.procs() {
+            if p as *const Process == init as *const Process {
+                continue
+            }
+            // Same as swap_out(), vm can only change under a process
+            // running on another hart.
+            let guard = p.meta.acquire();
+            let vm = match guard.state {
+                ProcState::SLEEPING | ProcState::RUNNABLE | ProcState::STOPPED => {
+                    unsafe{ (*p.data.get()).vm.clone() }
+                },
+                ProcState::RUNNING if p as *const Process == me as *const Process => {
+                    unsafe{ (*p.data.get()).vm.clone() }
+                },
+                _ => None
+            };
+            drop(guard);
+            let vm = match vm {
+                Some(vm) => vm,
+                None => continue
+            };
+            let score = policy(p, &vm);
+            if score > 0 && victim.as_ref().map_or(true, |(best, _, _)| score > *best) {
+                victim = Some((score, p, vm));
+            }
+        }
+        let (score, victim, vm) = match victim {
+            Some(victim) => victim,
+            None => return false
+        };
+
+        println!("oom: killing pid {} ({}), {} pages", victim.pid(), victim.name(), score);
+        OOM_KILLS.fetch_add(1, Ordering::Relaxed);
+        let mut killed_me = false;
+        for p in self.procs() {
+            let mut guard = p.meta.acquire();
+            let shares = guard.state != ProcState::UNUSED && unsafe{ (*p.data.get()).vm.as_ref() }
+                .map_or(false, |pvm| Arc::ptr_eq(pvm, &vm));
+            if shares {
+                post_signal(p, &mut guard, signal::SIGKILL);
+                killed_me |= p as *const Process == me as *const Process;
+            }
+            drop(guard);
+        }
+        drop(vm);
+        if killed_me {
+            return false
+        }
+        wait_tick(me);
+        true
+    }
+}
+
+/// Give the victim a tick to exit. Yielding alone could come
+/// straight back when nothing else is runnable on this cpu.
+fn wait_tick(me: &Process) {
+    let ticks_guard = unsafe{ TICKS_LOCK.acquire() };
+    me.sleep(ticks_channel(), ticks_guard);
+}
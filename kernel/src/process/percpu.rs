@@ -0,0 +1,58 @@
+//! Per-cpu data, one T for each hart, indexed by the hartid in tp.
+//!
+//! Only shared references to a T are handed out, never &mut T, so
+//! with() nests and get() from another hart aliases nothing mutable.
+//! What a hart changes in its own T sits in Cells, which it touches
+//! only with interrupts off, so nothing else on the hart runs
+//! meanwhile. Other harts look only at the fields that are atomics
+//! or behind a lock.
+
+use core::cell::UnsafeCell;
+
+use crate::arch::riscv::qemu::param::NCPU;
+use super::{ IntrGuard, cpuid };
+
+pub struct PerCpu<T> {
+    data: UnsafeCell<[T; NCPU]>,
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(data: [T; NCPU]) -> Self {
+        Self {
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn slot(&self, id: usize) -> *const T {
+        assert!(id < NCPU, "percpu: no cpu {}", id);
+        unsafe{ (self.data.get() as *const T).add(id) }
+    }
+
+    /// Run f on this cpu's T, with interrupts off.
+    /// Must not switch to another process in f.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let _intr = IntrGuard::new();
+        f(unsafe{ &*self.slot(cpuid()) })
+    }
+
+    /// This cpu's T, outside of with().
+    /// SAFETY: interrupts must be off, or the process
+    /// could move to another cpu while it holds the T.
+    pub unsafe fn current(&self) -> &T {
+        &*self.slot(cpuid())
+    }
+
+    /// The T of cpu id, to look at its shared fields.
+    /// No &mut T is ever made, so this aliases none.
+    pub fn get(&self, id: usize) -> &T {
+        unsafe{ &*self.slot(id) }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..NCPU).map(move |id| self.get(id))
+    }
+}
+
+// Each T is only changed by its own hart, see above,
+// and it may be handed to any hart, so it must be Send.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
@@ -0,0 +1,64 @@
+use core::fmt;
+
+use crate::arch::riscv::qemu::param::PID_MAX;
+
+/// Process ID. Kept apart from plain usize so it can't be mixed up
+/// with the other counters in a process. 0 is never handed out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Pid(usize);
+
+impl Pid {
+    pub const fn new(pid: usize) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Hands out increasing pids, wrapping around at PID_MAX.
+/// A pid stays taken in the bitmap until its proc is freed, 
+/// so a ZOMBIE nobody has waited for keeps its pid. 
+pub struct PidAllocator {
+    next: usize,
+    used: [u64; PID_MAX / 64],
+}
+
+impl PidAllocator {
+    pub const fn new() -> Self {
+        Self {
+            next: 1,
+            used: [0; PID_MAX / 64],
+        }
+    }
+
+    fn is_used(&self, pid: usize) -> bool {
+        self.used[pid / 64] & (1 << (pid % 64)) != 0
+    }
+
+    pub fn alloc(&mut self) -> Option<Pid> {
+        for _ in 1..PID_MAX {
+            let pid = self.next;
+            self.next = if pid + 1 == PID_MAX { 1 } else { pid + 1 };
+            if !self.is_used(pid) {
+                self.used[pid / 64] |= 1 << (pid % 64);
+                return Some(Pid(pid))
+            }
+        }
+        None
+    }
+
+    pub fn free(&mut self, pid: Pid) {
+        if pid.0 == 0 || !self.is_used(pid.0) {
+            panic!("pid free: {} not allocated", pid);
+        }
+        self.used[pid.0 / 64] &= !(1 << (pid.0 % 64));
+    }
+}
@@ -4,21 +4,25 @@ use core::cell::{ UnsafeCell, RefCell };
 use core::str::from_utf8;
 use alloc::vec::Vec;
 use alloc::vec;
-use alloc::sync::Arc;
+use crate::lock::arc::Arc;
 use array_macro::array;
 
 use crate::arch::riscv::qemu::fs::{NFILE, NOFILE};
-use crate::lock::spinlock::{ Spinlock, SpinlockGuard };
+use crate::lock::spinlock::Spinlock;
+use crate::lock::SleepGuard;
 use crate::memory::{
     kalloc::*,
     address::{ PhysicalAddress, VirtualAddress, Addr },
-    mapping::{ page_table::PageTable, page_table_entry::PteFlags},
-    RawPage
+    mapping::{ page_table::PageTable, page_table_entry::MapPerm},
+    PageBox, tlb, page_round_up
 };
-use crate::arch::riscv::qemu::layout::{ PGSIZE, TRAMPOLINE, TRAPFRAME };
+use crate::arch::riscv::qemu::layout::{ PGSIZE, TRAMPOLINE, TRAPFRAME, thread_trapframe };
+use crate::arch::riscv::qemu::param::{ DEFAULT_PRIORITY, DEFAULT_TICKETS, ALL_CPUS };
 use crate::arch::riscv::register::satp;
+use crate::trap::ticks;
 use super::*;
-use crate::fs::{FileType, Inode, VFile};
+use super::signal::SigState;
+use crate::fs::{FileType, Inode, VFile, LOG};
 
 
 use alloc::boxed::Box;
@@ -31,13 +35,17 @@ pub enum ProcState{
     RUNNABLE,
     RUNNING,
     ZOMBIE,
-    ALLOCATED
+    ALLOCATED,
+    STOPPED, // by a stop signal, until SIGCONT
 }
 
 
 pub struct Process {
     pub meta: Spinlock<ProcMeta>,
     pub data: UnsafeCell<ProcData>,
+    // Link on the wait queue of its channel while sleeping, 
+    // the queue's lock must be held when using it. 
+    pub wait_link: UnsafeCell<WaitLink>,
 }
 
 pub struct ProcMeta {
@@ -46,7 +54,20 @@ pub struct ProcMeta {
     pub channel: usize, // If non-zero, sleeping on chan
     pub killed: bool, // If non-zero, have been killed
     pub xstate: usize, // Exit status to be returned to parent's wait
-    pub pid: usize,   // Process ID
+    pub pid: Pid,   // Process ID
+    pub priority: u8, // Scheduling priority, 0 is the highest
+    pub ticks: usize, // Ticks used of the current time slice
+    pub tickets: usize, // Lottery tickets, CPU share is proportional to it
+    pub affinity: usize, // Bit i set if the process may run on cpu i
+    pub pgid: Pid, // Process group ID
+    pub sid: Pid, // Session ID
+    pub pending: u32, // Bit i set if signal i is pending
+    pub sleep_start: usize, // Tick at which it last went to sleep
+    pub bonus: u8, // Interactive bonus, levels above priority
+    pub class: SchedClass, // Scheduling class
+    pub rt_priority: u8, // Priority in the real-time class, 0 is the highest
+    pub deadline: usize, // If non-zero, tick at which a sleep times out
+    pub context: Context, // switch() here to run process, held across the switch
 }
 
 impl ProcMeta {
@@ -56,28 +77,64 @@ impl ProcMeta {
             channel: 0,
             killed: false,
             xstate: 0,
-            pid: 0,
-
+            pid: Pid::new(0),
+            priority: DEFAULT_PRIORITY,
+            ticks: 0,
+            tickets: DEFAULT_TICKETS,
+            affinity: ALL_CPUS,
+            pgid: Pid::new(0),
+            sid: Pid::new(0),
+            pending: 0,
+            sleep_start: 0,
+            bonus: 0,
+            class: SchedClass::Normal,
+            rt_priority: 0,
+            deadline: 0,
+            context: Context::new(),
         }
     }
 
     pub fn set_state(&mut self, state: ProcState) {
         self.state = state;
     }
+
+    /// The priority level it is queued at, 
+    /// raised by the interactive bonus. 
+    pub fn effective_priority(&self) -> usize {
+        self.priority.saturating_sub(self.bonus) as usize
+    }
+
+    /// Whether the affinity mask allows running on the cpu. 
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        self.affinity & (1 << cpu) != 0
+    }
+
+    /// Start the context at forkret, on the kernel stack at kstack. 
+    pub fn init_context(&mut self, kstack: usize) {
+        self.context.write_zero();
+        self.context.write_ra(fork_ret as usize);
+        self.context.write_sp(kstack + PGSIZE * KSTACK_PAGES);
+    }
 }
 
 pub struct ProcData {
     // these are private to the process, so p->lock need to be held
     pub kstack:usize,  // Virtual address of kernel stack
-    pub size:usize, // size of process memory
-    pub pagetable: Option<Box<PageTable>>, // User page table
-    pub trapframe: *mut Trapframe, // data page for trampoline.S
-    pub context: Context, // switch() here to run processs
+    pub vm: Option<Arc<AddressSpace>>, // User page table and memory size, shared by threads
+    pub trapframe: Option<PageBox<Trapframe>>, // data page for trampoline.S
+    pub trapframe_va: usize, // where trapframe is mapped in user space
     pub name: [u8; 16],   // Process name (debugging)
-    // proc_tree_lock must be held when using this:
-    pub parent: Option<*mut Process>,   
+    // proc_tree_lock must be held when using this,
+    // proc slots are never freed, see manager.rs:
+    pub parent: Option<&'static Process>,   
     pub open_files: [Option<Arc<VFile>>; NFILE],
-    pub cwd: Option<Inode>
+    pub cwd: Option<Inode>,
+    pub kthread: Option<(KthreadFn, usize)>, // Entry and argument of a kernel thread
+    pub rusage: Rusage, // CPU time and context switches
+    pub alarm: Alarm, // sigalarm() state
+    pub signals: SigState, // blocked signals and signal actions
+    pub rlimits: RLimits, // resource limits
+    pub start_time: usize, // tick at which it was created
 
 }
 
@@ -85,19 +142,29 @@ impl ProcData {
     pub const fn new() -> Self {
         Self {
             kstack:0,
-            size: 0,
-            pagetable: None,
-            trapframe: null_mut(),
-            context: Context::new(),
+            vm: None,
+            trapframe: None,
+            trapframe_va: TRAPFRAME,
             name: [0u8; 16],
             parent: None,
             open_files: array![_ => None; NFILE],
-            cwd: None
+            cwd: None,
+            kthread: None,
+            rusage: Rusage::new(),
+            alarm: Alarm::new(),
+            signals: SigState::new(),
+            rlimits: RLimits::new(),
+            start_time: 0,
         }
     }
 
     pub fn get_trapframe(&self) -> *mut Trapframe {
-        self.trapframe
+        self.trapframe.as_ref().map_or(null_mut(), |tf| tf.as_ptr())
+    }
+
+    /// The trapframe of a user process, for the trap and syscall code. 
+    pub fn trapframe(&self) -> &mut Trapframe {
+        unsafe{ &mut *self.trapframe.as_ref().expect("Fail to get trapframe").as_ptr() }
     }
 
     pub fn set_name(&mut self, name: &[u8]) {
@@ -110,7 +177,7 @@ impl ProcData {
         }
     }
 
-    pub fn set_parent(&mut self, parent: Option<*mut Process>) {
+    pub fn set_parent(&mut self, parent: Option<&'static Process>) {
         self.parent = parent;
     }
 
@@ -118,28 +185,13 @@ impl ProcData {
         self.kstack = ksatck;
     }
 
-    pub fn set_trapframe(&mut self, trapframe: *mut Trapframe) {
-        self.trapframe = trapframe;
-    }
-
-    pub fn set_pagetable(&mut self, pagetable: Option<Box<PageTable>>) {
-        self.pagetable = pagetable
-    }
-
-    pub fn set_context(&mut self, ctx: Context) {
-        self.context = ctx
-    }
-
-    pub fn get_context_mut(&mut self) -> *mut Context {
-        &mut self.context as *mut Context
+    pub fn vm(&self) -> &Arc<AddressSpace> {
+        self.vm.as_ref().expect("Fail to get address space")
     }
 
-    pub fn init_context(&mut self) {
-
-        let kstack = self.kstack;
-        self.context.write_zero();
-        self.context.write_ra(fork_ret as usize);
-        self.context.write_sp(kstack + PGSIZE);
+    /// Program break, the end of the heap. 
+    pub fn brk(&self) -> usize {
+        self.vm.as_ref().map_or(0, |vm| vm.brk())
     }
 
     /// Find an unallocated file desprictor in proc
@@ -153,8 +205,9 @@ impl ProcData {
     }
 
     // Create a user page table for a given process,
-    // with no user memory, but with trampoline pages
-    pub unsafe fn proc_pagetable(&mut self) {
+    // with no user memory, but with trampoline pages. 
+    // Returns false if memory runs out. 
+    pub unsafe fn proc_pagetable(&mut self) -> bool {
 
         extern "C" {
             fn trampoline();
@@ -170,22 +223,25 @@ impl ProcData {
             VirtualAddress::new(TRAMPOLINE),
             PhysicalAddress::new(trampoline as usize),
             PGSIZE,
-            PteFlags::R | PteFlags::X
+            MapPerm::Trampoline
         ) {
-            page_table.uvm_free(0);
+            // The page table is freed as it is dropped. 
+            return false
         }
 
         // map the trapframe just below TRAMPOLINE, for trampoline.S 
         if !page_table.map(
             VirtualAddress::new(TRAPFRAME), 
-            PhysicalAddress::new(self.trapframe as usize),
+            PhysicalAddress::new(self.get_trapframe() as usize),
             PGSIZE,
-            PteFlags::R | PteFlags::W
+            MapPerm::KernelRW
         ) {
-            page_table.uvm_free(0);
+            page_table.uvm_unmap(VirtualAddress::new(TRAMPOLINE), 1, false);
+            return false
         }
 
-        self.pagetable = Some(page_table);
+        self.vm = Some(AddressSpace::new(page_table));
+        true
     }
 
     /// Initialize first user process
@@ -193,11 +249,11 @@ impl ProcData {
         extern "C" {
             fn user_trap();
         }
-        let tf = unsafe{ &mut *self.trapframe };
+        let tf = self.trapframe();
         // kernel page table
         tf.kernel_satp = unsafe{ satp::read() };
         // process's kernel stack 
-        tf.kernel_sp = self.kstack + PGSIZE * 4;
+        tf.kernel_sp = self.kstack + PGSIZE * KSTACK_PAGES;
         // kernel user trap address
         tf.kernel_trap = user_trap as usize;
         // current process's cpu id.
@@ -214,17 +270,16 @@ impl Process{
         Self{    
             meta: Spinlock::new(ProcMeta::new(), "process"),
             data: UnsafeCell::new(ProcData::new()),
+            wait_link: UnsafeCell::new(WaitLink::new()),
         }
     }
 
-    pub fn init(&mut self, kstack: usize) {
+    pub fn init(&mut self) {
         let pdata = unsafe {
             &mut *self.data.get()
         };
 
         pdata.open_files = array![_ => None; NFILE];
-
-        pdata.set_kstack(kstack);
     }
 
     pub fn as_ptr(&self) -> *const Process{
@@ -250,7 +305,7 @@ impl Process{
         killed
     }
 
-    pub fn pid(&self) -> usize {
+    pub fn pid(&self) -> Pid {
         let proc_data = self.meta.acquire();
         let pid = proc_data.pid;
         drop(proc_data);
@@ -278,7 +333,8 @@ impl Process{
 
     pub fn name(&self) -> &str {
         let pdata = unsafe{ &*self.data.get() };
-        from_utf8(&pdata.name).unwrap()
+        let len = pdata.name.iter().position(|&c| c == 0).unwrap_or(pdata.name.len());
+        from_utf8(&pdata.name[..len]).unwrap_or("???")
     }
 
     pub fn modify_kill(&self, killed: bool) {
@@ -287,10 +343,9 @@ impl Process{
         drop(proc_data);
     }
 
-    pub fn page_table(&self) -> &mut Box<PageTable> {
-        let pdata = unsafe{ &mut *self.data.get() };
-        let page_table = pdata.pagetable.as_mut().expect("Fail to get page table");
-        page_table
+    pub fn vm(&self) -> &Arc<AddressSpace> {
+        let pdata = unsafe{ &*self.data.get() };
+        pdata.vm()
     }
 
     /// Create a user page table for a given process,
@@ -308,9 +363,9 @@ impl Process{
             VirtualAddress::new(TRAMPOLINE), 
             PhysicalAddress::new(trampoline as usize),
              PGSIZE, 
-             PteFlags::R | PteFlags::X
+             MapPerm::Trampoline
             ) {
-                page_table.uvm_free(0);
+                // The page table is freed as it is dropped. 
                 return None
             }
 
@@ -319,14 +374,13 @@ impl Process{
                 VirtualAddress::new(TRAPFRAME), 
                 PhysicalAddress::new((&*self.data.get()).get_trapframe() as usize), 
                 PGSIZE, 
-                PteFlags::R | PteFlags::W
+                MapPerm::KernelRW
             ) {
                 page_table.uvm_unmap(
-                    VirtualAddress::new(TRAPFRAME), 
+                    VirtualAddress::new(TRAMPOLINE), 
                     1, 
                     false
                 );
-                page_table.uvm_free(0);
                 return None
             }
         }
@@ -338,104 +392,189 @@ impl Process{
     /// p.acquire() must be held.
     pub fn free_proc(&mut self) {
         let mut pdata = self.data.get_mut();
-        if !pdata.trapframe.is_null() {
-            drop(pdata.trapframe as *mut RawPage);
-            pdata.set_trapframe(0 as *mut Trapframe);
-
-            if let Some(page_table) = pdata.pagetable.as_mut() {
-                page_table.proc_free_pagetable(pdata.size);
+        // Kernel threads have no trapframe or user memory. 
+        if pdata.trapframe.take().is_some() {
+
+            // exit() has put the address space already, 
+            // only a fork that failed still holds it. 
+            if let Some(vm) = pdata.vm.take() {
+                if pdata.trapframe_va != TRAPFRAME {
+                    vm.with_page_table(|pt| pt.uvm_unmap(
+                        VirtualAddress::new(pdata.trapframe_va),
+                        1,
+                        false
+                    ));
+                    tlb::flush_range(vm.asid(), pdata.trapframe_va, PGSIZE);
+                }
+                AddressSpace::put(vm);
             }
+        }
 
+        let mut guard = self.meta.acquire();
 
-            let mut guard = self.meta.acquire();
-
-            pdata.set_pagetable(None);
-            pdata.set_parent(None);
-            pdata.size = 0;
-
-            guard.pid = 0;
-            guard.channel = 0;
-            guard.killed = false;
-            guard.xstate = 0;
-            guard.set_state(ProcState::UNUSED);
-
-            drop(guard);
-            
+        pdata.vm = None;
+        if pdata.kstack != 0 {
+            kstack_free(pdata.kstack);
+            pdata.kstack = 0;
+        }
+        pdata.trapframe_va = TRAPFRAME;
+        pdata.set_parent(None);
+        pdata.kthread = None;
+        pdata.rusage = Rusage::new();
+        pdata.alarm = Alarm::new();
+        pdata.signals = SigState::new();
+        pdata.rlimits = RLimits::new();
+        pdata.start_time = 0;
+
+        if guard.pid != Pid::new(0) {
+            unsafe{ PROC_MANAGER.free_pid(guard.pid); }
         }
+        guard.pid = Pid::new(0);
+        guard.channel = 0;
+        guard.killed = false;
+        guard.xstate = 0;
+        guard.priority = DEFAULT_PRIORITY;
+        guard.ticks = 0;
+        guard.tickets = DEFAULT_TICKETS;
+        guard.affinity = ALL_CPUS;
+        guard.pgid = Pid::new(0);
+        guard.sid = Pid::new(0);
+        guard.pending = 0;
+        guard.sleep_start = 0;
+        guard.bonus = 0;
+        guard.class = SchedClass::Normal;
+        guard.rt_priority = 0;
+        guard.deadline = 0;
+        guard.set_state(ProcState::UNUSED);
+
+        drop(guard);
     }
 
     
-    /// Grow or shrink user memory by n bytes. 
-    /// Return true on success, false on failure. 
+    /// Grow or shrink the heap by n bytes. 
+    /// Growing only moves the program break, the pages are 
+    /// allocated on first touch by AddressSpace::fault(). 
     pub fn grow_proc(&mut self, count: isize) -> Result<(), &'static str> {
-        let mut pdata = self.data.get_mut();
-        let mut size = pdata.size; 
-        let page_table = pdata.pagetable.as_mut().unwrap();
-        if count > 0 {
-            match unsafe { page_table.uvm_alloc(size, size + count as usize) } {
-                Some(new_size) => {
-                    size = new_size;
-                },
-
-                None => {
-                    return Err("Fail to allocate virtual memory for user")
-                }
-            }
-        } else if count < 0 {
-            let new_size = (size as isize + count) as usize;
-            size = page_table.uvm_dealloc(size, new_size);
+        let pdata = self.data.get_mut();
+        let brk = pdata.brk(); 
+        if count > 0 && brk.saturating_add(count as usize) > pdata.rlimits.cur(RLIMIT_AS) {
+            return Err("Exceed the address space limit")
+        }
+        let new_brk = if count >= 0 {
+            brk.checked_add(count as usize).ok_or("Exceed the user address space")?
+        } else {
+            brk.checked_sub(count.unsigned_abs()).ok_or("sbrk: below the heap")?
+        };
+        // The pages are charged when touched, but refuse a heap 
+        // that could not all be touched within the page limit. 
+        let vm = pdata.vm();
+        vm.sync_tables();
+        if new_brk > brk && !vm.memcg().fits((page_round_up(new_brk) - page_round_up(brk)) / PGSIZE) {
+            return Err("Exceed the page limit")
         }
+        vm.set_brk(new_brk)
+    }
 
-        pdata.size = size;
 
-        Ok(())
+    /// Called on every timer interrupt taken while this process runs, 
+    /// user tells whether it interrupted user mode. 
+    /// Returns true if it is time to give up the CPU.
+    pub fn timer_tick(&self, user: bool) -> bool {
+        let rusage = unsafe{ &mut (*self.data.get()).rusage };
+        if user {
+            rusage.utime += 1;
+        } else {
+            rusage.stime += 1;
+        }
+        let mut pmeta = self.meta.acquire();
+        let expired = pmeta.state == ProcState::RUNNING && task_tick(&mut pmeta);
+        drop(pmeta);
+        expired
     }
 
+    /// Stop on a stop signal, and give up the CPU until 
+    /// SIGCONT (or SIGKILL) makes it runnable again. 
+    pub fn stop(&self) {
+        let mut pmeta = self.meta.acquire();
+        // A SIGCONT sent since the stop signal was taken cancels it. 
+        if pmeta.killed || pmeta.pending & signal::sig_bit(signal::SIGCONT) != 0 {
+            drop(pmeta);
+            return
+        }
+        pmeta.set_state(ProcState::STOPPED);
+        unsafe {
+            let my_cpu = CPU_MANAGER.mycpu();
+            pmeta = my_cpu.sched(pmeta);
+        }
+        drop(pmeta)
+    }
 
     /// Give up the CPU for one scheduling round.
     /// yield is a keyword in rust
     pub fn yielding(&mut self) {
         // println!("[Debug] 让出 CPU");
         let mut pmeta = self.meta.acquire();
-        let ctx = self.data.get_mut().get_context_mut();
-        pmeta.set_state(ProcState::RUNNABLE);
+        make_runnable_local(self, &mut pmeta);
 
         unsafe {
             let my_cpu = CPU_MANAGER.mycpu();
-            pmeta = my_cpu.sched(
-                pmeta,
-                ctx
-            );
+            pmeta = my_cpu.sched(pmeta);
         }
         drop(pmeta)
     }
 
     /// Atomically release lock and sleep on chan
     /// Reacquires lock when awakened.
-    pub fn sleep<T>(&self, channel: usize, lock: SpinlockGuard<'_, T>) {
+    /// lock is the guard of any spinlock, a Spinlock or an RwSpinlock. 
+    pub fn sleep<L: SleepGuard>(&self, channel: usize, lock: L) {
+        self.sleep_until(channel, lock, 0);
+    }
+
+    /// Like sleep(), but also woken once timeout ticks have passed 
+    /// without a wakeup on chan. 
+    /// Returns false if it was the timeout that woke it up. 
+    pub fn sleep_timeout<L: SleepGuard>(&self, channel: usize, lock: L, timeout: usize) -> bool {
+        self.sleep_until(channel, lock, ticks() + timeout.max(1))
+    }
+
+    /// Sleep on chan, until the tick deadline if it is non-zero. 
+    /// Returns false if woken by the deadline. 
+    fn sleep_until<L: SleepGuard>(&self, channel: usize, lock: L, deadline: usize) -> bool {
         // Must acquire p->lock in order to 
         // change p->state and then call sched.
         // Once we hold p->lock, we can be
         // guaranteed that we won't miss any wakeup
         // (wakeup locks p->lock)
         // so it's okay to release lk;
+        // Join the wait queue first, while lk still keeps 
+        // the waker away, and the queue lock goes before p->lock. 
+        let queue = wait_queue(channel);
+        queue.acquire().push(NonNull::from(&*self), channel);
         let mut guard = self.meta.acquire();
         drop(lock);
         // Go to sleep.
         guard.channel = channel;
+        guard.sleep_start = ticks();
+        guard.deadline = deadline;
+        if deadline != 0 {
+            unsafe{ PROC_MANAGER.arm_deadline(deadline); }
+        }
         guard.set_state(ProcState::SLEEPING);
         unsafe {
             let my_cpu = CPU_MANAGER.mycpu();
-            let ctx = (&mut (*self.data.get())).get_context_mut();      
             // get schedule process
-            guard = my_cpu.sched(
-                guard, 
-                ctx
-            );
+            guard = my_cpu.sched(guard);
             // Tide up
             guard.channel = 0;
-            drop(guard);
         }
+        // The deadline is cleared by the timer when it expires. 
+        let woken = deadline == 0 || guard.deadline != 0;
+        guard.deadline = 0;
+        drop(guard);
+        // Still queued if woken by something else than 
+        // wake_up(), e.g. kill(). 
+        queue.acquire().remove(NonNull::from(&*self));
+        woken
     }
 
     /// Find a unallocated fd
@@ -448,44 +587,147 @@ impl Process{
         Ok(fd)       
     } 
 
+    /// Create a new process, copying the parent. 
+    /// Sets up child kernel stack to return as if from fork() system call. 
     pub fn fork(&mut self) -> Option<&mut Self> {
+        if !self.may_add_proc() {
+            return None
+        }
         // 从表中获取未被分配的子进程
-        if let Some(child_proc) = unsafe{ PROC_MANAGER.alloc_proc() } {
-            // 从当前进程的页表拷贝到子进程中
-            let pdata = unsafe{ &mut *self.data.get() };
-            let child_data = unsafe{ &mut *child_proc.data.get() };
-            if unsafe{ pdata.pagetable.as_mut().unwrap().uvm_copy(
-                child_data.pagetable.as_mut().unwrap(), 
-                pdata.size
-            ).is_err() } {
-                panic!("fork: Fail to copy data from parent process.")
+        let child_proc = match unsafe{ PROC_MANAGER.alloc_proc() } {
+            Some(proc) => proc,
+            None => {
+                println!("[Kernel] fork: None");
+                return None
             }
-            // 将当前进程的 trapframe 拷贝到子进程
-            let ptf = pdata.trapframe as *const Trapframe;
-            let child_tf = unsafe{ &mut *child_data.trapframe };
-            unsafe{ copy_nonoverlapping(ptf, child_tf, 1); }
-            // fork 后子进程应当返回0
-            child_tf.a0 = 0;
-
-            // 子进程拷贝父进程的文件和工作目录
-            child_data.open_files.clone_from(&pdata.open_files);
-            child_data.cwd.clone_from(&pdata.cwd);
-
-            child_data.name = pdata.name;
-            child_data.size = pdata.size;
-
-            let mut child_meta = child_proc.meta.acquire();
-            child_meta.state = ProcState::RUNNABLE;
-            drop(child_meta);
-
-            let wait = unsafe{ PROC_MANAGER.wait_lock.acquire() };
-            child_data.parent = Some(self as *mut Process);
-            drop(wait);
-            Some(child_proc)
-        }else {
-            println!("[Kernel] fork: None");
-            None
+        };
+
+        // 从当前进程的地址空间拷贝到子进程中
+        let pdata = unsafe{ &mut *self.data.get() };
+        let child_data = unsafe{ &mut *child_proc.data.get() };
+        // The child's pages are charged to a group of its own 
+        // inside the parent's, limited by the inherited RLIMIT_PAGES. 
+        child_data.vm().set_memcg(MemCg::new(
+            Some(Arc::clone(pdata.vm().memcg())), 
+            pdata.rlimits.cur(RLIMIT_PAGES)
+        ));
+        if pdata.vm().copy_to(child_data.vm()).is_err() {
+            // 拷贝失败时释放子进程，而不是让整个内核 panic
+            println!("[Kernel] fork: Fail to copy data from parent process.");
+            child_proc.free_proc();
+            return None
+        }
+
+        // 将当前进程的 trapframe 拷贝到子进程
+        let child_tf = child_data.trapframe();
+        unsafe{ copy_nonoverlapping(pdata.get_trapframe(), child_tf, 1); }
+        // fork 后子进程应当返回0
+        child_tf.a0 = 0;
+
+        // 子进程拷贝父进程的文件和工作目录
+        child_data.open_files.clone_from(&pdata.open_files);
+        child_data.cwd.clone_from(&pdata.cwd);
+
+        child_data.name = pdata.name;
+
+        self.start_child(child_proc);
+        Some(child_proc)
+    }
+
+    /// Create a thread sharing the address space of this process. 
+    /// The thread gets its own trapframe and kernel stack, and
+    /// returns to user space on the stack whose top is stack. 
+    pub fn clone_thread(&mut self, stack: usize) -> Option<&mut Self> {
+        if !self.may_add_proc() {
+            return None
+        }
+        let manager = unsafe{ &mut PROC_MANAGER };
+        let child_proc = match manager.alloc_slot() {
+            Some(proc) => proc,
+            None => {
+                println!("[Kernel] clone: None");
+                return None
+            }
+        };
+        let index = manager.slot_index(child_proc);
+
+        let pdata = unsafe{ &mut *self.data.get() };
+        let child_data = unsafe{ &mut *child_proc.data.get() };
+        child_data.trapframe = unsafe{ PageBox::new_zeroed() };
+        if child_data.trapframe.is_none() {
+            println!("[Kernel] clone: Fail to alloc trapframe.");
+            child_proc.free_proc();
+            return None
+        }
+        child_data.vm = pdata.vm.clone();
+
+        // 线程的 trapframe 映射在共享地址空间中属于自己的位置
+        let trapframe_va = thread_trapframe(index);
+        let trapframe_pa = child_data.get_trapframe() as usize;
+        if !child_data.vm().with_page_table(|pt| unsafe{ pt.map(
+            VirtualAddress::new(trapframe_va),
+            PhysicalAddress::new(trapframe_pa),
+            PGSIZE,
+            MapPerm::KernelRW
+        ) }) {
+            println!("[Kernel] clone: Fail to map trapframe.");
+            child_proc.free_proc();
+            return None
         }
+        child_data.trapframe_va = trapframe_va;
+
+        let child_tf = child_data.trapframe();
+        unsafe{ copy_nonoverlapping(pdata.get_trapframe(), child_tf, 1); }
+        // clone 后线程返回0，并在新的栈上运行
+        child_tf.a0 = 0;
+        child_tf.sp = stack;
+
+        child_data.open_files.clone_from(&pdata.open_files);
+        child_data.cwd.clone_from(&pdata.cwd);
+
+        child_data.name = pdata.name;
+
+        self.start_child(child_proc);
+        Some(child_proc)
+    }
+
+    /// Whether RLIMIT_NPROC allows one more process. 
+    fn may_add_proc(&self) -> bool {
+        let limit = unsafe{ (*self.data.get()).rlimits.cur(RLIMIT_NPROC) };
+        unsafe{ PROC_MANAGER.nr_procs() < limit }
+    }
+
+    /// Link a new child of fork or clone to this process, 
+    /// let it inherit scheduling and job control state, 
+    /// and make it runnable. 
+    fn start_child(&mut self, child_proc: &mut Process) {
+        let child_data = unsafe{ &mut *child_proc.data.get() };
+        // The child must be linked to its parent before it becomes
+        // runnable, otherwise it could exit without a parent to wake. 
+        // self is a slot of the process table, which lives forever. 
+        let parent = unsafe{ &*(self as *const Process) };
+        unsafe{ PROC_MANAGER.set_parent(child_proc, parent); }
+
+        // Signal actions and mask are inherited, pending signals are not. 
+        child_data.signals = unsafe{ (*self.data.get()).signals };
+        child_data.rlimits = unsafe{ (*self.data.get()).rlimits };
+
+        // 子进程继承父进程的优先级、彩票数、CPU 亲和性、进程组、会话和调度类
+        let pmeta = self.meta.acquire();
+        let (priority, tickets, affinity) = (pmeta.priority, pmeta.tickets, pmeta.affinity);
+        let (pgid, sid) = (pmeta.pgid, pmeta.sid);
+        let (class, rt_priority) = (pmeta.class, pmeta.rt_priority);
+        drop(pmeta);
+        let mut child_meta = child_proc.meta.acquire();
+        child_meta.priority = priority;
+        child_meta.tickets = tickets;
+        child_meta.affinity = affinity;
+        child_meta.pgid = pgid;
+        child_meta.sid = sid;
+        child_meta.class = class;
+        child_meta.rt_priority = rt_priority;
+        make_runnable(child_proc, &mut child_meta);
+        drop(child_meta);
     }
 }
 
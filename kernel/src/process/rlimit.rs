@@ -0,0 +1,60 @@
+// Per-process resource limits, set by setrlimit() and inherited
+// across fork. Only the process itself changes them, so they 
+// live in ProcData. 
+
+pub const RLIMIT_FSIZE: usize = 0; // largest file the process may write
+pub const RLIMIT_NPROC: usize = 1; // number of processes that may exist
+pub const RLIMIT_AS: usize = 2; // size of the user address space
+pub const RLIMIT_PAGES: usize = 3; // pages charged to the process and its children, see memcg.rs
+pub const RLIM_NLIMITS: usize = 4;
+
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RLimit {
+    pub cur: usize, // soft limit, the one enforced
+    pub max: usize, // hard limit, ceiling for cur
+}
+
+impl RLimit {
+    pub const fn new() -> Self {
+        Self {
+            cur: RLIM_INFINITY,
+            max: RLIM_INFINITY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RLimits {
+    limits: [RLimit; RLIM_NLIMITS],
+}
+
+impl RLimits {
+    pub const fn new() -> Self {
+        Self {
+            limits: [RLimit::new(); RLIM_NLIMITS],
+        }
+    }
+
+    pub fn get(&self, resource: usize) -> Option<RLimit> {
+        self.limits.get(resource).copied()
+    }
+
+    /// The soft limit of resource. 
+    pub fn cur(&self, resource: usize) -> usize {
+        self.limits[resource].cur
+    }
+
+    /// Without privileges, a hard limit can only be lowered
+    /// and the soft limit can't go above it. 
+    pub fn set(&mut self, resource: usize, limit: RLimit) -> Result<(), ()> {
+        let old = self.limits.get_mut(resource).ok_or(())?;
+        if limit.cur > limit.max || limit.max > old.max {
+            return Err(())
+        }
+        *old = limit;
+        Ok(())
+    }
+}
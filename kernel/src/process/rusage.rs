@@ -0,0 +1,22 @@
+// Resource usage of a process, returned to user space by getrusage(). 
+// Only the process itself updates it, from the timer interrupt and 
+// sched(), so it lives in ProcData and needs no lock. 
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rusage {
+    pub utime: usize, // ticks spent in user mode
+    pub stime: usize, // ticks spent in kernel mode
+    pub nvcsw: usize, // voluntary context switches, by sleeping
+    pub nivcsw: usize, // involuntary context switches, by preemption
+}
+
+impl Rusage {
+    pub const fn new() -> Self {
+        Self {
+            utime: 0,
+            stime: 0,
+            nvcsw: 0,
+            nivcsw: 0,
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use core::ptr::NonNull;
+
+use super::{ Process, ProcMeta, SchedPolicy, Fifo, RunQueue };
+
+// policy argument of sched_setscheduler()
+pub const SCHED_NORMAL: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+
+/// Scheduling class of a process, set with sched_setscheduler(). 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchedClass {
+    /// Scheduled by the policy chosen at build time.
+    Normal,
+    /// Real-time, always ahead of Normal, see Fifo.
+    Fifo,
+}
+
+impl SchedClass {
+    pub fn from_policy(policy: usize) -> Option<Self> {
+        match policy {
+            SCHED_NORMAL => Some(SchedClass::Normal),
+            SCHED_FIFO => Some(SchedClass::Fifo),
+            _ => None
+        }
+    }
+}
+
+/// The run queue of a cpu: real-time processes first, 
+/// the normal class only gets the CPU when none is waiting. 
+pub struct Classes {
+    fifo: Fifo,
+    normal: RunQueue,
+}
+
+impl Classes {
+    pub const fn new() -> Self {
+        Self {
+            fifo: Fifo::new(),
+            normal: RunQueue::new(),
+        }
+    }
+}
+
+impl SchedPolicy for Classes {
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta) {
+        match pmeta.class {
+            SchedClass::Fifo => self.fifo.enqueue(proc, pmeta),
+            SchedClass::Normal => self.normal.enqueue(proc, pmeta),
+        }
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        self.fifo.dequeue(proc) || self.normal.dequeue(proc)
+    }
+
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        self.fifo.pick_next().or_else(|| self.normal.pick_next())
+    }
+
+    /// A normal process is also preempted as soon as 
+    /// a real-time one is waiting.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        match pmeta.class {
+            SchedClass::Fifo => self.fifo.task_tick(pmeta),
+            SchedClass::Normal => self.normal.task_tick(pmeta) || self.fifo.len() > 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.fifo.len() + self.normal.len()
+    }
+
+    fn time_slice(pmeta: &ProcMeta) -> usize {
+        match pmeta.class {
+            SchedClass::Fifo => Fifo::time_slice(pmeta),
+            SchedClass::Normal => RunQueue::time_slice(pmeta),
+        }
+    }
+
+    fn task_wakeup(pmeta: &mut ProcMeta) {
+        if pmeta.class == SchedClass::Normal {
+            RunQueue::task_wakeup(pmeta);
+        }
+    }
+
+    fn boost_proc(pmeta: &mut ProcMeta) {
+        if pmeta.class == SchedClass::Normal {
+            RunQueue::boost_proc(pmeta);
+        }
+    }
+
+    fn boost(&mut self) {
+        self.normal.boost();
+    }
+}
@@ -0,0 +1,59 @@
+use core::ptr::NonNull;
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::NRTPRIO;
+use super::{ Process, ProcMeta, ProcQueue, SchedPolicy };
+
+/// Real-time first-in first-out, by the rt_priority set with 
+/// sched_setscheduler(). Level 0 is the highest priority. 
+/// A process runs until it blocks or gives up the CPU itself, 
+/// the timer only takes the CPU away for a higher level. 
+pub struct Fifo {
+    levels: [ProcQueue; NRTPRIO],
+}
+
+impl Fifo {
+    pub const fn new() -> Self {
+        Self {
+            levels: array![_ => ProcQueue::new(); NRTPRIO],
+        }
+    }
+}
+
+impl SchedPolicy for Fifo {
+    /// Put a process at the tail of the queue for its priority.
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta) {
+        let priority = pmeta.rt_priority as usize;
+        let level = if priority < NRTPRIO { priority } else { NRTPRIO - 1 };
+        self.levels[level].push_back(proc);
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        self.levels.iter_mut().any(|level| level.remove(proc))
+    }
+
+    /// Take the process at the head of the highest non-empty level.
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        self.levels
+            .iter_mut()
+            .find(|level| !level.is_empty())
+            .and_then(|level| level.pop_front())
+    }
+
+    /// No time slice, only preempted by a higher level.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        self.levels
+            .iter()
+            .take(pmeta.rt_priority as usize)
+            .any(|level| !level.is_empty())
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    fn time_slice(_pmeta: &ProcMeta) -> usize {
+        0
+    }
+}
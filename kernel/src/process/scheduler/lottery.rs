@@ -0,0 +1,91 @@
+use core::ptr::NonNull;
+
+use crate::arch::riscv::qemu::param::NPROC;
+use super::{ Process, ProcMeta, SchedPolicy };
+
+/// Lottery scheduling. Every RUNNABLE process holds some tickets, 
+/// and each pick draws one ticket at random, so a process gets 
+/// CPU in proportion to its tickets.
+pub struct Lottery {
+    procs: [Option<(NonNull<Process>, usize)>; NPROC],
+    len: usize,
+    total: usize,
+    seed: u64,
+}
+
+impl Lottery {
+    pub const fn new() -> Self {
+        Self {
+            procs: [None; NPROC],
+            len: 0,
+            total: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Take the entry at i out of the pool.
+    fn take(&mut self, i: usize) -> NonNull<Process> {
+        let (proc, tickets) = self.procs[i].take().unwrap();
+        self.len -= 1;
+        self.procs[i] = self.procs[self.len].take();
+        self.total -= tickets;
+        proc
+    }
+
+    /// xorshift64*
+    fn rand(&mut self) -> u64 {
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        self.seed.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl SchedPolicy for Lottery {
+    /// Put a process into the lottery with its tickets.
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta) {
+        if self.len == NPROC {
+            panic!("lottery: full");
+        }
+        self.procs[self.len] = Some((proc, pmeta.tickets));
+        self.len += 1;
+        self.total = self.total.checked_add(pmeta.tickets).expect("lottery: too many tickets");
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        match (0..self.len).find(|&i| self.procs[i].unwrap().0 == proc) {
+            Some(i) => {
+                self.take(i);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Draw a winning ticket and take its holder out of the pool.
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        if self.len == 0 {
+            return None
+        }
+        let mut winner = (self.rand() % self.total as u64) as usize;
+        let mut i = 0;
+        loop {
+            let (_, tickets) = self.procs[i].unwrap();
+            if winner < tickets || i == self.len - 1 {
+                break;
+            }
+            winner -= tickets;
+            i += 1;
+        }
+        Some(self.take(i))
+    }
+
+    /// Every time slice starts a new draw.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        use_slice(pmeta, TIME_SLICE)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
@@ -0,0 +1,99 @@
+use core::ptr::NonNull;
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::{ NPRIO, MLFQ_SLICE };
+use super::{ Process, ProcMeta, ProcQueue, SchedPolicy };
+
+/// Multi-level feedback queue. Like Priority, but priorities are adjusted:
+/// - a process that uses up the time slice of its level is demoted;
+/// - a process woken up from sleep is promoted by one level;
+/// - every MLFQ_BOOST_INTERVAL ticks all processes go back to level 0,
+///   so CPU-bound processes can not be starved.
+pub struct Mlfq {
+    levels: [ProcQueue; NPRIO],
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self {
+            levels: array![_ => ProcQueue::new(); NPRIO],
+        }
+    }
+
+    /// Whether some process with a higher priority than the given one is waiting.
+    fn has_higher(&self, priority: usize) -> bool {
+        self.levels
+            .iter()
+            .take(priority)
+            .any(|level| !level.is_empty())
+    }
+}
+
+impl SchedPolicy for Mlfq {
+    /// Put a process at the tail of the queue for its priority.
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta) {
+        let priority = pmeta.effective_priority();
+        let level = if priority < NPRIO { priority } else { NPRIO - 1 };
+        self.levels[level].push_back(proc);
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        self.levels.iter_mut().any(|level| level.remove(proc))
+    }
+
+    /// Take the process at the head of the highest non-empty level.
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        self.levels
+            .iter_mut()
+            .find(|level| !level.is_empty())
+            .and_then(|level| level.pop_front())
+    }
+
+    /// Demotes the process once it has used up the slice of its level,
+    /// and preempts it if a higher level has work.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        let level = pmeta.priority as usize;
+        pmeta.ticks += 1;
+        if pmeta.ticks >= MLFQ_SLICE[level] {
+            if level + 1 < NPRIO {
+                pmeta.priority += 1;
+            }
+            pmeta.ticks = 0;
+            return true
+        }
+        self.has_higher(pmeta.effective_priority())
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    fn time_slice(pmeta: &ProcMeta) -> usize {
+        MLFQ_SLICE[(pmeta.priority as usize).min(NPRIO - 1)]
+    }
+
+    /// A process gave up the CPU to wait for I/O, reward it
+    /// by raising its priority one level.
+    fn task_wakeup(pmeta: &mut ProcMeta) {
+        if pmeta.priority > 0 {
+            pmeta.priority -= 1;
+        }
+        pmeta.ticks = 0;
+    }
+
+    fn boost_proc(pmeta: &mut ProcMeta) {
+        pmeta.priority = 0;
+        pmeta.ticks = 0;
+    }
+
+    /// Move every waiting process to level 0, keeping the
+    /// order within each level.
+    fn boost(&mut self) {
+        for i in 1..NPRIO {
+            while let Some(proc) = self.levels[i].pop_front() {
+                self.levels[0].push_back(proc);
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Run queues used by the scheduler to pick the next process.
+//!
+//! Each CPU owns a run queue. A process woken up or newly created is put
+//! on the least loaded CPU, while a process that yields stays on its own
+//! CPU. A CPU whose queue runs dry steals from the busiest other CPU.
+//! Placement respects the affinity mask of the process, and a CPU that
+//! picks up a process it may not run hands it on to an allowed CPU.
+//!
+//! How a run queue orders its processes is up to a SchedPolicy,
+//! chosen at build time by cargo feature:
+//! - `sched-rr`: plain round-robin;
+//! - `sched-priority`: strict priority, round-robin within a level;
+//! - `sched-lottery`: CPU share proportional to lottery tickets;
+//! - none of the above: multi-level feedback queue.
+//!
+//! Processes of the real-time SCHED_FIFO class are kept apart
+//! and always run ahead of the ones under that policy.
+
+use core::ptr::NonNull;
+
+use crate::arch::riscv::qemu::param::{ BONUS_SLEEP_TICKS, MAX_BONUS, TIME_SLICE };
+use crate::trap::ticks;
+use super::{ Process, ProcMeta, ProcState, CPU_MANAGER, cpuid };
+
+mod queue;
+mod rr;
+mod priority;
+mod mlfq;
+mod lottery;
+mod fifo;
+mod class;
+pub use queue::ProcQueue;
+pub use rr::RoundRobin;
+pub use priority::Priority;
+pub use mlfq::Mlfq;
+pub use lottery::Lottery;
+pub use fifo::Fifo;
+pub use class::{ SchedClass, Classes, SCHED_NORMAL, SCHED_FIFO };
+
+#[cfg(any(
+    all(feature = "sched-rr", feature = "sched-priority"),
+    all(feature = "sched-rr", feature = "sched-lottery"),
+    all(feature = "sched-priority", feature = "sched-lottery"),
+))]
+compile_error!("only one of the sched-* features can be enabled");
+
+/// The policy of the normal class in every cpu's run queue. 
+#[cfg(feature = "sched-rr")]
+pub type RunQueue = RoundRobin;
+#[cfg(feature = "sched-priority")]
+pub type RunQueue = Priority;
+#[cfg(feature = "sched-lottery")]
+pub type RunQueue = Lottery;
+#[cfg(not(any(feature = "sched-rr", feature = "sched-priority", feature = "sched-lottery")))]
+pub type RunQueue = Mlfq;
+
+/// A scheduling policy, holding the RUNNABLE processes of one cpu.
+/// Lock order: p->lock may be held while acquiring a run queue lock,
+/// never the other way around, and at most one run queue lock is held.
+/// Methods taking pmeta are called with that process's p->lock held.
+pub trait SchedPolicy {
+    /// Add a RUNNABLE process.
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta);
+
+    /// Take a waiting process out of the queue, 
+    /// false if it is not on this queue.
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool;
+
+    /// Take the process that should run next.
+    fn pick_next(&mut self) -> Option<NonNull<Process>>;
+
+    /// Charge one timer tick to the running process.
+    /// Returns true if it should give up the CPU.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool;
+
+    /// Number of processes waiting.
+    fn len(&self) -> usize;
+
+    /// Length in ticks of the time slice the process gets, 
+    /// 0 if it is never preempted for using it up.
+    fn time_slice(_pmeta: &ProcMeta) -> usize {
+        TIME_SLICE
+    }
+
+    /// A sleeping process is being woken up.
+    fn task_wakeup(_pmeta: &mut ProcMeta) {}
+
+    /// Periodic boost, called for every process and then 
+    /// for every run queue each MLFQ_BOOST_INTERVAL ticks.
+    fn boost_proc(_pmeta: &mut ProcMeta) {}
+    fn boost(&mut self) {}
+}
+
+/// The queues only hold pointers into the process table,
+/// which lives for the whole run of the kernel.
+unsafe impl Send for RoundRobin {}
+unsafe impl Send for Priority {}
+unsafe impl Send for Mlfq {}
+unsafe impl Send for Lottery {}
+unsafe impl Send for Fifo {}
+unsafe impl Send for Classes {}
+
+/// Charge one tick of a time slice of slice ticks. 
+/// Returns true, and starts a new slice, once it is used up.
+fn use_slice(pmeta: &mut ProcMeta, slice: usize) -> bool {
+    pmeta.ticks += 1;
+    if pmeta.ticks >= slice {
+        pmeta.ticks = 0;
+        return true
+    }
+    false
+}
+
+/// Mark a process RUNNABLE and put it on the run queue
+/// of the least loaded CPU it may run on.
+/// Caller must hold p->lock, passed in as pmeta.
+pub fn make_runnable(proc: &Process, pmeta: &mut ProcMeta) {
+    pmeta.set_state(ProcState::RUNNABLE);
+    let cpu = CPU_MANAGER.least_loaded(pmeta.affinity);
+    cpu.run_queue.acquire().enqueue(NonNull::from(proc), pmeta);
+}
+
+/// Mark a process RUNNABLE and put it back on the run queue
+/// of the current CPU, used when it gives up the CPU by itself.
+/// Falls back to make_runnable if the current CPU is not allowed.
+/// Caller must hold p->lock, passed in as pmeta.
+pub fn make_runnable_local(proc: &Process, pmeta: &mut ProcMeta) {
+    if !pmeta.can_run_on(unsafe{ cpuid() }) {
+        return make_runnable(proc, pmeta)
+    }
+    pmeta.set_state(ProcState::RUNNABLE);
+    CPU_MANAGER.with(|c| c.run_queue.acquire().enqueue(NonNull::from(proc), pmeta));
+}
+
+/// Charge one timer tick to the process running on this cpu.
+/// Returns true if the process should give up the CPU.
+/// Caller must hold p->lock, passed in as pmeta.
+pub fn task_tick(pmeta: &mut ProcMeta) -> bool {
+    // Running wears the interactive bonus off, so a process 
+    // that sleeps only briefly between long bursts can't keep it. 
+    pmeta.bonus = pmeta.bonus.saturating_sub(1);
+    CPU_MANAGER.with(|c| c.run_queue.acquire().task_tick(pmeta))
+}
+
+/// A sleeping process is being woken up. 
+/// One that slept long is likely waiting on I/O, like an 
+/// interactive shell, give it a bonus over CPU-bound processes 
+/// that grows with the time it slept. 
+/// Caller must hold p->lock, passed in as pmeta.
+pub fn task_wakeup(pmeta: &mut ProcMeta) {
+    let slept = ticks().wrapping_sub(pmeta.sleep_start);
+    let bonus = (slept / BONUS_SLEEP_TICKS).min(MAX_BONUS as usize) as u8;
+    pmeta.bonus = pmeta.bonus.max(bonus);
+    Classes::task_wakeup(pmeta);
+}
+
+/// Requeue a RUNNABLE process after its scheduling 
+/// parameters changed, so they take effect right away.
+/// Caller must hold p->lock, passed in as pmeta.
+pub fn requeue(proc: &Process, pmeta: &ProcMeta) {
+    if pmeta.state != ProcState::RUNNABLE {
+        return
+    }
+    let proc = NonNull::from(proc);
+    for cpu in CPU_MANAGER.cpus() {
+        let mut run_queue = cpu.run_queue.acquire();
+        if run_queue.dequeue(proc) {
+            run_queue.enqueue(proc, pmeta);
+            return
+        }
+    }
+}
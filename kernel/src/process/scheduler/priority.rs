@@ -0,0 +1,61 @@
+use core::ptr::NonNull;
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::{ NPRIO, TIME_SLICE };
+use super::{ Process, ProcMeta, ProcQueue, SchedPolicy, use_slice };
+
+/// Strict priority by the priority set with setpriority(). 
+/// Level 0 is the highest priority. Processes that give up the CPU
+/// are put back at the tail of their level, which gives round-robin
+/// among processes of equal priority.
+pub struct Priority {
+    levels: [ProcQueue; NPRIO],
+}
+
+impl Priority {
+    pub const fn new() -> Self {
+        Self {
+            levels: array![_ => ProcQueue::new(); NPRIO],
+        }
+    }
+
+    /// Whether some process with a higher priority than the given one is waiting.
+    fn has_higher(&self, priority: usize) -> bool {
+        self.levels
+            .iter()
+            .take(priority)
+            .any(|level| !level.is_empty())
+    }
+}
+
+impl SchedPolicy for Priority {
+    /// Put a process at the tail of the queue for its priority.
+    fn enqueue(&mut self, proc: NonNull<Process>, pmeta: &ProcMeta) {
+        let priority = pmeta.effective_priority();
+        let level = if priority < NPRIO { priority } else { NPRIO - 1 };
+        self.levels[level].push_back(proc);
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        self.levels.iter_mut().any(|level| level.remove(proc))
+    }
+
+    /// Take the process at the head of the highest non-empty level.
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        self.levels
+            .iter_mut()
+            .find(|level| !level.is_empty())
+            .and_then(|level| level.pop_front())
+    }
+
+    /// Preempts at the end of the slice, or at once 
+    /// if a higher level has work.
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        use_slice(pmeta, TIME_SLICE) || self.has_higher(pmeta.effective_priority())
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+}
@@ -0,0 +1,64 @@
+use core::ptr::NonNull;
+
+use crate::arch::riscv::qemu::param::NPROC;
+use super::Process;
+
+/// A FIFO of processes, large enough to hold every process.
+pub struct ProcQueue {
+    procs: [Option<NonNull<Process>>; NPROC],
+    head: usize,
+    len: usize,
+}
+
+impl ProcQueue {
+    pub const fn new() -> Self {
+        Self {
+            procs: [None; NPROC],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_back(&mut self, proc: NonNull<Process>) {
+        if self.len == NPROC {
+            panic!("proc queue: full");
+        }
+        let tail = (self.head + self.len) % NPROC;
+        self.procs[tail] = Some(proc);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<NonNull<Process>> {
+        if self.len == 0 {
+            return None
+        }
+        let proc = self.procs[self.head].take();
+        self.head = (self.head + 1) % NPROC;
+        self.len -= 1;
+        proc
+    }
+
+    /// Take proc out of the queue, keeping the order of the others.
+    pub fn remove(&mut self, proc: NonNull<Process>) -> bool {
+        let pos = match (0..self.len).find(|i| {
+            self.procs[(self.head + i) % NPROC] == Some(proc)
+        }) {
+            Some(pos) => pos,
+            None => return false
+        };
+        for i in pos..self.len - 1 {
+            self.procs[(self.head + i) % NPROC] = self.procs[(self.head + i + 1) % NPROC];
+        }
+        self.procs[(self.head + self.len - 1) % NPROC] = None;
+        self.len -= 1;
+        true
+    }
+}
@@ -0,0 +1,39 @@
+use core::ptr::NonNull;
+
+use crate::arch::riscv::qemu::param::TIME_SLICE;
+use super::{ Process, ProcMeta, ProcQueue, SchedPolicy, use_slice };
+
+/// Round-robin, every process gets TIME_SLICE ticks in turn.
+pub struct RoundRobin {
+    queue: ProcQueue,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self {
+            queue: ProcQueue::new(),
+        }
+    }
+}
+
+impl SchedPolicy for RoundRobin {
+    fn enqueue(&mut self, proc: NonNull<Process>, _pmeta: &ProcMeta) {
+        self.queue.push_back(proc);
+    }
+
+    fn dequeue(&mut self, proc: NonNull<Process>) -> bool {
+        self.queue.remove(proc)
+    }
+
+    fn pick_next(&mut self) -> Option<NonNull<Process>> {
+        self.queue.pop_front()
+    }
+
+    fn task_tick(&self, pmeta: &mut ProcMeta) -> bool {
+        use_slice(pmeta, TIME_SLICE)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
@@ -0,0 +1,156 @@
+//! System V style shared memory. 
+//! A segment is a set of physical pages named by a key. shmat() 
+//! maps all of them into the caller's address space at once, 
+//! taking a reference to each page, so the segment's pages are 
+//! the same in every process that attached it. The segment keeps 
+//! its own reference and is freed once it has been removed with 
+//! shmctl(IPC_RMID) and the last process has detached. 
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::arch::riscv::qemu::param::{ NSHM, SHM_MAXPAGES };
+use crate::lock::spinlock::Spinlock;
+use crate::memory::{ PageTable, MapPerm, VirtualAddress, PhysicalAddress, PAGE_REF, kalloc_pages, kfree_pages, page_round_up };
+
+pub const IPC_PRIVATE: usize = 0; // key of a segment no shmget() finds again
+pub const IPC_CREAT: usize = 0o1000; // create the segment if the key has none
+pub const IPC_EXCL: usize = 0o2000; // with IPC_CREAT, fail if it exists
+pub const IPC_RMID: usize = 0; // shmctl(): remove the segment
+
+pub const SHM_RDONLY: usize = 0o10000; // shmat(): attach read-only
+
+struct ShmSeg {
+    used: bool,
+    key: usize,
+    npages: usize,
+    pages: [usize; SHM_MAXPAGES],
+    nattach: usize, // address spaces it is mapped into
+    removed: bool, // freed at the last detach
+}
+
+impl ShmSeg {
+    const fn new() -> Self {
+        Self {
+            used: false,
+            key: IPC_PRIVATE,
+            npages: 0,
+            pages: [0; SHM_MAXPAGES],
+            nattach: 0,
+            removed: false,
+        }
+    }
+
+    /// Drop the segment's references to its pages. 
+    fn free(&mut self) {
+        for &pa in self.pages[..self.npages].iter() {
+            unsafe{ kfree_pages(pa, 0); }
+        }
+        *self = Self::new();
+    }
+}
+
+static SHM_TABLE: Spinlock<[ShmSeg; NSHM]> = Spinlock::new(array![_ => ShmSeg::new(); NSHM], "shm");
+
+/// shmget(): the id of the segment with key, or of a new zeroed 
+/// segment of size bytes if IPC_CREAT is in flags and the key 
+/// has none. IPC_PRIVATE always makes a new segment. 
+pub fn shm_get(key: usize, size: usize, flags: usize) -> Result<usize, &'static str> {
+    let mut table = SHM_TABLE.acquire();
+    if key != IPC_PRIVATE {
+        if let Some(id) = table.iter().position(|seg| seg.used && !seg.removed && seg.key == key) {
+            if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 {
+                return Err("shmget: segment exists")
+            }
+            if size > table[id].npages * PGSIZE {
+                return Err("shmget: segment too small")
+            }
+            return Ok(id)
+        }
+        if flags & IPC_CREAT == 0 {
+            return Err("shmget: no such segment")
+        }
+    }
+    let npages = page_round_up(size) / PGSIZE;
+    if npages == 0 || npages > SHM_MAXPAGES {
+        return Err("shmget: bad size")
+    }
+    let id = table.iter().position(|seg| !seg.used).ok_or("shmget: too many segments")?;
+    let seg = &mut table[id];
+    seg.used = true;
+    seg.key = key;
+    for i in 0..npages {
+        match kalloc_pages(0) {
+            Some(pa) => {
+                seg.pages[i] = pa;
+                seg.npages += 1;
+            },
+            None => {
+                seg.free();
+                return Err("shmget: out of memory")
+            }
+        }
+    }
+    Ok(id)
+}
+
+/// shmat(): attach segment id, which must not be removed. 
+/// attach is given the segment's pages, maps them with 
+/// shm_map_pages() where there is room and returns the start. 
+/// The segment can't change meanwhile, the table stays locked. 
+pub fn shm_attach(
+    id: usize, 
+    attach: impl FnOnce(&[usize]) -> Result<usize, &'static str>
+) -> Result<usize, &'static str> {
+    let mut table = SHM_TABLE.acquire();
+    let seg = table.get_mut(id).filter(|seg| seg.used && !seg.removed).ok_or("shm: no such segment")?;
+    let start = attach(&seg.pages[..seg.npages])?;
+    seg.nattach += 1;
+    Ok(start)
+}
+
+/// fork(): map the pages of segment id at start in the child's 
+/// page_table and count the attach, even if it was removed. 
+pub fn shm_map(id: usize, page_table: &mut PageTable, start: usize, perm: MapPerm) -> Result<(), &'static str> {
+    let mut table = SHM_TABLE.acquire();
+    let seg = table.get_mut(id).filter(|seg| seg.used).ok_or("shm: no such segment")?;
+    shm_map_pages(&seg.pages[..seg.npages], page_table, start, perm)?;
+    seg.nattach += 1;
+    Ok(())
+}
+
+/// Map pages at start in page_table, taking a reference to each. 
+/// Nothing is left mapped if it fails. 
+pub fn shm_map_pages(pages: &[usize], page_table: &mut PageTable, start: usize, perm: MapPerm) -> Result<(), &'static str> {
+    for (i, &pa) in pages.iter().enumerate() {
+        let va = VirtualAddress::new(start + i * PGSIZE);
+        if !unsafe{ page_table.map(va, PhysicalAddress::new(pa), PGSIZE, perm) } {
+            unsafe{ page_table.uvm_unmap(VirtualAddress::new(start), i, true); }
+            return Err("shmat: out of memory")
+        }
+        PAGE_REF.inc(pa);
+    }
+    Ok(())
+}
+
+/// An address space unmapped segment id. 
+pub fn shm_detach(id: usize) {
+    let mut table = SHM_TABLE.acquire();
+    let seg = &mut table[id];
+    seg.nattach -= 1;
+    if seg.removed && seg.nattach == 0 {
+        seg.free();
+    }
+}
+
+/// shmctl(IPC_RMID): no shmget() finds segment id any more, 
+/// and it is freed when the last process detaches. 
+pub fn shm_remove(id: usize) -> Result<(), &'static str> {
+    let mut table = SHM_TABLE.acquire();
+    let seg = table.get_mut(id).filter(|seg| seg.used && !seg.removed).ok_or("shmctl: no such segment")?;
+    seg.removed = true;
+    if seg.nattach == 0 {
+        seg.free();
+    }
+    Ok(())
+}
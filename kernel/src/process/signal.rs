@@ -0,0 +1,228 @@
+use core::mem::size_of;
+
+use super::*;
+
+// UNIX-style signals. Pending signals are in ProcMeta, since other 
+// processes post them under p->lock; the blocked mask and the actions
+// are only touched by the process itself and live in ProcData. 
+
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGABRT: usize = 6;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// how argument of sigprocmask()
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Signals whose action and mask can't be changed. 
+const UNCATCHABLE: u32 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+/// Signals that stop a process by default, 
+/// cancelled by SIGCONT and the other way around. 
+pub const STOP_SIGNALS: u32 = (1 << SIGSTOP) | (1 << SIGTSTP) | (1 << SIGTTIN) | (1 << SIGTTOU);
+
+#[inline]
+pub const fn sig_bit(sig: usize) -> u32 {
+    1 << sig
+}
+
+/// What a signal does by default. 
+#[derive(Debug, PartialEq)]
+pub enum SigDefault {
+    Terminate,
+    Ignore,
+    Stop,
+}
+
+/// SIGCONT is ignored when delivered, continuing 
+/// a stopped process is done as soon as it is sent. 
+pub fn default_action(sig: usize) -> SigDefault {
+    match sig {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH => SigDefault::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => SigDefault::Stop,
+        _ => SigDefault::Terminate
+    }
+}
+
+/// Disposition of a signal, as passed to sigaction(). 
+/// The handler returns through restorer, 
+/// which is expected to call rt_sigreturn(). 
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigAction {
+    pub handler: usize, // SIG_DFL, SIG_IGN or a user address
+    pub mask: usize, // signals blocked while the handler runs
+    pub restorer: usize, // return address of the handler
+}
+
+impl SigAction {
+    pub const fn new() -> Self {
+        Self {
+            handler: SIG_DFL,
+            mask: 0,
+            restorer: 0,
+        }
+    }
+}
+
+/// Saved on the user stack while a handler runs. 
+#[repr(C)]
+struct SigFrame {
+    tf: Trapframe,
+    blocked: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct SigState {
+    pub blocked: u32,
+    pub actions: [SigAction; NSIG],
+}
+
+impl SigState {
+    pub const fn new() -> Self {
+        Self {
+            blocked: 0,
+            actions: [SigAction::new(); NSIG],
+        }
+    }
+
+    /// exec() keeps ignored signals ignored and 
+    /// resets caught ones to the default. 
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::new();
+            }
+        }
+    }
+
+    /// Install act for sig and return the previous action. 
+    pub fn set_action(&mut self, sig: usize, act: SigAction) -> Result<SigAction, ()> {
+        if sig == 0 || sig >= NSIG || sig_bit(sig) & UNCATCHABLE != 0 {
+            return Err(())
+        }
+        let old = self.actions[sig];
+        self.actions[sig] = act;
+        Ok(old)
+    }
+
+    /// Change the blocked mask as sigprocmask() does, returning the old one. 
+    pub fn set_mask(&mut self, how: usize, set: u32) -> Result<u32, ()> {
+        let old = self.blocked;
+        self.blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(())
+        } & !UNCATCHABLE;
+        Ok(old)
+    }
+}
+
+/// Deliver the pending, unblocked signals of the current process
+/// on its way back to user space. 
+/// A default action may not return, a caught signal
+/// makes the process resume in its handler. 
+pub unsafe fn handle_signals(p: &mut Process) {
+    let pdata = &mut *p.data.get();
+    loop {
+        let mut guard = p.meta.acquire();
+        let deliverable = guard.pending & !pdata.signals.blocked;
+        if deliverable == 0 {
+            drop(guard);
+            return
+        }
+        let sig = deliverable.trailing_zeros() as usize;
+        guard.pending &= !sig_bit(sig);
+        drop(guard);
+
+        let action = pdata.signals.actions[sig];
+        match action.handler {
+            SIG_IGN => {},
+            SIG_DFL => {
+                match default_action(sig) {
+                    SigDefault::Terminate => exit(-1),
+                    SigDefault::Stop => p.stop(),
+                    SigDefault::Ignore => {}
+                }
+            },
+            handler => {
+                if push_frame(pdata, sig, handler, &action).is_err() {
+                    exit(-1);
+                }
+                return
+            }
+        }
+    }
+}
+
+/// Save the interrupted context on the user stack and 
+/// enter the handler with a0 = sig. 
+fn push_frame(
+    pdata: &mut ProcData, 
+    sig: usize, 
+    handler: usize, 
+    action: &SigAction
+) -> Result<(), ()> {
+    let tf = pdata.trapframe();
+    let frame = SigFrame { tf: *tf, blocked: pdata.signals.blocked };
+    let sp = tf.sp.checked_sub(size_of::<SigFrame>()).ok_or(())? & !0xf;
+    // Don't let a bad stack pointer make copy_out walk unmapped memory. 
+    if !pdata.vm().range_in_bounds(sp, size_of::<SigFrame>()) {
+        return Err(())
+    }
+    pdata.vm().copy_out(
+        sp, 
+        &frame as *const SigFrame as *const u8, 
+        size_of::<SigFrame>()
+    ).map_err(|_| ())?;
+
+    tf.sp = sp;
+    tf.epc = handler;
+    tf.a0 = sig;
+    tf.ra = action.restorer;
+    pdata.signals.blocked |= (action.mask as u32 | sig_bit(sig)) & !UNCATCHABLE;
+    Ok(())
+}
+
+/// Undo push_frame() once the handler returns, the frame is at 
+/// the current user stack pointer. Returns the restored a0. 
+pub fn sigreturn(pdata: &mut ProcData) -> Result<usize, ()> {
+    let tf = pdata.trapframe();
+    let sp = tf.sp;
+    if !pdata.vm().range_in_bounds(sp, size_of::<SigFrame>()) {
+        return Err(())
+    }
+    let mut frame = SigFrame { tf: *tf, blocked: 0 };
+    pdata.vm().copy_in(
+        &mut frame as *mut SigFrame as *mut u8, 
+        sp, 
+        size_of::<SigFrame>()
+    ).map_err(|_| ())?;
+    *tf = frame.tf;
+    pdata.signals.blocked = frame.blocked & !UNCATCHABLE;
+    Ok(tf.a0)
+}
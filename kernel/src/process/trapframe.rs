@@ -14,6 +14,7 @@ use crate::arch::riscv::register::satp;
 // return-to-user path via usertrapret() doesn't return through
 // the entire kernel call stack.
 
+#[derive(Clone, Copy)]
 pub struct Trapframe {
     /*0 */      pub kernel_satp:usize, // kernel page table
     /*8 */      pub kernel_sp:usize, // top of process's kernel stack
@@ -0,0 +1,186 @@
+// The regions of a user address space: the program segments 
+// exec() leaves to be paged in from the executable, the stack, 
+// the heap sbrk() moves the end of, and the mappings made by 
+// mmap() and shmat(). User memory is exactly these regions. 
+// A region only records where it is and what backs it, 
+// its pages are filled in on the first page fault and 
+// the dirty ones of a shared file mapping are written 
+// back to the file by msync(), munmap() or exit. 
+
+use crate::lock::arc::Arc;
+
+use crate::arch::riscv::qemu::fs::{ BSIZE, MAXOPBLOCKS };
+use crate::arch::riscv::qemu::layout::PGSIZE;
+use crate::fs::{ VFile, LOG };
+use crate::memory::MapPerm;
+
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+/// W^X: no user page may be both writable and executable, 
+/// unless the kernel is built with the allow-wx feature. 
+pub fn check_wx(prot: usize) -> Result<(), &'static str> {
+    if cfg!(not(feature = "allow-wx")) && prot & PROT_WRITE != 0 && prot & PROT_EXEC != 0 {
+        return Err("writable and executable mapping")
+    }
+    Ok(())
+}
+
+pub const MAP_SHARED: usize = 0x01; // writes go back to the file
+pub const MAP_PRIVATE: usize = 0x02; // writes stay in this process
+pub const MAP_ANONYMOUS: usize = 0x20; // zero-filled, no file
+
+/// What made a region. 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VmaKind {
+    Segment, // an ELF segment of the image, from exec()
+    Stack, // the user stack, the page below is its guard
+    Heap, // grows and shrinks with sbrk()
+    Mmap, // from mmap()
+    Shm(usize), // the shared memory segment attached here
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize, // page aligned
+    pub len: usize, // multiple of PGSIZE
+    pub prot: usize,
+    pub flags: usize,
+    pub file: Option<Arc<VFile>>, // None for an anonymous mapping
+    pub offset: usize, // file offset mapped at start
+    pub file_len: usize, // bytes from start backed by the file, zero after
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    /// A private zero-filled region. 
+    pub fn anonymous(start: usize, len: usize, prot: usize, kind: VmaKind) -> Self {
+        Self {
+            start,
+            len,
+            prot,
+            flags: MAP_PRIVATE | MAP_ANONYMOUS,
+            file: None,
+            offset: 0,
+            file_len: 0,
+            kind,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, va: usize) -> bool {
+        va >= self.start && va < self.end()
+    }
+
+    /// Permissions of the pages of this mapping. 
+    pub fn map_perm(&self) -> MapPerm {
+        let (read, write, exec) = (
+            self.prot & PROT_READ != 0, 
+            self.prot & PROT_WRITE != 0, 
+            self.prot & PROT_EXEC != 0
+        );
+        match (read, write, exec) {
+            (false, false, false) => MapPerm::UserNone,
+            (true, false, false) => MapPerm::UserR,
+            (false, false, true) => MapPerm::UserX,
+            (true, false, true) => MapPerm::UserRX,
+            // Writable without readable is reserved in Sv39. 
+            (_, true, false) => MapPerm::UserRW,
+            #[cfg(feature = "allow-wx")]
+            (_, true, true) => MapPerm::UserRWX,
+            // check_wx() refuses these. 
+            #[cfg(not(feature = "allow-wx"))]
+            (_, true, true) => panic!("vma: writable and executable"),
+        }
+    }
+
+    /// Cut the mapping at page-aligned at inside it, 
+    /// keeping the part below and returning the rest. 
+    pub fn split_off(&mut self, at: usize) -> Vma {
+        let rel = at - self.start;
+        let rest = Vma {
+            start: at,
+            len: self.len - rel,
+            // Out of u32 range anyway if it saturates, 
+            // fill_page() and write_back() refuse it. 
+            offset: self.offset.saturating_add(rel),
+            file_len: self.file_len.saturating_sub(rel),
+            ..self.clone()
+        };
+        self.len = rel;
+        self.file_len = self.file_len.min(rel);
+        rest
+    }
+
+    /// The shared memory segment attached here, if any. 
+    pub fn shm(&self) -> Option<usize> {
+        match self.kind {
+            VmaKind::Shm(id) => Some(id),
+            _ => None
+        }
+    }
+
+    /// Whether the page's changes must reach the file. 
+    pub fn is_shared_file(&self) -> bool {
+        self.flags & MAP_SHARED != 0 && self.file.is_some()
+    }
+
+    /// Fill the zeroed kernel page at pa with the contents 
+    /// belonging at user page va, the part past file_len or 
+    /// the end of the file stays zero. 
+    pub fn fill_page(&self, va: usize, pa: usize) -> Result<(), &'static str> {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return Ok(())
+        };
+        let inode = file.inode.as_ref().ok_or("mmap: not an inode")?;
+        let rel = va - self.start;
+        if rel >= self.file_len {
+            return Ok(())
+        }
+        let offset = self.offset.checked_add(rel).ok_or("mmap: file offset overflow")?;
+        let mut inode_guard = inode.lock();
+        let size = inode_guard.dinode.size as usize;
+        if offset < size {
+            // size is a u32, so offset and count are too. 
+            let count = (size - offset).min(self.file_len - rel).min(PGSIZE);
+            inode_guard.read(false, pa, offset as u32, count as u32)?;
+        }
+        drop(inode_guard);
+        Ok(())
+    }
+
+    /// Write the page at user page va, whose contents are at pa, 
+    /// back to the file. The file does not grow. 
+    pub fn write_back(&self, va: usize, pa: usize) -> Result<(), &'static str> {
+        let file = self.file.as_ref().ok_or("mmap: no file to write back to")?;
+        let inode = file.inode.as_ref().ok_or("mmap: not an inode")?;
+        // A few blocks at a time like VFile::write, 
+        // to stay inside one log transaction. 
+        let max = ((MAXOPBLOCKS -1 -1 -2) / 2) * BSIZE;
+        let offset = self.offset.checked_add(va - self.start).ok_or("mmap: file offset overflow")?;
+        let mut done = 0;
+        loop {
+            LOG.begin_op();
+            let mut inode_guard = inode.lock();
+            let size = inode_guard.dinode.size as usize;
+            let count = size.saturating_sub(offset + done).min(PGSIZE - done).min(max);
+            // Only bytes below size, a u32, are written. 
+            let res = match count {
+                0 => Ok(0),
+                _ => inode_guard.write(false, pa + done, (offset + done) as u32, count as u32)
+            };
+            drop(inode_guard);
+            LOG.end_op();
+            res?;
+            if count == 0 {
+                return Ok(())
+            }
+            done += count;
+        }
+    }
+}
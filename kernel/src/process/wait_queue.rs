@@ -0,0 +1,126 @@
+use core::ptr::NonNull;
+
+use array_macro::array;
+
+use crate::arch::riscv::qemu::param::NWAITQ;
+use crate::lock::spinlock::Spinlock;
+use super::Process;
+
+// Sleeping processes are kept on wait queues, hashed by channel, 
+// so that wake_up() only looks at processes that may sleep on it 
+// instead of every slot of the process table. 
+//
+// Lock order: a wait queue lock is acquired before p->lock, 
+// so sleep() joins the queue before taking p->lock and leaves
+// it after releasing p->lock. 
+
+/// Intrusive link of a process on a wait queue, 
+/// guarded by the lock of the queue it is on. 
+pub struct WaitLink {
+    next: Option<NonNull<Process>>,
+    channel: usize,
+    linked: bool,
+}
+
+impl WaitLink {
+    pub const fn new() -> Self {
+        Self {
+            next: None,
+            channel: 0,
+            linked: false,
+        }
+    }
+}
+
+/// Singly linked list of the processes sleeping on 
+/// the channels that hash to one bucket. 
+pub struct WaitQueue {
+    head: Option<NonNull<Process>>,
+}
+
+/// The list only points into the process table, 
+/// which lives for the whole run of the kernel.
+unsafe impl Send for WaitQueue {}
+
+static WAIT_QUEUES: [Spinlock<WaitQueue>; NWAITQ] = 
+    array![_ => Spinlock::new(WaitQueue::new(), "wait_queue"); NWAITQ];
+
+/// The wait queue holding the sleepers on channel. 
+pub fn wait_queue(channel: usize) -> &'static Spinlock<WaitQueue> {
+    // channels are addresses, drop the low bits that are 
+    // the same for every aligned object. 
+    &WAIT_QUEUES[(channel >> 3) % NWAITQ]
+}
+
+#[inline]
+fn link(proc: NonNull<Process>) -> &'static mut WaitLink {
+    unsafe{ &mut *proc.as_ref().wait_link.get() }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+        }
+    }
+
+    /// Add proc as a sleeper on channel. 
+    pub fn push(&mut self, proc: NonNull<Process>, channel: usize) {
+        let l = link(proc);
+        l.next = self.head;
+        l.channel = channel;
+        l.linked = true;
+        self.head = Some(proc);
+    }
+
+    /// Take proc off the queue, if it is still on it. 
+    pub fn remove(&mut self, proc: NonNull<Process>) {
+        if !link(proc).linked {
+            return
+        }
+        let mut cur = &mut self.head;
+        while let Some(p) = *cur {
+            if p == proc {
+                *cur = link(p).next;
+                let l = link(p);
+                l.next = None;
+                l.linked = false;
+                return
+            }
+            cur = &mut link(p).next;
+        }
+    }
+
+    /// Take the process that has slept on channel the longest 
+    /// off the queue, the last one on it as sleepers are pushed first. 
+    pub fn take_oldest(&mut self, channel: usize) -> Option<NonNull<Process>> {
+        let mut oldest = None;
+        let mut cur = self.head;
+        while let Some(p) = cur {
+            let l = link(p);
+            if l.channel == channel {
+                oldest = Some(p);
+            }
+            cur = l.next;
+        }
+        self.remove(oldest?);
+        oldest
+    }
+
+    /// Take every process sleeping on channel off the queue 
+    /// and call f on each of them. 
+    pub fn drain(&mut self, channel: usize, mut f: impl FnMut(NonNull<Process>)) {
+        let mut cur = &mut self.head;
+        while let Some(p) = *cur {
+            let l = link(p);
+            if l.channel == channel {
+                *cur = l.next;
+                l.next = None;
+                l.linked = false;
+                f(p);
+            } else {
+                cur = &mut l.next;
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Kernel entropy pool. 
+//!
+//! There is no hardware random source on the virt machine, so the 
+//! pool mixes in the cycle counter at unpredictable moments: device 
+//! interrupts feed it through add_entropy(), and every rand() also 
+//! mixes in the time it is called. Good enough to place address 
+//! spaces at random, not for cryptography. 
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::arch::riscv::time;
+
+static POOL: AtomicU64 = AtomicU64::new(0x9e37_79b9_7f4a_7c15);
+
+/// Stir sample, e.g. an irq number, into the pool 
+/// together with the time it arrived. 
+pub fn add_entropy(sample: usize) {
+    let now = unsafe{ time::read() } as u64;
+    let _ = POOL.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pool| {
+        Some((pool.rotate_left(17) ^ sample as u64 ^ now).wrapping_mul(0x2545_f491_4f6c_dd1d))
+    });
+}
+
+/// A random number from the pool. 
+pub fn rand() -> u64 {
+    add_entropy(0);
+    let mut x = POOL.load(Ordering::Relaxed);
+    // xorshift64*
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    POOL.store(x, Ordering::Relaxed);
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
@@ -1,4 +1,9 @@
 use crate::syscall::kernel_env_call;
+use crate::arch::riscv::qemu::layout::{ VIRT_TEST, PGSIZE };
+use crate::memory::mmio::Mmio;
+
+/// qemu's test device, which powers off or resets the machine. 
+pub const VIRT_TEST_REGS: Mmio<u32> = unsafe{ Mmio::new(VIRT_TEST, PGSIZE) };
 
 pub const SHUTDOWN: usize = 8;
 pub const REBOOT: usize = 9;
@@ -20,8 +25,6 @@ const TEST_PASS:u32 = 0x5555;
 const TEST_RESET:u32 = 0x7777;
 
 pub fn system_reset(reset_type: usize, reset_reason: usize) {
-    const VIRT_TEST: *mut u32 = 0x10_0000 as *mut u32;
-
     // Fail = 0x3333,
     // Pass = 0x5555,
     // Reset = 0x7777,
@@ -37,9 +40,7 @@ pub fn system_reset(reset_type: usize, reset_reason: usize) {
         value = TEST_FAIL
     }
 
-    unsafe {
-        core::ptr::write_volatile(VIRT_TEST, value);
-    }
+    VIRT_TEST_REGS.write(0, value);
 
     unreachable!();
 }
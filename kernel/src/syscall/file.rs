@@ -8,7 +8,7 @@ use core::cell::RefCell;
 
 use crate::arch::riscv::qemu::fs::DIRSIZ;
 use crate::arch::riscv::qemu::layout::PGSIZE;
-use crate::arch::riscv::qemu::param::MAXARG;
+use crate::arch::riscv::qemu::param::{ MAXARG, PGACCESS_MAX };
 use crate::memory::{ RawPage, PageAllocator };
 use crate::misc::str_cmp;
 use crate::{arch::riscv::qemu::{fs::OpenMode, param::MAXPATH}, fs::{FileType, ICACHE, Inode, InodeData, InodeType, LOG, VFile}, lock::sleeplock::{SleepLock, SleepLockGuard}};
@@ -16,7 +16,7 @@ use crate::fs::{Pipe, DirEntry};
 use super::*;
 
 use alloc::string::String;
-use alloc::sync::Arc;
+use crate::lock::arc::Arc;
 use alloc::vec;
 use bit_field::BitField;
 
@@ -26,7 +26,7 @@ impl Syscall<'_> {
         let pdata = unsafe{ &mut *self.process.data.get() };
         let file = pdata.open_files[old_fd].as_ref().unwrap();
         // 使用 Arc 来代替 refs
-        let new_fd = unsafe{ CPU_MANAGER.alloc_fd(&file) }.unwrap();
+        let new_fd = CPU_MANAGER.alloc_fd(&file).unwrap();
         let new_file = Arc::clone(&file);
         pdata.open_files[new_fd].replace(new_file);
         Ok(new_fd)
@@ -86,7 +86,7 @@ impl Syscall<'_> {
         let mut inode_guard: SleepLockGuard<InodeData>;
         // Get file path
         let addr = self.arg(0);
-        self.copy_from_str(addr, &mut path, MAXPATH).unwrap();
+        self.copy_from_str(addr, &mut path, MAXPATH)?;
         // Get open mode
         let open_mode = self.arg(1);
         // Start write log
@@ -156,7 +156,7 @@ impl Syscall<'_> {
         file.writeable = open_mode.get_bit(0) | open_mode.get_bit(1);
         file.readable = !open_mode.get_bit(0) | open_mode.get_bit(1);
         let fd;
-        match unsafe { CPU_MANAGER.alloc_fd(&file) } {
+        match CPU_MANAGER.alloc_fd(&file) {
             Ok(new_fd) => {
                 fd = new_fd;
                 // println!("[Kernel] fd: {}", fd);
@@ -175,7 +175,7 @@ impl Syscall<'_> {
         let mut argv = [0 as *mut u8; MAXARG];
         let mut user_arg: usize;
         let addr = self.arg(0);
-        self.copy_from_str(addr, &mut path, MAXPATH).unwrap();
+        self.copy_from_str(addr, &mut path, MAXPATH)?;
         let user_argv = self.arg(1);
         let path = from_utf8(&path).unwrap();
     
@@ -335,7 +335,10 @@ impl Syscall<'_> {
         let mut wf: &mut VFile = &mut VFile::init();
         // arg_addr(0, &mut &mut fd_array)?;
         let fd_array = self.arg(0);
-        Pipe::alloc(&mut rf, &mut wf);
+        if Pipe::alloc(&mut rf, &mut wf).is_none() {
+            println!("[Kernel] sys_pipe: Fail to allocate pipe");
+            return Err(())
+        }
 
         let p = unsafe {
             CPU_MANAGER.myproc().expect("Fail to get my process.")
@@ -369,10 +372,10 @@ impl Syscall<'_> {
             }
         }
 
-        let pgt = p.page_table();
+        let vm = p.vm();
         let pdata = unsafe{ &mut *self.process.data.get() };
         let open_files = &mut pdata.open_files;
-        if pgt.copy_out(fd_array, rf as *const _ as *const u8, size_of::<usize>()).is_err() {
+        if vm.copy_out(fd_array, rf as *const _ as *const u8, size_of::<usize>()).is_err() {
             open_files[rfd].take();
             open_files[wfd].take();
             // rf.close();
@@ -380,7 +383,7 @@ impl Syscall<'_> {
             return Err(())
         }
 
-        if pgt.copy_out(
+        if vm.copy_out(
             fd_array + size_of::<usize>(), 
             wf as *const _ as *const u8, 
             size_of::<usize>()
@@ -538,10 +541,133 @@ impl Syscall<'_> {
         }
     }
 
-}
+    /// mmap(addr, len, prot, flags, fd, offset), addr is only a hint 
+    /// and ignored. flags is MAP_SHARED or MAP_PRIVATE, plus 
+    /// MAP_ANONYMOUS for zero-filled memory without a file. 
+    /// Returns the start of the new mapping. 
+    pub fn sys_mmap(&self) -> SysResult {
+        let len = self.arg(1);
+        let prot = self.arg(2);
+        let flags = self.arg(3);
+        let fd = self.arg(4);
+        let offset = self.arg(5);
+        if len == 0 || offset % PGSIZE != 0 || 
+            (flags & MAP_SHARED != 0) == (flags & MAP_PRIVATE != 0) {
+            return Err(())
+        }
+        // inode offsets are u32, every byte mapped must have one. 
+        match offset.checked_add(len) {
+            Some(end) if end <= u32::MAX as usize => {},
+            _ => return Err(())
+        }
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let file = if flags & MAP_ANONYMOUS != 0 {
+            None
+        } else {
+            let file = pdata.open_files.get(fd).and_then(|f| f.as_ref()).ok_or(())?;
+            if file.ftype != FileType::Inode || !file.readable || 
+                (flags & MAP_SHARED != 0 && prot & PROT_WRITE != 0 && !file.writeable) {
+                return Err(())
+            }
+            Some(Arc::clone(file))
+        };
+        pdata.vm().mmap(len, prot, flags, file, offset).map_err(|err| {
+            println!("[Kernel] sys_mmap: err: {}", err);
+        })
+    }
 
+    /// munmap(addr, len), the range must be at the start 
+    /// or the end of a mapping. 
+    pub fn sys_munmap(&self) -> SysResult {
+        let addr = self.arg(0);
+        let len = self.arg(1);
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().munmap(addr, len).map(|_| 0).map_err(|err| {
+            println!("[Kernel] sys_munmap: err: {}", err);
+        })
+    }
 
+    /// msync(addr, len), write the modified pages of shared 
+    /// file mappings in the range back to their files. 
+    pub fn sys_msync(&self) -> SysResult {
+        let addr = self.arg(0);
+        let len = self.arg(1);
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().msync(addr, len).map(|_| 0).map_err(|err| {
+            println!("[Kernel] sys_msync: err: {}", err);
+        })
+    }
 
+    /// mprotect(addr, len, prot), change the protection of a range 
+    /// inside one mapping. 
+    pub fn sys_mprotect(&self) -> SysResult {
+        let addr = self.arg(0);
+        let len = self.arg(1);
+        let prot = self.arg(2);
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().mprotect(addr, len, prot).map(|_| 0).map_err(|err| {
+            println!("[Kernel] sys_mprotect: err: {}", err);
+        })
+    }
+
+    /// pgaccess(addr, npages, mask), set bit i of the bitmask at mask 
+    /// if page i from addr was accessed since the last call. 
+    pub fn sys_pgaccess(&self) -> SysResult {
+        let addr = self.arg(0);
+        let npages = self.arg(1);
+        let mask_addr = self.arg(2);
+        if npages > PGACCESS_MAX {
+            println!("[Kernel] sys_pgaccess: more than {} pages", PGACCESS_MAX);
+            return Err(())
+        }
+        let mut mask = [0u8; PGACCESS_MAX / 8];
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().pgaccess(addr, npages, &mut mask).map_err(|err| {
+            println!("[Kernel] sys_pgaccess: err: {}", err);
+        })?;
+        pdata.vm().copy_out(mask_addr, mask.as_ptr(), (npages + 7) / 8).map_err(|_| ())?;
+        Ok(0)
+    }
 
+    /// shmget(key, size, flags), the id of the shared memory segment 
+    /// with key, created if flags has IPC_CREAT. 
+    pub fn sys_shmget(&self) -> SysResult {
+        let key = self.arg(0);
+        let size = self.arg(1);
+        let flags = self.arg(2);
+        shm_get(key, size, flags).map_err(|err| {
+            println!("[Kernel] sys_shmget: err: {}", err);
+        })
+    }
+
+    /// shmat(id, addr, flags), attach segment id and return its address. 
+    /// addr is only a hint and ignored, flags may have SHM_RDONLY. 
+    pub fn sys_shmat(&self) -> SysResult {
+        let id = self.arg(0);
+        let flags = self.arg(2);
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().shmat(id, flags & SHM_RDONLY != 0).map_err(|err| {
+            println!("[Kernel] sys_shmat: err: {}", err);
+        })
+    }
 
+    /// shmdt(addr), detach the segment attached at addr. 
+    pub fn sys_shmdt(&self) -> SysResult {
+        let addr = self.arg(0);
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().shmdt(addr).map(|_| 0).map_err(|err| {
+            println!("[Kernel] sys_shmdt: err: {}", err);
+        })
+    }
 
+    /// shmctl(id, cmd), only IPC_RMID. 
+    pub fn sys_shmctl(&self) -> SysResult {
+        let id = self.arg(0);
+        match self.arg(1) {
+            IPC_RMID => shm_remove(id).map(|_| 0).map_err(|err| {
+                println!("[Kernel] sys_shmctl: err: {}", err);
+            }),
+            _ => Err(())
+        }
+    }
+}
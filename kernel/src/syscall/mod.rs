@@ -8,15 +8,15 @@ use crate::{println, process::*};
 use crate::fs::VFile;
 
 use core::borrow::BorrowMut;
-use core::mem::size_of;
 use core::ops::IndexMut;
+use core::mem::size_of;
 use core::str::from_utf8;
-use alloc::sync::Arc;
+use crate::lock::arc::Arc;
 
 type SyscallFn = fn() -> SysResult;
 pub type SysResult = Result<usize, ()>;
 
-pub const SYSCALL_NUM:usize = 21;
+pub const SYSCALL_NUM:usize = 57;
 pub const SHUTDOWN: usize = 8;
 pub const REBOOT: usize = 9;
 
@@ -26,11 +26,11 @@ pub unsafe fn handle_syscall() {
     let mut syscall = Syscall{ process: proc };
     if let Ok(res) = syscall.syscall() {
         let pdata = &mut *proc.data.get();
-        let tf = &mut *pdata.trapframe;
+        let tf = pdata.trapframe();
         tf.a0 = res;
     }else{
         let pdata = &mut *proc.data.get();
-        let tf = &mut *pdata.trapframe;
+        let tf = pdata.trapframe();
         tf.a0 = -1 as isize as usize
     }
     
@@ -61,6 +61,42 @@ pub enum SysCallID {
     SysLink = 19,
     SysMkdir = 20,
     SysClose = 21,
+    SysSetPriority = 22,
+    SysSetTickets = 23,
+    SysSchedSetAffinity = 24,
+    SysSchedGetAffinity = 25,
+    SysGetPPid = 26,
+    SysSetPgid = 27,
+    SysGetPgid = 28,
+    SysClone = 29,
+    SysJoin = 30,
+    SysGetRusage = 31,
+    SysWaitPid = 32,
+    SysSigAlarm = 33,
+    SysSigReturn = 34,
+    SysSigAction = 35,
+    SysSigProcMask = 36,
+    SysRtSigReturn = 37,
+    SysGetRlimit = 38,
+    SysSetRlimit = 39,
+    SysYield = 40,
+    SysSchedSetScheduler = 41,
+    SysSchedRrGetInterval = 42,
+    SysPrctl = 43,
+    SysReboot = 44,
+    SysMmap = 45,
+    SysMunmap = 46,
+    SysSysctl = 47,
+    SysVmprint = 48,
+    SysSysinfo = 49,
+    SysShmget = 50,
+    SysShmat = 51,
+    SysShmdt = 52,
+    SysShmctl = 53,
+    SysMprotect = 54,
+    SysMsync = 55,
+    SysPgaccess = 56,
+    SysFutex = 57,
     Unknown
 }
 
@@ -87,7 +123,43 @@ impl SysCallID {
             18 => { Self::SysUnlink },
             19 => { Self::SysLink },
             20 => { Self::SysMkdir },
-            21 => { Self::SysClose }
+            21 => { Self::SysClose },
+            22 => { Self::SysSetPriority },
+            23 => { Self::SysSetTickets },
+            24 => { Self::SysSchedSetAffinity },
+            25 => { Self::SysSchedGetAffinity },
+            26 => { Self::SysGetPPid },
+            27 => { Self::SysSetPgid },
+            28 => { Self::SysGetPgid },
+            29 => { Self::SysClone },
+            30 => { Self::SysJoin },
+            31 => { Self::SysGetRusage },
+            32 => { Self::SysWaitPid },
+            33 => { Self::SysSigAlarm },
+            34 => { Self::SysSigReturn },
+            35 => { Self::SysSigAction },
+            36 => { Self::SysSigProcMask },
+            37 => { Self::SysRtSigReturn },
+            38 => { Self::SysGetRlimit },
+            39 => { Self::SysSetRlimit },
+            40 => { Self::SysYield },
+            41 => { Self::SysSchedSetScheduler },
+            42 => { Self::SysSchedRrGetInterval },
+            43 => { Self::SysPrctl },
+            44 => { Self::SysReboot },
+            45 => { Self::SysMmap },
+            46 => { Self::SysMunmap },
+            47 => { Self::SysSysctl },
+            48 => { Self::SysVmprint },
+            49 => { Self::SysSysinfo },
+            50 => { Self::SysShmget },
+            51 => { Self::SysShmat },
+            52 => { Self::SysShmdt },
+            53 => { Self::SysShmctl },
+            54 => { Self::SysMprotect },
+            55 => { Self::SysMsync },
+            56 => { Self::SysPgaccess },
+            57 => { Self::SysFutex },
             _ => { Self::Unknown }
         }
     }
@@ -101,7 +173,7 @@ impl Syscall<'_> {
     pub fn syscall(&mut self) -> SysResult {
         let pdata = self.process.data.get_mut();
         // 获取进程的trapframe
-        let tf = unsafe{ &mut *pdata.trapframe };
+        let tf = pdata.trapframe();
         // 获取系统调用 id 号
         let sys_id = SysCallID::new(tf.a7);
         
@@ -109,6 +181,7 @@ impl Syscall<'_> {
             SysCallID::SysFork => { self.sys_fork() },
             SysCallID::SysExit => { self.sys_exit() },
             SysCallID::SysWait => { self.sys_wait() },
+            SysCallID::SysKill => { self.sys_kill() },
             SysCallID::SysRead => { self.sys_read() },
             SysCallID::SysWrite => { self.sys_write() },
             SysCallID::SysOpen => { self.sys_open() },
@@ -116,7 +189,9 @@ impl Syscall<'_> {
             SysCallID::SysMknod => { self.sys_mknod() },
             SysCallID::SysClose => { self.sys_close() },
             SysCallID::SysDup => { self.sys_dup() },
-            SysCallID::SysUptime => { Ok(0) },
+            SysCallID::SysGetPid => { self.sys_getpid() },
+            SysCallID::SysUptime => { self.sys_uptime() },
+            SysCallID::SysSleep => { self.sys_sleep() },
             SysCallID::SysSbrk => { self.sys_sbrk() },
             SysCallID::SysFstat => { self.sys_fstat() },
             SysCallID::SysChdir => { self.sys_chdir()},
@@ -124,6 +199,42 @@ impl Syscall<'_> {
             SysCallID::SysUnlink => { self.sys_unlink() },
             SysCallID::SysLink => { self.sys_link() },
             SysCallID::SysMkdir => { self.sys_mkdir() },
+            SysCallID::SysSetPriority => { self.sys_setpriority() },
+            SysCallID::SysSetTickets => { self.sys_settickets() },
+            SysCallID::SysSchedSetAffinity => { self.sys_sched_setaffinity() },
+            SysCallID::SysSchedGetAffinity => { self.sys_sched_getaffinity() },
+            SysCallID::SysGetPPid => { self.sys_getppid() },
+            SysCallID::SysSetPgid => { self.sys_setpgid() },
+            SysCallID::SysGetPgid => { self.sys_getpgid() },
+            SysCallID::SysClone => { self.sys_clone() },
+            SysCallID::SysJoin => { self.sys_join() },
+            SysCallID::SysGetRusage => { self.sys_getrusage() },
+            SysCallID::SysWaitPid => { self.sys_waitpid() },
+            SysCallID::SysSigAlarm => { self.sys_sigalarm() },
+            SysCallID::SysSigReturn => { self.sys_sigreturn() },
+            SysCallID::SysSigAction => { self.sys_sigaction() },
+            SysCallID::SysSigProcMask => { self.sys_sigprocmask() },
+            SysCallID::SysRtSigReturn => { self.sys_rt_sigreturn() },
+            SysCallID::SysGetRlimit => { self.sys_getrlimit() },
+            SysCallID::SysSetRlimit => { self.sys_setrlimit() },
+            SysCallID::SysYield => { self.sys_yield() },
+            SysCallID::SysSchedSetScheduler => { self.sys_sched_setscheduler() },
+            SysCallID::SysSchedRrGetInterval => { self.sys_sched_rr_get_interval() },
+            SysCallID::SysPrctl => { self.sys_prctl() },
+            SysCallID::SysReboot => { self.sys_reboot() },
+            SysCallID::SysMmap => { self.sys_mmap() },
+            SysCallID::SysMunmap => { self.sys_munmap() },
+            SysCallID::SysSysctl => { self.sys_sysctl() },
+            SysCallID::SysVmprint => { self.sys_vmprint() },
+            SysCallID::SysSysinfo => { self.sys_sysinfo() },
+            SysCallID::SysShmget => { self.sys_shmget() },
+            SysCallID::SysShmat => { self.sys_shmat() },
+            SysCallID::SysShmdt => { self.sys_shmdt() },
+            SysCallID::SysShmctl => { self.sys_shmctl() },
+            SysCallID::SysMprotect => { self.sys_mprotect() },
+            SysCallID::SysMsync => { self.sys_msync() },
+            SysCallID::SysPgaccess => { self.sys_pgaccess() },
+            SysCallID::SysFutex => { self.sys_futex() },
             _ => { panic!("Invalid syscall id: {:?}", sys_id) }
         }
     }
@@ -131,7 +242,7 @@ impl Syscall<'_> {
     /// 获取第n个位置的参数
     pub fn arg(&self, id: usize) -> usize {
         let pdata = unsafe{ &mut* self.process.data.get() };
-        let tf = unsafe{ &*pdata.trapframe };
+        let tf = pdata.trapframe();
         match id {
             0 => tf.a0,
             1 => tf.a1,
@@ -146,8 +257,8 @@ impl Syscall<'_> {
     /// 通过地址获取str并将其填入到缓冲区中
     pub fn copy_from_str(&self, addr: usize, buf: &mut [u8], max_len: usize) -> Result<(), ()> {
         let pdata = unsafe{ &mut *self.process.data.get() };
-        let pgt = pdata.pagetable.as_mut().unwrap();
-        if pgt.copy_in_str(buf.as_mut_ptr(), addr, max_len).is_err() {
+        let vm = pdata.vm();
+        if vm.copy_in_str(buf.as_mut_ptr(), addr, max_len).is_err() {
             println!("Fail to copy in str");
             return Err(())
         }
@@ -156,15 +267,9 @@ impl Syscall<'_> {
 
     pub fn copy_form_addr(&self, addr: usize, buf: &mut [u8], len: usize) -> Result<(), ()> {
         let pdata = unsafe{ &mut *self.process.data.get() };
-    
-        if addr > pdata.size || addr + size_of::<usize>() > pdata.size {
-            println!("[Debug] addr: 0x{:x}", addr);
-            println!("[Debug] pdata size: 0x{:x}", pdata.size);
-            panic!("拷贝的地址值超出了进程")
-        }
-    
-        let pgt = pdata.pagetable.as_mut().unwrap();
-        if pgt.copy_in(buf.as_mut_ptr(), addr, len).is_err() {
+        // copy_in() checks addr against the process size. 
+        let vm = pdata.vm();
+        if vm.copy_in(buf.as_mut_ptr(), addr, len).is_err() {
             println!("Fail copy data from pagetable!");
             return Err(())
         }
@@ -1,6 +1,30 @@
-use crate::trap::TICKS_LOCK;
+use crate::trap::{ TICKS_LOCK, ticks_channel };
+use crate::process::signal::{ self, SigAction };
+use crate::memory::KERNEL_HEAP;
+use core::sync::atomic::Ordering;
 use super::*;
 
+// prctl() options
+const PR_SET_NAME: usize = 15;
+const PR_GET_NAME: usize = 16;
+
+// reboot() commands
+const REBOOT_CMD_POWER_OFF: usize = 0;
+const REBOOT_CMD_RESTART: usize = 1;
+
+// sysctl() names
+const CTL_RANDOMIZE_VA: usize = 0;
+
+// System state returned to user space by sysinfo(). 
+#[repr(C)]
+struct SysInfo {
+    total_pages: usize, // pages of the kernel heap
+    free_pages: usize,
+    alloc_failures: usize, // kernel allocations that found no memory
+    nproc: usize, // processes not UNUSED
+    oom_kills: usize, // processes killed for memory
+}
+
 impl Syscall<'_> {
     pub fn sys_fork(&mut self) -> SysResult {
         let proc_meta = self.process.meta.acquire();
@@ -9,7 +33,25 @@ impl Syscall<'_> {
         let pmeta = child_proc.meta.acquire();
         let pid = pmeta.pid;
         drop(pmeta);
-        Ok(pid)
+        Ok(pid.as_usize())
+    }
+
+    /// clone(stack), stack is the top of the new thread's user stack. 
+    pub fn sys_clone(&mut self) -> SysResult {
+        let stack = self.arg(0);
+        let thread = self.process.clone_thread(stack).ok_or(())?;
+        let pmeta = thread.meta.acquire();
+        let pid = pmeta.pid;
+        drop(pmeta);
+        Ok(pid.as_usize())
+    }
+
+    /// join(&status), wait for a thread created by clone() to exit. 
+    pub fn sys_join(&self) -> SysResult {
+        let addr = self.arg(0);
+        unsafe {
+            PROC_MANAGER.join(addr).map(Pid::as_usize).ok_or(())
+        }
     }
 
     pub fn sys_exit(&self) -> SysResult {
@@ -25,7 +67,7 @@ impl Syscall<'_> {
             PROC_MANAGER.wait(addr)
         } {
             Some(pid) => {
-                Ok(pid)
+                Ok(pid.as_usize())
             },
     
             None => {
@@ -34,18 +76,103 @@ impl Syscall<'_> {
         }
     }
 
+    /// waitpid(pid, &status, options)
+    pub fn sys_waitpid(&self) -> SysResult {
+        let pid = self.arg(0) as isize;
+        let addr = self.arg(1);
+        let options = self.arg(2);
+        unsafe {
+            PROC_MANAGER.waitpid(pid, addr, options).map(Pid::as_usize).ok_or(())
+        }
+    }
+
+    /// sigalarm(ticks, handler), call handler every ticks ticks 
+    /// of CPU time, ticks 0 stops the alarm. 
+    pub fn sys_sigalarm(&self) -> SysResult {
+        let ticks = self.arg(0);
+        let handler = self.arg(1);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        pdata.alarm.set(ticks, handler);
+        Ok(0)
+    }
+
+    /// sigreturn(), resume where the alarm interrupted the process. 
+    /// Returns the restored a0, so that the syscall doesn't clobber it. 
+    pub fn sys_sigreturn(&self) -> SysResult {
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let tf = pdata.trapframe();
+        pdata.alarm.restore(tf)?;
+        Ok(tf.a0)
+    }
+
+    /// futex(addr, op, val, timeout) 
+    /// FUTEX_WAIT: sleep if *addr is val, for at most timeout ticks unless 0. 
+    /// FUTEX_WAKE: wake at most val processes waiting on addr, returns how many. 
+    pub fn sys_futex(&self) -> SysResult {
+        let addr = self.arg(0);
+        let op = self.arg(1);
+        let val = self.arg(2);
+        let timeout = self.arg(3);
+        let res = match op {
+            FUTEX_WAIT => futex_wait(addr, val as u32, timeout).map(|_| 0),
+            FUTEX_WAKE => futex_wake(addr, val),
+            _ => Err("futex: bad op")
+        };
+        // A changed value or a timeout is the normal way
+        // for a wait to end early, not worth a line on the console.
+        res.map_err(|_| ())
+    }
+
+    /// getrlimit(resource, &rlim)
+    pub fn sys_getrlimit(&self) -> SysResult {
+        let resource = self.arg(0);
+        let addr = self.arg(1);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let limit = pdata.rlimits.get(resource).ok_or(())?;
+        pdata.vm().copy_out(
+            addr, 
+            &limit as *const RLimit as *const u8, 
+            size_of::<RLimit>()
+        ).map_err(|_| ())?;
+        Ok(0)
+    }
+
+    /// setrlimit(resource, &rlim)
+    pub fn sys_setrlimit(&self) -> SysResult {
+        let resource = self.arg(0);
+        let addr = self.arg(1);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let mut limit = RLimit::new();
+        pdata.vm().copy_in(
+            &mut limit as *mut RLimit as *mut u8, 
+            addr, 
+            size_of::<RLimit>()
+        ).map_err(|_| ())?;
+        pdata.rlimits.set(resource, limit)?;
+        if resource == RLIMIT_PAGES {
+            pdata.vm().memcg().set_limit(limit.cur);
+        }
+        Ok(0)
+    }
+
     pub fn sys_getpid(&self) -> SysResult {
         let pmeta = self.process.meta.acquire();
         let pid = pmeta.pid;
         drop(pmeta);
-        Ok(pid)
+        Ok(pid.as_usize())
+    }
+
+    pub fn sys_getppid(&self) -> SysResult {
+        unsafe {
+            Ok(PROC_MANAGER.parent_pid(self.process).as_usize())
+        }
     }
     
     
     pub fn sys_sbrk(&mut self) -> SysResult {
         let size = self.arg(0);
         let pdata = unsafe{ &*self.process.data.get() };
-        let addr = pdata.size;
+        let addr = pdata.brk();
         drop(pdata);
         match self.process.grow_proc(size as isize) {
             Ok(()) => {
@@ -60,6 +187,92 @@ impl Syscall<'_> {
     
     
     
+    /// prctl(option, arg), only PR_SET_NAME and PR_GET_NAME: 
+    /// arg is the address of a name of at most 15 bytes plus the NUL. 
+    pub fn sys_prctl(&self) -> SysResult {
+        let option = self.arg(0);
+        let addr = self.arg(1);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        match option {
+            PR_SET_NAME => {
+                let mut name = [0u8; 16];
+                pdata.vm().copy_in_str(
+                    name.as_mut_ptr(), 
+                    addr, 
+                    name.len() - 1
+                ).map_err(|_| ())?;
+                pdata.name = name;
+                Ok(0)
+            },
+
+            PR_GET_NAME => {
+                let name = pdata.name;
+                pdata.vm().copy_out(
+                    addr, 
+                    name.as_ptr(), 
+                    name.len()
+                ).map_err(|_| ())?;
+                Ok(0)
+            },
+
+            _ => Err(())
+        }
+    }
+
+    /// reboot(cmd), end the run after all processes have exited: 
+    /// REBOOT_CMD_POWER_OFF or REBOOT_CMD_RESTART. 
+    pub fn sys_reboot(&self) -> SysResult {
+        let restart = match self.arg(0) {
+            REBOOT_CMD_POWER_OFF => false,
+            REBOOT_CMD_RESTART => true,
+            _ => return Err(())
+        };
+        unsafe {
+            PROC_MANAGER.shutdown(restart)
+        }
+    }
+
+    /// sysctl(name, value), read a kernel tunable and set it to value, 
+    /// or only read it if value is -1. Returns the old value. 
+    /// CTL_RANDOMIZE_VA: 1 if exec() randomizes the stack and mmap() base. 
+    pub fn sys_sysctl(&self) -> SysResult {
+        let value = self.arg(1);
+        match self.arg(0) {
+            CTL_RANDOMIZE_VA => {
+                let old = RANDOMIZE_VA.load(Ordering::Relaxed) as usize;
+                match value {
+                    0 | 1 => RANDOMIZE_VA.store(value == 1, Ordering::Relaxed),
+                    usize::MAX => {},
+                    _ => return Err(())
+                }
+                Ok(old)
+            },
+
+            _ => Err(())
+        }
+    }
+
+    /// vmprint(), print the page table of this process to the console. 
+    pub fn sys_vmprint(&self) -> SysResult {
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        println!("vmprint: pid {}", self.process.pid());
+        pdata.vm().with_page_table(|pt| pt.vmprint());
+        Ok(0)
+    }
+
+    /// yield(), give up the CPU to other runnable processes. 
+    pub fn sys_yield(&mut self) -> SysResult {
+        self.process.yielding();
+        Ok(0)
+    }
+
+    /// uptime(), clock ticks since boot. 
+    pub fn sys_uptime(&self) -> SysResult {
+        Ok(unsafe{ TICKS_LOCK.with(|ticks| *ticks) })
+    }
+
+    /// sleep(n), sleep for n clock ticks. 
+    /// Fails early if the process is killed meanwhile. 
     pub fn sys_sleep(&self) -> SysResult {
         let time_span = self.arg(0);
 
@@ -76,7 +289,7 @@ impl Syscall<'_> {
                 drop(ticks_guard);           
                 return Err(())
             } else {
-                my_proc.sleep(0, ticks_guard);
+                my_proc.sleep(ticks_channel(), ticks_guard);
                 ticks_guard = unsafe {
                     TICKS_LOCK.acquire()
                 }
@@ -88,10 +301,195 @@ impl Syscall<'_> {
     }
     
     
+    /// kill(pid, sig), a negative pid signals the whole process group -pid, 
+    /// and -1 every process but init and the caller. 
     pub fn sys_kill(&self) -> SysResult {
-        let pid = self.arg(0);
+        let pid = self.arg(0) as isize;
+        let sig = self.arg(1);
+        unsafe {
+            if pid == -1 {
+                PROC_MANAGER.kill_all(self.process, sig)
+            } else if pid < 0 {
+                let pgid = pid.checked_neg().ok_or(())?;
+                PROC_MANAGER.kill_group(Pid::new(pgid as usize), sig)
+            } else {
+                PROC_MANAGER.kill(Pid::new(pid as usize), sig)
+            }
+        }
+    }
+
+    /// sigaction(sig, &act, &oldact), either pointer may be null. 
+    pub fn sys_sigaction(&self) -> SysResult {
+        let sig = self.arg(0);
+        let act_addr = self.arg(1);
+        let old_addr = self.arg(2);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let mut act = pdata.signals.actions.get(sig).copied().ok_or(())?;
+        if act_addr != 0 {
+            pdata.vm().copy_in(
+                &mut act as *mut SigAction as *mut u8, 
+                act_addr, 
+                size_of::<SigAction>()
+            ).map_err(|_| ())?;
+        }
+        let old = pdata.signals.set_action(sig, act)?;
+        if old_addr != 0 {
+            pdata.vm().copy_out(
+                old_addr, 
+                &old as *const SigAction as *const u8, 
+                size_of::<SigAction>()
+            ).map_err(|_| ())?;
+        }
+        Ok(0)
+    }
+
+    /// sigprocmask(how, &set, &oldset), either pointer may be null. 
+    pub fn sys_sigprocmask(&self) -> SysResult {
+        let how = self.arg(0);
+        let set_addr = self.arg(1);
+        let old_addr = self.arg(2);
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        let old = if set_addr != 0 {
+            let mut set: u32 = 0;
+            pdata.vm().copy_in(
+                &mut set as *mut u32 as *mut u8, 
+                set_addr, 
+                size_of::<u32>()
+            ).map_err(|_| ())?;
+            pdata.signals.set_mask(how, set)?
+        } else {
+            pdata.signals.blocked
+        };
+        if old_addr != 0 {
+            pdata.vm().copy_out(
+                old_addr, 
+                &old as *const u32 as *const u8, 
+                size_of::<u32>()
+            ).map_err(|_| ())?;
+        }
+        Ok(0)
+    }
+
+    /// rt_sigreturn(), called by the restorer when a signal handler returns. 
+    pub fn sys_rt_sigreturn(&self) -> SysResult {
+        let pdata = unsafe{ &mut *self.process.data.get() };
+        signal::sigreturn(pdata)
+    }
+
+    /// setpgid(pid, pgid), pid 0 means the calling process. 
+    pub fn sys_setpgid(&mut self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let pgid = Pid::new(self.arg(1));
+        let pid = if pid == Pid::new(0) { self.process.pid() } else { pid };
+        unsafe {
+            PROC_MANAGER.set_pgid(self.process, pid, pgid)
+        }
+    }
+
+    /// getpgid(pid), pid 0 means the calling process. 
+    pub fn sys_getpgid(&self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let pid = if pid == Pid::new(0) { self.process.pid() } else { pid };
+        unsafe {
+            PROC_MANAGER.get_pgid(pid)
+        }
+    }
+
+    /// getrusage(&rusage), copy the caller's resource usage out. 
+    pub fn sys_getrusage(&self) -> SysResult {
+        let addr = self.arg(0);
+        let pdata = unsafe{ &*self.process.data.get() };
+        let rusage = pdata.rusage;
+        pdata.vm().copy_out(
+            addr, 
+            &rusage as *const Rusage as *const u8, 
+            size_of::<Rusage>()
+        ).map_err(|_| ())?;
+        Ok(0)
+    }
+
+    /// sysinfo(&info), copy memory statistics, the number of processes 
+    /// and of those killed by the out-of-memory killer out. 
+    pub fn sys_sysinfo(&self) -> SysResult {
+        let addr = self.arg(0);
+        let stats = KERNEL_HEAP.stats();
+        let info = SysInfo {
+            total_pages: stats.total_pages,
+            free_pages: stats.free_pages,
+            alloc_failures: stats.alloc_failures,
+            nproc: unsafe{ PROC_MANAGER.nr_procs() },
+            oom_kills: oom_kills(),
+        };
+        let pdata = unsafe{ &*self.process.data.get() };
+        pdata.vm().copy_out(
+            addr, 
+            &info as *const SysInfo as *const u8, 
+            size_of::<SysInfo>()
+        ).map_err(|_| ())?;
+        Ok(0)
+    }
+
+    /// settickets(n)
+    pub fn sys_settickets(&self) -> SysResult {
+        let tickets = self.arg(0);
+        unsafe {
+            PROC_MANAGER.set_tickets(self.process, tickets)
+        }
+    }
+
+    /// sched_setaffinity(pid, mask), pid 0 means the calling process. 
+    pub fn sys_sched_setaffinity(&mut self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let mask = self.arg(1);
+        let my_pid = self.process.pid();
+        let pid = if pid == Pid::new(0) { my_pid } else { pid };
+        unsafe {
+            PROC_MANAGER.set_affinity(pid, mask)?;
+            // Move off this cpu right away if it is no longer allowed. 
+            if pid == my_pid && mask & (1 << cpuid()) == 0 {
+                self.process.yielding();
+            }
+        }
+        Ok(0)
+    }
+
+    /// sched_getaffinity(pid), returns the mask. 
+    pub fn sys_sched_getaffinity(&self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let pid = if pid == Pid::new(0) { self.process.pid() } else { pid };
+        unsafe {
+            PROC_MANAGER.get_affinity(pid)
+        }
+    }
+
+    /// sched_setscheduler(pid, policy, rt_priority), pid 0 means the calling process. 
+    /// Only the caller, its children and init may be changed. 
+    pub fn sys_sched_setscheduler(&self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let class = SchedClass::from_policy(self.arg(1)).ok_or(())?;
+        let rt_priority = self.arg(2);
+        let pid = if pid == Pid::new(0) { self.process.pid() } else { pid };
+        unsafe {
+            PROC_MANAGER.set_scheduler(self.process, pid, class, rt_priority)
+        }
+    }
+
+    /// sched_rr_get_interval(pid), time slice of the process in ticks, 
+    /// pid 0 means the calling process. 
+    pub fn sys_sched_rr_get_interval(&self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let pid = if pid == Pid::new(0) { self.process.pid() } else { pid };
+        unsafe {
+            PROC_MANAGER.time_slice(pid)
+        }
+    }
+
+    /// setpriority(pid, priority)
+    pub fn sys_setpriority(&self) -> SysResult {
+        let pid = Pid::new(self.arg(0));
+        let priority = self.arg(1);
         unsafe {
-            PROC_MANAGER.kill(pid)
+            PROC_MANAGER.set_priority(pid, priority)
         }
     }
     
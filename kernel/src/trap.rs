@@ -1,19 +1,25 @@
 use core::panic;
+use core::sync::atomic::{ AtomicUsize, Ordering };
 
 use crate::syscall::handle_syscall;
 use crate::driver::plic::{plic_claim, plic_complete};
 use crate::driver::virtio_disk::DISK;
 use crate::arch::riscv::qemu::fs::DIRSIZ;
-use crate::arch::riscv::{sepc, sstatus, scause, stval, stvec, sip, scause::{Scause, Exception, Trap, Interrupt}};
+use crate::arch::riscv::{sepc, sstatus, scause, stval, stvec, sip, satp, scause::{Scause, Exception, Trap, Interrupt}};
+use crate::memory::{ PageTable, tlb };
 use crate::lock::spinlock::Spinlock;
 use crate::process::cpu;
 use crate::arch::riscv::qemu::layout::*;
+use crate::arch::riscv::qemu::param::MLFQ_BOOST_INTERVAL;
 use crate::process::*;
 use crate::driver::console::*;
 use crate::shutdown::*;
 use super::*;
 
 pub static mut TICKS_LOCK:Spinlock<usize> = Spinlock::new(0, "time");
+/// Copy of the tick count that can be read without TICKS_LOCK, 
+/// e.g. while holding a p->lock. 
+static TICKS: AtomicUsize = AtomicUsize::new(0);
 
 /// Set up to take exceptions and traps while in the kernel.
 pub unsafe fn trap_init_hart() {
@@ -41,10 +47,13 @@ pub unsafe fn user_trap() {
     }
     stvec::write(kernelvec as usize);
 
+    // uservec flushed the TLB switching back to the kernel page table. 
+    tlb::set_active(0);
+
     let my_proc = CPU_MANAGER.myproc().unwrap();
     let pdata = my_proc.data.get_mut();
 
-    let tf = &mut *pdata.trapframe;
+    let tf = pdata.trapframe();
     tf.epc = sepc;
 
     match scause.cause() {
@@ -64,43 +73,38 @@ pub unsafe fn user_trap() {
         },
 
         // Device interrupt
-        Trap::Interrupt(Interrupt::SupervisorExternal) => {
-            // this is a supervisor external interrupt, via PLIC.
-            // irq indicates which device interrupted.
-            if let Some(interrupt) = plic_claim() {
-                match interrupt {
-                    VIRTIO0_IRQ => {
-                        DISK.acquire().intr();
-                    },
-
-                    UART0_IRQ => {
-                        UART.intr();
-                    },
-
-                    _ => {
-                        panic!("Unresolved interrupt");
-                    }
-                }
-                plic_complete(interrupt);
-            }
-            
-        },
+        Trap::Interrupt(Interrupt::SupervisorExternal) => dev_intr(),
 
         // Clock Interrupt
         Trap::Interrupt(Interrupt::SupervisorSoft) => {
-            // software interrupt from a machine-mode timer interrupt,
-            // forwarded by timervec in kernelvec.S.
-            if cpu::cpuid() == 0{
-                clock_intr();
-            }
-            // acknowledge the software interrupt by clearing
-            // the SSIP bit in sip.
-            sip::clear_ssip();
+            timer_intr();
             if my_proc.killed() {
                 exit(-1);
             }
+            pdata.alarm.tick(tf);
             // yield up the CPU if this is a timer interrupt
-            my_proc.yielding();
+            // and the time slice is used up. 
+            if my_proc.timer_tick(true) {
+                my_proc.yielding();
+            }
+        },
+
+        // Page not allocated yet since sbrk() or not paged in 
+        // from the executable, or a bad access. 
+        Trap::Exception(Exception::InstructionPageFault) | 
+        Trap::Exception(Exception::LoadPageFault) | 
+        Trap::Exception(Exception::StorePageFault) => {
+            let stval = stval::read();
+            let vm = pdata.vm.as_ref().expect("Fail to get address space");
+            if vm.is_guard_page(stval) {
+                println!("usertrap: stack overflow into the guard page, pid: {}", my_proc.pid());
+                println!("sepc: 0x{:x}, stval: 0x{:x}", sepc, stval);
+                my_proc.modify_kill(true);
+            } else if let Err(err) = vm.fault(stval, scause.cause() == Trap::Exception(Exception::StorePageFault)) {
+                println!("usertrap: {}, pid: {}", err, my_proc.pid());
+                println!("sepc: 0x{:x}, stval: 0x{:x}", sepc, stval);
+                my_proc.modify_kill(true);
+            }
         },
 
         _ => {
@@ -114,6 +118,8 @@ pub unsafe fn user_trap() {
     if my_proc.killed() {
         exit(-1);
     }
+
+    signal::handle_signals(my_proc);
     
     user_trap_ret();
 }
@@ -153,10 +159,13 @@ pub unsafe fn user_trap_ret() -> ! {
     sstatus::write(sstatus);
 
     // set S Exception Program Counter to the saved user pc. 
-    sepc::write((*pdata.trapframe).epc);
+    sepc::write(pdata.trapframe().epc);
     
-    // tell trampoline.S the user page table to switch to
-    let satp = pdata.pagetable.as_ref().unwrap().as_satp();
+    // tell trampoline.S the user page table to switch to, 
+    // and let tlb shootdowns of it reach this hart from now on. 
+    let vm = pdata.vm();
+    let satp = vm.satp();
+    tlb::set_active(vm.asid());
 
     // jump to trampoline.S at the top of memory, which
     // switches to the user page table, restores user registers,
@@ -164,16 +173,49 @@ pub unsafe fn user_trap_ret() -> ! {
     let userret_virt = TRAMPOLINE + (userret as usize - trampoline as usize);
     let userret_virt: extern "C" fn(usize, usize) -> ! = 
     core::mem::transmute(userret_virt as usize);
-    userret_virt(TRAPFRAME, satp);
+    userret_virt(pdata.trapframe_va, satp);
+}
+
+/// Registers of the kernel code that trapped, 
+/// saved by kernelvec on its stack, in this order. 
+#[repr(C)]
+pub struct KernelFrame {
+    regs: [usize; 31],
+}
+
+const KERNEL_FRAME_REGS: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1",
+    "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+    "t3", "t4", "t5", "t6",
+];
+
+impl KernelFrame {
+    /// a7, which kernel_env_call() puts the call number in. 
+    fn a7(&self) -> usize {
+        self.regs[16]
+    }
+
+    /// Print the registers, three to a line. 
+    /// sp is the one after kernelvec made room for the frame. 
+    fn dump(&self) {
+        for (i, (name, reg)) in KERNEL_FRAME_REGS.iter().zip(self.regs.iter()).enumerate() {
+            print!("{:>3}: 0x{:016x}", name, reg);
+            if i % 3 == 2 || i == self.regs.len() - 1 {
+                println!("");
+            } else {
+                print!("  ");
+            }
+        }
+    }
 }
 
 /// interrupts and exceptions from kernel code go here via kernelvec,
 /// on whatever the current kernel stack is.
+/// A process may yield in here, sepc and sstatus are 
+/// kept across it, as another trap would change them. 
 #[no_mangle]
-pub unsafe fn kernel_trap(
-   _: usize, _: usize, _: usize, _: usize,
-   _: usize, _: usize, _: usize, which: usize
-) {
+pub unsafe fn kernel_trap(frame: &mut KernelFrame) {
     let sepc = sepc::read();
     let sstatus = sstatus::read();
     let scause = scause::read();
@@ -196,18 +238,16 @@ pub unsafe fn kernel_trap(
             println!("BreakPoint!");
         },
 
-        Trap::Exception(Exception::LoadFault) => panic!("Load Fault!"),
-
-        Trap::Exception(Exception::LoadPageFault) => {
-            panic!("[Panic] Load Page Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
-        },
+        Trap::Exception(Exception::LoadFault) => kernel_fault(scause, stval, sepc, frame),
 
-        Trap::Exception(Exception::StorePageFault) => {
-            panic!("[Panic] Store Page Fault!\n stval: 0x{:x}\n sepc: 0x{:x}\n", stval, sepc);
+        Trap::Exception(Exception::LoadPageFault) | 
+        Trap::Exception(Exception::StorePageFault) | 
+        Trap::Exception(Exception::InstructionPageFault) => {
+            kernel_page_fault(scause, stval, sepc);
         },
 
         Trap::Exception(Exception::KernelEnvCall) => {
-            match which  {
+            match frame.a7()  {
                 SHUTDOWN => {
                     println!("\x1b[1;31mShutdown!\x1b[0m");
                     system_reset(
@@ -230,56 +270,23 @@ pub unsafe fn kernel_trap(
             }
         },
 
-        Trap::Exception(Exception::InstructionFault) => panic!("Instruction Fault, sepc: 0x{:x}", sepc),
+        Trap::Exception(Exception::InstructionFault) => kernel_fault(scause, stval, sepc, frame),
 
-        Trap::Exception(Exception::InstructionPageFault) => {
-            println!("sepc: 0x{:x}", sepc);
-            println!("stval: 0x{:x}", stval);
-            panic!();
-        },
 
         // Device Interruput
-        Trap::Interrupt(Interrupt::SupervisorExternal) => {
-            // this is a supervisor external interrupt, via PLIC.
-            // interrupt indicates which device interrupted.
-            if let Some(interrupt) = plic_claim() {
-                match interrupt {
-                    VIRTIO0_IRQ => {
-                        DISK.acquire().intr();
-                    },
-
-                    UART0_IRQ => {
-                        UART.intr();
-                        // uart_intr();
-                    },
-
-                    _ => {
-                        panic!("Unresolved interrupt");
-                    }
-                }
-                plic_complete(interrupt);
-            }
-            
-        },
+        Trap::Interrupt(Interrupt::SupervisorExternal) => dev_intr(),
 
         // Clock Interrupt
         Trap::Interrupt(Interrupt::SupervisorSoft) => {
-            // software interrupt from a machine-mode timer interrupt,
-            // forwarded by timervec in kernelvec.S.
-
-            if cpu::cpuid() == 0{
-                clock_intr();
-            }
-            // acknowledge the software interrupt by clearing
-            // the SSIP bit in sip.
-            sip::clear_ssip();
-
+            timer_intr();
             // give up the cpu. 
-            CPU_MANAGER.mycpu().try_yield_proc();
+            CPU_MANAGER.try_yield_proc();
         }
 
+        Trap::Exception(_) => kernel_fault(scause, stval, sepc, frame),
+
         _ => {       
-            panic!("Unresolved Trap!");
+            panic!("Unresolved Trap: scause 0x{:x}", scause.bits());
         }
     }
     // store context
@@ -289,8 +296,112 @@ pub unsafe fn kernel_trap(
 }
 
 
+/// An exception in kernel code is a bug, print the 
+/// registers it had to find it, and panic. 
+unsafe fn kernel_fault(scause: Scause, stval: usize, sepc: usize, frame: &KernelFrame) -> ! {
+    println!("kernel_trap: unexpected exception {:?} on hart {}", scause.cause(), cpu::cpuid());
+    println!("scause: 0x{:x}, stval: 0x{:x}, sepc: 0x{:x}", scause.bits(), stval, sepc);
+    frame.dump();
+    panic!("kernel fault at sepc 0x{:x}", sepc);
+}
+
+/// A supervisor external interrupt, via PLIC, from user or kernel mode. 
+/// The PLIC tells which device interrupted. 
+unsafe fn dev_intr() {
+    if let Some(interrupt) = plic_claim() {
+        crate::random::add_entropy(interrupt);
+        match interrupt {
+            VIRTIO0_IRQ => {
+                DISK.acquire().intr();
+            },
+
+            UART0_IRQ => {
+                UART.intr();
+            },
+
+            _ => {
+                panic!("Unresolved interrupt {}", interrupt);
+            }
+        }
+        plic_complete(interrupt);
+    }
+}
+
+/// Software interrupt from a machine-mode timer interrupt, 
+/// forwarded by timervec in kernelvec.S. 
+unsafe fn timer_intr() {
+    if cpu::cpuid() == 0{
+        clock_intr();
+    }
+    // Interrupts were on, so this hart was in no read-side section. 
+    // Counts for a hart that never goes back to the scheduler, 
+    // e.g. running a SCHED_FIFO process. 
+    crate::lock::rcu::quiescent();
+    // acknowledge the software interrupt by clearing
+    // the SSIP bit in sip.
+    sip::clear_ssip();
+}
+
+/// A page fault in the kernel is a bug unless it is a kernel stack 
+/// overflow. Print what it takes to find the bug: the cause, the 
+/// faulting address, the pc, the process, and the PTEs leading 
+/// to the address in the page table in use, then panic. 
+unsafe fn kernel_page_fault(scause: Scause, stval: usize, sepc: usize) -> ! {
+    if kstack_guard_slot(stval).is_some() {
+        kernel_stack_overflow(stval);
+    }
+    println!("kernel page fault: {:?}", scause.cause());
+    println!("scause: 0x{:x}, stval: 0x{:x}, sepc: 0x{:x}", scause.bits(), stval, sepc);
+    match CPU_MANAGER.myproc() {
+        Some(p) => println!("pid: {}, name: {}", p.meta.get_unchecked().pid, p.name()),
+        None => println!("no process on cpu {}", cpu::cpuid())
+    }
+    let satp = satp::read();
+    println!("page table walk, satp: 0x{:x}", satp);
+    let page_table = &*(((satp & satp::SATP_PPN) << PGSHIFT) as *const PageTable);
+    page_table.print_walk(stval);
+    panic!("kernel page fault at 0x{:x}, sepc: 0x{:x}", stval, sepc);
+}
+
+/// TRAMPOLINE for kernelvec, which cannot use the constant 
+/// since it depends on the paging mode. 
+#[no_mangle]
+#[used]
+static KERNELVEC_TRAMPOLINE: usize = TRAMPOLINE;
+
+/// A kernel stack overflowed into its guard pages at addr. 
+/// Called from kernel_trap, or from kernelvec on an overflow stack 
+/// when sp itself is in the guard pages. 
+#[no_mangle]
+pub unsafe extern "C" fn kernel_stack_overflow(addr: usize) -> ! {
+    let pid = CPU_MANAGER.myproc().map(|p| p.meta.get_unchecked().pid);
+    match pid {
+        Some(pid) => panic!("kernel stack overflow in pid {} at 0x{:x}", pid, addr),
+        None => panic!("kernel stack overflow at 0x{:x}", addr)
+    }
+}
+
+/// Clock ticks since boot. 
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Channel that processes sleeping for some ticks wait on. 
+pub fn ticks_channel() -> usize {
+    unsafe{ &TICKS_LOCK as *const _ as usize }
+}
+
 pub unsafe fn clock_intr(){
     let mut ticks = TICKS_LOCK.acquire();
     *ticks = *ticks + 1;
+    TICKS.store(*ticks, Ordering::Relaxed);
+    let boost = *ticks % MLFQ_BOOST_INTERVAL == 0;
+    // Wake up the processes in sleep(). 
+    PROC_MANAGER.wake_up(ticks_channel());
+    let now = *ticks;
     drop(ticks);
+    PROC_MANAGER.expire_deadlines(now);
+    if boost {
+        PROC_MANAGER.priority_boost();
+    }
 }